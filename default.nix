@@ -35,6 +35,11 @@ pkgs.rustPlatform.buildRustPackage rec {
       --no-out-link
   '';
 
+  # `lorri-run` is `lorri run`, for `#!/usr/bin/env lorri-run` lines
+  postInstall = ''
+    ln -s lorri $out/bin/lorri-run
+  '';
+
   # Darwin fails to build doctests with:
   # dyld: Symbol not found __ZTIN4llvm2cl18GenericOptionValueE
   # see: https://github.com/NixOS/nixpkgs/pull/49839
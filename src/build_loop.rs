@@ -2,43 +2,442 @@
 //! evaluate and build a given Nix file.
 
 use crate::builder;
+use crate::config::ProjectConfig;
+use crate::env::{EnvDiff, ShellEnv};
+use crate::history;
 use crate::notify;
-use crate::pathreduction::reduce_paths;
+use crate::pathreduction::{group_paths, reduce_paths};
+use crate::project::Project;
 use crate::roots;
-use crate::roots::Roots;
-use crate::watch::Watch;
+use crate::roots::{RootPath, Roots};
+use crate::trust::Trust;
+use crate::watch::{Change, ChangeKind, FileWatcher, Watch};
 use crate::NixFile;
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::mpsc::Sender;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often a running build sends an `Event::Heartbeat`.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often a waiting `BuildLoop` which follows a shared
+/// configuration (see `BuildLoop::follow_config`) checks it for
+/// changes, and one which can be stopped whether it should stop.
+pub const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many lines of build output per second a `BuildLoop` passes
+/// on. The rest is skipped (and counted), so a build flooding its
+/// output can’t hog the daemon the other projects share.
+pub const MAX_LOG_LINES_PER_SECOND: usize = 1000;
+
+/// Identifies a single build.
+///
+/// Ids are strictly increasing within a lorri process. They are
+/// derived from the wall clock, so they also keep increasing
+/// across restarts (unless the clock is set back).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct BuildId(u64);
+
+impl BuildId {
+    /// Generate a new id, larger than every id generated before.
+    pub fn next() -> BuildId {
+        lazy_static! {
+            static ref LAST_BUILD_ID: Mutex<u64> = Mutex::new(0);
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis()))
+            .unwrap_or(0);
+        let mut last = LAST_BUILD_ID.lock().expect("build id mutex poisoned");
+        *last = std::cmp::max(*last + 1, now);
+        BuildId(*last)
+    }
+}
+
+impl std::fmt::Display for BuildId {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The inverse of `Display`, e.g. for `lorri logs --build`.
+impl std::str::FromStr for BuildId {
+    type Err = std::num::ParseIntError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(BuildId)
+    }
+}
 
 /// Builder events sent back over `BuildLoop.tx`.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Event {
     /// The build has started
-    Started,
+    Started(BuildId, Reason),
     /// The build completed successfully
     Completed(BuildResults),
     /// The build command returned a failing exit status
     Failure(BuildExitFailure),
+    /// The build is still running, sent every `HEARTBEAT_INTERVAL`
+    Heartbeat(Heartbeat),
+    /// Something unexpected happened, but the loop keeps going
+    Warning(Warning),
+    /// The build changed the variables of the shell environment
+    EnvDiff(EnvDiff),
+    /// A line of the running build’s log output
+    Log(BuildId, String),
+}
+
+/// Why a build was started.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Reason {
+    /// The `BuildLoop` just started
+    Startup,
+    /// Input files changed
+    FilesChanged(Vec<Change>),
+    /// No input files changed for the project’s `rebuild_interval`
+    Scheduled,
+    /// The project’s configuration changed how it is built
+    ConfigChanged,
+    /// The user asked for a rebuild, see `BuildLoop::rebuild_on`
+    Requested,
+}
+
+impl Reason {
+    /// Add `changes` which will be built, too. Other reasons become
+    /// `FilesChanged`, the changes are what the build is about now.
+    fn add_changes(&mut self, changes: Vec<Change>) {
+        match self {
+            Reason::FilesChanged(pending) => pending.extend(changes),
+            other => *other = Reason::FilesChanged(changes),
+        }
+    }
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Reason::Startup => write!(f, "first build"),
+            Reason::FilesChanged(changes) if changes.is_empty() => {
+                write!(f, "changes may have been missed")
+            }
+            Reason::FilesChanged(changes) => {
+                let changes = changes.iter().map(Change::to_string).collect::<Vec<_>>();
+                write!(f, "{}", changes.join(", "))
+            }
+            Reason::Scheduled => write!(f, "scheduled rebuild"),
+            Reason::ConfigChanged => write!(f, "configuration changed"),
+            Reason::Requested => write!(f, "rebuild requested"),
+        }
+    }
+}
+
+/// Problems the user should know about, which do not stop the `BuildLoop`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Warning {
+    /// These paths were written to while the build was running, so
+    /// they are most likely outputs of the build (`result` symlinks,
+    /// `node_modules`, …). They are no longer watched, to prevent
+    /// an endless rebuild loop.
+    ExcludedBuildOutputs(Vec<PathBuf>),
+    /// The operating system’s limit of file watches is exhausted.
+    /// Only the directories of new input files are watched, changes
+    /// in new subdirectories go unnoticed.
+    WatchLimitReached,
+    /// The project directory (or a parent) of the first nix file was
+    /// moved or removed. The `BuildLoop` stopped; the second one is
+    /// where the nix file is now, if it was found. Continue there with
+    /// `BuildLoop::follow_move`, which takes over the project’s state.
+    ProjectMoved(NixFile, Option<NixFile>),
+    /// The file watcher failed for this reason and was restarted.
+    /// Changes in between may have been missed, so the project is
+    /// rebuilt.
+    WatcherRestarted(String),
+    /// The nix file is not allowed as it is now, for this reason (see
+    /// `BuildLoop::check_trust`). The `BuildLoop` builds it once it is
+    /// allowed again.
+    NotAllowed(String),
+    /// The `BuildLoop` crashed with this message. The daemon starts a
+    /// new one after the given delay, which grows with every crash.
+    Crashed(String, Duration),
+}
+
+impl std::fmt::Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Warning::ExcludedBuildOutputs(paths) => {
+                writeln!(
+                    f,
+                    "These paths were changed by the build and will not be watched:"
+                )?;
+                for path in paths {
+                    writeln!(f, "  {}", path.display())?;
+                }
+                Ok(())
+            }
+            Warning::ProjectMoved(nix_file, Some(moved)) => writeln!(
+                f,
+                "The directory of {} was moved, continuing with {}.",
+                nix_file, moved
+            ),
+            Warning::ProjectMoved(nix_file, None) => writeln!(
+                f,
+                "The directory of {} was moved or removed, no longer watching it.",
+                nix_file
+            ),
+            Warning::WatchLimitReached => writeln!(
+                f,
+                "Reached the limit of file watches, some changes will go unnoticed. \
+                 Raise it with `sysctl fs.inotify.max_user_watches=524288`, \
+                 or `boot.kernel.sysctl.\"fs.inotify.max_user_watches\"` on NixOS."
+            ),
+            Warning::WatcherRestarted(reason) => writeln!(
+                f,
+                "The file watcher was restarted ({}), rebuilding in case changes were missed.",
+                reason
+            ),
+            Warning::NotAllowed(reason) => writeln!(f, "Not building: {}", reason),
+            Warning::Crashed(message, restart_in) => writeln!(
+                f,
+                "The build loop crashed ({}), restarting it in {}s. Please report this as a bug.",
+                message,
+                restart_in.as_secs()
+            ),
+        }
+    }
+}
+
+/// What a `BuildLoop` watches, to find out why a change did or
+/// didn’t trigger a rebuild. Written to `Roots::watch_set_file`
+/// after every build.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WatchSet {
+    /// Every path with a watch, including subdirectories of
+    /// watched directories.
+    pub watched: Vec<PathBuf>,
+    /// The input files of the last evaluation, grouped by the
+    /// path they were reduced to before watching.
+    pub inputs: BTreeMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl WatchSet {
+    /// Read the watch set the `BuildLoop` of a project recorded.
+    pub fn load(roots: &Roots) -> Result<WatchSet, std::io::Error> {
+        let contents = std::fs::read(roots.watch_set_file())?;
+        serde_json::from_slice(&contents)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    fn save(&self, roots: &Roots) -> Result<(), std::io::Error> {
+        let path = roots.watch_set_file();
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        std::fs::rename(&tmp, &path)
+    }
+}
+
+/// Sign of life of a build which has not finished yet.
+/// Lets consumers tell a long-running build apart from a hung one.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Heartbeat {
+    /// The build which is still running
+    pub build_id: BuildId,
+    /// What the build is doing right now
+    pub phase: builder::Phase,
+    /// Time since the build was started
+    pub elapsed: Duration,
+}
+
+/// Consumer of the `Event`s a `BuildLoop` produces.
+///
+/// Implemented for `mpsc::Sender<Event>` and for closures, so events
+/// can be passed on to channels, sockets or callbacks directly,
+/// without a thread bridging between them.
+pub trait EventHandler: Send {
+    /// Called for every event, in the order they happen.
+    fn handle(&mut self, event: Event);
+}
+
+impl EventHandler for Sender<Event> {
+    fn handle(&mut self, event: Event) {
+        self.send(event)
+            .expect("Failed to send a build event, the receiver hung up")
+    }
+}
+
+impl<F> EventHandler for F
+where
+    F: FnMut(Event) + Send,
+{
+    fn handle(&mut self, event: Event) {
+        self(event)
+    }
 }
 
 /// Results of a single, successful build.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BuildResults {
+    /// The build which produced these results
+    pub build_id: BuildId,
+    /// The results of a build before the `BuildLoop` started, which
+    /// `forever` sends first. No `Event::Started` precedes them.
+    #[serde(default)]
+    pub cached: bool,
     /// See `build::Info.drvs`
-    drvs: HashMap<usize, PathBuf>,
+    drvs: HashMap<usize, RootPath>,
     /// See `build::Info.drvs`
-    pub named_drvs: HashMap<String, PathBuf>,
+    pub named_drvs: HashMap<String, RootPath>,
 }
 
 /// Results of a single, failing build.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct BuildExitFailure {
+    /// The build which failed
+    pub build_id: BuildId,
     /// stderr log output
     pub log_lines: Vec<String>,
 }
 
+/// Counts the log lines of a build, see `MAX_LOG_LINES_PER_SECOND`.
+struct LogLimit {
+    /// When the current second started.
+    window: Instant,
+    /// Lines passed on within the current second.
+    sent: usize,
+    /// Lines skipped since the last `take_skipped`.
+    skipped: usize,
+}
+
+impl LogLimit {
+    fn new(now: Instant) -> LogLimit {
+        LogLimit {
+            window: now,
+            sent: 0,
+            skipped: 0,
+        }
+    }
+
+    /// Whether a line arriving at `now` is passed on.
+    fn admit(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.window) >= Duration::from_secs(1) {
+            self.window = now;
+            self.sent = 0;
+        }
+        if self.sent < MAX_LOG_LINES_PER_SECOND {
+            self.sent += 1;
+            true
+        } else {
+            self.skipped += 1;
+            false
+        }
+    }
+
+    /// How many lines were skipped since the last call.
+    fn take_skipped(&mut self) -> usize {
+        std::mem::replace(&mut self.skipped, 0)
+    }
+}
+
+/// Limits how many `BuildLoop`s build at the same time.
+///
+/// Clones share their slots. A `BuildLoop` which wants to build while
+/// all slots are taken waits until another one finishes.
+#[derive(Clone, Debug)]
+pub struct BuildSlots(Arc<(Mutex<SlotCount>, Condvar)>);
+
+/// How many slots of `BuildSlots` there are, how many are taken and
+/// how many builds wait for one.
+#[derive(Debug)]
+struct SlotCount {
+    size: usize,
+    taken: usize,
+    waiting: usize,
+}
+
+/// A taken slot of `BuildSlots`, freed when dropped.
+struct Slot(BuildSlots);
+
+impl BuildSlots {
+    /// Allow `size` builds at the same time (at least one).
+    pub fn new(size: usize) -> BuildSlots {
+        BuildSlots(Arc::new((
+            Mutex::new(SlotCount {
+                size: size.max(1),
+                taken: 0,
+                waiting: 0,
+            }),
+            Condvar::new(),
+        )))
+    }
+
+    /// How many builds may run at the same time.
+    pub fn size(&self) -> usize {
+        self.count().size
+    }
+
+    /// How many builds are running.
+    pub fn taken(&self) -> usize {
+        self.count().taken
+    }
+
+    /// How many builds wait for a free slot.
+    pub fn waiting(&self) -> usize {
+        self.count().waiting
+    }
+
+    /// Allow `size` builds at the same time from now on (at least
+    /// one). Running builds are not affected, if there are more than
+    /// `size` of them the others wait until enough have finished.
+    pub fn resize(&self, size: usize) {
+        self.count().size = size.max(1);
+        self.freed().notify_all();
+    }
+
+    fn count(&self) -> std::sync::MutexGuard<'_, SlotCount> {
+        // only plain counters, which a panic can’t leave half-updated
+        (self.0)
+            .0
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Signals that a slot was freed, or that there are more.
+    fn freed(&self) -> &Condvar {
+        &(self.0).1
+    }
+
+    /// Take a free slot, waiting for one if necessary.
+    fn take(&self, nix_file: &NixFile) -> Slot {
+        let mut count = self.count();
+        if count.taken >= count.size {
+            info!(
+                "{}: waiting, {} build(s) are running already",
+                nix_file, count.taken
+            );
+        }
+        count.waiting += 1;
+        while count.taken >= count.size {
+            count = self
+                .freed()
+                .wait(count)
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+        }
+        count.waiting -= 1;
+        count.taken += 1;
+        Slot(self.clone())
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.count().taken -= 1;
+        self.0.freed().notify_one();
+    }
+}
+
 /// The BuildLoop repeatedly builds the Nix expression in
 /// `nix_root_path` each time a source file influencing
 /// a previous build changes.
@@ -49,48 +448,653 @@ pub struct BuildLoop {
     roots: Roots,
     /// Watches all input files for changes.
     /// As new input files are discovered, they are added to the watchlist.
-    watch: Watch,
+    watch: Box<dyn FileWatcher>,
+    /// Project settings, like the interval of scheduled rebuilds.
+    config: ProjectConfig,
+    /// Input files changed while the last build was running,
+    /// so the next build should start right away.
+    pending_changes: Vec<Change>,
+    /// Warnings collected during the last build, not yet sent out.
+    warnings: Vec<Warning>,
+    /// The inputs of the last evaluation, see `WatchSet::inputs`.
+    inputs: BTreeMap<PathBuf, Vec<PathBuf>>,
+    /// How the last build changed the shell environment, not yet sent out.
+    env_diff: Option<EnvDiff>,
+    /// Shared with other `BuildLoop`s to limit concurrent builds, if set.
+    slots: Option<BuildSlots>,
+    /// Replaces `config` whenever it changes, if set.
+    shared_config: Option<Arc<Mutex<ProjectConfig>>>,
+    /// Stop once this is set, see `stop_on`.
+    stop: Option<Arc<AtomicBool>>,
+    /// Rebuild once this is set, see `rebuild_on`.
+    rebuild: Option<Arc<AtomicBool>>,
+    /// Don’t start builds while this is set, see `pause_on`.
+    paused: Option<Arc<AtomicBool>>,
+    /// Only build what the user allowed, see `check_trust`.
+    trust: Option<Trust>,
+    /// Where the nix file was moved to, see `follow_move`.
+    moved_to: Option<NixFile>,
 }
 
 impl BuildLoop {
     /// Instatiate a new BuildLoop. Uses an internal filesystem
     /// watching implementation.
-    pub fn new(nix_root_path: NixFile, roots: Roots) -> BuildLoop {
+    pub fn new(nix_root_path: NixFile, roots: Roots, config: ProjectConfig) -> BuildLoop {
+        let project_dir = Self::project_dir_of(&nix_root_path);
+        let watch = Watch::init_for(config.watch_backend(), config.poll_interval(), &project_dir)
+            .expect("Failed to initialize watch");
+        BuildLoop::with_watch(nix_root_path, roots, config, Box::new(watch))
+    }
+
+    /// Instatiate a new BuildLoop which uses `watch` to find out
+    /// about changes, e.g. a `watch::scripted::ScriptedWatch` in tests.
+    pub fn with_watch(
+        nix_root_path: NixFile,
+        roots: Roots,
+        config: ProjectConfig,
+        mut watch: Box<dyn FileWatcher>,
+    ) -> BuildLoop {
+        let project_dir = Self::project_dir_of(&nix_root_path);
+        watch.respect_gitignore(config.respect_gitignore());
+        watch.ignore(project_dir.clone(), config.ignore());
+        if config.project_gc_root() {
+            // written by every build, never an input
+            watch.exclude(&[project_dir.join(".lorri")]);
+        }
         BuildLoop {
             nix_root_path,
             roots,
-            watch: Watch::init().expect("Failed to initialize watch"),
+            watch,
+            config,
+            pending_changes: vec![],
+            warnings: vec![],
+            inputs: BTreeMap::new(),
+            env_diff: None,
+            slots: None,
+            shared_config: None,
+            stop: None,
+            rebuild: None,
+            paused: None,
+            trust: None,
+            moved_to: None,
+        }
+    }
+
+    /// Apply the settings in `config` whenever they change, e.g. when
+    /// the daemon reloads its configuration. Running builds finish
+    /// with the settings they started with; settings which affect the
+    /// result (like `attr`) start a new build.
+    /// The watch backend can’t be changed this way.
+    pub fn follow_config(&mut self, config: Arc<Mutex<ProjectConfig>>) {
+        self.shared_config = Some(config);
+    }
+
+    /// Switch to the shared configuration, if it changed.
+    /// Returns whether the project has to be rebuilt because of that.
+    fn apply_config_changes(&mut self) -> bool {
+        let config = match self.shared_config {
+            Some(ref shared) => shared.lock().expect("config mutex poisoned").clone(),
+            None => return false,
+        };
+        if config == self.config {
+            return false;
+        }
+        info!("{}: applying the changed configuration", self.nix_root_path);
+        if config.watch_backend() != self.config.watch_backend()
+            || config.poll_interval() != self.config.poll_interval()
+        {
+            warn!(
+                "{}: the watch backend only changes when the daemon restarts",
+                self.nix_root_path
+            );
+        }
+        let project_dir = Self::project_dir_of(&self.nix_root_path);
+        self.watch.respect_gitignore(config.respect_gitignore());
+        self.watch.ignore(project_dir.clone(), config.ignore());
+        if config.project_gc_root() {
+            self.watch.exclude(&[project_dir.join(".lorri")]);
         }
+        let rebuild = config.attr() != self.config.attr()
+            || config.project_gc_root() != self.config.project_gc_root();
+        self.config = config;
+        rebuild
+    }
+
+    /// Return from `forever` soon after `stop` is set, instead of
+    /// waiting for the next change. A running build is not
+    /// interrupted, cancel it with `builder::cancel`.
+    pub fn stop_on(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = Some(stop);
+    }
+
+    fn stopped(&self) -> bool {
+        self.stop
+            .as_ref()
+            .map_or(false, |stop| stop.load(Ordering::SeqCst))
+    }
+
+    /// Start a build soon after `rebuild` is set, even without
+    /// changes, and unset it. If a build is running, the next one
+    /// starts right after it.
+    pub fn rebuild_on(&mut self, rebuild: Arc<AtomicBool>) {
+        self.rebuild = Some(rebuild);
+    }
+
+    fn rebuild_requested(&self) -> bool {
+        self.rebuild
+            .as_ref()
+            .map_or(false, |rebuild| rebuild.swap(false, Ordering::SeqCst))
+    }
+
+    /// Don’t start builds for changes while `paused` is set. The
+    /// changes are collected and built once it is unset; a requested
+    /// rebuild (see `rebuild_on`) builds anyway.
+    pub fn pause_on(&mut self, paused: Arc<AtomicBool>) {
+        self.paused = Some(paused);
+    }
+
+    fn paused(&self) -> bool {
+        self.paused
+            .as_ref()
+            .map_or(false, |paused| paused.load(Ordering::SeqCst))
+    }
+
+    /// Check that the user allowed the nix file (see `trust`) before
+    /// every build. While it is not allowed, e.g. after it changed,
+    /// the `BuildLoop` sends a `Warning::NotAllowed` and waits,
+    /// collecting changes, until it is allowed again.
+    pub fn check_trust(&mut self, trust: Trust) {
+        self.trust = Some(trust);
+    }
+
+    /// Continue with the project at its new location after `forever`
+    /// returned because its directory was moved (see
+    /// `Warning::ProjectMoved`). The loop keeps its settings, and
+    /// takes over the project’s state in `gc_root_dir`. `false` if the
+    /// project was not moved, or its new location is unknown.
+    pub fn follow_move(&mut self, gc_root_dir: &Path) -> Result<bool, std::io::Error> {
+        let nix_file = match self.moved_to.take() {
+            Some(nix_file) => nix_file,
+            None => return Ok(false),
+        };
+        let roots = Roots::from_project(&Project::new(&nix_file, gc_root_dir))?;
+        info!("{}: continuing with {}", self.nix_root_path, nix_file);
+        let mut moved = BuildLoop::new(nix_file, roots, self.config.clone());
+        moved.slots = self.slots.take();
+        moved.shared_config = self.shared_config.take();
+        moved.stop = self.stop.take();
+        moved.rebuild = self.rebuild.take();
+        moved.paused = self.paused.take();
+        moved.trust = self.trust.take();
+        *self = moved;
+        Ok(true)
+    }
+
+    /// The nix file the loop builds, which changes with `follow_move`.
+    pub fn nix_file(&self) -> &NixFile {
+        &self.nix_root_path
+    }
+
+    /// The GC roots of the project, which change with `follow_move`.
+    pub fn roots(&self) -> &Roots {
+        &self.roots
+    }
+
+    /// Block while the nix file, as it is now, is not allowed (see
+    /// `check_trust`). Changes arriving meanwhile are added to
+    /// `reason`. `false` if the loop should stop instead.
+    fn wait_until_allowed(&mut self, reason: &mut Reason, send: &dyn Fn(Event)) -> bool {
+        let mut warned = false;
+        loop {
+            let not_allowed = match self.trust {
+                Some(ref trust) => match trust.check(&self.nix_root_path) {
+                    Ok(()) => return true,
+                    Err(e) => e,
+                },
+                None => return true,
+            };
+            if !warned {
+                warn!("{}: not building, {}", self.nix_root_path, not_allowed);
+                send(Event::Warning(Warning::NotAllowed(not_allowed.to_string())));
+                warned = true;
+            }
+            if self.stopped() {
+                return false;
+            }
+            // wake up regularly to look for a `lorri allow`
+            if let Some(changes) = self.watch.block_timeout(CONFIG_CHECK_INTERVAL) {
+                if let Some(restart) = self.watch.take_restart() {
+                    self.warnings.push(Warning::WatcherRestarted(restart));
+                }
+                reason.add_changes(changes);
+            }
+        }
+    }
+
+    /// Only build while holding one of `slots`, so that `BuildLoop`s
+    /// sharing them build at most `slots.size()` projects at a time.
+    pub fn share_slots(&mut self, slots: BuildSlots) {
+        self.slots = Some(slots);
     }
 
     /// Loop forever, watching the filesystem for changes. Blocks.
-    /// Sends `Event`s over `Self.tx` once they happen.
+    /// Passes `Event`s to `handler` once they happen.
     /// When new filesystem changes are detected while a build is
     /// still running, it is finished first before starting a new build.
-    pub fn forever(&mut self, tx: Sender<Event>) {
+    /// If the project has a `rebuild_interval`, a new build is also
+    /// started when no change was detected for that long. With a
+    /// `min_build_interval`, builds start at most that often.
+    /// Returns if the project directory is moved or removed, or when
+    /// asked to (see `stop_on`).
+    pub fn forever<H>(&mut self, handler: H)
+    where
+        H: EventHandler + 'static,
+    {
+        // shared with the heartbeat thread
+        let handler = Arc::new(Mutex::new(handler));
+        let send = |event: Event| {
+            handler
+                .lock()
+                .expect("event handler mutex poisoned")
+                .handle(event)
+        };
+
+        match self.roots.verify() {
+            Ok(problems) => {
+                for problem in &problems {
+                    info!("Removing a broken GC root: {}", problem);
+                }
+                if let Err(e) = self.roots.repair(&problems) {
+                    warn!("Failed to remove broken GC roots: {}", e);
+                }
+            }
+            Err(e) => debug!("could not verify the GC roots: {}", e),
+        }
+
+        // Don’t make consumers wait for the first build if a previous
+        // run left us with a usable result. It is marked as cached,
+        // since this loop never started that build.
+        if let Some(cached) = self.previous_results() {
+            send(Event::Completed(cached));
+        }
+
+        let mut reason = Reason::Startup;
+        let mut last_started = None;
         loop {
+            if let Some(last_started) = last_started {
+                if !self.throttle(last_started, &mut reason) {
+                    info!("{}: stopped", self.nix_root_path);
+                    return;
+                }
+            }
+            if !self.wait_until_allowed(&mut reason, &send) {
+                info!("{}: stopped", self.nix_root_path);
+                return;
+            }
             // TODO: Make err use Display instead of Debug.
             // Otherwise user errors (especially for IO errors)
             // are pretty hard to debug. Might need to review
             // whether we can handle some errors earlier than here.
-            tx.send(Event::Started)
-                .expect("Failed to notify a started evaluation");
+            // queued builds only count as started once they run
+            let slot = self
+                .slots
+                .as_ref()
+                .map(|slots| slots.take(&self.nix_root_path));
+            if self.stopped() {
+                return;
+            }
+            // to find the nix file again if its directory is moved
+            if let Err(e) = self.roots.record_project(&self.nix_root_path) {
+                debug!("could not record {}: {}", self.nix_root_path, e);
+            }
+            let build_id = BuildId::next();
+            let started = Instant::now();
+            let started_at = SystemTime::now();
+            last_started = Some(started);
+            send(Event::Started(build_id, reason.clone()));
 
-            match self.once() {
-                Ok(result) => {
-                    tx.send(Event::Completed(result))
-                        .expect("Failed to notify the results of a completed evaluation");
-                }
-                Err(BuildError::Recoverable(failure)) => {
-                    tx.send(Event::Failure(failure))
-                        .expect("Failed to notify the results of a failed evaluation");
+            let (log_tx, log_rx) = channel();
+            let stored_log = history::BuildLog::create(&self.roots, build_id)
+                .map_err(|e| warn!("Failed to store the build log: {}", e))
+                .ok();
+            let log_forwarder = Self::forward_log(handler.clone(), build_id, log_rx, stored_log);
+            let progress = builder::Progress::with_log(log_tx);
+            let stop_heartbeat = Self::start_heartbeat(handler.clone(), build_id, progress.clone());
+            let result = self.build(build_id, &progress);
+            drop(stop_heartbeat);
+            drop(slot);
+            // all log lines are sent before the result
+            drop(progress);
+            if log_forwarder.join().is_err() {
+                warn!("the log forwarding thread panicked");
+            }
+
+            for warning in self.warnings.drain(..) {
+                send(Event::Warning(warning));
+            }
+
+            let env_diff = self.env_diff.take();
+            let outcome = match result {
+                Ok(_) => Some(history::Outcome::Success),
+                Err(BuildError::Recoverable(_)) => Some(history::Outcome::Failure),
+                Err(_) => None,
+            };
+            if let Some(outcome) = outcome {
+                let record = history::BuildRecord::new(
+                    build_id,
+                    &reason,
+                    started_at,
+                    started.elapsed(),
+                    outcome,
+                    env_diff.as_ref(),
+                );
+                if let Err(e) = history::append(&self.roots, record) {
+                    warn!("Failed to record the build: {}", e);
                 }
+            }
+            match result {
+                Ok(result) => send(Event::Completed(result)),
+                Err(BuildError::Recoverable(failure)) => send(Event::Failure(failure)),
                 otherwise => {
                     otherwise.unwrap();
                 }
             }
+            if let Some(diff) = env_diff {
+                send(Event::EnvDiff(diff));
+            }
+
+            reason = match self.wait_for_trigger() {
+                Some(reason) => reason,
+                None if self.stopped() => {
+                    info!("{}: stopped", self.nix_root_path);
+                    return;
+                }
+                None => {
+                    warn!("{} is gone, stopping", self.nix_root_path);
+                    self.moved_to = self.roots.moved_nix_file();
+                    send(Event::Warning(Warning::ProjectMoved(
+                        self.nix_root_path.clone(),
+                        self.moved_to.clone(),
+                    )));
+                    return;
+                }
+            };
+        }
+    }
+
+    /// The results of the last successful build of a previous run,
+    /// if their GC roots still resolve. They keep the id that build
+    /// has in the history, if it is recorded.
+    fn previous_results(&self) -> Option<BuildResults> {
+        let roots = match self.roots.existing() {
+            Ok(roots) => roots,
+            Err(e) => {
+                debug!("could not read previous GC roots: {}", e);
+                return None;
+            }
+        };
+
+        let build_id = history::load(&self.roots)
+            .unwrap_or_default()
+            .iter()
+            .rev()
+            .find(|record| record.outcome == history::Outcome::Success)
+            .map(|record| record.build_id);
+        let mut results = BuildResults {
+            build_id: build_id.unwrap_or_else(BuildId::next),
+            cached: true,
+            drvs: HashMap::new(),
+            named_drvs: HashMap::new(),
+        };
+        for (name, path) in roots {
+            if name.starts_with("attr-") {
+                results
+                    .named_drvs
+                    .insert(name["attr-".len()..].to_string(), path);
+            } else if name.starts_with("build-") {
+                if let Ok(i) = name["build-".len()..].parse::<usize>() {
+                    results.drvs.insert(i, path);
+                }
+            }
+        }
+
+        if results.drvs.is_empty() {
+            None
+        } else {
+            info!("reusing the results of a previous build: {:?}", results);
+            Some(results)
+        }
+    }
+
+    /// Block until the next build should start.
+    /// `None` if the project directory is gone, or the loop should stop.
+    fn wait_for_trigger(&mut self) -> Option<Reason> {
+        let waiting_since = Instant::now();
+        loop {
+            if self.stopped() {
+                return None;
+            }
+            if self.rebuild_requested() {
+                self.pending_changes.clear();
+                return Some(Reason::Requested);
+            }
+            if self.paused() {
+                if let Some(changes) = self.watch.block_timeout(CONFIG_CHECK_INTERVAL) {
+                    self.pending_changes.extend(changes);
+                }
+                continue;
+            }
+            if !self.pending_changes.is_empty() {
+                return Some(Reason::FilesChanged(std::mem::replace(
+                    &mut self.pending_changes,
+                    vec![],
+                )));
+            }
+            if self.apply_config_changes() {
+                return Some(Reason::ConfigChanged);
+            }
+            let scheduled = self.config.rebuild_interval().map(|interval| {
+                let now = Instant::now();
+                if waiting_since + interval > now {
+                    waiting_since + interval - now
+                } else {
+                    Duration::from_secs(0)
+                }
+            });
+            // wake up regularly to look for configuration changes
+            let polling = self.shared_config.is_some()
+                || self.stop.is_some()
+                || self.rebuild.is_some()
+                || self.paused.is_some();
+            let timeout = match (scheduled, polling) {
+                (Some(scheduled), true) => Some(std::cmp::min(scheduled, CONFIG_CHECK_INTERVAL)),
+                (None, true) => Some(CONFIG_CHECK_INTERVAL),
+                (scheduled, false) => scheduled,
+            };
+            let changes = match timeout {
+                None => match self.watch.wait_for_change() {
+                    Some(changes) => changes,
+                    None => {
+                        warn!("The file watcher stopped, retrying in a second");
+                        std::thread::sleep(Duration::from_secs(1));
+                        continue;
+                    }
+                },
+                Some(timeout) => match self.watch.block_timeout(timeout) {
+                    Some(changes) => changes,
+                    // only checking the configuration
+                    None if Some(timeout) != scheduled => continue,
+                    None => {
+                        info!(
+                            "no changes for {}s, starting a scheduled rebuild",
+                            waiting_since.elapsed().as_secs()
+                        );
+                        return Some(Reason::Scheduled);
+                    }
+                },
+            };
 
-            self.watch.wait_for_change().expect("Waiter exited");
+            if let Some(reason) = self.watch.take_restart() {
+                self.warnings.push(Warning::WatcherRestarted(reason));
+            }
+
+            if self.project_dir_gone() {
+                return None;
+            }
+
+            // Building without the root file fails for sure. It is
+            // most likely being saved atomically, so wait until the
+            // new version is in place.
+            if self.root_file_removed(&changes) {
+                info!(
+                    "{} was removed, waiting for it to reappear",
+                    self.nix_root_path
+                );
+                continue;
+            }
+            return Some(Reason::FilesChanged(changes));
+        }
+    }
+
+    /// Wait until the project’s `min_build_interval` passed since the
+    /// last build started at `last_started`. Changes arriving
+    /// meanwhile are added to `reason`, they are built anyway.
+    /// `false` if the loop should stop instead.
+    fn throttle(&mut self, last_started: Instant, reason: &mut Reason) -> bool {
+        let next_start = match self.config.min_build_interval() {
+            Some(interval) => last_started + interval,
+            None => return true,
+        };
+        let mut waiting = false;
+        loop {
+            if self.stopped() {
+                return false;
+            }
+            let now = Instant::now();
+            if next_start <= now {
+                return true;
+            }
+            let remaining = next_start - now;
+            if !waiting {
+                info!(
+                    "{}: waiting {}s before the next build (min_build_interval)",
+                    self.nix_root_path,
+                    remaining.as_secs()
+                );
+                waiting = true;
+            }
+            // wake up regularly to look for a stop
+            let timeout = std::cmp::min(remaining, CONFIG_CHECK_INTERVAL);
+            if let Some(changes) = self.watch.block_timeout(timeout) {
+                if let Some(restart) = self.watch.take_restart() {
+                    self.warnings.push(Warning::WatcherRestarted(restart));
+                }
+                reason.add_changes(changes);
+            }
+        }
+    }
+
+    /// The directory of the project’s nix file.
+    fn project_dir_of(nix_root_path: &NixFile) -> PathBuf {
+        Path::new(nix_root_path.as_os_str())
+            .parent()
+            .unwrap_or_else(|| Path::new("/"))
+            .to_path_buf()
+    }
+
+    fn project_dir_gone(&self) -> bool {
+        let root = Path::new(self.nix_root_path.as_os_str());
+        !root.exists() && !root.parent().map_or(false, Path::exists)
+    }
+
+    fn root_file_removed(&self, changes: &[Change]) -> bool {
+        let root = Path::new(self.nix_root_path.as_os_str());
+        !root.exists()
+            && changes.iter().any(|change| {
+                change.path == root
+                    && (change.kind == ChangeKind::Removed || change.kind == ChangeKind::Renamed)
+            })
+    }
+
+    /// Send an `Event::Heartbeat` every `HEARTBEAT_INTERVAL`,
+    /// until the returned `Sender` is dropped.
+    fn start_heartbeat<H>(
+        handler: Arc<Mutex<H>>,
+        build_id: BuildId,
+        progress: builder::Progress,
+    ) -> Sender<()>
+    where
+        H: EventHandler + 'static,
+    {
+        let (stop_tx, stop_rx) = channel::<()>();
+        let start = Instant::now();
+        thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stop_rx.recv_timeout(HEARTBEAT_INTERVAL) {
+                let heartbeat = Heartbeat {
+                    build_id,
+                    phase: progress.phase(),
+                    elapsed: start.elapsed(),
+                };
+                handler
+                    .lock()
+                    .expect("event handler mutex poisoned")
+                    .handle(Event::Heartbeat(heartbeat));
+            }
+        });
+        stop_tx
+    }
+
+    /// Send every line received on `log` as an `Event::Log`, until
+    /// the channel is closed. Lines beyond `MAX_LOG_LINES_PER_SECOND`
+    /// are replaced by a note how many were skipped. `stored` gets
+    /// every line.
+    fn forward_log<H>(
+        handler: Arc<Mutex<H>>,
+        build_id: BuildId,
+        log: Receiver<String>,
+        mut stored: Option<history::BuildLog>,
+    ) -> thread::JoinHandle<()>
+    where
+        H: EventHandler + 'static,
+    {
+        let skipped_note = move |skipped| {
+            Event::Log(
+                build_id,
+                format!("lorri: skipped {} lines of build output", skipped),
+            )
+        };
+        thread::spawn(move || {
+            let mut limit = LogLimit::new(Instant::now());
+            for line in log {
+                if let Some(Err(e)) = stored.as_mut().map(|stored| stored.write_line(&line)) {
+                    warn!("Failed to store the build log: {}", e);
+                    stored = None;
+                }
+                if !limit.admit(Instant::now()) {
+                    continue;
+                }
+                let mut handler = handler.lock().expect("event handler mutex poisoned");
+                let skipped = limit.take_skipped();
+                if skipped > 0 {
+                    handler.handle(skipped_note(skipped));
+                }
+                handler.handle(Event::Log(build_id, line));
+            }
+            let skipped = limit.take_skipped();
+            if skipped > 0 {
+                handler
+                    .lock()
+                    .expect("event handler mutex poisoned")
+                    .handle(skipped_note(skipped));
+            }
+        })
+    }
+
+    /// The paths currently watched for changes.
+    pub fn watch_set(&self) -> WatchSet {
+        WatchSet {
+            watched: self.watch.watched_paths(),
+            inputs: self.inputs.clone(),
         }
     }
 
@@ -99,17 +1103,35 @@ impl BuildLoop {
     /// This will create GC roots and expand the file watch list for
     /// the evaluation.
     pub fn once(&mut self) -> Result<BuildResults, BuildError> {
-        let build = builder::run(&self.nix_root_path)?;
+        self.once_with(&builder::Progress::new())
+    }
+
+    /// Like `once`, reporting the phase and log of the build to
+    /// `progress`.
+    pub fn once_with(&mut self, progress: &builder::Progress) -> Result<BuildResults, BuildError> {
+        self.build(BuildId::next(), progress)
+    }
+
+    fn build(
+        &mut self,
+        build_id: BuildId,
+        progress: &builder::Progress,
+    ) -> Result<BuildResults, BuildError> {
+        let build = builder::run(&self.nix_root_path, self.config.attr(), progress)?;
 
-        let paths = build.paths;
-        debug!("original paths: {:?}", paths.len());
+        self.exclude_build_outputs(&build.paths);
 
-        let paths = reduce_paths(&paths);
+        debug!("original paths: {:?}", build.paths.len());
+
+        let paths = reduce_paths(&build.paths);
         debug!("  -> reduced to: {:?}", paths.len());
+        self.inputs = group_paths(&build.paths, &paths);
 
         debug!("named drvs: {:#?}", build.named_drvs);
 
         let mut event = BuildResults {
+            build_id,
+            cached: false,
             drvs: HashMap::new(),
             named_drvs: HashMap::new(),
         };
@@ -125,20 +1147,109 @@ impl BuildLoop {
                 .drvs
                 .insert(i, self.roots.add(&format!("build-{}", i), &drv)?);
         }
+        // the first result is the shell environment
+        if let Some(shell) = build.drvs.first() {
+            let previous = ShellEnv::load(&self.roots.shell_gc_root()).ok();
+            self.roots.update_shell_gc_root(shell)?;
+            if self.config.project_gc_root() {
+                let project_dir = Self::project_dir_of(&self.nix_root_path);
+                self.roots.update_project_gc_root(&project_dir, shell)?;
+            }
+            if let Ok(current) = ShellEnv::load(&self.roots.shell_gc_root()) {
+                if let Err(e) = current.save_dump(&self.roots) {
+                    warn!("Failed to dump the environment: {}", e);
+                }
+                if let Err(e) = history::save_env(&self.roots, build_id, &current) {
+                    warn!("Failed to store the environment: {}", e);
+                }
+                if let Some(previous) = previous {
+                    let diff = previous.diff(&current);
+                    if !diff.is_empty() {
+                        self.env_diff = Some(diff);
+                    }
+                }
+            }
+        }
 
-        // add all new (reduced) nix sources to the input source watchlist
-        self.watch.extend(&paths.into_iter().collect::<Vec<_>>())?;
+        // watch exactly the (reduced) nix sources of this evaluation
+        self.watch.update(&paths.into_iter().collect::<Vec<_>>())?;
+        if self.watch.take_watch_limit_reached() {
+            self.warnings.push(Warning::WatchLimitReached);
+        }
+        if let Err(e) = self.watch_set().save(&self.roots) {
+            warn!("Failed to record the watched paths: {}", e);
+        }
 
         if build.exec_result.success() {
+            if let Err(e) = self.roots.update_build_marker(&build_id.to_string()) {
+                warn!("Failed to update the build marker: {}", e);
+            }
             Ok(event)
         } else {
             Err(BuildError::Recoverable(BuildExitFailure {
+                build_id,
                 log_lines: build.log_lines,
             }))
         }
     }
 }
 
+impl BuildLoop {
+    /// Look at the changes which happened while the build was running.
+    ///
+    /// Changes to files the evaluation read, or to anything below
+    /// the directories it read, are real changes (e.g. edits during
+    /// the build) and trigger the next build. Only paths outside of
+    /// every input were written by the build itself and are excluded
+    /// from the watch, otherwise we would rebuild forever.
+    fn exclude_build_outputs(&mut self, inputs: &[PathBuf]) {
+        // compare both as given and with symlinks resolved
+        let inputs = inputs
+            .iter()
+            .flat_map(|input| vec![input.clone(), resolve(input)])
+            .collect::<HashSet<PathBuf>>();
+        let below_inputs = |path: &Path| {
+            let resolved = resolve(path);
+            inputs
+                .iter()
+                .any(|input| path.starts_with(input) || resolved.starts_with(input))
+        };
+        let (changed_inputs, outputs): (Vec<Change>, Vec<Change>) = self
+            .watch
+            .drain_changes()
+            .into_iter()
+            .partition(|change| below_inputs(&change.path));
+
+        if !changed_inputs.is_empty() {
+            debug!("inputs changed during the build: {:?}", changed_inputs);
+            self.pending_changes.extend(changed_inputs);
+        }
+        let mut outputs = outputs
+            .into_iter()
+            .map(|change| change.path)
+            .collect::<Vec<PathBuf>>();
+        outputs.sort();
+        outputs.dedup();
+        if !outputs.is_empty() {
+            warn!("excluding paths written by the build: {:?}", outputs);
+            self.watch.exclude(&outputs);
+            self.warnings.push(Warning::ExcludedBuildOutputs(outputs));
+        }
+    }
+}
+
+/// `path` with all symlinks resolved, as far as it exists: a removed
+/// file is resolved through its directory.
+fn resolve(path: &Path) -> PathBuf {
+    if let Ok(resolved) = path.canonicalize() {
+        return resolved;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => resolve(dir).join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
 /// Error classes returnable from a build.
 ///
 /// Callers should probably exit on Unrecoverable errors, but retry
@@ -182,3 +1293,422 @@ impl From<notify::Error> for BuildError {
         BuildError::Unrecoverable(UnrecoverableErrors::Notify(e))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        BuildId, BuildLoop, BuildSlots, Event, LogLimit, Reason, Warning, MAX_LOG_LINES_PER_SECOND,
+    };
+    use crate::config::ProjectConfig;
+    use crate::history;
+    use crate::project::Project;
+    use crate::roots::Roots;
+    use crate::trust::Trust;
+    use crate::watch::scripted::{ScriptedWatch, Step};
+    use crate::watch::{Change, ChangeKind};
+    use crate::NixFile;
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tempfile::{tempdir, TempDir};
+
+    /// A `BuildLoop` for a `shell.nix` in a temporary directory.
+    fn build_loop(
+        config: ProjectConfig,
+        watch: ScriptedWatch,
+    ) -> (BuildLoop, PathBuf, TempDir, TempDir) {
+        let project_dir = tempdir().unwrap();
+        let gc_root = tempdir().unwrap();
+        let shell_nix = project_dir.path().join("shell.nix");
+        std::fs::write(&shell_nix, "{}").unwrap();
+        let nix_file = NixFile::from(shell_nix.clone());
+        let roots = Roots::from_project(&Project::new(&nix_file, gc_root.path())).unwrap();
+        let build_loop = BuildLoop::with_watch(nix_file, roots, config, Box::new(watch));
+        (build_loop, shell_nix, project_dir, gc_root)
+    }
+
+    fn change(path: &Path, kind: ChangeKind) -> Change {
+        Change {
+            path: path.to_path_buf(),
+            kind,
+        }
+    }
+
+    #[test]
+    fn changes_during_a_build_trigger_the_next_one() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("default.nix");
+        let output = dir.path().join("result");
+        let watch = ScriptedWatch::new().during_build(vec![
+            change(&input, ChangeKind::Modified),
+            change(&output, ChangeKind::Created),
+        ]);
+        let (mut build_loop, _, _project, _gc) = build_loop(ProjectConfig::default(), watch);
+
+        build_loop.exclude_build_outputs(&[input.clone()]);
+        match build_loop.warnings.as_slice() {
+            [Warning::ExcludedBuildOutputs(paths)] => assert_eq!(paths, &vec![output]),
+            otherwise => panic!("unexpected warnings: {:?}", otherwise),
+        }
+        // the script has no further steps, so this must not block
+        match build_loop.wait_for_trigger() {
+            Some(Reason::FilesChanged(changes)) => {
+                assert_eq!(changes, vec![change(&input, ChangeKind::Modified)])
+            }
+            otherwise => panic!("unexpected trigger: {:?}", otherwise),
+        }
+    }
+
+    #[test]
+    fn changes_below_inputs_are_not_excluded() {
+        let dir = tempdir().unwrap();
+        let src = dir.path().join("src");
+        std::fs::create_dir(&src).unwrap();
+        let input = dir.path().join("default.nix");
+        std::fs::write(&input, "{}").unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(dir.path(), &link).unwrap();
+        let edits = vec![
+            change(&src.join("main.c"), ChangeKind::Modified),
+            change(&link.join("default.nix"), ChangeKind::Modified),
+        ];
+        let output = dir.path().join("node_modules");
+        let mut changes = edits.clone();
+        changes.push(change(&output, ChangeKind::Created));
+        let watch = ScriptedWatch::new().during_build(changes);
+        let (mut build_loop, _, _project, _gc) = build_loop(ProjectConfig::default(), watch);
+
+        build_loop.exclude_build_outputs(&[src, input]);
+        match build_loop.warnings.as_slice() {
+            [Warning::ExcludedBuildOutputs(paths)] => assert_eq!(paths, &vec![output]),
+            otherwise => panic!("unexpected warnings: {:?}", otherwise),
+        }
+        assert_eq!(build_loop.pending_changes, edits);
+    }
+
+    #[test]
+    fn previous_results_keep_their_build_id() {
+        let (build_loop, shell_nix, _project, _gc) =
+            build_loop(ProjectConfig::default(), ScriptedWatch::new());
+        assert!(build_loop.previous_results().is_none());
+
+        std::os::unix::fs::symlink(&shell_nix, build_loop.roots.root("build-0").as_path()).unwrap();
+        let build_id = BuildId::next();
+        let record = history::BuildRecord::new(
+            build_id,
+            &Reason::Startup,
+            std::time::SystemTime::now(),
+            Duration::from_secs(1),
+            history::Outcome::Success,
+            None,
+        );
+        history::append(&build_loop.roots, record).unwrap();
+
+        let results = build_loop.previous_results().unwrap();
+        assert!(results.cached);
+        assert_eq!(results.build_id, build_id);
+    }
+
+    #[test]
+    fn scheduled_rebuilds() {
+        let config = ProjectConfig {
+            rebuild_interval: Some(60),
+            ..ProjectConfig::default()
+        };
+        let watch = ScriptedWatch::new().then(Step::Timeout);
+        let (mut build_loop, _, _project, _gc) = build_loop(config, watch);
+        match build_loop.wait_for_trigger() {
+            Some(Reason::Scheduled) => {}
+            otherwise => panic!("unexpected trigger: {:?}", otherwise),
+        }
+    }
+
+    #[test]
+    fn builds_wait_for_the_min_build_interval() {
+        let dir = tempdir().unwrap();
+        let first = dir.path().join("first.nix");
+        let second = dir.path().join("second.nix");
+        let config = ProjectConfig {
+            min_build_interval: Some(60),
+            ..ProjectConfig::default()
+        };
+        let watch = ScriptedWatch::new()
+            .then(Step::Changes(vec![change(&second, ChangeKind::Modified)]))
+            .then(Step::Timeout);
+        let (mut build_loop, _, _project, _gc) = build_loop(config, watch);
+
+        // the last build started almost a minute ago
+        let last_started = Instant::now() - Duration::from_millis(59_950);
+        let mut reason = Reason::FilesChanged(vec![change(&first, ChangeKind::Modified)]);
+        assert!(build_loop.throttle(last_started, &mut reason));
+        assert!(last_started.elapsed() >= Duration::from_secs(60));
+        match reason {
+            Reason::FilesChanged(changes) => assert_eq!(
+                changes,
+                vec![
+                    change(&first, ChangeKind::Modified),
+                    change(&second, ChangeKind::Modified)
+                ]
+            ),
+            otherwise => panic!("unexpected reason: {:?}", otherwise),
+        }
+
+        // changes turn other reasons into changes, too
+        let third = dir.path().join("third.nix");
+        build_loop.watch = Box::new(
+            ScriptedWatch::new()
+                .then(Step::Changes(vec![change(&third, ChangeKind::Modified)]))
+                .then(Step::Timeout),
+        );
+        let last_started = Instant::now() - Duration::from_millis(59_950);
+        let mut reason = Reason::Scheduled;
+        assert!(build_loop.throttle(last_started, &mut reason));
+        match reason {
+            Reason::FilesChanged(changes) => {
+                assert_eq!(changes, vec![change(&third, ChangeKind::Modified)])
+            }
+            otherwise => panic!("unexpected reason: {:?}", otherwise),
+        }
+
+        // without the setting, builds never wait
+        build_loop.config.min_build_interval = None;
+        assert!(build_loop.throttle(Instant::now(), &mut Reason::Scheduled));
+
+        // stopped loops stop waiting
+        build_loop.config.min_build_interval = Some(60);
+        build_loop.stop_on(Arc::new(AtomicBool::new(true)));
+        assert!(!build_loop.throttle(Instant::now(), &mut Reason::Scheduled));
+    }
+
+    #[test]
+    fn paused_loops_build_requests_and_later_changes() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("default.nix");
+        let watch =
+            ScriptedWatch::new().then(Step::Changes(vec![change(&input, ChangeKind::Modified)]));
+        let (mut build_loop, _, _project, _gc) = build_loop(ProjectConfig::default(), watch);
+        let paused = Arc::new(AtomicBool::new(true));
+        let rebuild = Arc::new(AtomicBool::new(true));
+        build_loop.pause_on(paused.clone());
+        build_loop.rebuild_on(rebuild.clone());
+
+        match build_loop.wait_for_trigger() {
+            Some(Reason::Requested) => {}
+            otherwise => panic!("unexpected trigger: {:?}", otherwise),
+        }
+        assert!(!rebuild.load(Ordering::SeqCst));
+
+        let unpause = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            paused.store(false, Ordering::SeqCst);
+        });
+        match build_loop.wait_for_trigger() {
+            Some(Reason::FilesChanged(changes)) => {
+                assert_eq!(changes, vec![change(&input, ChangeKind::Modified)])
+            }
+            otherwise => panic!("unexpected trigger: {:?}", otherwise),
+        }
+        unpause.join().unwrap();
+    }
+
+    #[test]
+    fn changed_nix_files_wait_until_they_are_allowed() {
+        let trust_dir = tempdir().unwrap();
+        let (mut build_loop, shell_nix, _project, _gc) =
+            build_loop(ProjectConfig::default(), ScriptedWatch::new());
+        let nix_file = NixFile::from(shell_nix.clone());
+        Trust::new(trust_dir.path()).allow(&nix_file).unwrap();
+        build_loop.check_trust(Trust::new(trust_dir.path()));
+        let events = Mutex::new(vec![]);
+        let send = |event| events.lock().unwrap().push(event);
+        assert!(build_loop.wait_until_allowed(&mut Reason::Startup, &send));
+        assert!(events.lock().unwrap().is_empty());
+
+        std::fs::write(&shell_nix, "import ./evil.nix").unwrap();
+        build_loop.watch = Box::new(ScriptedWatch::new().then(Step::Changes(vec![change(
+            &shell_nix,
+            ChangeKind::Modified,
+        )])));
+        let allow_dir = trust_dir.path().to_owned();
+        let allow = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            Trust::new(&allow_dir).allow(&nix_file).unwrap();
+        });
+        let mut reason = Reason::Scheduled;
+        assert!(build_loop.wait_until_allowed(&mut reason, &send));
+        allow.join().unwrap();
+        match events.lock().unwrap().as_slice() {
+            [Event::Warning(Warning::NotAllowed(_))] => {}
+            otherwise => panic!("unexpected events: {:?}", otherwise),
+        }
+        match reason {
+            Reason::FilesChanged(changes) => {
+                assert_eq!(changes, vec![change(&shell_nix, ChangeKind::Modified)])
+            }
+            otherwise => panic!("unexpected reason: {:?}", otherwise),
+        }
+
+        // stopped loops stop waiting
+        std::fs::write(&shell_nix, "import ./worse.nix").unwrap();
+        build_loop.stop_on(Arc::new(AtomicBool::new(true)));
+        assert!(!build_loop.wait_until_allowed(&mut Reason::Scheduled, &send));
+    }
+
+    #[test]
+    fn changed_configurations_are_applied() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("default.nix");
+        let watch =
+            ScriptedWatch::new().then(Step::Changes(vec![change(&input, ChangeKind::Modified)]));
+        let (mut build_loop, _, _project, _gc) = build_loop(ProjectConfig::default(), watch);
+        let shared = Arc::new(Mutex::new(ProjectConfig::default()));
+        build_loop.follow_config(shared.clone());
+
+        // settings which don’t affect the result apply silently
+        shared.lock().unwrap().ignore = Some(vec![String::from("node_modules/")]);
+        match build_loop.wait_for_trigger() {
+            Some(Reason::FilesChanged(_)) => {}
+            otherwise => panic!("unexpected trigger: {:?}", otherwise),
+        }
+        assert_eq!(build_loop.config, *shared.lock().unwrap());
+
+        shared.lock().unwrap().attr = Some(String::from("shell"));
+        match build_loop.wait_for_trigger() {
+            Some(Reason::ConfigChanged) => {}
+            otherwise => panic!("unexpected trigger: {:?}", otherwise),
+        }
+        assert_eq!(build_loop.config.attr(), Some("shell"));
+    }
+
+    #[test]
+    fn stopped_loops_stop_waiting() {
+        let dir = tempdir().unwrap();
+        let input = dir.path().join("default.nix");
+        let watch = ScriptedWatch::new()
+            .then(Step::Timeout)
+            .then(Step::Changes(vec![change(&input, ChangeKind::Modified)]));
+        let (mut build_loop, _, _project, _gc) = build_loop(ProjectConfig::default(), watch);
+        let stop = Arc::new(AtomicBool::new(false));
+        build_loop.stop_on(stop.clone());
+
+        // only polling whether to stop, not a scheduled rebuild
+        match build_loop.wait_for_trigger() {
+            Some(Reason::FilesChanged(_)) => {}
+            otherwise => panic!("unexpected trigger: {:?}", otherwise),
+        }
+        stop.store(true, Ordering::SeqCst);
+        assert!(build_loop.wait_for_trigger().is_none());
+    }
+
+    #[test]
+    fn removal_of_the_root_file_waits_for_its_return() {
+        let dir = tempdir().unwrap();
+        let other = dir.path().join("other.nix");
+        let (mut build_loop, shell_nix, _project, _gc) = build_loop(
+            ProjectConfig {
+                rebuild_interval: Some(60),
+                ..ProjectConfig::default()
+            },
+            ScriptedWatch::new(),
+        );
+        std::fs::remove_file(&shell_nix).unwrap();
+        build_loop.watch = Box::new(
+            ScriptedWatch::new()
+                .then(Step::Changes(vec![change(&shell_nix, ChangeKind::Removed)]))
+                .then(Step::Changes(vec![change(&other, ChangeKind::Modified)])),
+        );
+        match build_loop.wait_for_trigger() {
+            Some(Reason::FilesChanged(changes)) => {
+                assert_eq!(changes, vec![change(&other, ChangeKind::Modified)])
+            }
+            otherwise => panic!("unexpected trigger: {:?}", otherwise),
+        }
+    }
+
+    #[test]
+    fn restarted_watchers_trigger_a_rebuild() {
+        let watch = ScriptedWatch::new().then(Step::Restart("events were lost".to_string()));
+        let (mut build_loop, _, _project, _gc) = build_loop(ProjectConfig::default(), watch);
+        match build_loop.wait_for_trigger() {
+            Some(Reason::FilesChanged(changes)) => assert!(changes.is_empty()),
+            otherwise => panic!("unexpected trigger: {:?}", otherwise),
+        }
+        match build_loop.warnings.as_slice() {
+            [Warning::WatcherRestarted(reason)] => assert_eq!(reason, "events were lost"),
+            otherwise => panic!("unexpected warnings: {:?}", otherwise),
+        }
+    }
+
+    #[test]
+    fn moved_projects_stop_the_loop() {
+        let (mut build_loop, shell_nix, project, _gc) =
+            build_loop(ProjectConfig::default(), ScriptedWatch::new());
+        build_loop.watch = Box::new(
+            ScriptedWatch::new().then(Step::Changes(vec![change(&shell_nix, ChangeKind::Renamed)])),
+        );
+        project.close().unwrap();
+        assert!(build_loop.wait_for_trigger().is_none());
+    }
+
+    #[test]
+    fn build_ids_increase() {
+        let ids = (0..100).map(|_| BuildId::next()).collect::<Vec<_>>();
+        for pair in ids.windows(2) {
+            assert!(pair[0] < pair[1]);
+        }
+    }
+
+    #[test]
+    fn log_floods_are_limited() {
+        let start = Instant::now();
+        let mut limit = LogLimit::new(start);
+        let admitted = (0..MAX_LOG_LINES_PER_SECOND + 5)
+            .filter(|_| limit.admit(start))
+            .count();
+        assert_eq!(admitted, MAX_LOG_LINES_PER_SECOND);
+        assert_eq!(limit.take_skipped(), 5);
+        assert_eq!(limit.take_skipped(), 0);
+        // the next second has a new budget
+        assert!(limit.admit(start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn builds_wait_for_a_free_slot() {
+        let slots = BuildSlots::new(1);
+        let nix_file = NixFile::from(PathBuf::from("/a/shell.nix"));
+        let first = slots.take(&nix_file);
+
+        let (tx, rx) = mpsc::channel();
+        let waiting = slots.clone();
+        let handle = std::thread::spawn(move || {
+            let _second = waiting.take(&nix_file);
+            tx.send(()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        assert_eq!((slots.taken(), slots.waiting()), (1, 1));
+        drop(first);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.join().unwrap();
+        assert_eq!((slots.taken(), slots.waiting()), (0, 0));
+    }
+
+    #[test]
+    fn growing_slots_lets_waiting_builds_start() {
+        let slots = BuildSlots::new(1);
+        let nix_file = NixFile::from(PathBuf::from("/a/shell.nix"));
+        let _first = slots.take(&nix_file);
+
+        let (tx, rx) = mpsc::channel();
+        let waiting = slots.clone();
+        let handle = std::thread::spawn(move || {
+            let _second = waiting.take(&nix_file);
+            tx.send(()).unwrap();
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+        slots.resize(2);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        handle.join().unwrap();
+        assert_eq!(slots.size(), 2);
+    }
+}
@@ -7,20 +7,193 @@
 //! can parse additional information from the `nix-build`
 //! `stderr`, like which source files are used by the evaluator.
 
+extern crate nix;
+
+use self::nix::sys::signal::{killpg, Signal};
+use self::nix::unistd::{setpgid, Pid};
 use regex::Regex;
 use std::any::Any;
 use std::collections::HashMap;
+use std::ffi::OsString;
 use std::io::{BufRead, BufReader};
+use std::os::unix::process::CommandExt;
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use NixFile;
 
-/// Builds the Nix expression in `root_nix_file`.
+/// Coarse-grained stage a running build is in,
+/// as far as we can tell from the `nix-build` log output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Phase {
+    /// Nix is evaluating the expression
+    Evaluating,
+    /// Nix is downloading paths from a binary cache
+    Fetching,
+    /// Nix is running derivation builders
+    Building,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Phase::Evaluating => "evaluating",
+            Phase::Fetching => "fetching",
+            Phase::Building => "building",
+        })
+    }
+}
+
+/// Shared view on the `Phase` of a running build.
 ///
-/// Instruments the nix file to gain extra information,
-/// which is valuable even if the build fails.
-pub fn run(root_nix_file: &NixFile) -> Result<Info, Error> {
+/// The builder updates it while reading the log output,
+/// other threads (e.g. a heartbeat) can read it at any time.
+#[derive(Clone, Debug)]
+pub struct Progress {
+    phase: Arc<Mutex<Phase>>,
+    /// Receives the log lines of the build, if set.
+    log: Option<Sender<String>>,
+}
+
+impl Progress {
+    /// A fresh build starts out evaluating.
+    pub fn new() -> Progress {
+        Progress {
+            phase: Arc::new(Mutex::new(Phase::Evaluating)),
+            log: None,
+        }
+    }
+
+    /// Like `new`, but every log line of the build which is not about
+    /// the evaluation itself is sent to `log` as soon as it is read.
+    /// The channel is closed once the build and all clones of the
+    /// returned `Progress` are done.
+    pub fn with_log(log: Sender<String>) -> Progress {
+        Progress {
+            log: Some(log),
+            ..Progress::new()
+        }
+    }
+
+    /// The phase the build is currently in.
+    pub fn phase(&self) -> Phase {
+        *self.phase.lock().expect("Progress mutex poisoned")
+    }
+
+    fn set(&self, phase: Phase) {
+        *self.phase.lock().expect("Progress mutex poisoned") = phase;
+    }
+
+    fn log(&self, line: &str) {
+        if let Some(ref log) = self.log {
+            // nobody may be interested anymore, that’s fine
+            let _ = log.send(line.to_string());
+        }
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Progress {
+        Progress::new()
+    }
+}
+
+lazy_static! {
+    /// The process groups of all running `nix-build`s and the nix
+    /// files they build, see `cancel_all`.
+    static ref RUNNING: Mutex<HashMap<i32, NixFile>> = Mutex::new(HashMap::new());
+}
+
+/// Registers a running `nix-build` in `RUNNING` until dropped.
+struct Running(i32);
+
+impl Running {
+    fn register(pid: u32, nix_file: &NixFile) -> Running {
+        let group = pid as i32;
+        RUNNING
+            .lock()
+            .expect("running builds mutex poisoned")
+            .insert(group, nix_file.clone());
+        Running(group)
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING
+            .lock()
+            .expect("running builds mutex poisoned")
+            .remove(&self.0);
+    }
+}
+
+/// Send `SIGTERM` to every running `nix-build` and the processes it
+/// started. Each `nix-build` runs in a process group of its own, so
+/// that signals meant for lorri (like a `^C` in the terminal) don’t
+/// reach it directly; this is how lorri passes them on.
+/// Returns how many builds were cancelled.
+pub fn cancel_all() -> usize {
+    cancel_where(|_| true)
+}
+
+/// The process groups of the running `nix-build`s and the nix files
+/// they build, ordered by process group.
+pub fn running() -> Vec<(i32, NixFile)> {
+    let mut running = RUNNING
+        .lock()
+        .expect("running builds mutex poisoned")
+        .iter()
+        .map(|(group, nix_file)| (*group, nix_file.clone()))
+        .collect::<Vec<_>>();
+    running.sort_by_key(|&(group, _)| group);
+    running
+}
+
+/// Like `cancel_all`, but only cancel the builds of `nix_file`.
+pub fn cancel(nix_file: &NixFile) -> usize {
+    cancel_where(|building| building == nix_file)
+}
+
+fn cancel_where<F>(wanted: F) -> usize
+where
+    F: Fn(&NixFile) -> bool,
+{
+    let running = RUNNING.lock().expect("running builds mutex poisoned");
+    let mut cancelled = 0;
+    for (group, nix_file) in running.iter() {
+        if !wanted(nix_file) {
+            continue;
+        }
+        cancelled += 1;
+        if let Err(e) = killpg(Pid::from_raw(*group), Signal::SIGTERM) {
+            debug!(
+                "could not cancel the build in process group {}: {}",
+                group, e
+            );
+        }
+    }
+    cancelled
+}
+
+/// The instrumented expression `run` evaluates, which imports the
+/// project’s expression and logs what it uses.
+pub const LOGGED_EVALUATION_NIX: &str = include_str!("./logged-evaluation.nix");
+
+/// The program `run` calls, with `nix_build_args`.
+pub const NIX_BUILD: &str = "nix-build";
+
+/// The `nix-build` call `run` makes for `root_nix_file` and `attr`,
+/// before its input and output are set up.
+pub fn nix_build_command(root_nix_file: &NixFile, attr: Option<&str>) -> Command {
+    let mut cmd = Command::new(NIX_BUILD);
+    cmd.args(nix_build_args(root_nix_file, attr));
+    cmd
+}
+
+/// The arguments of `nix_build_command`.
+pub fn nix_build_args(root_nix_file: &NixFile, attr: Option<&str>) -> Vec<OsString> {
     // We're looking for log lines matching:
     //
     //     copied source '...' -> '/nix/store/...'
@@ -29,28 +202,70 @@ pub fn run(root_nix_file: &NixFile) -> Result<Info, Error> {
     // to determine which files we should setup watches on.
     // Increasing verbosity by two levels via `-vv` satisfies that.
 
-    let mut cmd = Command::new("nix-build");
-
-    cmd.args(&[
+    let mut args = [
         "-vv",
         // TODO: we should pass this as a file instead of a 12k argv string
         "--expr",
-        include_str!("./logged-evaluation.nix"),
+        LOGGED_EVALUATION_NIX,
         "--no-out-link",
+    ]
+    .iter()
+    .map(OsString::from)
+    .collect::<Vec<_>>();
+    args.extend(evaluation_args(root_nix_file, attr));
+    args
+}
+
+/// The arguments `LOGGED_EVALUATION_NIX` is called with, which select
+/// the derivation of `root_nix_file` (or of its attribute `attr`).
+fn evaluation_args(root_nix_file: &NixFile, attr: Option<&str>) -> Vec<OsString> {
+    let mut args = [
         "--argstr",
         "runTimeClosure",
         crate::RUN_TIME_CLOSURE,
         "--argstr",
+        "attr",
+        attr.unwrap_or(""),
+        "--argstr",
         "src",
-    ])
-    .arg(root_nix_file.as_os_str())
-    .stdin(Stdio::null())
-    .stdout(Stdio::piped())
-    .stderr(Stdio::piped());
+    ]
+    .iter()
+    .map(OsString::from)
+    .collect::<Vec<_>>();
+    args.push(root_nix_file.as_os_str().to_owned());
+    args
+}
+
+/// Builds the Nix expression in `root_nix_file`, or its attribute
+/// `attr` (like `nix-build -A`).
+///
+/// Instruments the nix file to gain extra information,
+/// which is valuable even if the build fails.
+///
+/// `progress` is updated with the current `Phase` while
+/// the build is running.
+pub fn run(
+    root_nix_file: &NixFile,
+    attr: Option<&str>,
+    progress: &Progress,
+) -> Result<Info, Error> {
+    let mut cmd = nix_build_command(root_nix_file, attr);
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // A process group of its own, see `cancel_all`. `setpgid` is
+    // async-signal-safe, so it may run between `fork` and `exec`.
+    unsafe {
+        cmd.pre_exec(|| {
+            setpgid(Pid::from_raw(0), Pid::from_raw(0))
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+        });
+    }
 
     debug!("$ {:?}", cmd);
 
     let mut child = cmd.spawn()?;
+    let running = Running::register(child.id(), root_nix_file);
 
     let stdout = child
         .stdout
@@ -61,11 +276,22 @@ pub fn run(root_nix_file: &NixFile) -> Result<Info, Error> {
         .take()
         .expect("we must be able to access the stderr of nix-build");
 
+    let progress = progress.clone();
     let stderr_results: thread::JoinHandle<Vec<LogDatum>> = thread::spawn(move || {
         let reader = BufReader::new(stderr);
         reader
             .lines()
-            .map(|line| parse_evaluation_line(&line.unwrap()))
+            .map(|line| {
+                let line = line.unwrap();
+                if let Some(phase) = parse_phase_line(&line) {
+                    progress.set(phase);
+                }
+                let datum = parse_evaluation_line(&line);
+                if let LogDatum::Text(ref text) = datum {
+                    progress.log(text);
+                }
+                datum
+            })
             .collect::<Vec<LogDatum>>()
     });
 
@@ -78,6 +304,7 @@ pub fn run(root_nix_file: &NixFile) -> Result<Info, Error> {
 
     let (exec_result, drvs, results) =
         (child.wait()?, produced_drvs.join()?, stderr_results.join()?);
+    drop(running);
 
     let (paths, named_drvs, log_lines): (Vec<PathBuf>, HashMap<String, PathBuf>, Vec<String>) =
         results.into_iter().fold(
@@ -105,6 +332,81 @@ pub fn run(root_nix_file: &NixFile) -> Result<Info, Error> {
     })
 }
 
+/// What realizing an expression would do, without actually doing it.
+#[derive(Debug, PartialEq, Default)]
+pub struct DryRun {
+    /// Derivations which would be built locally
+    pub to_build: Vec<PathBuf>,
+    /// Store paths which would be fetched from a binary cache
+    pub to_fetch: Vec<PathBuf>,
+}
+
+/// Evaluate the Nix expression in `root_nix_file` (or its attribute
+/// `attr`) and determine which derivations would have to be built or
+/// fetched, stopping before anything is realized. Evaluates it like
+/// `run`, so it selects the same derivation.
+pub fn dry_run(root_nix_file: &NixFile, attr: Option<&str>) -> Result<DryRun, Error> {
+    let instantiate = Command::new("nix-instantiate")
+        .arg("--expr")
+        .arg(LOGGED_EVALUATION_NIX)
+        .args(evaluation_args(root_nix_file, attr))
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !instantiate.status.success() {
+        return Err(Error::ExecutionFailed(instantiate));
+    }
+    let drvs = String::from_utf8_lossy(&instantiate.stdout)
+        .lines()
+        .map(PathBuf::from)
+        .collect::<Vec<PathBuf>>();
+    debug!("instantiated drvs: {:?}", drvs);
+
+    // `--dry-run` reports what it would do on stderr
+    let realise = Command::new("nix-store")
+        .arg("--realise")
+        .arg("--dry-run")
+        .args(&drvs)
+        .stdin(Stdio::null())
+        .output()?;
+    if !realise.status.success() {
+        return Err(Error::ExecutionFailed(realise));
+    }
+
+    Ok(parse_dry_run(&String::from_utf8_lossy(&realise.stderr)))
+}
+
+/// Parse the output of `nix-store --realise --dry-run`, which looks like
+///
+/// ```text
+/// these derivations will be built:
+///   /nix/store/…-foo.drv
+/// these paths will be fetched (0.05 MiB download, 0.23 MiB unpacked):
+///   /nix/store/…-bar
+/// ```
+fn parse_dry_run(output: &str) -> DryRun {
+    let mut result = DryRun::default();
+    // whether the paths listed next are built (or else fetched)
+    let mut building = None;
+    for line in output.lines() {
+        if line.starts_with("  ") {
+            let path = PathBuf::from(line.trim());
+            match building {
+                Some(true) => result.to_build.push(path),
+                Some(false) => result.to_fetch.push(path),
+                None => {}
+            }
+        } else if line.contains("will be built") {
+            building = Some(true);
+        } else if line.contains("will be fetched") {
+            building = Some(false);
+        } else {
+            building = None;
+        }
+    }
+    result
+}
+
 #[derive(Debug, PartialEq)]
 enum LogDatum {
     Source(PathBuf),
@@ -145,6 +447,18 @@ fn parse_evaluation_line(line: &str) -> LogDatum {
     }
 }
 
+/// Examine a line of output and determine whether it marks
+/// the start of a new build `Phase`.
+pub fn parse_phase_line(line: &str) -> Option<Phase> {
+    if line.starts_with("building '") {
+        Some(Phase::Building)
+    } else if line.starts_with("copying path '") || line.starts_with("downloading '") {
+        Some(Phase::Fetching)
+    } else {
+        None
+    }
+}
+
 /// The results of an individual build.
 /// Even if the exit code is not 0, there is still
 /// valuable information in the output, like new paths
@@ -178,7 +492,21 @@ pub enum Error {
 
     /// Failed to spawn a log processing thread
     ThreadFailure(std::boxed::Box<(dyn std::any::Any + std::marker::Send + 'static)>),
+
+    /// A nix command exited with a failing status
+    ExecutionFailed(std::process::Output),
+}
+impl Error {
+    /// Nix could not be started because it is not installed, or not
+    /// in `PATH`.
+    pub fn nix_missing(&self) -> bool {
+        match self {
+            Error::Io(e) => e.kind() == std::io::ErrorKind::NotFound,
+            _ => false,
+        }
+    }
 }
+
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Error {
         Error::Io(e)
@@ -192,7 +520,7 @@ impl From<Box<dyn Any + Send + 'static>> for Error {
 
 #[cfg(test)]
 mod tests {
-    use super::{parse_evaluation_line, LogDatum};
+    use super::{parse_dry_run, parse_evaluation_line, parse_phase_line, DryRun, LogDatum, Phase};
     use std::path::PathBuf;
 
     #[test]
@@ -230,4 +558,52 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn test_phase_line() {
+        assert_eq!(
+            parse_phase_line("building '/nix/store/q3ngidzvincycjjvlilf1z6vj1w4wnas-lorri.drv'..."),
+            Some(Phase::Building)
+        );
+        assert_eq!(
+            parse_phase_line("copying path '/nix/store/zqxha3ax0w771jf25qdblakka83660gr-source' from 'https://cache.nixos.org'..."),
+            Some(Phase::Fetching)
+        );
+        assert_eq!(
+            parse_phase_line(
+                "downloading 'https://static.rust-lang.org/dist/channel-rust-stable.toml'..."
+            ),
+            Some(Phase::Fetching)
+        );
+        assert_eq!(
+            parse_phase_line("evaluating file '/nix/store/zqxha3ax0w771jf25qdblakka83660gr-source/lib/systems/for-meta.nix'"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_dry_run() {
+        assert_eq!(
+            parse_dry_run(
+                "these derivations will be built:
+  /nix/store/q3ngidzvincycjjvlilf1z6vj1w4wnas-lorri.drv
+these paths will be fetched (0.05 MiB download, 0.23 MiB unpacked):
+  /nix/store/9krlzvny65gdc8s7kpb6lkx8cd02c25b-default-builder.sh
+  /nix/store/zqxha3ax0w771jf25qdblakka83660gr-source
+warning: you did it
+"
+            ),
+            DryRun {
+                to_build: vec![PathBuf::from(
+                    "/nix/store/q3ngidzvincycjjvlilf1z6vj1w4wnas-lorri.drv"
+                )],
+                to_fetch: vec![
+                    PathBuf::from("/nix/store/9krlzvny65gdc8s7kpb6lkx8cd02c25b-default-builder.sh"),
+                    PathBuf::from("/nix/store/zqxha3ax0w771jf25qdblakka83660gr-source"),
+                ],
+            }
+        );
+
+        assert_eq!(parse_dry_run(""), DryRun::default());
+    }
 }
@@ -1,7 +1,9 @@
 //! Defines the CLI interface using structopt.
 
+use crate::build_loop::BuildId;
+use std::ffi::OsString;
 use std::path::PathBuf;
-use NixFile;
+use structopt::clap::Shell;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "lorri")]
@@ -11,9 +13,47 @@ pub struct Arguments {
     /// Increase debug logging, can be passed multiple times.
     /// Supports up to -vvvv, and this setting is ignored if RUST_LOG
     /// is set.
-    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    #[structopt(
+        short = "v",
+        long = "verbose",
+        parse(from_occurrences),
+        raw(global = "true")
+    )]
     pub verbosity: u8,
 
+    /// Print less: -q hides progress messages, -qq also warnings and
+    /// error logs, leaving only results and fatal errors. Cancels out
+    /// -v. Nix output, e.g. of failed builds, is not affected.
+    #[structopt(
+        short = "q",
+        long = "quiet",
+        parse(from_occurrences),
+        raw(global = "true")
+    )]
+    pub quiet: u8,
+
+    /// How to print the error if a command fails: `human` (default),
+    /// or `json`, an object with a stable `code`, the `message` and
+    /// the `exit_code`, for tools wrapping lorri.
+    #[structopt(
+        long = "error-format",
+        value_name = "FORMAT",
+        default_value = "human",
+        raw(possible_values = "&[\"human\", \"json\"]", global = "true")
+    )]
+    pub error_format: ErrorFormat,
+
+    /// The project's nix file, instead of `shell.nix` in the current
+    /// directory, e.g. `nix/shell.nix`. Relative to the current
+    /// directory.
+    #[structopt(
+        long = "shell-file",
+        value_name = "PATH",
+        parse(from_os_str),
+        raw(global = "true")
+    )]
+    pub shell_file: Option<PathBuf>,
+
     /// Sub-command to execute
     #[structopt(subcommand)]
     pub command: Command,
@@ -22,34 +62,114 @@ pub struct Arguments {
 #[derive(StructOpt, Debug)]
 /// Sub-commands which Lorri can execute
 pub enum Command {
-    /// Build attributes inside your release.nix. Alias: b
+    /// Build the environment of the project once, as the build loop
+    /// does, print its store path and exit, e.g. in CI. Alias: b
     #[structopt(name = "build", alias = "b")]
-    Build,
+    Build(BuildOptions),
 
     /// Emit shell script intended to be evaluated as part of
     /// direnv's .envrc, via: `eval "$(lorri direnv)"`
     #[structopt(name = "direnv")]
     Direnv,
 
-    /// (Unsupported!) Open up a project development shell. Alias: s
+    /// Install the `use lorri` function into direnv's library, so an
+    /// `.envrc` only needs to contain `use lorri`
+    #[structopt(name = "direnv-install")]
+    DirenvInstall,
+
+    /// Open up a project development shell, with the environment of
+    /// `shell.nix` applied on top of yours. Starts your `$SHELL` if it
+    /// is zsh or fish, and bash otherwise. The environment of every
+    /// later build is applied at the next prompt. Alias: s
     #[structopt(name = "shell", alias = "s")]
-    Shell,
+    Shell(ShellOptions),
+
+    /// Run a command in the latest environment of the project,
+    /// without building anything: `lorri exec -- make test`
+    #[structopt(name = "exec")]
+    Exec(ExecOptions),
+
+    /// Run a script in the environment of the project around it,
+    /// building it first if it never was. Usable as an interpreter,
+    /// via `#!/usr/bin/env lorri-run`
+    #[structopt(
+        name = "run",
+        raw(setting = "structopt::clap::AppSettings::TrailingVarArg")
+    )]
+    Run(RunOptions),
+
+    /// Print the latest environment of the project, in the syntax of
+    /// `--format`: bash (default), zsh, fish, json or dotenv
+    #[structopt(name = "export")]
+    Export(ExportOptions),
+
+    /// Allow lorri to build `shell.nix` in the background (watch,
+    /// daemon, direnv), until it changes
+    #[structopt(name = "allow")]
+    Allow,
 
-    /// Show information about the current Lorri project
+    /// Refuse building `shell.nix` in the background until it is
+    /// allowed again
+    #[structopt(name = "deny")]
+    Deny,
+
+    /// Show information about the current Lorri project: its nix
+    /// file, project id, GC roots, watched files and last build
     #[structopt(name = "info", alias = "information")]
-    Info,
+    Info(InfoOptions),
 
     /// Build `shell.nix` whenever an input file changes
     #[structopt(name = "watch")]
-    Watch,
+    Watch(WatchOptions),
 
     /// Start the multi-project daemon. Replaces `lorri watch`
     #[structopt(name = "daemon")]
     Daemon,
 
-    /// (plumbing) Tell the lorri daemon to care about the current directory's project
-    #[structopt(name = "ping_")]
-    Ping_(Ping_),
+    /// Show what the lorri daemon is doing for each of its projects
+    #[structopt(name = "status")]
+    Status(StatusOptions),
+
+    /// Print the events of the lorri daemon's projects as they happen,
+    /// one JSON object per line, starting with the recent ones
+    #[structopt(name = "events")]
+    Events(EventsOptions),
+
+    /// Print the lorri daemon's events of the given project, like
+    /// `lorri events`. The integration point for editor plugins and
+    /// status bars
+    #[structopt(
+        name = "stream-events",
+        raw(setting = "structopt::clap::AppSettings::Hidden")
+    )]
+    StreamEvents(StreamEventsOptions),
+
+    /// List the recent builds of the current directory's project:
+    /// when and why they started, how long they took, how they ended
+    /// and how they changed the environment
+    #[structopt(name = "builds")]
+    Builds(BuildsOptions),
+
+    /// Show how the environment of the current directory's project
+    /// changed between two builds, by default the latest and the one
+    /// before
+    #[structopt(name = "env-diff")]
+    EnvDiff(EnvDiffOptions),
+
+    /// Show the recent build output the lorri daemon kept for the
+    /// current directory's project
+    #[structopt(name = "logs")]
+    Logs(LogsOptions),
+
+    /// Tell the lorri daemon to build the current directory's project,
+    /// e.g. from `.envrc`. Returns immediately
+    #[structopt(name = "ping", alias = "ping_")]
+    Ping(PingOptions),
+
+    /// Make the lorri daemon stop building the current directory's
+    /// project, and not resume it after a restart
+    #[structopt(name = "forget")]
+    Forget(ForgetOptions),
 
     /// Upgrade Lorri
     #[structopt(name = "self-upgrade", alias = "self-update")]
@@ -57,7 +177,383 @@ pub enum Command {
 
     /// Bootstrap files for a new setup
     #[structopt(name = "init")]
-    Init,
+    Init(InitOptions),
+
+    /// List the GC roots of all projects, with their closure sizes
+    #[structopt(name = "roots")]
+    Roots,
+
+    /// Remove the GC roots of projects whose nix file is gone
+    #[structopt(name = "gc")]
+    Gc(GcOptions),
+
+    /// Remove everything lorri keeps for the current project: its GC
+    /// roots, cached environment and the daemon's build of it
+    #[structopt(name = "clean")]
+    Clean(CleanOptions),
+
+    /// Check the lorri setup and the current project for problems
+    #[structopt(name = "doctor")]
+    Doctor(DoctorOptions),
+
+    /// Build a small bundled shell end-to-end and report which phase
+    /// failed: evaluation, build, root creation or environment capture.
+    /// If it passes, lorri works and a failing project's expression is
+    /// the problem
+    #[structopt(name = "self-test")]
+    SelfTest,
+
+    /// Print a completion script for bash, zsh or fish, e.g. `lorri
+    /// completions bash > /etc/bash_completion.d/lorri`
+    #[structopt(name = "completions")]
+    Completions(CompletionsOptions),
+
+    /// Print the candidates the completion scripts complete
+    /// dynamically, one per line
+    #[structopt(
+        name = "complete",
+        raw(setting = "structopt::clap::AppSettings::Hidden")
+    )]
+    Complete(CompleteOptions),
+
+    /// Print the nix-build command line lorri runs for the current
+    /// directory's project, the environment it runs in and the
+    /// instrumented expression it evaluates, to reproduce a build
+    /// without lorri
+    #[structopt(
+        name = "dump-nix-args",
+        raw(setting = "structopt::clap::AppSettings::Hidden")
+    )]
+    DumpNixArgs(DumpNixArgsOptions),
+
+    /// List the top-level attributes of the current directory's nix
+    /// file, whether each is a derivation, and which one lorri builds:
+    /// the values `--attr` and the `attr` setting can select
+    #[structopt(name = "attrs")]
+    Attrs(AttrsOptions),
+}
+
+/// Options for the `attrs` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct AttrsOptions {
+    /// Print a JSON list of `{"name": ..., "derivation": ...}`, for
+    /// scripts.
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// Options for the `dump-nix-args` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct DumpNixArgsOptions {
+    /// Write the instrumented expression to this file, which the
+    /// printed command line reads, instead of printing it.
+    #[structopt(long = "instrumentation", value_name = "PATH", parse(from_os_str))]
+    pub instrumentation: Option<PathBuf>,
+}
+
+/// Options for the `completions` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct CompletionsOptions {
+    /// The shell to complete for.
+    #[structopt(raw(possible_values = "&[\"bash\", \"zsh\", \"fish\"]"))]
+    pub shell: Shell,
+}
+
+/// Options for the `complete` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct CompleteOptions {
+    /// `attrs`: the attributes of the current project's nix file,
+    /// `projects`: the nix files of the projects lorri built.
+    #[structopt(raw(possible_values = "&[\"attrs\", \"projects\"]"))]
+    pub what: String,
+}
+
+/// Options for the `build` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct BuildOptions {
+    /// Also create this symlink to the environment, like the `result`
+    /// of `nix-build`. It is a GC root, so the environment can be
+    /// archived or inspected as long as it exists.
+    #[structopt(
+        long = "out-link",
+        short = "o",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    pub out_link: Option<PathBuf>,
+
+    /// Print for CI logs: the build log in a foldable group per phase
+    /// (GitHub Actions `::group::`), all of the log of a failed build,
+    /// and a last line `lorri-summary: {...}` with the result as JSON.
+    #[structopt(long = "ci")]
+    pub ci: bool,
+
+    /// Abort the build after this many seconds, killing Nix, and exit
+    /// with code 124 (error code `timed-out`), so a hung download
+    /// does not block a CI runner until its own timeout.
+    #[structopt(long = "timeout", value_name = "SECS")]
+    pub timeout: Option<u64>,
+}
+
+/// Options for the `clean` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct CleanOptions {
+    /// Remove the state of every project, the list of projects the
+    /// daemon resumes and all `lorri allow` decisions.
+    #[structopt(long = "all")]
+    pub all: bool,
+}
+
+/// Options for the `doctor` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct DoctorOptions {
+    /// Fix the problems which can be fixed automatically.
+    #[structopt(long = "repair")]
+    pub repair: bool,
+}
+
+/// Options for the `shell` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct ShellOptions {
+    /// Instead of an interactive shell, run this command with `/bin/sh`
+    /// in the environment and exit with its status. Alias: --run
+    #[structopt(long = "command", alias = "run", value_name = "COMMAND")]
+    pub command: Option<String>,
+
+    /// Start from an environment with only `HOME`, `TERM` and the
+    /// variables in the `pure_keep` setting, instead of yours.
+    #[structopt(long = "pure")]
+    pub pure: bool,
+
+    /// Use the environment of this attribute of the nix file, like
+    /// `nix-shell -A`. Overrides the `attr` setting.
+    #[structopt(long = "attr", short = "A", value_name = "ATTRIBUTE")]
+    pub attr: Option<String>,
+}
+
+/// Options for the `info` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct InfoOptions {
+    /// Print the information as JSON.
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// Options for the `status` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct StatusOptions {
+    /// Print the status as JSON.
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// Options for the `events` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct EventsOptions {
+    /// Print the events of every project, not only the current
+    /// directory's.
+    #[structopt(long = "all")]
+    pub all: bool,
+}
+
+/// Options for the `stream-events` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct StreamEventsOptions {
+    /// The .nix file of the project. Defaults to `shell.nix` in the
+    /// current directory.
+    #[structopt(parse(from_os_str))]
+    pub nix_file: Option<PathBuf>,
+}
+
+/// Options for the `builds` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct BuildsOptions {
+    /// How many builds to list, the latest first.
+    #[structopt(short = "n", long = "count", default_value = "10")]
+    pub count: usize,
+    /// Print the builds as JSON, for scripts.
+    #[structopt(long = "json")]
+    pub json: bool,
+}
+
+/// Options for the `env-diff` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct EnvDiffOptions {
+    /// The older build, see `lorri builds`. Defaults to the build
+    /// before NEW.
+    pub old: Option<BuildId>,
+    /// The newer build. Defaults to the latest build.
+    pub new: Option<BuildId>,
+}
+
+/// Options for the `logs` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct LogsOptions {
+    /// Keep printing new output as the daemon builds.
+    #[structopt(short = "f", long = "follow")]
+    pub follow: bool,
+    /// Show the output of every project, prefixed with its nix file.
+    #[structopt(long = "all")]
+    pub all: bool,
+    /// Show the complete output of this build of the current
+    /// directory's project, see `lorri builds`. lorri keeps the
+    /// output of the last 20 builds.
+    #[structopt(long = "build", raw(conflicts_with_all = "&[\"follow\", \"all\"]"))]
+    pub build: Option<BuildId>,
+}
+
+/// Options for the `export` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct ExportOptions {
+    /// The syntax of the output.
+    #[structopt(
+        long = "format",
+        default_value = "bash",
+        raw(possible_values = "&[\"bash\", \"zsh\", \"fish\", \"json\", \"dotenv\"]")
+    )]
+    pub format: ExportFormat,
+}
+
+/// The output formats of `lorri export`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    /// `export NAME='value'` lines.
+    Bash,
+    /// Like bash.
+    Zsh,
+    /// `set -gx NAME 'value'` lines.
+    Fish,
+    /// A JSON object.
+    Json,
+    /// `NAME="value"` lines, as read by docker-compose.
+    Dotenv,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ExportFormat, String> {
+        match s {
+            "bash" => Ok(ExportFormat::Bash),
+            "zsh" => Ok(ExportFormat::Zsh),
+            "fish" => Ok(ExportFormat::Fish),
+            "json" => Ok(ExportFormat::Json),
+            "dotenv" => Ok(ExportFormat::Dotenv),
+            _ => Err(format!("unknown format {}", s)),
+        }
+    }
+}
+
+/// How a failed command prints its error.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    /// The message, for humans.
+    Human,
+    /// A JSON object, see `ExitError::to_json`.
+    Json,
+}
+
+impl std::str::FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ErrorFormat, String> {
+        match s {
+            "human" => Ok(ErrorFormat::Human),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("unknown format {}", s)),
+        }
+    }
+}
+
+impl ErrorFormat {
+    /// The `--error-format` given in the command line `args`, found
+    /// without parsing them, so that errors in the other arguments
+    /// can be printed in it. Arguments after `--` belong to commands.
+    pub fn requested(args: &[OsString]) -> ErrorFormat {
+        const OPTION: &str = "--error-format";
+        let mut args = args
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .take_while(|arg| arg != "--");
+        let mut format = ErrorFormat::Human;
+        while let Some(arg) = args.next() {
+            let value = if arg == OPTION {
+                args.next()
+            } else if arg.starts_with(OPTION) && arg[OPTION.len()..].starts_with('=') {
+                Some(arg[OPTION.len() + 1..].to_string().into())
+            } else {
+                continue;
+            };
+            if let Some(Ok(requested)) = value.map(|value| value.parse()) {
+                format = requested;
+            }
+        }
+        format
+    }
+}
+
+/// Options for the `init` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct InitOptions {
+    /// Pin nixpkgs in the new `shell.nix` to this git revision (or
+    /// branch) of https://github.com/NixOS/nixpkgs, instead of using
+    /// `<nixpkgs>`.
+    #[structopt(long = "pin", value_name = "REVISION")]
+    pub pin: Option<String>,
+}
+
+/// Options for the `exec` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct ExecOptions {
+    /// The command and its arguments.
+    #[structopt(name = "COMMAND", raw(required = "true"))]
+    pub command: Vec<String>,
+}
+
+/// Options for the `run` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct RunOptions {
+    /// The script. The project is the closest `shell.nix` (or
+    /// `--shell-file`) in its directory or a parent directory.
+    #[structopt(name = "SCRIPT", parse(from_os_str))]
+    pub script: PathBuf,
+    /// The arguments of the script.
+    #[structopt(name = "ARGS")]
+    pub args: Vec<String>,
+}
+
+/// Options for the `gc` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct GcOptions {
+    /// Only show which roots would be removed.
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+
+    /// Also remove the roots of projects which were not
+    /// built successfully for this many days.
+    #[structopt(long = "older-than", value_name = "DAYS")]
+    pub older_than: Option<u64>,
+}
+
+/// Options for the `watch` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct WatchOptions {
+    /// Only evaluate the expression and list the derivations
+    /// which would be built or fetched, then exit.
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+    /// Don't build, show what the lorri daemon does for the project
+    /// instead. Registers the project with the daemon, like `lorri ping`.
+    #[structopt(long = "attach")]
+    pub attach: bool,
+    /// Print one JSON object per event and line, for scripts and
+    /// editor plugins. See the README for the fields.
+    #[structopt(long = "json")]
+    pub json: bool,
+    /// Show the status, a scrolling log and the watched inputs on one
+    /// screen, with keys to rebuild (r), pause (p) and quit (q).
+    #[structopt(long = "tui", conflicts_with = "json")]
+    pub tui: bool,
 }
 
 /// Send a message with a lorri project.
@@ -66,10 +562,24 @@ pub enum Command {
 /// If the daemon has not been pinged for a project, it begins listening. If it does not
 /// get pinged for a long time, it may stop watching the project for changes.
 #[derive(StructOpt, Debug)]
-pub struct Ping_ {
-    /// The .nix file to watch and build on changes.
+pub struct PingOptions {
+    /// The .nix file to watch and build on changes. Defaults to
+    /// `shell.nix` in the current directory.
+    #[structopt(parse(from_os_str))]
+    pub nix_file: Option<PathBuf>,
+}
+
+/// Options for the `forget` subcommand.
+#[derive(StructOpt, Debug)]
+pub struct ForgetOptions {
+    /// The .nix file of the project. Defaults to `shell.nix` (or
+    /// `--shell-file`) in the current directory; it may be gone already.
     #[structopt(parse(from_os_str))]
-    pub nix_file: NixFile,
+    pub nix_file: Option<PathBuf>,
+    /// Also remove the project's GC roots and cached state, so
+    /// `nix-collect-garbage` can free its environment.
+    #[structopt(long = "delete-roots")]
+    pub delete_roots: bool,
 }
 
 /// A stub struct to represent how what we want to upgrade to.
@@ -95,11 +605,23 @@ pub enum UpgradeSource {
     #[structopt(name = "master")]
     Master,
 
+    /// Upgrade to the current version of a branch, which will be
+    /// fetched from git and built locally.
+    #[structopt(name = "branch")]
+    Branch(BranchDest),
+
     /// Upgrade to a version in an arbitrary local directory.
     #[structopt(name = "local")]
     Local(LocalDest),
 }
 
+/// Install the version of Lorri on a branch of its git repository.
+#[derive(StructOpt, Debug)]
+pub struct BranchDest {
+    /// the name of the branch, e.g. `master`.
+    pub name: String,
+}
+
 /// Install an arbitrary version of Lorri from a local directory.
 #[derive(StructOpt, Debug)]
 pub struct LocalDest {
@@ -107,3 +629,36 @@ pub struct LocalDest {
     #[structopt(parse(from_os_str))]
     pub path: PathBuf,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::ErrorFormat;
+    use std::ffi::OsString;
+
+    #[test]
+    fn error_format_before_parsing() {
+        let requested = |args: &[&str]| {
+            ErrorFormat::requested(&args.iter().map(OsString::from).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            requested(&["lorri", "build", "--bogus"]),
+            ErrorFormat::Human
+        );
+        assert_eq!(
+            requested(&["lorri", "--error-format", "json", "bogus"]),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            requested(&["lorri", "build", "--error-format=json", "--bogus"]),
+            ErrorFormat::Json
+        );
+        assert_eq!(
+            requested(&["lorri", "--error-format", "yaml", "bogus"]),
+            ErrorFormat::Human
+        );
+        assert_eq!(
+            requested(&["lorri", "exec", "--", "tool", "--error-format=json"]),
+            ErrorFormat::Human
+        );
+    }
+}
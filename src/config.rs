@@ -0,0 +1,363 @@
+//! User configuration of lorri.
+//!
+//! lorri reads an optional JSON file from the user’s configuration
+//! directory (`$XDG_CONFIG_HOME/lorri/config.json` on Linux, see
+//! `constants::Paths::config_file`). All settings are optional:
+//!
+//! ```json
+//! {
+//!   "defaults": { "rebuild_interval": 86400, "ignore": [ "node_modules/" ] },
+//!   "projects": {
+//!     "/home/alice/foo/shell.nix": { "rebuild_interval": 3600 }
+//!   }
+//! }
+//! ```
+//!
+//! `defaults` apply to every project, `projects` overrides single
+//! settings for the project with the given nix file.
+
+use crate::env::Passthrough;
+use crate::watch::ignore::Patterns;
+use crate::watch::Backend;
+use crate::NixFile;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Settings which apply to a single project.
+///
+/// Every field is optional; unset fields fall back to the global
+/// `defaults`, and then to lorri’s built-in behaviour.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    /// Rebuild the project every `rebuild_interval` seconds, even if
+    /// none of its input files changed. Picks up channel updates and
+    /// keeps the binary cache warm. Off by default.
+    pub rebuild_interval: Option<u64>,
+
+    /// Start builds of the project at most every `min_build_interval`
+    /// seconds. Changes in between are built together once the
+    /// interval passed, so projects whose generated files change all
+    /// the time don’t keep the machine busy. Off by default.
+    pub min_build_interval: Option<u64>,
+
+    /// Don’t watch files which are ignored by git, even if the
+    /// evaluation read them. On by default.
+    pub respect_gitignore: Option<bool>,
+
+    /// Paths which are never watched, in `.gitignore` syntax; patterns
+    /// containing a `/` are relative to the project directory.
+    /// Project patterns are added to the global ones.
+    pub ignore: Option<Vec<String>>,
+
+    /// How to detect changes: `"native"` (inotify & co.), `"poll"`,
+    /// `"watchman"`, or `"auto"`, which polls on network filesystems.
+    /// `"auto"` by default.
+    pub watch_backend: Option<Backend>,
+
+    /// Seconds between checks of the `"poll"` backend. 1 by default.
+    pub poll_interval: Option<u64>,
+
+    /// Also link the latest shell environment to `.lorri/gc_root` in
+    /// the project directory. Off by default.
+    pub project_gc_root: Option<bool>,
+
+    /// The attribute of the nix file which provides the environment,
+    /// like `nix-build -A`. By default the expression itself, or its
+    /// `shell` attribute, or its only derivation.
+    pub attr: Option<String>,
+
+    /// Variables `lorri shell --pure` keeps from the user’s
+    /// environment, besides `HOME` and `TERM`. Project variables are
+    /// added to the global ones.
+    pub pure_keep: Option<Vec<String>>,
+
+    /// If set, only these variables of the user’s environment (and
+    /// `HOME` and `TERM`) are passed into the project’s environment.
+    /// A trailing `*` matches any suffix. Project variables are added
+    /// to the global ones.
+    pub env_allow: Option<Vec<String>>,
+
+    /// Variables of the user’s environment which are never passed
+    /// into the project’s environment, like `env_allow`.
+    pub env_deny: Option<Vec<String>>,
+}
+
+impl ProjectConfig {
+    /// Fill every unset setting from `fallback`.
+    fn or(self, fallback: &ProjectConfig) -> ProjectConfig {
+        ProjectConfig {
+            rebuild_interval: self.rebuild_interval.or(fallback.rebuild_interval),
+            min_build_interval: self.min_build_interval.or(fallback.min_build_interval),
+            respect_gitignore: self.respect_gitignore.or(fallback.respect_gitignore),
+            ignore: combine(&fallback.ignore, self.ignore),
+            watch_backend: self.watch_backend.or(fallback.watch_backend),
+            poll_interval: self.poll_interval.or(fallback.poll_interval),
+            project_gc_root: self.project_gc_root.or(fallback.project_gc_root),
+            attr: self.attr.or_else(|| fallback.attr.clone()),
+            pure_keep: combine(&fallback.pure_keep, self.pure_keep),
+            env_allow: combine(&fallback.env_allow, self.env_allow),
+            env_deny: combine(&fallback.env_deny, self.env_deny),
+        }
+    }
+
+    /// The interval for scheduled rebuilds, if any.
+    pub fn rebuild_interval(&self) -> Option<Duration> {
+        self.rebuild_interval.map(Duration::from_secs)
+    }
+
+    /// The least time between the starts of two builds, if any.
+    pub fn min_build_interval(&self) -> Option<Duration> {
+        self.min_build_interval.map(Duration::from_secs)
+    }
+
+    /// Whether paths ignored by git should be watched.
+    pub fn respect_gitignore(&self) -> bool {
+        self.respect_gitignore.unwrap_or(true)
+    }
+
+    /// How to detect changes.
+    pub fn watch_backend(&self) -> Backend {
+        self.watch_backend.unwrap_or(Backend::Auto)
+    }
+
+    /// How often the polling backend checks for changes.
+    pub fn poll_interval(&self) -> Duration {
+        Duration::from_secs(self.poll_interval.unwrap_or(1))
+    }
+
+    /// Whether to link the shell environment into the project directory.
+    pub fn project_gc_root(&self) -> bool {
+        self.project_gc_root.unwrap_or(false)
+    }
+
+    /// The attribute which provides the environment, if not the whole
+    /// expression.
+    pub fn attr(&self) -> Option<&str> {
+        self.attr.as_ref().map(String::as_str)
+    }
+
+    /// The variables `lorri shell --pure` keeps, besides the ones it
+    /// always keeps.
+    pub fn pure_keep(&self) -> &[String] {
+        self.pure_keep.as_ref().map_or(&[], |keep| &keep[..])
+    }
+
+    /// Which variables of the user’s environment are passed into the
+    /// project’s environment.
+    pub fn passthrough(&self) -> Passthrough {
+        Passthrough {
+            allow: self.env_allow.clone(),
+            deny: self.env_deny.clone().unwrap_or_default(),
+        }
+    }
+
+    /// The paths which should never be watched.
+    pub fn ignore(&self) -> Patterns {
+        Patterns::from_lines(self.ignore.iter().flatten())
+    }
+}
+
+/// A list setting of a project, added to the global one.
+fn combine(global: &Option<Vec<String>>, project: Option<Vec<String>>) -> Option<Vec<String>> {
+    match (global.as_ref(), project) {
+        (Some(global), Some(project)) => Some([&global[..], &project[..]].concat()),
+        (global, project) => project.or_else(|| global.cloned()),
+    }
+}
+
+/// The contents of the lorri configuration file.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Settings for every project.
+    #[serde(default)]
+    pub defaults: ProjectConfig,
+
+    /// Settings for specific projects, keyed by their nix file.
+    #[serde(default)]
+    pub projects: HashMap<PathBuf, ProjectConfig>,
+
+    /// Let the daemon remove the GC roots of projects which were not
+    /// built for this many days, or whose nix file is gone.
+    /// Off by default.
+    pub prune_roots_after: Option<u64>,
+
+    /// Keep GC roots in this directory instead of lorri’s data
+    /// directory. `$LORRI_ROOTS_DIR` takes precedence.
+    pub roots_dir: Option<PathBuf>,
+
+    /// When the daemon is asked to stop, let running builds finish
+    /// for up to this many seconds before cancelling them.
+    /// 0 (cancel them right away) by default.
+    pub shutdown_wait: Option<u64>,
+
+    /// How many projects the daemon builds at the same time, the
+    /// others wait for their turn. 2 by default.
+    pub max_builds: Option<usize>,
+
+    /// Serve a status page of the daemon at `http://<http_address>/`
+    /// (`/status.json` for scripts), and its metrics in the Prometheus
+    /// text format at `/metrics`, e.g. `"127.0.0.1:9188"`.
+    /// Only loopback addresses are allowed. Off by default.
+    pub http_address: Option<String>,
+}
+
+/// Error conditions encountered when loading the configuration file.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file exists, but could not be read
+    Io(std::io::Error, PathBuf),
+
+    /// The file is not a valid configuration
+    Parse(serde_json::Error, PathBuf),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e, path) => write!(f, "Cannot read {}: {}", path.display(), e),
+            ConfigError::Parse(e, path) => write!(f, "Invalid config {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from `path`.
+    /// A missing file is not an error, it means “use the defaults”.
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        match std::fs::read(path) {
+            Ok(contents) => {
+                serde_json::from_slice(&contents).map_err(|e| ConfigError::Parse(e, path.into()))
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(ConfigError::Io(e, path.into())),
+        }
+    }
+
+    /// How long a project may go without being built before the
+    /// daemon removes its GC roots, if it should do that at all.
+    pub fn prune_roots_after(&self) -> Option<Duration> {
+        self.prune_roots_after
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60))
+    }
+
+    /// How long the daemon waits for running builds when it stops.
+    pub fn shutdown_wait(&self) -> Duration {
+        Duration::from_secs(self.shutdown_wait.unwrap_or(0))
+    }
+
+    /// How many projects the daemon builds at the same time.
+    pub fn max_builds(&self) -> usize {
+        self.max_builds.unwrap_or(2)
+    }
+
+    /// Where the daemon serves its status page and metrics, if at all.
+    pub fn http_address(&self) -> Result<Option<SocketAddr>, String> {
+        let address = match self.http_address {
+            Some(ref address) => address,
+            None => return Ok(None),
+        };
+        let parsed = address
+            .parse::<SocketAddr>()
+            .map_err(|e| format!("Invalid http_address {:?}: {}", address, e))?;
+        if !parsed.ip().is_loopback() {
+            return Err(format!(
+                "http_address {:?} is not a loopback address, the status page must not be \
+                 reachable from other machines",
+                address
+            ));
+        }
+        Ok(Some(parsed))
+    }
+
+    /// The effective settings for the project of `nix_file`.
+    pub fn project(&self, nix_file: &NixFile) -> ProjectConfig {
+        match self.projects.get(Path::new(nix_file.as_os_str())) {
+            Some(project) => project.clone().or(&self.defaults),
+            None => self.defaults.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ProjectConfig};
+    use crate::NixFile;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn missing_file_is_default() {
+        let config = Config::load(&PathBuf::from("/this/lorri/config/does/not/exist.json"));
+        assert_eq!(config.unwrap(), Config::default());
+    }
+
+    #[test]
+    fn project_overrides_defaults() {
+        let config: Config = serde_json::from_str(
+            r#"{
+              "defaults": { "rebuild_interval": 100 },
+              "projects": { "/foo/shell.nix": { "rebuild_interval": 5 } }
+            }"#,
+        )
+        .unwrap();
+
+        let foo = config.project(&NixFile::from(PathBuf::from("/foo/shell.nix")));
+        assert_eq!(foo.rebuild_interval(), Some(Duration::from_secs(5)));
+
+        let bar = config.project(&NixFile::from(PathBuf::from("/bar/shell.nix")));
+        assert_eq!(bar.rebuild_interval(), Some(Duration::from_secs(100)));
+
+        assert_eq!(ProjectConfig::default().rebuild_interval(), None);
+        assert_eq!(ProjectConfig::default().min_build_interval(), None);
+    }
+
+    #[test]
+    fn pruning_is_opt_in() {
+        assert_eq!(Config::default().prune_roots_after(), None);
+        let config: Config = serde_json::from_str(r#"{ "prune_roots_after": 30 }"#).unwrap();
+        assert_eq!(
+            config.prune_roots_after(),
+            Some(Duration::from_secs(30 * 24 * 60 * 60))
+        );
+    }
+
+    #[test]
+    fn ignore_patterns_are_combined() {
+        let config: Config = serde_json::from_str(
+            r#"{
+              "defaults": { "ignore": [ ".git/" ] },
+              "projects": { "/foo/shell.nix": { "ignore": [ "target/" ] } }
+            }"#,
+        )
+        .unwrap();
+
+        let foo = config.project(&NixFile::from(PathBuf::from("/foo/shell.nix")));
+        assert_eq!(foo.ignore, Some(vec![".git/".into(), "target/".into()]));
+
+        let bar = config.project(&NixFile::from(PathBuf::from("/bar/shell.nix")));
+        assert_eq!(bar.ignore, Some(vec![".git/".into()]));
+    }
+
+    #[test]
+    fn http_address_must_be_loopback() {
+        let address = |a: &str| {
+            Config {
+                http_address: Some(a.into()),
+                ..Config::default()
+            }
+            .http_address()
+        };
+        assert_eq!(Config::default().http_address(), Ok(None));
+        assert_eq!(
+            address("127.0.0.1:9188"),
+            Ok(Some("127.0.0.1:9188".parse().unwrap()))
+        );
+        assert!(address("[::1]:9188").unwrap().is_some());
+        assert!(address("0.0.0.0:9188").is_err());
+        assert!(address("localhost").is_err());
+    }
+}
@@ -3,12 +3,24 @@
 extern crate directories;
 
 use self::directories::ProjectDirs;
+use crate::config::Config;
+use std::env;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 
+/// Environment variable overriding the directory of GC roots.
+pub const ROOTS_DIR_ENV: &str = "LORRI_ROOTS_DIR";
+
+/// Environment variable overriding the path of the daemon socket.
+pub const SOCKET_ENV: &str = "LORRI_DAEMON_SOCKET";
+
 /// Path constants like the GC root directory.
 pub struct Paths {
     gc_root_dir: PathBuf,
     daemon_socket_file: PathBuf,
+    config_file: PathBuf,
+    allow_dir: PathBuf,
+    daemon_projects_file: PathBuf,
 }
 
 impl Paths {
@@ -19,29 +31,170 @@ impl Paths {
         let create_dir = |dir: PathBuf| -> std::io::Result<PathBuf> {
             std::fs::create_dir_all(&dir).and(Ok(dir))
         };
-        Ok(Paths {
-            gc_root_dir: create_dir(pd.cache_dir().join("gc_roots"))?,
-            daemon_socket_file: create_dir(
-                pd.runtime_dir()
+        let config_file = pd.config_dir().join("config.json");
+        // an invalid configuration is reported when it is loaded
+        let configured = Config::load(&config_file)
+            .ok()
+            .and_then(|config| config.roots_dir);
+        let gc_root_dir = choose_gc_root_dir(
+            env::var_os(ROOTS_DIR_ENV).map(PathBuf::from),
+            configured,
+            pd.cache_dir().join("gc_roots"),
+            pd.data_dir().join("gc_roots"),
+        );
+        let daemon_socket_file = match env::var_os(SOCKET_ENV).filter(|p| !p.is_empty()) {
+            Some(path) => {
+                let path = env::current_dir()?.join(path);
+                if let Some(dir) = path.parent() {
+                    create_private_dir(dir)?;
+                }
+                path
+            }
+            None => {
+                let dir = pd
+                    .runtime_dir()
                     // fall back to the cache dir on non-linux
-                    .unwrap_or_else(|| pd.cache_dir())
-                    .to_owned(),
-            )?
-            .join("daemon.socket"),
+                    .unwrap_or_else(|| pd.cache_dir());
+                create_private_dir(dir)?;
+                restrict_to_owner(dir)?;
+                dir.join("daemon.socket")
+            }
+        };
+        Ok(Paths {
+            gc_root_dir: create_dir(env::current_dir()?.join(gc_root_dir))?,
+            daemon_socket_file,
+            config_file,
+            allow_dir: pd.data_dir().join("allow"),
+            daemon_projects_file: pd.data_dir().join("daemon_projects.json"),
         })
     }
 
-    /// Default location in the user's XDG directories to keep
-    /// GC root pins
+    /// The paths below `dir` instead of the user’s directories, for
+    /// tests which must not touch the user’s state. Creates nothing.
+    pub fn below(dir: &Path) -> Paths {
+        Paths {
+            gc_root_dir: dir.join("gc_roots"),
+            daemon_socket_file: dir.join("daemon.socket"),
+            config_file: dir.join("config.json"),
+            allow_dir: dir.join("allow"),
+            daemon_projects_file: dir.join("daemon_projects.json"),
+        }
+    }
+
+    /// Where GC root pins are kept. In the user’s XDG data directory
+    /// by default, see `choose_gc_root_dir`.
     pub fn gc_root_dir(&self) -> &Path {
         &self.gc_root_dir
     }
 
-    /// Path to the socket file.
+    /// Path to the socket file: `$LORRI_DAEMON_SOCKET` if it is set,
+    /// else `$XDG_RUNTIME_DIR/lorri/daemon.socket` (in a directory
+    /// only the user can access).
     ///
     /// The daemon uses this path to create its Unix socket on
     /// (see `::daemon` and `::socket::communicate`).
     pub fn daemon_socket_file(&self) -> &Path {
         &self.daemon_socket_file
     }
+
+    /// Where `lorri allow` and `lorri deny` record which nix files
+    /// may be built (see `::trust`). Created on the first decision.
+    pub fn allow_dir(&self) -> &Path {
+        &self.allow_dir
+    }
+
+    /// Where the daemon saves the projects it builds, to resume them
+    /// after a restart (see `::daemon::Daemon::resume`).
+    pub fn daemon_projects_file(&self) -> &Path {
+        &self.daemon_projects_file
+    }
+
+    /// Path to the user’s configuration file (see `::config`).
+    /// The file does not necessarily exist.
+    pub fn config_file(&self) -> &Path {
+        &self.config_file
+    }
+}
+
+/// Create `dir` and its missing parents, accessible only by the user.
+/// Existing directories are left alone.
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+}
+
+/// Make lorri’s own directory `dir` accessible only by the user, in
+/// case an earlier version of lorri created it with the default
+/// permissions. Other users must not connect to the daemon.
+fn restrict_to_owner(dir: &Path) -> std::io::Result<()> {
+    let permissions = std::fs::metadata(dir)?.permissions();
+    if permissions.mode() & 0o077 != 0 {
+        std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// The directory for GC roots: `$LORRI_ROOTS_DIR` if it is set, else
+/// the configured `roots_dir`, else `default`. Roots in `legacy` (the
+/// cache directory earlier versions of lorri used) stay where they
+/// are, since Nix only knows them by that path.
+fn choose_gc_root_dir(
+    from_env: Option<PathBuf>,
+    configured: Option<PathBuf>,
+    legacy: PathBuf,
+    default: PathBuf,
+) -> PathBuf {
+    from_env
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .or(configured)
+        .unwrap_or_else(|| if legacy.is_dir() { legacy } else { default })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{choose_gc_root_dir, create_private_dir, restrict_to_owner};
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn gc_root_dir_precedence() {
+        let temp = tempdir().unwrap();
+        let legacy = temp.path().join("cache");
+        let default = temp.path().join("data");
+        let dir = |name: &str| Some(PathBuf::from(name));
+
+        assert_eq!(
+            choose_gc_root_dir(dir("/env"), dir("/config"), legacy.clone(), default.clone()),
+            PathBuf::from("/env")
+        );
+        assert_eq!(
+            choose_gc_root_dir(dir(""), dir("/config"), legacy.clone(), default.clone()),
+            PathBuf::from("/config")
+        );
+        assert_eq!(
+            choose_gc_root_dir(None, None, legacy.clone(), default.clone()),
+            default
+        );
+        std::fs::create_dir(&legacy).unwrap();
+        assert_eq!(
+            choose_gc_root_dir(None, None, legacy.clone(), default.clone()),
+            legacy
+        );
+    }
+
+    #[test]
+    fn socket_dir_is_private() {
+        let temp = tempdir().unwrap();
+        let dir = temp.path().join("runtime/lorri");
+        let mode = |dir: &std::path::Path| std::fs::metadata(dir).unwrap().permissions().mode();
+        create_private_dir(&dir).unwrap();
+        assert_eq!(mode(&dir) & 0o777, 0o700);
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        restrict_to_owner(&dir).unwrap();
+        assert_eq!(mode(&dir) & 0o777, 0o700);
+    }
 }
@@ -1,20 +1,63 @@
 //! The lorri daemon, watches multiple projects in the background.
 
-use crate::build_loop::BuildLoop;
+use crate::build_loop::{BuildLoop, BuildSlots, Event, Warning, WatchSet};
+use crate::builder;
+use crate::config::{Config, ProjectConfig};
+use crate::duration;
+use crate::metrics::{Gauges, Metrics};
 use crate::project::Project;
-use crate::roots::Roots;
-use crate::socket::communicate::{NoMessage, Ping, DEFAULT_READ_TIMEOUT};
+use crate::roots::{ProjectRoots, Roots};
+use crate::socket::communicate::{
+    BuildState, ForgetRequest, ForgetResponse, LogLine, LogsRequest, NoMessage, Ping,
+    ProjectStatus, ShellMessage, ShellRequest, SubscribeRequest, UpgradeRequest, UpgradeResponse,
+    DEFAULT_READ_TIMEOUT,
+};
 use crate::socket::{ReadError, ReadWriter, Timeout};
+use crate::state_dump;
+use crate::status_page::{self, ProjectPage};
+use crate::trust::Trust;
 use crate::NixFile;
-use std::collections::HashMap;
-use std::sync::mpsc;
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How many recent events the daemon keeps per project,
+/// to replay them to subscribers connecting later.
+pub const EVENT_BUFFER_SIZE: usize = 32;
+
+/// How many recent build log lines the daemon keeps per project,
+/// see `lorri logs`.
+pub const LOG_BUFFER_SIZE: usize = 1000;
+
+/// How many events a subscriber may fall behind before the daemon
+/// drops it, see `EventHub::notify`.
+pub const SUBSCRIBER_QUEUE_SIZE: usize = 4096;
+
+/// How long the daemon waits before restarting a crashed `BuildLoop`
+/// the first time. Doubles with every crash, up to `MAX_RESTART_DELAY`.
+pub const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest wait before restarting a crashed `BuildLoop`. A loop
+/// which ran for longer than this before crashing starts over with
+/// `RESTART_DELAY`.
+pub const MAX_RESTART_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// How long `Daemon::forget` waits for a project’s `BuildLoop` to
+/// stop, before removing its GC roots anyway.
+pub const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the daemon looks for GC roots to prune,
+/// if `prune_roots_after` is configured.
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 /// Indicate that the user is interested in a specific nix file.
 /// Usually a nix file describes the environment of a project,
 /// so the user editor would send this message when a file
 /// in the project is opened, through `lorri direnv` for example.
 ///
-/// `lorri ping_` is the plumbing command which triggers this signal.
+/// `lorri ping` is the command which triggers this signal.
 ///
 /// Note especially that we don’t want to fix the server reaction to
 /// this signal yet, sending `IndicateActivity` does not necessarily
@@ -26,40 +69,572 @@ pub struct IndicateActivity {
     pub nix_file: NixFile,
 }
 
+/// Ask the daemon to stop building a project, see `lorri forget`.
+pub struct ForgetProject {
+    /// What to forget.
+    pub request: ForgetRequest,
+    /// Receives the outcome once the project is forgotten.
+    pub done: mpsc::Sender<ForgetResponse>,
+}
+
+/// Ask the daemon to continue as another lorri, see `lorri self-upgrade`.
+pub struct UpgradeDaemon {
+    /// Which lorri to continue as.
+    pub request: UpgradeRequest,
+    /// Receives the answer right before the daemon is replaced.
+    pub done: mpsc::Sender<UpgradeResponse>,
+    /// Closed once the answer is sent to the client, so the daemon
+    /// is not replaced while sending it.
+    pub answered: mpsc::Receiver<()>,
+}
+
+/// A `build_loop::Event`, together with the project it belongs to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProjectEvent {
+    /// The nix file of the `BuildLoop` which sent the event.
+    pub nix_file: NixFile,
+    /// The event itself.
+    pub event: Event,
+}
+
+/// Distributes the events of all projects to any number of subscribers.
+///
+/// The most recent events of every project are kept in a bounded
+/// buffer, so that subscribers which arrive late (e.g. after the
+/// build already finished) still learn about the current state.
+struct EventHub {
+    /// Recent events, keyed by project.
+    buffers: HashMap<NixFile, VecDeque<Event>>,
+    /// Recent build log lines, keyed by project. They are not replayed
+    /// to subscribers, but can be fetched with `lorri logs`.
+    logs: HashMap<NixFile, VecDeque<String>>,
+    /// The state of every project, as far as its events tell.
+    projects: HashMap<NixFile, Tracked>,
+    /// Everyone who is interested in new events.
+    subscribers: Vec<mpsc::SyncSender<ProjectEvent>>,
+    /// The daemon’s own consumer of the events, see `Daemon::new`.
+    /// Unlike `subscribers` it is never dropped for falling behind.
+    daemon: Option<mpsc::Sender<ProjectEvent>>,
+    /// Where the tracked projects are saved whenever they change,
+    /// if at all.
+    projects_file: Option<PathBuf>,
+    /// Whether `projects` changed since they were last taken to be
+    /// saved, see `save`.
+    unsaved: bool,
+    /// Counted from the events, see `HandlerFns::metrics`.
+    metrics: Metrics,
+}
+
+/// Lock the `EventHub` shared by all projects. A panic while it was
+/// locked (in some project’s thread) leaves its state usable, so the
+/// other projects carry on instead of panicking as well.
+fn lock_hub(events: &Mutex<EventHub>) -> MutexGuard<'_, EventHub> {
+    events.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A project the daemon builds, as saved across restarts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Registered {
+    nix_file: PathBuf,
+    state: BuildState,
+    /// When the last build finished, in seconds since the epoch.
+    last_build: Option<u64>,
+    /// How long the last build took, in seconds.
+    last_build_duration: Option<f64>,
+}
+
+/// The tracked projects of an `EventHub`, taken to be written to its
+/// `projects_file` after the hub is unlocked again.
+struct Unsaved {
+    projects_file: PathBuf,
+    registered: Vec<Registered>,
+}
+
+impl Unsaved {
+    /// Write the projects to `projects_file`, atomically.
+    fn write(&self) {
+        let tmp = self.projects_file.with_extension("json.tmp");
+        let saved = std::fs::write(
+            &tmp,
+            serde_json::to_vec_pretty(&self.registered).expect("projects serialize to JSON"),
+        )
+        .and_then(|()| std::fs::rename(&tmp, &self.projects_file));
+        if let Err(e) = saved {
+            warn!(
+                "Failed to save the projects to {}: {}",
+                self.projects_file.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Write the tracked projects of `events` to its `projects_file` if
+/// they changed, without holding its lock meanwhile. `saving` keeps
+/// concurrent writes in order, the projects taken last are written
+/// last.
+fn save(events: &Mutex<EventHub>, saving: &Mutex<()>) {
+    let _saving = saving.lock().unwrap_or_else(PoisonError::into_inner);
+    let unsaved = lock_hub(events).unsaved();
+    if let Some(unsaved) = unsaved {
+        unsaved.write();
+    }
+}
+
+/// The projects a previous daemon saved to `projects_file`.
+/// A missing file means there are none.
+fn load_registered(projects_file: &Path) -> std::io::Result<Vec<Registered>> {
+    match std::fs::read(projects_file) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// The state of a project the daemon builds, see `lorri status`.
+struct Tracked {
+    roots: Roots,
+    state: BuildState,
+    /// When the running build started.
+    started: Option<Instant>,
+    /// When the last build finished, and how long it took.
+    last_build: Option<(SystemTime, Duration)>,
+}
+
+impl Tracked {
+    /// When the last build finished, in seconds since the epoch.
+    fn last_build_secs(&self) -> Option<u64> {
+        self.last_build.and_then(|(finished, _)| {
+            finished
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|since_epoch| since_epoch.as_secs())
+        })
+    }
+
+    fn registered(&self, nix_file: &NixFile) -> Registered {
+        Registered {
+            nix_file: PathBuf::from(nix_file.as_os_str()),
+            state: self.state,
+            last_build: self.last_build_secs(),
+            last_build_duration: self.last_build.map(|(_, took)| duration::as_secs_f64(took)),
+        }
+    }
+
+    /// What the project’s build loop is doing, for humans.
+    fn describe(&self) -> String {
+        let mut out = String::from(status_page::state(self.state));
+        if let (BuildState::Building, Some(started)) = (self.state, self.started) {
+            out.push_str(&format!(" for {}s", started.elapsed().as_secs()));
+        }
+        if let Some((finished, took)) = self.last_build {
+            out.push_str(&format!(
+                ", the last build finished {}s ago and took {}s",
+                finished.elapsed().unwrap_or_default().as_secs(),
+                took.as_secs()
+            ));
+        }
+        out
+    }
+
+    fn status(&self, nix_file: &NixFile) -> ProjectStatus {
+        ProjectStatus {
+            nix_file: nix_file.clone(),
+            state: self.state,
+            last_build: self.last_build_secs(),
+            last_build_duration: self.last_build.map(|(_, took)| duration::as_secs_f64(took)),
+            gc_root: self.roots.shell_gc_root().as_path().to_owned(),
+            watched_files: WatchSet::load(&self.roots)
+                .map(|watch_set| watch_set.watched.len())
+                .unwrap_or(0),
+        }
+    }
+}
+
+impl EventHub {
+    fn new() -> EventHub {
+        EventHub {
+            buffers: HashMap::new(),
+            logs: HashMap::new(),
+            projects: HashMap::new(),
+            subscribers: vec![],
+            daemon: None,
+            projects_file: None,
+            unsaved: false,
+            metrics: Metrics::default(),
+        }
+    }
+
+    /// Start tracking the state of the project `nix_file`, continuing
+    /// from what a previous daemon saved about it, if anything.
+    fn track(&mut self, nix_file: NixFile, roots: Roots, previous: Option<&Registered>) {
+        let last_build = previous.and_then(|previous| {
+            match (previous.last_build, previous.last_build_duration) {
+                (Some(finished), Some(took)) => Some((
+                    UNIX_EPOCH + Duration::from_secs(finished),
+                    duration::from_secs_f64(took),
+                )),
+                _ => None,
+            }
+        });
+        let state = match previous.map(|previous| previous.state) {
+            // that build was interrupted
+            Some(BuildState::Building) | None => BuildState::Pending,
+            Some(state) => state,
+        };
+        self.projects.entry(nix_file).or_insert(Tracked {
+            roots,
+            state,
+            started: None,
+            last_build,
+        });
+        self.unsaved = true;
+    }
+
+    /// Stop tracking `nix_file` and drop its buffered events and log
+    /// lines. Returns whether it was tracked.
+    fn untrack(&mut self, nix_file: &NixFile) -> bool {
+        self.buffers.remove(nix_file);
+        self.logs.remove(nix_file);
+        let tracked = self.projects.remove(nix_file).is_some();
+        if tracked {
+            self.unsaved = true;
+        }
+        tracked
+    }
+
+    /// Every tracked project, if they changed since they were last
+    /// taken and are saved at all.
+    fn unsaved(&mut self) -> Option<Unsaved> {
+        let projects_file = match self.projects_file {
+            Some(ref projects_file) if self.unsaved => projects_file.clone(),
+            _ => return None,
+        };
+        self.unsaved = false;
+        let mut registered = self
+            .projects
+            .iter()
+            .map(|(nix_file, tracked)| tracked.registered(nix_file))
+            .collect::<Vec<_>>();
+        registered.sort_by(|a, b| a.nix_file.cmp(&b.nix_file));
+        Some(Unsaved {
+            projects_file,
+            registered,
+        })
+    }
+
+    /// The status of every tracked project, ordered by nix file.
+    fn statuses(&self) -> Vec<ProjectStatus> {
+        let mut statuses = self
+            .projects
+            .iter()
+            .map(|(nix_file, tracked)| tracked.status(nix_file))
+            .collect::<Vec<_>>();
+        statuses.sort_by(|a, b| a.nix_file.as_os_str().cmp(b.nix_file.as_os_str()));
+        statuses
+    }
+
+    /// The status and recent events of every tracked project, ordered
+    /// by nix file.
+    fn pages(&self) -> Vec<ProjectPage> {
+        self.statuses()
+            .into_iter()
+            .map(|status| ProjectPage {
+                recent_events: self
+                    .buffers
+                    .get(&status.nix_file)
+                    .map(|buffer| buffer.iter().cloned().collect())
+                    .unwrap_or_default(),
+                status,
+            })
+            .collect()
+    }
+
+    /// The state of the daemon for `HandlerFns::dump`.
+    fn dump(&self, slots: &BuildSlots) -> String {
+        let mut projects = self.projects.iter().collect::<Vec<_>>();
+        projects.sort_by(|a, b| a.0.as_os_str().cmp(b.0.as_os_str()));
+        let buffers = projects
+            .iter()
+            .map(|(nix_file, _)| {
+                self.buffers
+                    .get(*nix_file)
+                    .map(|buffer| buffer.iter().cloned().collect())
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<Vec<Event>>>();
+        let projects = projects
+            .iter()
+            .zip(&buffers)
+            .map(|((nix_file, tracked), recent_events)| state_dump::Project {
+                nix_file,
+                state: tracked.describe(),
+                watch_set: WatchSet::load(&tracked.roots).ok(),
+                recent_events,
+            })
+            .collect::<Vec<_>>();
+        state_dump::dump("lorri daemon", Some(slots), &projects)
+    }
+
+    /// Whether any tracked project is being built right now.
+    fn is_building(&self) -> bool {
+        self.projects
+            .values()
+            .any(|tracked| tracked.state == BuildState::Building)
+    }
+
+    /// The buffered log lines of `nix_file`, or of every project
+    /// (ordered by nix file) if `None`.
+    fn logs(&self, nix_file: Option<&NixFile>) -> Vec<LogLine> {
+        let mut projects = self
+            .logs
+            .iter()
+            .filter(|(project, _)| nix_file.map_or(true, |nix_file| nix_file == *project))
+            .collect::<Vec<_>>();
+        projects.sort_by(|(a, _), (b, _)| a.as_os_str().cmp(b.as_os_str()));
+        projects
+            .into_iter()
+            .flat_map(|(project, lines)| {
+                lines.iter().map(move |line| LogLine {
+                    nix_file: project.clone(),
+                    line: line.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// Record the event and pass it on to all subscribers.
+    /// Subscribers which hung up are dropped. Returns whether the
+    /// tracked projects changed and have to be `save`d.
+    fn publish(&mut self, project_event: ProjectEvent) -> bool {
+        if let Event::Log(_, ref line) = project_event.event {
+            {
+                let lines = self.logs.entry(project_event.nix_file.clone()).or_default();
+                if lines.len() == LOG_BUFFER_SIZE {
+                    lines.pop_front();
+                }
+                lines.push_back(line.clone());
+            }
+            self.notify(&project_event);
+            return false;
+        }
+
+        let mut changed = false;
+        if let Some(tracked) = self.projects.get_mut(&project_event.nix_file) {
+            let finished = match project_event.event {
+                Event::Started(_, _) => {
+                    tracked.state = BuildState::Building;
+                    tracked.started = Some(Instant::now());
+                    self.metrics.build_started(&project_event.nix_file);
+                    None
+                }
+                Event::Warning(Warning::WatcherRestarted(_))
+                | Event::Warning(Warning::WatchLimitReached) => {
+                    self.metrics.watcher_error(&project_event.nix_file);
+                    None
+                }
+                Event::Completed(_) => Some(BuildState::Success),
+                Event::Failure(_) => Some(BuildState::Failure),
+                // the build died with the loop
+                Event::Warning(Warning::Crashed(_, _)) if tracked.state == BuildState::Building => {
+                    Some(BuildState::Failure)
+                }
+                _ => None,
+            };
+            if let Some(state) = finished {
+                tracked.state = state;
+                let started = tracked.started.take();
+                // a cached result from before the daemon started is no
+                // build, the last one is restored already
+                if let Some(started) = started {
+                    let took = started.elapsed();
+                    self.metrics
+                        .build_finished(&project_event.nix_file, state, took);
+                    tracked.last_build = Some((SystemTime::now(), took));
+                    changed = true;
+                }
+            }
+        }
+        self.unsaved |= changed;
+
+        {
+            let buffer = self
+                .buffers
+                .entry(project_event.nix_file.clone())
+                .or_default();
+            // a build sends many heartbeats, only the latest one is interesting
+            if let (Some(Event::Heartbeat(_)), Event::Heartbeat(_)) =
+                (buffer.back(), &project_event.event)
+            {
+                buffer.pop_back();
+            }
+            if buffer.len() == EVENT_BUFFER_SIZE {
+                buffer.pop_front();
+            }
+            buffer.push_back(project_event.event.clone());
+        }
+
+        self.notify(&project_event);
+        changed
+    }
+
+    /// Pass `project_event` on to all subscribers. Subscribers which
+    /// hung up are dropped, and so are those which fell more than
+    /// `SUBSCRIBER_QUEUE_SIZE` events behind: a slow client must not
+    /// hold up the projects publishing events.
+    fn notify(&mut self, project_event: &ProjectEvent) {
+        if let Some(ref daemon) = self.daemon {
+            // it only hangs up when the daemon exits
+            let _ = daemon.send(project_event.clone());
+        }
+        self.subscribers
+            .retain(|sub| match sub.try_send(project_event.clone()) {
+                Ok(()) => true,
+                Err(mpsc::TrySendError::Full(_)) => {
+                    warn!("Dropped a subscriber which fell too far behind");
+                    false
+                }
+                Err(mpsc::TrySendError::Disconnected(_)) => false,
+            });
+    }
+
+    /// Add a new subscriber. The recent events of every project are
+    /// replayed to it before it receives any new ones.
+    fn subscribe(&mut self) -> mpsc::Receiver<ProjectEvent> {
+        let replay = self.buffers.values().map(VecDeque::len).sum::<usize>();
+        let (tx, rx) = mpsc::sync_channel(replay + SUBSCRIBER_QUEUE_SIZE);
+        for (nix_file, buffer) in self.buffers.iter() {
+            for event in buffer {
+                tx.try_send(ProjectEvent {
+                    nix_file: nix_file.clone(),
+                    event: event.clone(),
+                })
+                .expect("the receiving end is still in scope");
+            }
+        }
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// Add a new subscriber, which only receives new events.
+    fn follow(&mut self) -> mpsc::Receiver<ProjectEvent> {
+        let (tx, rx) = mpsc::sync_channel(SUBSCRIBER_QUEUE_SIZE);
+        self.subscribers.push(tx);
+        rx
+    }
+}
+
 /// Keeps all state of the running `lorri daemon` service, watches nix files and runs builds.
 pub struct Daemon<'a> {
     /// A thread for each `BuildLoop`, keyed by the nix files listened on.
-    handler_threads: HashMap<NixFile, std::thread::JoinHandle<()>>,
-    /// Sending end that we pass to every `BuildLoop` the daemon controls.
-    // TODO: this needs to transmit information to identify the builder with
-    build_events_tx: mpsc::Sender<::build_loop::Event>,
+    handler_threads: HashMap<NixFile, LoopThread>,
+    /// Collects the events of every `BuildLoop` the daemon controls.
+    events: Arc<Mutex<EventHub>>,
+    /// Held while saving the projects, see `save`.
+    saving: Arc<Mutex<()>>,
     /// Static paths the daemon has access to.
     paths: &'a ::constants::Paths,
+    /// User configuration, for the settings of each project.
+    config: Config,
+    /// Shared by all `BuildLoop`s, see `Config::max_builds`.
+    slots: BuildSlots,
+    /// The settings each `BuildLoop` follows, see `reload`.
+    project_configs: HashMap<NixFile, Arc<Mutex<ProjectConfig>>>,
     /// The handlers functions for incoming requests
     handler_fns: HandlerFns,
 }
 
+/// The thread running the `BuildLoop` of a project.
+struct LoopThread {
+    /// Stops the `BuildLoop`, see `BuildLoop::stop_on`.
+    stop: Arc<AtomicBool>,
+    /// Set once the thread is done, a running build might still add
+    /// GC roots until then.
+    finished: Arc<AtomicBool>,
+}
+
 // TODO: set a `Listener` up in the daemon instead of manually outside
 
 impl<'a> Daemon<'a> {
     /// Create a new daemon. Also return an `mpsc::Receiver` that
     /// receives `build_loop::Event`s for all builders this daemon
-    /// supervises.
-    pub fn new(paths: &'a ::constants::Paths) -> (Daemon<'a>, mpsc::Receiver<::build_loop::Event>) {
+    /// supervises. Its queue is unbounded, it misses no events
+    /// however far it falls behind.
+    pub fn new(
+        paths: &'a ::constants::Paths,
+        config: Config,
+    ) -> (Daemon<'a>, mpsc::Receiver<ProjectEvent>) {
+        let mut hub = EventHub::new();
         let (tx, rx) = mpsc::channel();
+        hub.daemon = Some(tx);
+        let events = Arc::new(Mutex::new(hub));
+        let slots = BuildSlots::new(config.max_builds());
         (
             Daemon {
                 handler_threads: HashMap::new(),
-                build_events_tx: tx,
                 paths,
+                slots: slots.clone(),
+                project_configs: HashMap::new(),
+                config,
                 handler_fns: HandlerFns {
                     read_timeout: DEFAULT_READ_TIMEOUT,
+                    events: events.clone(),
+                    slots,
                 },
+                events,
+                saving: Arc::new(Mutex::new(())),
             },
             rx,
         )
     }
 
+    /// Save the projects to `Paths::daemon_projects_file` whenever
+    /// they change, for the next daemon to `resume` them. Only the
+    /// user’s daemon does, not e.g. the daemons of tests.
+    pub fn save_projects(&mut self) {
+        let mut hub = lock_hub(&self.events);
+        hub.projects_file = Some(self.paths.daemon_projects_file().to_owned());
+    }
+
+    /// Receive the events of all builders this daemon supervises.
+    ///
+    /// The most recent events of every project (up to `EVENT_BUFFER_SIZE`)
+    /// are replayed first, so a late subscriber learns the current state.
+    pub fn subscribe(&self) -> mpsc::Receiver<ProjectEvent> {
+        lock_hub(&self.events).subscribe()
+    }
+
+    /// How many projects the daemon builds.
+    pub fn project_count(&self) -> usize {
+        self.handler_threads.len()
+    }
+
+    /// Whether any of the daemon’s projects is being built right now.
+    pub fn is_building(&self) -> bool {
+        lock_hub(&self.events).is_building()
+    }
+
+    /// The configuration the daemon currently uses.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Switch to `config`, e.g. after the user edited the configuration
+    /// file. Projects and running builds are kept, the `BuildLoop`s
+    /// pick up their new settings (see `BuildLoop::follow_config`).
+    pub fn reload(&mut self, config: Config) {
+        if config.roots_dir != self.config.roots_dir {
+            warn!("The GC root directory only changes when the daemon restarts");
+        }
+        self.slots.resize(config.max_builds());
+        for (nix_file, project_config) in &self.project_configs {
+            *project_config.lock().expect("config mutex poisoned") = config.project(nix_file);
+        }
+        self.config = config;
+        info!("Reloaded the configuration");
+    }
+
     /// The handler daemon message handler functions
     pub fn handlers(&self) -> HandlerFns {
         self.handler_fns.clone()
@@ -68,24 +643,252 @@ impl<'a> Daemon<'a> {
     /// Add nix file to the set of files this daemon watches
     /// & build if they change.
     pub fn add(&mut self, nix_file: NixFile) {
-        let tx = self.build_events_tx.clone();
+        self.start(nix_file, None)
+    }
+
+    /// Resume building the projects the previous daemon built (see
+    /// `Paths::daemon_projects_file`), with their last build status,
+    /// if their nix file still exists and `allowed` agrees.
+    pub fn resume<F>(&mut self, allowed: F)
+    where
+        F: Fn(&NixFile) -> bool,
+    {
+        let projects_file = self.paths.daemon_projects_file();
+        let registered = match load_registered(projects_file) {
+            Ok(registered) => registered,
+            Err(e) => {
+                warn!(
+                    "Cannot read the projects of the previous daemon from {}: {}",
+                    projects_file.display(),
+                    e
+                );
+                return;
+            }
+        };
+        for project in registered {
+            let nix_file = NixFile::from(project.nix_file.clone());
+            if !project.nix_file.is_file() {
+                info!("Not resuming {}, it is gone", nix_file);
+            } else if allowed(&nix_file) {
+                info!("Resuming {}", nix_file);
+                self.start(nix_file, Some(project));
+            }
+        }
+    }
+
+    fn start(&mut self, nix_file: NixFile, previous: Option<Registered>) {
+        if self.handler_threads.contains_key(&nix_file) {
+            return;
+        }
+        let events = self.events.clone();
         let root_dir = self.paths.gc_root_dir().to_owned();
+        let global_config = &self.config;
+        let config = self
+            .project_configs
+            .entry(nix_file.clone())
+            .or_insert_with(|| Arc::new(Mutex::new(global_config.project(&nix_file))))
+            .clone();
+        let slots = self.slots.clone();
+        let allow_dir = self.paths.allow_dir().to_owned();
+
+        // We construct a Project here for each dependency we get.
+        let roots = {
+            let project = Project::new(&nix_file, &root_dir);
+            // one broken project must not take the daemon down
+            match Roots::from_project(&project) {
+                Ok(roots) => roots,
+                Err(e) => {
+                    warn!(
+                        "Not building {}, cannot set up its GC roots: {}",
+                        nix_file, e
+                    );
+                    return;
+                }
+            }
+        };
+        lock_hub(&events).track(nix_file.clone(), roots.clone(), previous.as_ref());
+        save(&events, &self.saving);
 
+        let loop_nix_file = nix_file.clone();
+        let project = nix_file.clone();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let saving = self.saving.clone();
+        // tag every event with the project it belongs to
+        let publish = move |event| {
+            // the project is forgotten already
+            if stopped.load(Ordering::SeqCst) {
+                return;
+            }
+            let changed = lock_hub(&events).publish(ProjectEvent {
+                nix_file: project.clone(),
+                event,
+            });
+            if changed {
+                save(&events, &saving);
+            }
+        };
+        let loop_stop = stop.clone();
+        let finished = Arc::new(AtomicBool::new(false));
+        let loop_finished = finished.clone();
+        std::thread::spawn(move || {
+            supervise(
+                RESTART_DELAY,
+                || {
+                    if loop_stop.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    let current = config.lock().expect("config mutex poisoned").clone();
+                    let mut build_loop =
+                        BuildLoop::new(loop_nix_file.clone(), roots.clone(), current);
+                    build_loop.share_slots(slots.clone());
+                    build_loop.follow_config(config.clone());
+                    build_loop.stop_on(loop_stop.clone());
+                    build_loop.check_trust(Trust::new(&allow_dir));
+                    build_loop.forever(publish.clone());
+                },
+                |message, restart_in| {
+                    warn!("The build loop of {} crashed: {}", loop_nix_file, message);
+                    publish(Event::Warning(Warning::Crashed(message, restart_in)))
+                },
+            );
+            loop_finished.store(true, Ordering::SeqCst);
+        });
         self.handler_threads
-            .entry(nix_file.clone())
-            .or_insert_with(|| {
-                // We construct a Project here for each dependency we get.
-                let project = Project::new(&nix_file, &root_dir);
-                // TODO unwrap
-                let roots = Roots::from_project(&project).unwrap();
-                let mut build_loop = BuildLoop::new(nix_file.clone(), roots);
-
-                std::thread::spawn(move || {
-                    // cloning the tx means the daemon’s rx gets all
-                    // messages from all builders.
-                    build_loop.forever(tx);
-                })
+            .insert(nix_file, LoopThread { stop, finished });
+    }
+
+    /// Stop building the project of `request.nix_file`: its
+    /// `BuildLoop` stops, a running build is cancelled, and it is no
+    /// longer resumed when the daemon restarts. If asked to, its GC
+    /// roots and cached state are removed as well.
+    pub fn forget(&mut self, request: &ForgetRequest) -> ForgetResponse {
+        let nix_file = &request.nix_file;
+        let thread = self.handler_threads.remove(nix_file);
+        if let Some(ref thread) = thread {
+            thread.stop.store(true, Ordering::SeqCst);
+            if builder::cancel(nix_file) > 0 {
+                info!("Cancelled the running build of {}", nix_file);
+            }
+        }
+        self.project_configs.remove(nix_file);
+        let tracked = lock_hub(&self.events).untrack(nix_file);
+        save(&self.events, &self.saving);
+        let was_watched = thread.is_some() || tracked;
+        info!("Forgot {}", nix_file);
+
+        let mut response = ForgetResponse {
+            was_watched,
+            roots_deleted: false,
+            error: None,
+        };
+        if !request.delete_roots {
+            return response;
+        }
+        // a finishing build must not add roots after they are removed
+        if let Some(thread) = thread {
+            let stopping = Instant::now();
+            while !thread.finished.load(Ordering::SeqCst) && stopping.elapsed() < STOP_TIMEOUT {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+        }
+        let base = self.paths.gc_root_dir();
+        let state_dir = base.join(Project::new(nix_file, base).hash());
+        match ProjectRoots::scan(base) {
+            Ok(projects) => {
+                if let Some(project) = projects.iter().find(|p| p.state_dir == state_dir) {
+                    match project.remove() {
+                        Ok(()) => {
+                            info!("Removed the GC roots of {}", project);
+                            response.roots_deleted = true;
+                        }
+                        Err(e) => response.error = Some(e.to_string()),
+                    }
+                }
+            }
+            // nothing was ever built
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => response.error = Some(e.to_string()),
+        }
+        response
+    }
+
+    /// Continue building the project of `from` with `to`, where its
+    /// directory was moved (see `Warning::ProjectMoved`). The new
+    /// `BuildLoop` takes over the project’s state.
+    pub fn moved(&mut self, from: &NixFile, to: NixFile) {
+        self.forget(&ForgetRequest {
+            nix_file: from.clone(),
+            delete_roots: false,
+        });
+        self.add(to);
+    }
+
+    /// Remove the GC roots of projects whose nix file is gone, or
+    /// which were not built for the configured `prune_roots_after`.
+    /// Projects the daemon watches are kept.
+    pub fn prune_roots(&self) {
+        let max_age = match self.config.prune_roots_after() {
+            Some(max_age) => max_age,
+            None => return,
+        };
+        let projects = match ProjectRoots::scan(self.paths.gc_root_dir()) {
+            Ok(projects) => projects,
+            Err(e) => {
+                warn!("Cannot read the GC roots to prune: {}", e);
+                return;
+            }
+        };
+        for project in projects {
+            let watched = project.nix_file.as_ref().map_or(false, |nix_file| {
+                self.handler_threads
+                    .contains_key(&NixFile::from(nix_file.clone()))
             });
+            let reason = if project.is_stale() {
+                "its nix file is gone"
+            } else if project.not_built_for(max_age) {
+                "it was not built for a long time"
+            } else {
+                continue;
+            };
+            if watched {
+                continue;
+            }
+            match project.remove() {
+                Ok(()) => info!("Pruned the GC roots of {}, {}", project, reason),
+                Err(e) => warn!("Failed to prune the GC roots of {}: {}", project, e),
+            }
+        }
+    }
+}
+
+/// Run `run` until it returns. Whenever it panics instead, pass the
+/// panic message and the delay until the restart to `crashed`, then
+/// wait and run it again. The delay starts at `delay` and doubles
+/// with every crash in a row, up to `MAX_RESTART_DELAY`.
+fn supervise<R, C>(delay: Duration, mut run: R, mut crashed: C)
+where
+    R: FnMut(),
+    C: FnMut(String, Duration),
+{
+    let mut next_delay = delay;
+    loop {
+        let started = Instant::now();
+        let panic = match std::panic::catch_unwind(std::panic::AssertUnwindSafe(&mut run)) {
+            Ok(()) => return,
+            Err(panic) => panic,
+        };
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| String::from("unknown cause"));
+        if started.elapsed() > MAX_RESTART_DELAY {
+            next_delay = delay;
+        }
+        crashed(message, next_delay);
+        std::thread::sleep(next_delay);
+        next_delay = std::cmp::min(next_delay * 2, MAX_RESTART_DELAY);
     }
 }
 
@@ -94,9 +897,103 @@ impl<'a> Daemon<'a> {
 pub struct HandlerFns {
     /// How long the daemon waits for messages to arrive after accept()
     read_timeout: Timeout,
+    /// The events and states of the daemon’s projects.
+    events: Arc<Mutex<EventHub>>,
+    /// The build slots of the daemon, for the metrics.
+    slots: BuildSlots,
 }
 
 impl HandlerFns {
+    /// What the status page shows, see `status_page`.
+    pub fn status_page(&self) -> Vec<ProjectPage> {
+        lock_hub(&self.events).pages()
+    }
+
+    /// Accept handler for `socket::communicate::ForgetRequest`
+    /// messages. Sends the request to `forget_chan` and answers the
+    /// client once the daemon is done.
+    pub fn forget(
+        &self,
+        mut rw: ReadWriter<ForgetRequest, ForgetResponse>,
+        forget_chan: mpsc::Sender<ForgetProject>,
+    ) {
+        let request = match rw.read(&self.read_timeout) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Client didn’t send a `ForgetRequest`: {:?}", e);
+                return;
+            }
+        };
+        let (done, response) = mpsc::channel();
+        if forget_chan.send(ForgetProject { request, done }).is_err() {
+            debug!("The daemon stopped before forgetting the project");
+            return;
+        }
+        match response.recv() {
+            Ok(response) => {
+                if let Err(e) = rw.write(&self.read_timeout, &response) {
+                    debug!("Failed to answer the client: {:?}", e);
+                }
+            }
+            Err(_) => debug!("The daemon did not forget the project"),
+        }
+    }
+
+    /// Accept handler for `socket::communicate::UpgradeRequest`
+    /// messages. Sends the request to `upgrade_chan` and answers the
+    /// client once the daemon is about to be replaced.
+    pub fn upgrade(
+        &self,
+        mut rw: ReadWriter<UpgradeRequest, UpgradeResponse>,
+        upgrade_chan: mpsc::Sender<UpgradeDaemon>,
+    ) {
+        let request = match rw.read(&self.read_timeout) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Client didn’t send an `UpgradeRequest`: {:?}", e);
+                return;
+            }
+        };
+        let (done, response) = mpsc::channel();
+        // dropped when this handler returns
+        let (_answered, answered) = mpsc::channel();
+        if upgrade_chan
+            .send(UpgradeDaemon {
+                request,
+                done,
+                answered,
+            })
+            .is_err()
+        {
+            debug!("The daemon stopped before upgrading");
+            return;
+        }
+        match response.recv() {
+            Ok(response) => {
+                if let Err(e) = rw.write(&self.read_timeout, &response) {
+                    debug!("Failed to answer the client: {:?}", e);
+                }
+            }
+            Err(_) => debug!("The daemon did not answer the upgrade request"),
+        }
+    }
+
+    /// What the daemon is doing, for humans, see `state_dump`.
+    pub fn dump(&self) -> String {
+        lock_hub(&self.events).dump(&self.slots)
+    }
+
+    /// The daemon’s metrics in the Prometheus text format,
+    /// see `Config::http_address`.
+    pub fn metrics(&self) -> String {
+        let events = lock_hub(&self.events);
+        events.metrics.render(&Gauges {
+            projects: events.projects.len(),
+            running: self.slots.taken(),
+            queued: self.slots.waiting(),
+        })
+    }
+
     /// Accept handler for `socket::communicate::Ping` messages.
     /// For a valid ping message, it sends an instruction to start
     /// the build to `build_chan`.
@@ -126,4 +1023,448 @@ impl HandlerFns {
             }
         }
     }
+
+    /// Accept handler for `socket::communicate::CommunicationType::Status`,
+    /// answers with the status of every project.
+    pub fn status(&self, mut rw: ReadWriter<NoMessage, Vec<ProjectStatus>>) {
+        let statuses = lock_hub(&self.events).statuses();
+        if let Err(e) = rw.write(&self.read_timeout, &statuses) {
+            debug!("Failed to send the status to the client: {:?}", e)
+        }
+    }
+
+    /// Accept handler for `socket::communicate::CommunicationType::Subscribe`,
+    /// sends the recent and then all new events until the client hangs up.
+    pub fn subscribe(&self, mut rw: ReadWriter<SubscribeRequest, ProjectEvent>) {
+        let request = match rw.read(&self.read_timeout) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!(
+                    "Client `SubscribeRequest` message could not be read: {:?}",
+                    e
+                );
+                return;
+            }
+        };
+        let events = lock_hub(&self.events).subscribe();
+        for project_event in events {
+            if request
+                .nix_file
+                .as_ref()
+                .map_or(false, |wanted| *wanted != project_event.nix_file)
+            {
+                continue;
+            }
+            if rw.write(&self.read_timeout, &project_event).is_err() {
+                debug!("Subscriber hung up");
+                return;
+            }
+        }
+    }
+
+    /// Accept handler for `socket::communicate::ShellRequest` messages.
+    /// Sends the project to `build_chan`, in case the daemon doesn’t
+    /// build it yet, then answers with its state and its new events
+    /// until the client hangs up.
+    pub fn shell(
+        &self,
+        mut rw: ReadWriter<ShellRequest, ShellMessage>,
+        build_chan: mpsc::Sender<IndicateActivity>,
+    ) {
+        let nix_file = match rw.read(&self.read_timeout) {
+            Ok(request) => request.nix_file,
+            Err(e) => {
+                debug!("Client `ShellRequest` message could not be read: {:?}", e);
+                return;
+            }
+        };
+        let (state, events) = {
+            let mut events = lock_hub(&self.events);
+            let state = events
+                .projects
+                .get(&nix_file)
+                .map_or(BuildState::Pending, |tracked| tracked.state);
+            // follow under the same lock, so no event is lost
+            (state, events.follow())
+        };
+        if build_chan
+            .send(IndicateActivity {
+                nix_file: nix_file.clone(),
+            })
+            .is_err()
+        {
+            debug!("The daemon stopped before building {}", nix_file);
+            return;
+        }
+        if let Err(e) = rw.write(&self.read_timeout, &ShellMessage::State(state)) {
+            debug!("Failed to send the state to the shell: {:?}", e);
+            return;
+        }
+        for ProjectEvent {
+            nix_file: project,
+            event,
+        } in events
+        {
+            if project != nix_file {
+                continue;
+            }
+            // the shell shows no build output, see `lorri logs`
+            if let Event::Log(_, _) = event {
+                continue;
+            }
+            if rw
+                .write(&self.read_timeout, &ShellMessage::Event(event))
+                .is_err()
+            {
+                debug!("Shell hung up");
+                return;
+            }
+        }
+    }
+
+    /// Accept handler for `socket::communicate::CommunicationType::Logs`,
+    /// answers with the buffered log lines and, if the client follows
+    /// them, with every new line until the client hangs up.
+    pub fn logs(&self, mut rw: ReadWriter<LogsRequest, Vec<LogLine>>) {
+        let request = match rw.read(&self.read_timeout) {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Client `LogsRequest` message could not be read: {:?}", e);
+                return;
+            }
+        };
+        let (backlog, new_events) = {
+            let mut events = lock_hub(&self.events);
+            let backlog = events.logs(request.nix_file.as_ref());
+            // subscribe under the same lock, so no line is lost or sent twice
+            let new_events = if request.follow {
+                Some(events.subscribe())
+            } else {
+                None
+            };
+            (backlog, new_events)
+        };
+        if let Err(e) = rw.write(&self.read_timeout, &backlog) {
+            debug!("Failed to send the logs to the client: {:?}", e);
+            return;
+        }
+        for ProjectEvent { nix_file, event } in new_events.into_iter().flatten() {
+            let line = match event {
+                Event::Log(_, line) => line,
+                _ => continue,
+            };
+            if request
+                .nix_file
+                .as_ref()
+                .map_or(false, |wanted| *wanted != nix_file)
+            {
+                continue;
+            }
+            let lines = vec![LogLine { nix_file, line }];
+            if rw.write(&self.read_timeout, &lines).is_err() {
+                debug!("Client stopped following the logs");
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        load_registered, lock_hub, save, supervise, EventHub, ProjectEvent, Registered,
+        EVENT_BUFFER_SIZE, LOG_BUFFER_SIZE, SUBSCRIBER_QUEUE_SIZE,
+    };
+    use crate::build_loop::{BuildExitFailure, BuildId, Event, Heartbeat, Reason, Warning};
+    use crate::builder::Phase;
+    use crate::project::Project;
+    use crate::roots::Roots;
+    use crate::socket::communicate::BuildState;
+    use crate::NixFile;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn event(nix_file: &str, event: Event) -> ProjectEvent {
+        ProjectEvent {
+            nix_file: NixFile::from(PathBuf::from(nix_file)),
+            event,
+        }
+    }
+
+    fn heartbeat() -> Event {
+        Event::Heartbeat(Heartbeat {
+            build_id: BuildId::next(),
+            phase: Phase::Evaluating,
+            elapsed: Duration::from_secs(5),
+        })
+    }
+
+    #[test]
+    fn late_subscribers_get_replay() {
+        let mut hub = EventHub::new();
+        let early = hub.subscribe();
+        hub.publish(event(
+            "/a/shell.nix",
+            Event::Started(BuildId::next(), Reason::Startup),
+        ));
+        hub.publish(event(
+            "/b/shell.nix",
+            Event::Started(BuildId::next(), Reason::Startup),
+        ));
+
+        let late = hub.subscribe();
+        assert_eq!(early.try_iter().count(), 2);
+        assert_eq!(late.try_iter().count(), 2);
+
+        hub.publish(event("/a/shell.nix", heartbeat()));
+        assert_eq!(early.try_iter().count(), 1);
+        assert_eq!(late.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn replay_is_bounded_and_coalesces_heartbeats() {
+        let mut hub = EventHub::new();
+        for _ in 0..EVENT_BUFFER_SIZE * 2 {
+            hub.publish(event(
+                "/a/shell.nix",
+                Event::Started(BuildId::next(), Reason::Startup),
+            ));
+        }
+        assert_eq!(hub.subscribe().try_iter().count(), EVENT_BUFFER_SIZE);
+
+        let mut hub = EventHub::new();
+        hub.publish(event(
+            "/a/shell.nix",
+            Event::Started(BuildId::next(), Reason::Startup),
+        ));
+        hub.publish(event("/a/shell.nix", heartbeat()));
+        hub.publish(event("/a/shell.nix", heartbeat()));
+        assert_eq!(hub.subscribe().try_iter().count(), 2);
+    }
+
+    #[test]
+    fn hung_up_subscribers_are_dropped() {
+        let mut hub = EventHub::new();
+        drop(hub.subscribe());
+        hub.publish(event(
+            "/a/shell.nix",
+            Event::Started(BuildId::next(), Reason::Startup),
+        ));
+        assert!(hub.subscribers.is_empty());
+    }
+
+    #[test]
+    fn slow_subscribers_are_dropped() {
+        let mut hub = EventHub::new();
+        let slow = hub.subscribe();
+        for _ in 0..=SUBSCRIBER_QUEUE_SIZE {
+            hub.publish(event(
+                "/a/shell.nix",
+                Event::Log(BuildId::next(), "spam".into()),
+            ));
+        }
+        assert!(hub.subscribers.is_empty());
+        assert_eq!(slow.try_iter().count(), SUBSCRIBER_QUEUE_SIZE);
+    }
+
+    #[test]
+    fn the_daemon_misses_no_events() {
+        let mut hub = EventHub::new();
+        let (tx, rx) = std::sync::mpsc::channel();
+        hub.daemon = Some(tx);
+        for _ in 0..=SUBSCRIBER_QUEUE_SIZE {
+            hub.publish(event(
+                "/a/shell.nix",
+                Event::Log(BuildId::next(), "spam".into()),
+            ));
+        }
+        assert!(hub.daemon.is_some());
+        assert_eq!(rx.try_iter().count(), SUBSCRIBER_QUEUE_SIZE + 1);
+    }
+
+    #[test]
+    fn a_panic_in_one_project_spares_the_hub() {
+        let hub = Arc::new(Mutex::new(EventHub::new()));
+        let crashing = hub.clone();
+        let _ = std::thread::spawn(move || {
+            let _locked = crashing.lock().unwrap();
+            panic!("crash while publishing");
+        })
+        .join();
+        assert!(hub.is_poisoned());
+        lock_hub(&hub).publish(event(
+            "/b/shell.nix",
+            Event::Started(BuildId::next(), Reason::Startup),
+        ));
+        assert_eq!(lock_hub(&hub).subscribe().try_iter().count(), 1);
+    }
+
+    #[test]
+    fn logs_are_bounded_and_not_replayed() {
+        let mut hub = EventHub::new();
+        let early = hub.subscribe();
+        let build_id = BuildId::next();
+        for i in 0..LOG_BUFFER_SIZE + 1 {
+            hub.publish(event(
+                "/a/shell.nix",
+                Event::Log(build_id, format!("line {}", i)),
+            ));
+        }
+        hub.publish(event(
+            "/b/shell.nix",
+            Event::Log(build_id, String::from("other")),
+        ));
+        assert_eq!(early.try_iter().count(), LOG_BUFFER_SIZE + 2);
+        assert_eq!(hub.subscribe().try_iter().count(), 0);
+
+        let a = NixFile::from(PathBuf::from("/a/shell.nix"));
+        let lines = hub.logs(Some(&a));
+        assert_eq!(lines.len(), LOG_BUFFER_SIZE);
+        assert_eq!(lines[0].line, "line 1");
+        let all = hub.logs(None);
+        assert_eq!(all.len(), LOG_BUFFER_SIZE + 1);
+        assert_eq!(all.last().unwrap().line, "other");
+    }
+
+    #[test]
+    fn project_states_follow_events() {
+        let gc_root = tempfile::tempdir().unwrap();
+        let nix_file = NixFile::from(PathBuf::from("/a/shell.nix"));
+        let roots = Roots::from_project(&Project::new(&nix_file, gc_root.path())).unwrap();
+        let mut hub = EventHub::new();
+        hub.track(nix_file.clone(), roots, None);
+        // untracked projects are ignored
+        hub.publish(event(
+            "/b/shell.nix",
+            Event::Started(BuildId::next(), Reason::Startup),
+        ));
+        let state = |hub: &EventHub| {
+            let statuses = hub.statuses();
+            assert_eq!(statuses.len(), 1);
+            assert_eq!(statuses[0].nix_file, nix_file);
+            (statuses[0].state, statuses[0].last_build_duration.is_some())
+        };
+        assert_eq!(state(&hub), (BuildState::Pending, false));
+
+        let build_id = BuildId::next();
+        hub.publish(event(
+            "/a/shell.nix",
+            Event::Started(build_id, Reason::Startup),
+        ));
+        assert_eq!(state(&hub), (BuildState::Building, false));
+        assert!(hub.is_building());
+        hub.publish(event(
+            "/a/shell.nix",
+            Event::Failure(BuildExitFailure {
+                build_id,
+                log_lines: vec![],
+            }),
+        ));
+        assert_eq!(state(&hub), (BuildState::Failure, true));
+        assert!(!hub.is_building());
+    }
+
+    #[test]
+    fn projects_are_saved_and_restored() {
+        let temp = tempfile::tempdir().unwrap();
+        let projects_file = temp.path().join("daemon_projects.json");
+        let nix_file = NixFile::from(PathBuf::from("/a/shell.nix"));
+        let roots = Roots::from_project(&Project::new(&nix_file, temp.path())).unwrap();
+
+        let hub = Mutex::new(EventHub::new());
+        let saving = Mutex::new(());
+        lock_hub(&hub).projects_file = Some(projects_file.clone());
+        lock_hub(&hub).track(nix_file.clone(), roots.clone(), None);
+        let build_id = BuildId::next();
+        lock_hub(&hub).publish(event(
+            "/a/shell.nix",
+            Event::Started(build_id, Reason::Startup),
+        ));
+        let changed = lock_hub(&hub).publish(event(
+            "/a/shell.nix",
+            Event::Failure(BuildExitFailure {
+                build_id,
+                log_lines: vec![],
+            }),
+        ));
+        assert!(changed);
+        // the hub leaves writing to `save`
+        assert!(!projects_file.exists());
+        save(&hub, &saving);
+        let saved = load_registered(&projects_file).unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].state, BuildState::Failure);
+
+        let mut restarted = EventHub::new();
+        let previous = Registered {
+            last_build: Some(1_000),
+            last_build_duration: Some(42.0),
+            ..saved[0].clone()
+        };
+        restarted.track(nix_file, roots, Some(&previous));
+        let status = &restarted.statuses()[0];
+        assert_eq!(status.state, BuildState::Failure);
+        assert_eq!(status.last_build, Some(1_000));
+
+        // results of no started build, like the cached one a new
+        // `BuildLoop` sends, keep the restored time
+        restarted.publish(event(
+            "/a/shell.nix",
+            Event::Failure(BuildExitFailure {
+                build_id,
+                log_lines: vec![],
+            }),
+        ));
+        let status = &restarted.statuses()[0];
+        assert_eq!(status.last_build, Some(1_000));
+        assert_eq!(status.last_build_duration, Some(42.0));
+
+        assert!(load_registered(&temp.path().join("missing.json"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn crashed_loops_are_restarted() {
+        let mut runs = 0;
+        let mut crashes = vec![];
+        supervise(
+            Duration::from_millis(1),
+            || {
+                runs += 1;
+                if runs < 3 {
+                    panic!("crash {}", runs);
+                }
+            },
+            |message, restart_in| crashes.push((message, restart_in)),
+        );
+        assert_eq!(runs, 3);
+        assert_eq!(
+            crashes,
+            vec![
+                (String::from("crash 1"), Duration::from_millis(1)),
+                (String::from("crash 2"), Duration::from_millis(2)),
+            ]
+        );
+
+        let gc_root = tempfile::tempdir().unwrap();
+        let nix_file = NixFile::from(PathBuf::from("/a/shell.nix"));
+        let roots = Roots::from_project(&Project::new(&nix_file, gc_root.path())).unwrap();
+        let mut hub = EventHub::new();
+        hub.track(nix_file, roots, None);
+        hub.publish(event(
+            "/a/shell.nix",
+            Event::Started(BuildId::next(), Reason::Startup),
+        ));
+        hub.publish(event(
+            "/a/shell.nix",
+            Event::Warning(Warning::Crashed(
+                String::from("oops"),
+                Duration::from_secs(1),
+            )),
+        ));
+        assert!(!hub.is_building());
+        assert_eq!(hub.statuses()[0].state, BuildState::Failure);
+    }
 }
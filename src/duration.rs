@@ -0,0 +1,34 @@
+//! Durations as fractional seconds, the unit of build durations in
+//! the history, metrics and status output. `Duration::as_secs_f64`
+//! and `Duration::from_secs_f64` only exist since Rust 1.38, the
+//! pinned nixpkgs ships an older rustc.
+
+use std::time::Duration;
+
+/// The number of seconds in `duration`, including the fraction.
+pub fn as_secs_f64(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+/// The duration of `secs` seconds, negative or invalid values are
+/// taken as zero.
+pub fn from_secs_f64(secs: f64) -> Duration {
+    if !secs.is_finite() || secs <= 0.0 {
+        return Duration::from_secs(0);
+    }
+    Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seconds_round_trip() {
+        let took = Duration::from_millis(2_500);
+        assert_eq!(as_secs_f64(took), 2.5);
+        assert_eq!(from_secs_f64(2.5), took);
+        assert_eq!(from_secs_f64(-1.0), Duration::from_secs(0));
+        assert_eq!(from_secs_f64(std::f64::NAN), Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,455 @@
+//! The environment of a project’s shell, as exported by the builder
+//! in `logged-evaluation.nix`, and how it is applied on top of the
+//! user’s environment. Follows what `nix-shell` does, like the
+//! direnv integration in `ops/direnv/envrc.bash`.
+
+use crate::roots::{RootPath, Roots};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Variables of the build environment which are never applied, since
+/// they describe the build sandbox rather than the user’s session.
+/// From https://github.com/NixOS/nix/blob/92d08c02c84be34ec0df56ed718526c382845d1a/src/nix-build/nix-build.cc
+const PUNTED: &[&str] = &[
+    "HOME",
+    "USER",
+    "LOGNAME",
+    "DISPLAY",
+    "TERM",
+    "IN_NIX_SHELL",
+    "TZ",
+    "PAGER",
+    "NIX_BUILD_SHELL",
+    "SHLVL",
+    "TEMPDIR",
+    "TMPDIR",
+    "TEMP",
+    "TMP",
+    "NIX_ENFORCE_PURITY",
+    // the shell starts in the user’s directory, not in the sandbox
+    "PWD",
+    "OLDPWD",
+];
+
+/// Variables of the user’s environment a pure shell always keeps.
+const PURE_KEEP: &[&str] = &["HOME", "TERM"];
+
+/// The variables of `host` a pure shell starts with: `HOME`, `TERM`
+/// and the ones named in `keep`.
+pub fn pure<I>(host: I, keep: &[String]) -> HashMap<String, String>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    host.into_iter()
+        .filter(|(name, _)| PURE_KEEP.contains(&name.as_str()) || keep.contains(name))
+        .collect()
+}
+
+/// Which variables of the user’s environment are passed into a
+/// project’s environment, configured by `env_allow` and `env_deny`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Passthrough {
+    /// If set, only these variables (and `HOME` and `TERM`) pass.
+    pub allow: Option<Vec<String>>,
+    /// These variables never pass.
+    pub deny: Vec<String>,
+}
+
+impl Passthrough {
+    /// Whether the variable `name` passes.
+    pub fn passes(&self, name: &str) -> bool {
+        let allowed = match &self.allow {
+            Some(allow) => PURE_KEEP.contains(&name) || matches_any(allow, name),
+            None => true,
+        };
+        allowed && !matches_any(&self.deny, name)
+    }
+
+    /// The variables of `host` which pass.
+    pub fn filter<I>(&self, host: I) -> HashMap<String, String>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        host.into_iter()
+            .filter(|(name, _)| self.passes(name))
+            .collect()
+    }
+}
+
+/// Whether `name` is one of `patterns`, where a trailing `*` matches
+/// any suffix.
+fn matches_any(patterns: &[String], name: &str) -> bool {
+    patterns.iter().any(|pattern| {
+        if pattern.ends_with('*') {
+            name.starts_with(&pattern[..pattern.len() - 1])
+        } else {
+            pattern == name
+        }
+    })
+}
+
+/// The variables a shell environment sets.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShellEnv {
+    /// Every exported variable of the build environment.
+    vars: HashMap<String, String>,
+    /// Variables extended by `addToSearchPath` in the build, which are
+    /// appended to the user’s value, with their separator.
+    appended: HashMap<String, String>,
+}
+
+impl ShellEnv {
+    /// Read the environment of the shell environment `root` points to.
+    pub fn load(root: &RootPath) -> Result<ShellEnv, std::io::Error> {
+        let vars = root.read_env()?;
+        let appended = match std::fs::read(root.output("varmap-v1")) {
+            Ok(varmap) => parse_varmap(&String::from_utf8_lossy(&varmap)),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(ShellEnv { vars, appended })
+    }
+
+    /// Read the environment the `BuildLoop` of a project dumped after
+    /// its last successful build.
+    pub fn load_dump(roots: &Roots) -> Result<ShellEnv, std::io::Error> {
+        let contents = std::fs::read(roots.env_dump_file())?;
+        serde_json::from_slice(&contents)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+    }
+
+    /// Dump the environment for `load_dump`, replacing the previous one.
+    pub fn save_dump(&self, roots: &Roots) -> Result<(), std::io::Error> {
+        let path = roots.env_dump_file();
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(&tmp, &path)
+    }
+
+    /// The latest environment of a project: its dump, or, for projects
+    /// last built by lorri versions without dumps, its shell GC root.
+    pub fn latest(roots: &Roots) -> Result<ShellEnv, std::io::Error> {
+        ShellEnv::load_dump(roots).or_else(|_| ShellEnv::load(&roots.current_shell_root()))
+    }
+
+    /// The environment of the shell: `host` (usually the environment
+    /// of the current process) with the variables of the build
+    /// environment applied. `PATH` is prepended to the user’s `PATH`.
+    pub fn apply<I>(&self, host: I) -> HashMap<String, String>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut env = host.into_iter().collect::<HashMap<String, String>>();
+        for (name, value) in &self.vars {
+            if PUNTED.contains(&name.as_str()) {
+                continue;
+            }
+            let combined = match (env.get(name), self.appended.get(name)) {
+                (Some(host), _) if name == "PATH" && !host.is_empty() => {
+                    format!("{}:{}", value, host)
+                }
+                (Some(host), Some(separator)) if !host.is_empty() => {
+                    format!("{}{}{}", host, separator, value)
+                }
+                _ => value.clone(),
+            };
+            env.insert(name.clone(), combined);
+        }
+        env.insert(String::from("IN_NIX_SHELL"), String::from("1"));
+        env
+    }
+
+    /// How the variables changed from this environment to `newer`.
+    pub fn diff(&self, newer: &ShellEnv) -> EnvDiff {
+        let names = self
+            .vars
+            .keys()
+            .chain(newer.vars.keys())
+            .filter(|name| !PUNTED.contains(&name.as_str()))
+            .collect::<BTreeSet<&String>>();
+        let mut diff = EnvDiff::default();
+        for name in names {
+            match (self.vars.get(name), newer.vars.get(name)) {
+                (None, Some(_)) => diff.added.push(name.clone()),
+                (Some(_), None) => diff.removed.push(name.clone()),
+                (Some(old), Some(new)) if old != new => diff.changed.push(name.clone()),
+                _ => {}
+            }
+        }
+        let old_path = self.vars.get("PATH").map_or("", |path| path.as_str());
+        let new_path = newer.vars.get("PATH").map_or("", |path| path.as_str());
+        let missing = |from: &str, to: &str| {
+            from.split(':')
+                .filter(|dir| !dir.is_empty() && !to.split(':').any(|other| other == *dir))
+                .map(String::from)
+                .collect()
+        };
+        diff.path_added = missing(new_path, old_path);
+        diff.path_removed = missing(old_path, new_path);
+        diff
+    }
+}
+
+impl From<HashMap<String, String>> for ShellEnv {
+    /// An environment which only sets variables.
+    fn from(vars: HashMap<String, String>) -> ShellEnv {
+        ShellEnv {
+            vars,
+            appended: HashMap::new(),
+        }
+    }
+}
+
+/// How the variables of a shell environment changed between two builds.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EnvDiff {
+    /// Variables which were not set before.
+    pub added: Vec<String>,
+    /// Variables which are no longer set.
+    pub removed: Vec<String>,
+    /// Variables with a different value.
+    pub changed: Vec<String>,
+    /// Directories which are new in `PATH`.
+    pub path_added: Vec<String>,
+    /// Directories which are no longer in `PATH`.
+    pub path_removed: Vec<String>,
+}
+
+impl EnvDiff {
+    /// Whether nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl std::fmt::Display for EnvDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let parts = [
+            ("added", &self.added),
+            ("removed", &self.removed),
+            ("changed", &self.changed),
+            ("new in PATH", &self.path_added),
+            ("gone from PATH", &self.path_removed),
+        ]
+        .iter()
+        .filter(|(_, names)| !names.is_empty())
+        .map(|(what, names)| format!("{} {}", what, names.join(", ")))
+        .collect::<Vec<String>>();
+        if parts.is_empty() {
+            write!(f, "nothing changed")
+        } else {
+            write!(f, "{}", parts.join("; "))
+        }
+    }
+}
+
+/// How a shell sets and unsets variables.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Syntax {
+    /// bash, zsh and other POSIX shells.
+    Posix,
+    /// fish.
+    Fish,
+}
+
+/// A script in the given `syntax` which exports the variables in
+/// `changes` with a value, and unsets those without one.
+pub fn script(changes: &BTreeMap<String, Option<String>>, syntax: Syntax) -> String {
+    let mut script = String::new();
+    for (name, value) in changes {
+        let line = match (syntax, value) {
+            (Syntax::Posix, Some(value)) => format!("export {}={}", name, posix_quote(value)),
+            (Syntax::Posix, None) => format!("unset {}", name),
+            (Syntax::Fish, Some(value)) => format!("set -gx {} {}", name, fish_quote(value)),
+            (Syntax::Fish, None) => format!("set -e {}", name),
+        };
+        script.push_str(&line);
+        script.push('\n');
+    }
+    script
+}
+
+/// Quote `value` as a single word for POSIX shells.
+pub fn posix_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn fish_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Parse `varmap-v1`, a list of NUL-separated triples of instruction,
+/// variable name and separator, written by the builder.
+fn parse_varmap(varmap: &str) -> HashMap<String, String> {
+    let fields = varmap.split('\0').collect::<Vec<&str>>();
+    fields
+        .chunks(3)
+        .filter_map(|chunk| match chunk {
+            ["append", name, separator] => Some((name.to_string(), separator.to_string())),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_varmap, pure, script, EnvDiff, Passthrough, ShellEnv, Syntax};
+    use crate::project::Project;
+    use crate::roots::Roots;
+    use crate::NixFile;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn vars(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn varmap() {
+        let varmap = "append\0XDG_DATA_DIRS\0:\0append\0NIX_LDFLAGS\0 \0";
+        assert_eq!(
+            parse_varmap(varmap),
+            vars(&[("XDG_DATA_DIRS", ":"), ("NIX_LDFLAGS", " ")])
+        );
+    }
+
+    #[test]
+    fn build_environment_is_applied_on_top() {
+        let shell = ShellEnv {
+            vars: vars(&[
+                ("PATH", "/nix/store/foo/bin"),
+                ("HOME", "/homeless-shelter"),
+                ("XDG_DATA_DIRS", "/nix/store/foo/share"),
+                ("buildInputs", "/nix/store/foo"),
+            ]),
+            appended: vars(&[("XDG_DATA_DIRS", ":")]),
+        };
+        let env = shell.apply(vars(&[
+            ("PATH", "/usr/bin"),
+            ("HOME", "/home/alice"),
+            ("XDG_DATA_DIRS", "/usr/share"),
+        ]));
+        assert_eq!(env["PATH"], "/nix/store/foo/bin:/usr/bin");
+        assert_eq!(env["HOME"], "/home/alice");
+        assert_eq!(env["XDG_DATA_DIRS"], "/usr/share:/nix/store/foo/share");
+        assert_eq!(env["buildInputs"], "/nix/store/foo");
+        assert_eq!(env["IN_NIX_SHELL"], "1");
+    }
+
+    #[test]
+    fn dumps_are_read_back() {
+        let gc_root = tempfile::tempdir().unwrap();
+        let nix_file = NixFile::from(gc_root.path().join("shell.nix"));
+        let roots = Roots::from_project(&Project::new(&nix_file, gc_root.path())).unwrap();
+        assert!(ShellEnv::latest(&roots).is_err());
+
+        let shell = ShellEnv {
+            vars: vars(&[("PATH", "/nix/store/foo/bin")]),
+            appended: vars(&[("XDG_DATA_DIRS", ":")]),
+        };
+        shell.save_dump(&roots).unwrap();
+        assert_eq!(ShellEnv::latest(&roots).unwrap(), shell);
+    }
+
+    #[test]
+    fn scripts_quote_values() {
+        let mut changes = BTreeMap::new();
+        changes.insert(String::from("FOO"), Some(String::from("it's $HOME\\")));
+        changes.insert(String::from("GONE"), None);
+        assert_eq!(
+            script(&changes, Syntax::Posix),
+            "export FOO='it'\\''s $HOME\\'\nunset GONE\n"
+        );
+        assert_eq!(
+            script(&changes, Syntax::Fish),
+            "set -gx FOO 'it\\'s $HOME\\\\'\nset -e GONE\n"
+        );
+    }
+
+    #[test]
+    fn pure_environments_keep_little() {
+        let host = vars(&[
+            ("HOME", "/home/alice"),
+            ("TERM", "xterm"),
+            ("PATH", "/usr/bin"),
+            ("SSH_AUTH_SOCK", "/run/user/1000/ssh"),
+            ("EDITOR", "vim"),
+        ]);
+        assert_eq!(
+            pure(host, &[String::from("SSH_AUTH_SOCK")]),
+            vars(&[
+                ("HOME", "/home/alice"),
+                ("TERM", "xterm"),
+                ("SSH_AUTH_SOCK", "/run/user/1000/ssh"),
+            ])
+        );
+    }
+
+    #[test]
+    fn environment_diff() {
+        let old = ShellEnv::from(vars(&[
+            ("PATH", "/nix/store/a/bin"),
+            ("FOO", "1"),
+            ("BAR", "1"),
+            ("TMPDIR", "/tmp/nix-build-1"),
+        ]));
+        let new = ShellEnv::from(vars(&[
+            ("PATH", "/nix/store/b/bin:/nix/store/a/bin"),
+            ("FOO", "2"),
+            ("BAZ", "1"),
+            ("TMPDIR", "/tmp/nix-build-2"),
+        ]));
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff,
+            EnvDiff {
+                added: vec![String::from("BAZ")],
+                removed: vec![String::from("BAR")],
+                changed: vec![String::from("FOO"), String::from("PATH")],
+                path_added: vec![String::from("/nix/store/b/bin")],
+                path_removed: vec![],
+            }
+        );
+        assert_eq!(
+            diff.to_string(),
+            "added BAZ; removed BAR; changed FOO, PATH; new in PATH /nix/store/b/bin"
+        );
+        assert!(old.diff(&old).is_empty());
+    }
+
+    #[test]
+    fn passthrough() {
+        let host = vars(&[
+            ("HOME", "/home/alice"),
+            ("SSH_AUTH_SOCK", "/run/user/1000/ssh"),
+            ("SSH_AGENT_PID", "1234"),
+            ("DISPLAY", ":0"),
+            ("AWS_SECRET_ACCESS_KEY", "hunter2"),
+        ]);
+        let deny = Passthrough {
+            allow: None,
+            deny: vec![String::from("AWS_*")],
+        };
+        assert_eq!(
+            deny.filter(host.clone()),
+            vars(&[
+                ("HOME", "/home/alice"),
+                ("SSH_AUTH_SOCK", "/run/user/1000/ssh"),
+                ("SSH_AGENT_PID", "1234"),
+                ("DISPLAY", ":0"),
+            ])
+        );
+        let allow = Passthrough {
+            allow: Some(vec![String::from("SSH_*")]),
+            deny: vec![String::from("SSH_AGENT_PID")],
+        };
+        assert_eq!(
+            allow.filter(host),
+            vars(&[
+                ("HOME", "/home/alice"),
+                ("SSH_AUTH_SOCK", "/run/user/1000/ssh"),
+            ])
+        );
+    }
+}
@@ -0,0 +1,282 @@
+//! The builds of a project, as its `BuildLoop` records them after
+//! every build, see `lorri builds`, and the output and environments
+//! of the latest builds, see `lorri logs --build` and `lorri env-diff`.
+
+use crate::build_loop::{BuildId, Reason};
+use crate::duration;
+use crate::env::{EnvDiff, ShellEnv};
+use crate::roots::Roots;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How many builds are kept per project, older ones are dropped.
+pub const MAX_RECORDS: usize = 200;
+
+/// How many build logs and environments are kept per project, older
+/// ones are removed.
+pub const MAX_LOGS: usize = 20;
+
+/// The size at which a stored build log is cut off, so a build
+/// flooding its output can’t fill the disk.
+pub const MAX_LOG_BYTES: u64 = 8 * 1024 * 1024;
+
+/// How a build ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    /// The environment was built.
+    Success,
+    /// Evaluating or building the environment failed.
+    Failure,
+}
+
+/// A finished build of a project.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BuildRecord {
+    /// The build, see `BuildId`.
+    pub build_id: BuildId,
+    /// When the build started, in seconds since the Unix epoch.
+    pub started: u64,
+    /// How long the build took, in seconds.
+    pub duration: f64,
+    /// Why the build started, see `build_loop::Reason`.
+    pub trigger: String,
+    /// The changed files which started the build.
+    pub changed_files: Vec<PathBuf>,
+    /// How the build ended.
+    pub outcome: Outcome,
+    /// How the build changed the environment (see `EnvDiff`), `None`
+    /// if it didn’t.
+    pub env_changes: Option<String>,
+}
+
+impl BuildRecord {
+    /// Record the build `build_id`, started at `started` for `reason`.
+    pub fn new(
+        build_id: BuildId,
+        reason: &Reason,
+        started: SystemTime,
+        duration: Duration,
+        outcome: Outcome,
+        env_diff: Option<&EnvDiff>,
+    ) -> BuildRecord {
+        BuildRecord {
+            build_id,
+            started: started
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            duration: duration::as_secs_f64(duration),
+            trigger: reason.to_string(),
+            changed_files: match reason {
+                Reason::FilesChanged(changes) => changes.iter().map(|c| c.path.clone()).collect(),
+                _ => vec![],
+            },
+            outcome,
+            env_changes: env_diff.map(EnvDiff::to_string),
+        }
+    }
+}
+
+/// The recorded builds of the project, the oldest first. Empty if
+/// the project was never built.
+pub fn load(roots: &Roots) -> Result<Vec<BuildRecord>, std::io::Error> {
+    match std::fs::read(roots.history_file()) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Add `record` to the project’s builds, dropping the oldest ones
+/// beyond `MAX_RECORDS`.
+pub fn append(roots: &Roots, record: BuildRecord) -> Result<(), std::io::Error> {
+    // a corrupt history is replaced, not kept forever
+    let mut records = load(roots).unwrap_or_default();
+    records.push(record);
+    let skip = records.len().saturating_sub(MAX_RECORDS);
+    let path = roots.history_file();
+    let tmp = path.with_extension("json.tmp");
+    std::fs::write(&tmp, serde_json::to_vec(&records[skip..])?)?;
+    std::fs::rename(&tmp, &path)
+}
+
+/// The stored output of a running build.
+pub struct BuildLog {
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl BuildLog {
+    /// Start storing the output of `build_id`, removing the logs of
+    /// the oldest builds beyond `MAX_LOGS`.
+    pub fn create(roots: &Roots, build_id: BuildId) -> Result<BuildLog, std::io::Error> {
+        let dir = roots.build_log_dir();
+        prune(&dir, "log")?;
+        Ok(BuildLog {
+            file: BufWriter::new(File::create(stored_file(&dir, build_id, "log"))?),
+            written: 0,
+        })
+    }
+
+    /// Append `line`, unless the log reached `MAX_LOG_BYTES`.
+    pub fn write_line(&mut self, line: &str) -> Result<(), std::io::Error> {
+        if self.written >= MAX_LOG_BYTES {
+            return Ok(());
+        }
+        self.written += line.len() as u64 + 1;
+        if self.written >= MAX_LOG_BYTES {
+            return writeln!(
+                self.file,
+                "lorri: the log exceeds {} bytes, the rest is not stored",
+                MAX_LOG_BYTES
+            );
+        }
+        writeln!(self.file, "{}", line)
+    }
+}
+
+/// The stored output of `build_id`.
+pub fn read_log(roots: &Roots, build_id: BuildId) -> Result<String, std::io::Error> {
+    std::fs::read_to_string(stored_file(&roots.build_log_dir(), build_id, "log"))
+}
+
+/// The builds whose output is stored, the oldest first.
+pub fn logged_builds(roots: &Roots) -> Result<Vec<BuildId>, std::io::Error> {
+    stored_builds(&roots.build_log_dir(), "log")
+}
+
+/// Store the environment `build_id` built, removing the environments
+/// of the oldest builds beyond `MAX_LOGS`.
+pub fn save_env(roots: &Roots, build_id: BuildId, env: &ShellEnv) -> Result<(), std::io::Error> {
+    let dir = roots.env_history_dir();
+    prune(&dir, "json")?;
+    std::fs::write(
+        stored_file(&dir, build_id, "json"),
+        serde_json::to_vec(env)?,
+    )
+}
+
+/// The stored environment of `build_id`.
+pub fn load_env(roots: &Roots, build_id: BuildId) -> Result<ShellEnv, std::io::Error> {
+    let contents = std::fs::read(stored_file(&roots.env_history_dir(), build_id, "json"))?;
+    serde_json::from_slice(&contents)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// The builds whose environment is stored, the oldest first.
+pub fn env_builds(roots: &Roots) -> Result<Vec<BuildId>, std::io::Error> {
+    stored_builds(&roots.env_history_dir(), "json")
+}
+
+fn stored_file(dir: &Path, build_id: BuildId, extension: &str) -> PathBuf {
+    dir.join(format!("{}.{}", build_id, extension))
+}
+
+/// The builds with a file in `dir`, the oldest first.
+fn stored_builds(dir: &Path, extension: &str) -> Result<Vec<BuildId>, std::io::Error> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut builds = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().and_then(|e| e.to_str()) != Some(extension) {
+            continue;
+        }
+        if let Some(build_id) = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|id| id.parse().ok())
+        {
+            builds.push(build_id);
+        }
+    }
+    builds.sort();
+    Ok(builds)
+}
+
+/// Make room in `dir` for the file of one more build, creating it if
+/// necessary.
+fn prune(dir: &Path, extension: &str) -> Result<(), std::io::Error> {
+    std::fs::create_dir_all(dir)?;
+    let stored = stored_builds(dir, extension)?;
+    for old in &stored[..stored.len().saturating_sub(MAX_LOGS - 1)] {
+        std::fs::remove_file(stored_file(dir, *old, extension))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        append, load, logged_builds, read_log, BuildLog, BuildRecord, Outcome, MAX_LOGS,
+        MAX_RECORDS,
+    };
+    use crate::build_loop::{BuildId, Reason};
+    use crate::project::Project;
+    use crate::roots::Roots;
+    use crate::NixFile;
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn history_keeps_the_latest_builds() {
+        let gc_root = tempfile::tempdir().unwrap();
+        let nix_file = NixFile::from(PathBuf::from("/home/alice/foo/shell.nix"));
+        let roots = Roots::from_project(&Project::new(&nix_file, gc_root.path())).unwrap();
+        assert_eq!(load(&roots).unwrap(), vec![]);
+
+        let record = |outcome| {
+            BuildRecord::new(
+                BuildId::next(),
+                &Reason::Startup,
+                UNIX_EPOCH + Duration::from_secs(1000),
+                Duration::from_millis(1500),
+                outcome,
+                None,
+            )
+        };
+        for _ in 0..MAX_RECORDS {
+            append(&roots, record(Outcome::Success)).unwrap();
+        }
+        let last = record(Outcome::Failure);
+        append(&roots, last.clone()).unwrap();
+
+        let records = load(&roots).unwrap();
+        assert_eq!(records.len(), MAX_RECORDS);
+        assert_eq!(records.last(), Some(&last));
+        assert_eq!(last.started, 1000);
+        assert_eq!(last.duration, 1.5);
+        assert_eq!(last.trigger, "first build");
+    }
+
+    #[test]
+    fn only_the_latest_logs_are_kept() {
+        let gc_root = tempfile::tempdir().unwrap();
+        let nix_file = NixFile::from(PathBuf::from("/home/alice/foo/shell.nix"));
+        let roots = Roots::from_project(&Project::new(&nix_file, gc_root.path())).unwrap();
+        assert_eq!(logged_builds(&roots).unwrap(), vec![]);
+
+        let builds = (0..MAX_LOGS + 2)
+            .map(|i| {
+                let build_id = BuildId::next();
+                let mut log = BuildLog::create(&roots, build_id).unwrap();
+                log.write_line(&format!("build {}", i)).unwrap();
+                build_id
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(logged_builds(&roots).unwrap(), builds[2..].to_vec());
+        assert_eq!(
+            read_log(&roots, *builds.last().unwrap()).unwrap(),
+            format!("build {}\n", MAX_LOGS + 1)
+        );
+        assert!(read_log(&roots, builds[0]).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+//! A minimal HTTP/1.0 server for the daemon’s read-only pages, like
+//! its metrics (see `Config::http_address`).
+//!
+//! Requests are answered one after the other and every connection is
+//! closed after its response, which is plenty for a scraper or a
+//! browser tab on the same machine.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::time::Duration;
+
+/// How long to wait for a client to send its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The longest request (line and headers) which is read.
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+
+/// What to answer a request with.
+#[derive(Debug, PartialEq)]
+pub struct Response {
+    /// The status line, e.g. `"200 OK"`.
+    pub status: &'static str,
+    /// The `Content-Type` of the body.
+    pub content_type: &'static str,
+    /// The body itself.
+    pub body: String,
+}
+
+impl Response {
+    /// A successful response.
+    pub fn ok(content_type: &'static str, body: String) -> Response {
+        Response {
+            status: "200 OK",
+            content_type,
+            body,
+        }
+    }
+
+    /// There is nothing at the requested path.
+    pub fn not_found() -> Response {
+        Response::error("404 Not Found")
+    }
+
+    fn error(status: &'static str) -> Response {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: format!("{}\n", status),
+        }
+    }
+}
+
+/// Answer every connection to `listener` with the response `handler`
+/// returns for the requested path. Never returns.
+pub fn serve<F>(listener: TcpListener, handler: F)
+where
+    F: Fn(&str) -> Response,
+{
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = answer(stream, &handler) {
+                    debug!("Failed to answer an HTTP request: {}", e);
+                }
+            }
+            Err(e) => debug!("Failed to accept an HTTP connection: {}", e),
+        }
+    }
+}
+
+fn answer<F>(stream: TcpStream, handler: &F) -> std::io::Result<()>
+where
+    F: Fn(&str) -> Response,
+{
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    (&mut reader)
+        .take(MAX_REQUEST_SIZE as u64)
+        .read_line(&mut request_line)?;
+    // the headers are of no interest, but have to be read anyway
+    let mut read = request_line.len();
+    loop {
+        let mut header = String::new();
+        let n = (&mut reader)
+            .take(MAX_REQUEST_SIZE as u64)
+            .read_line(&mut header)?;
+        read += n;
+        if n == 0 || header.trim().is_empty() || read > MAX_REQUEST_SIZE {
+            break;
+        }
+    }
+
+    let (response, head_only) = match parse_request_line(&request_line) {
+        Some(("GET", path)) => (handler(path), false),
+        Some(("HEAD", path)) => (handler(path), true),
+        Some(_) => (Response::error("405 Method Not Allowed"), false),
+        None => (Response::error("400 Bad Request"), false),
+    };
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    if !head_only {
+        stream.write_all(response.body.as_bytes())?;
+    }
+    stream.flush()
+}
+
+/// The method and path (without the query) of a request line like
+/// `GET /metrics HTTP/1.1`.
+fn parse_request_line(line: &str) -> Option<(&str, &str)> {
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?;
+    let target = parts.next()?;
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    let path = target.split('?').next().unwrap_or(target);
+    Some((method, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_request_line, serve, Response};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn request_lines() {
+        assert_eq!(
+            parse_request_line("GET /metrics HTTP/1.1\r\n"),
+            Some(("GET", "/metrics"))
+        );
+        assert_eq!(
+            parse_request_line("HEAD /metrics?x=1 HTTP/1.0\r\n"),
+            Some(("HEAD", "/metrics"))
+        );
+        assert_eq!(parse_request_line("GET /metrics\r\n"), None);
+        assert_eq!(parse_request_line("\r\n"), None);
+    }
+
+    fn request(address: std::net::SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn answers_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            serve(listener, |path| match path {
+                "/hello" => Response::ok("text/plain", String::from("hi\n")),
+                _ => Response::not_found(),
+            })
+        });
+
+        let ok = request(address, "GET /hello HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(ok.starts_with("HTTP/1.0 200 OK\r\n"), "{}", ok);
+        assert!(ok.contains("Content-Length: 3\r\n"), "{}", ok);
+        assert!(ok.ends_with("\r\n\r\nhi\n"), "{}", ok);
+
+        let head = request(address, "HEAD /hello HTTP/1.1\r\n\r\n");
+        assert!(head.ends_with("\r\n\r\n"), "{}", head);
+
+        let missing = request(address, "GET /nope HTTP/1.1\r\n\r\n");
+        assert!(
+            missing.starts_with("HTTP/1.0 404 Not Found\r\n"),
+            "{}",
+            missing
+        );
+
+        let post = request(address, "POST /hello HTTP/1.1\r\n\r\n");
+        assert!(post.starts_with("HTTP/1.0 405 "), "{}", post);
+    }
+}
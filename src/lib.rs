@@ -33,17 +33,28 @@ pub mod build_loop;
 pub mod builder;
 pub mod changelog;
 pub mod cli;
+pub mod config;
 pub mod constants;
 pub mod daemon;
+pub mod duration;
+pub mod env;
+pub mod history;
+pub mod http;
 pub mod locate_file;
 pub mod logging;
+pub mod metrics;
 pub mod mpsc;
 pub mod nix;
 pub mod ops;
 pub mod pathreduction;
 pub mod project;
 pub mod roots;
+pub mod sha256;
+pub mod signal;
 pub mod socket;
+pub mod state_dump;
+pub mod status_page;
+pub mod trust;
 pub mod watch;
 
 use std::path::PathBuf;
@@ -2,7 +2,7 @@
 
 use std::env;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 /// Error conditions encountered when hunting for a file on disk
 #[derive(Debug)]
@@ -22,7 +22,7 @@ impl From<std::io::Error> for FileLocationError {
 }
 
 /// Hunt for filename `name` in the current directory
-pub fn in_cwd(name: &str) -> Result<PathBuf, FileLocationError> {
+pub fn in_cwd<P: AsRef<Path>>(name: P) -> Result<PathBuf, FileLocationError> {
     let mut path = env::current_dir()?;
     path.push(name);
     if path.is_file() {
@@ -32,11 +32,45 @@ pub fn in_cwd(name: &str) -> Result<PathBuf, FileLocationError> {
     }
 }
 
+/// Hunt for filename `name` in `dir` and its parents, the closest
+/// first.
+pub fn upwards<P: AsRef<Path>>(dir: &Path, name: P) -> Result<PathBuf, FileLocationError> {
+    let dir = dir.canonicalize()?;
+    dir.ancestors()
+        .map(|ancestor| ancestor.join(name.as_ref()))
+        .find(|path| path.is_file())
+        .ok_or(FileLocationError::NotFound)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{in_cwd, FileLocationError};
+    use super::{in_cwd, upwards, FileLocationError};
     use std::path::Path;
 
+    #[test]
+    fn test_locate_file_upwards() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        let nested = root.join("scripts/ci");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(root.join("shell.nix"), "{}").unwrap();
+        assert_eq!(
+            upwards(&nested, "shell.nix").unwrap(),
+            root.join("shell.nix")
+        );
+
+        std::fs::write(root.join("scripts/shell.nix"), "{}").unwrap();
+        assert_eq!(
+            upwards(&nested, "shell.nix").unwrap(),
+            root.join("scripts/shell.nix")
+        );
+
+        match upwards(&nested, "this-lorri-specific-file-probably-does-not-exist") {
+            Err(FileLocationError::NotFound) => (),
+            otherwise => panic!("unexpected result: {:?}", otherwise),
+        }
+    }
+
     #[test]
     fn test_locate_config_file() {
         let result = in_cwd("shell.nix");
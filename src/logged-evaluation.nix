@@ -1,4 +1,4 @@
-{ src, runTimeClosure }:
+{ src, runTimeClosure, attr ? "" }:
 let
   runtimeCfg = import runTimeClosure;
 
@@ -20,6 +20,30 @@ let
     then raw {}
     else raw;
 
+  isDerivation = value: builtins.isAttrs value && (value.type or null) == "derivation";
+
+  # The attribute (path) `attr` of the expression, like `nix-build -A`.
+  # Without `attr`, the expression itself if it is a derivation, else
+  # its `shell` attribute, else its only derivation.
+  selected =
+    let
+      names = builtins.filter builtins.isString (builtins.split "\\." attr);
+      available = value: builtins.concatStringsSep ", " (builtins.attrNames value);
+      get = value: name:
+        if builtins.isAttrs value && builtins.hasAttr name value
+        then builtins.getAttr name value
+        else throw ("lorri: attribute '${attr}' not found"
+          + (if builtins.isAttrs value then ", available: ${available value}" else ""));
+      derivations = builtins.filter (name: isDerivation imported.${name})
+        (builtins.attrNames imported);
+    in if attr != "" then builtins.foldl' get imported names
+    else if !builtins.isAttrs imported || isDerivation imported then imported
+    else if isDerivation (imported.shell or null) then imported.shell
+    else if builtins.length derivations == 1 then imported.${builtins.head derivations}
+    else throw ("lorri: ${toString src} evaluates to several derivations and none is "
+      + "called 'shell', choose one with the `attr` setting or `lorri shell --attr`. "
+      + "Available: ${available imported}");
+
   trace_attribute_msg = name: value:
     "lorri attribute: '${name}' -> '${value.drvPath}'";
 
@@ -138,7 +162,7 @@ let
   trace_attribute = name: drv:
     builtins.trace (trace_attribute_msg name drv);
 
-  gc-root = keep-env-hack imported;
-in (trace_attribute "shell" imported)
+  gc-root = keep-env-hack selected;
+in (trace_attribute "shell" selected)
    (trace_attribute "shell_gc_root" gc-root)
    gc-root
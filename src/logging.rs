@@ -1,11 +1,18 @@
 //! Utilities for configuring env_logger based on the number of -v
-//! arguments passed at the CLI
+//! and -q arguments passed at the CLI
 //!
 //! Note this is only a default, and the environment variable
-//! RUST_LOG will override it.
+//! RUST_LOG will override it. The -q arguments also silence the
+//! progress messages and warnings of the commands, see
+//! `show_progress` and `show_warnings`. Neither changes how verbose
+//! Nix is.
 
 use env_logger;
 use std::env;
+use std::sync::atomic::{AtomicIsize, Ordering};
+
+/// The number of -v arguments minus the number of -q arguments.
+static VERBOSITY: AtomicIsize = AtomicIsize::new(0);
 
 /// Potentially set the RUST_LOG environment, and configure env_logger
 /// based on if RUST_LOG is set already.
@@ -13,7 +20,9 @@ use std::env;
 /// If RUST_LOG is set already, assume the setter is trying to
 /// investigate something specific. However, we also want a useful
 /// `-v` option as a quick shortcut.
-pub fn init_with_default_log_level(verbosity: u8) {
+pub fn init_with_default_log_level(verbosity: u8, quiet: u8) {
+    let verbosity = verbosity as isize - quiet as isize;
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
     let requested_level = level_from_verbosity(verbosity);
 
     if env::var_os("RUST_LOG").is_none() {
@@ -26,10 +35,24 @@ pub fn init_with_default_log_level(verbosity: u8) {
     }
 }
 
-/// Convert a number of -v flags in to a default RUST_LOG value
-fn level_from_verbosity(verbosity: u8) -> &'static str {
+/// Whether commands print what they are doing, e.g. that they wait
+/// for a build. Silenced by -q.
+pub fn show_progress() -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= 0
+}
+
+/// Whether commands print warnings which don’t stop them. Silenced
+/// by -qq, which leaves only the results and fatal errors.
+pub fn show_warnings() -> bool {
+    VERBOSITY.load(Ordering::Relaxed) >= -1
+}
+
+/// Convert the number of -v flags minus the number of -q flags in to
+/// a default RUST_LOG value
+fn level_from_verbosity(verbosity: isize) -> &'static str {
     match verbosity {
-        0 => "error",
+        std::isize::MIN..=-2 => "off",
+        -1 | 0 => "error",
         1 => "warn",
         2 => "info",
         _ => "debug",
@@ -45,5 +68,7 @@ mod tests {
         assert_eq!(level_from_verbosity(0), "error");
         assert_eq!(level_from_verbosity(3), "debug");
         assert_eq!(level_from_verbosity(19), "debug");
+        assert_eq!(level_from_verbosity(-1), "error");
+        assert_eq!(level_from_verbosity(-2), "off");
     }
 }
@@ -6,21 +6,47 @@ extern crate log;
 use lorri::locate_file;
 use lorri::NixFile;
 
-use lorri::cli::{Arguments, Command};
+use lorri::cli::{Arguments, Command, ErrorFormat};
 use lorri::ops::{
-    build, daemon, direnv, info, init, ping, shell, upgrade, watch, ExitError, OpResult,
+    allow, attrs, build, builds, clean, completions, daemon, deny, direnv, direnv_install, doctor,
+    dump_nix_args, env_diff, events, exec, export, forget, gc, info, init, logs, ping, roots, run,
+    self_test, shell, status, upgrade, watch, ErrorCode, ExitError, OpResult,
 };
 use lorri::project::Project;
 use std::env;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 const TRIVIAL_SHELL_SRC: &str = include_str!("./trivial-shell.nix");
 const DEFAULT_ENVRC: &str = "eval \"$(lorri direnv)\"";
 
+/// The name under which lorri behaves as `lorri run`, to be the
+/// interpreter of scripts: `#!/usr/bin/env lorri-run`.
+const RUN_NAME: &str = "lorri-run";
+
 fn main() {
+    let args = arguments();
+    let opts = match Arguments::from_iter_safe(&args) {
+        Ok(opts) => opts,
+        Err(e) => match e.kind {
+            structopt::clap::ErrorKind::HelpDisplayed
+            | structopt::clap::ErrorKind::VersionDisplayed => e.exit(),
+            _ if ErrorFormat::requested(&args) == ErrorFormat::Json => {
+                let err = ExitError::errmsg(e.message).with_code(ErrorCode::InvalidArguments);
+                eprintln!("{}", err.to_json());
+                std::process::exit(err.exitcode());
+            }
+            _ => e.exit(),
+        },
+    };
+    let error_format = opts.error_format;
+
     let exit = |result: OpResult| match result {
         Err(err) => {
-            eprintln!("{}", err.message());
+            match error_format {
+                ErrorFormat::Human => eprintln!("{}", err.message()),
+                ErrorFormat::Json => eprintln!("{}", err.to_json()),
+            }
             std::process::exit(err.exitcode());
         }
         Ok(Some(msg)) => {
@@ -32,21 +58,50 @@ fn main() {
         }
     };
 
-    let opts = Arguments::from_args();
-
-    lorri::logging::init_with_default_log_level(opts.verbosity);
+    lorri::logging::init_with_default_log_level(opts.verbosity, opts.quiet);
     debug!("Input options: {:?}", opts);
 
+    if let Err(e) = lorri::signal::install() {
+        warn!("Cannot handle termination signals: {}", e);
+    }
+
     let result = run_command(opts);
     exit(result);
 }
 
-/// Try to read `shell.nix` from the current working dir.
-fn get_shell_nix() -> Result<NixFile, ExitError> {
+/// The command line, with `lorri-run` replaced by `lorri run --`:
+/// all arguments of `lorri-run` belong to the script.
+fn arguments() -> Vec<std::ffi::OsString> {
+    let mut args: Vec<_> = env::args_os().collect();
+    let invoked_as_run = args
+        .first()
+        .and_then(|arg0| std::path::Path::new(arg0).file_name())
+        .map_or(false, |name| name == RUN_NAME);
+    if invoked_as_run {
+        args.splice(0..1, vec!["lorri".into(), "run".into(), "--".into()]);
+    }
+    args
+}
+
+/// Try to read `shell_file`, by default `shell.nix`, from the current
+/// working dir.
+fn get_shell_nix(shell_file: &Option<PathBuf>) -> Result<NixFile, ExitError> {
     let current_dir_msg = || match env::current_dir() {
         Err(_) => String::from(""),
         Ok(pb) => format!(" ({})", pb.display()),
     };
+    if let Some(shell_file) = shell_file {
+        return locate_file::in_cwd(shell_file)
+            .map(NixFile::from)
+            .map_err(|_| {
+                ExitError::errmsg(format!(
+                    "There is no `{}` in the current directory{}",
+                    shell_file.display(),
+                    current_dir_msg()
+                ))
+                .with_code(ErrorCode::NoProject)
+            });
+    }
     // use shell.nix from cwd
     Ok(NixFile::from(locate_file::in_cwd("shell.nix").map_err(
         |_| {
@@ -57,6 +112,7 @@ fn get_shell_nix() -> Result<NixFile, ExitError> {
                 current_dir_msg(),
                 TRIVIAL_SHELL_SRC
             ))
+            .with_code(ErrorCode::NoProject)
         },
     )?))
 }
@@ -64,25 +120,150 @@ fn get_shell_nix() -> Result<NixFile, ExitError> {
 /// Run the main function of the relevant command.
 fn run_command(opts: Arguments) -> OpResult {
     let paths = lorri::ops::get_paths()?;
+    let shell_file = opts.shell_file;
     match opts.command {
-        Command::Info => info::main(&Project::new(&get_shell_nix()?, paths.gc_root_dir())),
+        Command::Allow => allow::main(&get_shell_nix(&shell_file)?),
+
+        Command::Deny => deny::main(&get_shell_nix(&shell_file)?),
+
+        Command::Info(opts) => info::main(
+            &Project::new(&get_shell_nix(&shell_file)?, paths.gc_root_dir()),
+            opts,
+        ),
+
+        Command::Build(opts) => build::main(
+            &Project::new(&get_shell_nix(&shell_file)?, paths.gc_root_dir()),
+            opts,
+        ),
+
+        Command::Builds(opts) => builds::main(
+            &Project::new(&get_shell_nix(&shell_file)?, paths.gc_root_dir()),
+            opts,
+        ),
 
-        Command::Build => build::main(&Project::new(&get_shell_nix()?, paths.gc_root_dir())),
+        Command::Direnv => direnv::main(&Project::new(
+            &get_shell_nix(&shell_file)?,
+            paths.gc_root_dir(),
+        )),
 
-        Command::Direnv => direnv::main(&Project::new(&get_shell_nix()?, paths.gc_root_dir())),
+        Command::DirenvInstall => direnv_install::main(),
 
-        Command::Shell => shell::main(Project::new(&get_shell_nix()?, paths.gc_root_dir())),
+        Command::Shell(opts) => shell::main(
+            Project::new(&get_shell_nix(&shell_file)?, paths.gc_root_dir()),
+            opts,
+        ),
 
-        Command::Watch => watch::main(&Project::new(&get_shell_nix()?, paths.gc_root_dir())),
+        Command::Exec(opts) => exec::main(
+            &Project::new(&get_shell_nix(&shell_file)?, paths.gc_root_dir()),
+            opts,
+        ),
+
+        Command::Run(opts) => run::main(paths.gc_root_dir(), shell_file, opts),
+
+        Command::EnvDiff(opts) => env_diff::main(
+            &Project::new(&get_shell_nix(&shell_file)?, paths.gc_root_dir()),
+            opts,
+        ),
+
+        Command::Export(opts) => export::main(
+            &Project::new(&get_shell_nix(&shell_file)?, paths.gc_root_dir()),
+            opts,
+        ),
+
+        Command::Watch(opts) => watch::main(
+            &Project::new(&get_shell_nix(&shell_file)?, paths.gc_root_dir()),
+            opts,
+        ),
 
         Command::Daemon => daemon::main(),
 
+        Command::Status(opts) => status::main(opts),
+
+        Command::Events(opts) => {
+            let nix_file = if opts.all {
+                None
+            } else {
+                Some(get_shell_nix(&shell_file)?)
+            };
+            events::main(nix_file)
+        }
+
+        Command::StreamEvents(opts) => {
+            let nix_file = match opts.nix_file {
+                Some(nix_file) => NixFile::from(nix_file),
+                None => get_shell_nix(&shell_file)?,
+            };
+            events::main(Some(nix_file))
+        }
+
+        Command::Logs(opts) => {
+            let nix_file = if opts.all {
+                None
+            } else {
+                Some(get_shell_nix(&shell_file)?)
+            };
+            logs::main(nix_file, opts)
+        }
+
         Command::Upgrade(args) => upgrade::main(args),
 
-        // TODO: remove
-        Command::Ping_(p) => ping::main(p.nix_file),
+        Command::Ping(opts) => ping::main(match opts.nix_file {
+            Some(nix_file) => NixFile::from(nix_file),
+            None => get_shell_nix(&shell_file)?,
+        }),
+
+        Command::Forget(opts) => {
+            // unlike `get_shell_nix`, the file may be gone already
+            let shell_file = shell_file.unwrap_or_else(|| PathBuf::from("shell.nix"));
+            let nix_file = match opts.nix_file {
+                Some(ref nix_file) => NixFile::from(nix_file.clone()),
+                None => env::current_dir()
+                    .map(|cwd| NixFile::from(cwd.join(&shell_file)))
+                    .map_err(|e| {
+                        ExitError::errmsg(format!("Cannot find the current directory: {}", e))
+                    })?,
+            };
+            forget::main(nix_file, opts)
+        }
+
+        Command::Init(opts) => init::main(TRIVIAL_SHELL_SRC, DEFAULT_ENVRC, shell_file, opts),
+
+        Command::Roots => roots::main(paths.gc_root_dir()),
+
+        Command::Gc(opts) => gc::main(paths.gc_root_dir(), opts),
+
+        Command::Clean(opts) => {
+            let nix_file = if opts.all {
+                None
+            } else {
+                Some(get_shell_nix(&shell_file)?)
+            };
+            clean::main(&paths, nix_file)
+        }
+
+        Command::Doctor(opts) => doctor::main(
+            &paths,
+            &Project::new(&get_shell_nix(&shell_file)?, paths.gc_root_dir()),
+            opts,
+        ),
+
+        Command::SelfTest => self_test::main(),
+
+        Command::Completions(opts) => completions::main(opts),
+
+        Command::Complete(opts) => {
+            completions::candidates(&paths, get_shell_nix(&shell_file).ok(), opts)
+        }
+
+        Command::Attrs(opts) => attrs::main(
+            &Project::new(&get_shell_nix(&shell_file)?, paths.gc_root_dir()),
+            opts,
+        ),
 
-        Command::Init => init::main(TRIVIAL_SHELL_SRC, DEFAULT_ENVRC),
+        Command::DumpNixArgs(opts) => dump_nix_args::main(
+            &Project::new(&get_shell_nix(&shell_file)?, paths.gc_root_dir()),
+            opts,
+        ),
     }
 }
 
@@ -0,0 +1,241 @@
+//! Counters of the daemon’s builds, served in the Prometheus text
+//! format (see `http` and `Config::http_address`).
+//!
+//! https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use crate::duration;
+use crate::socket::communicate::BuildState;
+use crate::NixFile;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Upper bounds of the build duration histogram buckets, in seconds.
+const DURATION_BUCKETS: [f64; 10] = [
+    1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0,
+];
+
+/// The metrics of every project, keyed by its nix file.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    projects: BTreeMap<String, ProjectMetrics>,
+}
+
+/// The name, help text and value of a per-project counter.
+type Counter = (&'static str, &'static str, fn(&ProjectMetrics) -> u64);
+
+#[derive(Debug, Default)]
+struct ProjectMetrics {
+    started: u64,
+    succeeded: u64,
+    failed: u64,
+    watcher_errors: u64,
+    /// How many builds took at most the `DURATION_BUCKETS` bound of
+    /// the same index.
+    duration_buckets: [u64; 10],
+    duration_sum: f64,
+}
+
+/// Gauges which are read when the metrics are rendered, instead of
+/// being counted along the way.
+#[derive(Debug, Default)]
+pub struct Gauges {
+    /// How many projects the daemon builds.
+    pub projects: usize,
+    /// How many builds are running.
+    pub running: usize,
+    /// How many builds wait for a free slot, see `BuildSlots`.
+    pub queued: usize,
+}
+
+impl Metrics {
+    fn project(&mut self, nix_file: &NixFile) -> &mut ProjectMetrics {
+        self.projects.entry(nix_file.to_string()).or_default()
+    }
+
+    /// A build of `nix_file` started.
+    pub fn build_started(&mut self, nix_file: &NixFile) {
+        self.project(nix_file).started += 1;
+    }
+
+    /// A build of `nix_file` finished after `took`.
+    pub fn build_finished(&mut self, nix_file: &NixFile, state: BuildState, took: Duration) {
+        let project = self.project(nix_file);
+        match state {
+            BuildState::Success => project.succeeded += 1,
+            _ => project.failed += 1,
+        }
+        let seconds = duration::as_secs_f64(took);
+        for (bound, bucket) in DURATION_BUCKETS
+            .iter()
+            .zip(project.duration_buckets.iter_mut())
+        {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        project.duration_sum += seconds;
+    }
+
+    /// The file watcher of `nix_file` failed.
+    pub fn watcher_error(&mut self, nix_file: &NixFile) {
+        self.project(nix_file).watcher_errors += 1;
+    }
+
+    /// All metrics in the Prometheus text format.
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+        let counters: [Counter; 4] = [
+            ("lorri_builds_started_total", "Builds started.", |p| {
+                p.started
+            }),
+            (
+                "lorri_builds_succeeded_total",
+                "Builds which succeeded.",
+                |p| p.succeeded,
+            ),
+            ("lorri_builds_failed_total", "Builds which failed.", |p| {
+                p.failed
+            }),
+            (
+                "lorri_watcher_errors_total",
+                "Times the file watcher failed or ran out of watches.",
+                |p| p.watcher_errors,
+            ),
+        ];
+        for (name, help, value) in counters.iter() {
+            header(&mut out, name, help, "counter");
+            for (project, metrics) in &self.projects {
+                let _ = writeln!(
+                    out,
+                    "{}{{project=\"{}\"}} {}",
+                    name,
+                    escape(project),
+                    value(metrics)
+                );
+            }
+        }
+
+        let name = "lorri_build_duration_seconds";
+        header(&mut out, name, "How long builds took.", "histogram");
+        for (project, metrics) in &self.projects {
+            let project = escape(project);
+            for (bound, count) in DURATION_BUCKETS.iter().zip(metrics.duration_buckets.iter()) {
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{{project=\"{}\",le=\"{}\"}} {}",
+                    name, project, bound, count
+                );
+            }
+            let finished = metrics.succeeded + metrics.failed;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{project=\"{}\",le=\"+Inf\"}} {}",
+                name, project, finished
+            );
+            let _ = writeln!(
+                out,
+                "{}_sum{{project=\"{}\"}} {}",
+                name, project, metrics.duration_sum
+            );
+            let _ = writeln!(
+                out,
+                "{}_count{{project=\"{}\"}} {}",
+                name, project, finished
+            );
+        }
+
+        let gauges = [
+            (
+                "lorri_projects",
+                "Projects the daemon builds.",
+                gauges.projects,
+            ),
+            (
+                "lorri_builds_running",
+                "Builds which are running.",
+                gauges.running,
+            ),
+            (
+                "lorri_build_queue_depth",
+                "Builds waiting for a free slot, see `max_builds`.",
+                gauges.queued,
+            ),
+        ];
+        for (name, help, value) in gauges.iter() {
+            header(&mut out, name, help, "gauge");
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Gauges, Metrics};
+    use crate::socket::communicate::BuildState;
+    use crate::NixFile;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn prometheus_format() {
+        let nix_file = NixFile::from(PathBuf::from("/a/\"quoted\"/shell.nix"));
+        let mut metrics = Metrics::default();
+        metrics.build_started(&nix_file);
+        metrics.build_finished(&nix_file, BuildState::Success, Duration::from_secs(7));
+        metrics.build_started(&nix_file);
+        metrics.build_finished(&nix_file, BuildState::Failure, Duration::from_secs(90));
+        let out = metrics.render(&Gauges {
+            projects: 1,
+            running: 0,
+            queued: 2,
+        });
+
+        let project = r#"project="/a/\"quoted\"/shell.nix""#;
+        for line in &[
+            format!("lorri_builds_started_total{{{}}} 2", project),
+            format!("lorri_builds_succeeded_total{{{}}} 1", project),
+            format!("lorri_builds_failed_total{{{}}} 1", project),
+            format!(
+                "lorri_build_duration_seconds_bucket{{{},le=\"5\"}} 0",
+                project
+            ),
+            format!(
+                "lorri_build_duration_seconds_bucket{{{},le=\"10\"}} 1",
+                project
+            ),
+            format!(
+                "lorri_build_duration_seconds_bucket{{{},le=\"120\"}} 2",
+                project
+            ),
+            format!(
+                "lorri_build_duration_seconds_bucket{{{},le=\"+Inf\"}} 2",
+                project
+            ),
+            format!("lorri_build_duration_seconds_sum{{{}}} 97", project),
+            String::from("# TYPE lorri_build_duration_seconds histogram"),
+            String::from("lorri_build_queue_depth 2"),
+        ] {
+            assert!(
+                out.lines().any(|l| l == line),
+                "missing {} in\n{}",
+                line,
+                out
+            );
+        }
+    }
+}
@@ -302,6 +302,42 @@ impl CallOpts {
     }
 }
 
+/// The total size in bytes of the closure of `paths`: the paths and
+/// everything they reference, each counted once.
+pub fn closure_size(paths: &[PathBuf]) -> Result<u64, EvaluationError> {
+    if paths.is_empty() {
+        return Ok(0);
+    }
+    let requisites = query_store("--requisites", paths)?;
+    let sizes = query_store("--size", &requisites)?;
+    Ok(sum_sizes(&sizes))
+}
+
+/// Run `nix-store --query` on `paths`, returning its output lines.
+fn query_store<P: AsRef<OsStr>>(query: &str, paths: &[P]) -> Result<Vec<String>, EvaluationError> {
+    let output = Command::new("nix-store")
+        .arg("--query")
+        .arg(query)
+        .args(paths)
+        .stdin(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(output.into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(String::from)
+        .collect())
+}
+
+/// Add up the output of `nix-store --query --size`, one size per line.
+fn sum_sizes(lines: &[String]) -> u64 {
+    lines
+        .iter()
+        .filter_map(|line| line.trim().parse::<u64>().ok())
+        .sum()
+}
+
 /// Possible error conditions encountered when executing Nix evaluation commands.
 #[derive(Debug)]
 pub enum EvaluationError {
@@ -396,9 +432,15 @@ impl From<BuildError> for OnePathError {
 
 #[cfg(test)]
 mod tests {
-    use super::CallOpts;
+    use super::{sum_sizes, CallOpts};
     use std::ffi::OsStr;
 
+    #[test]
+    fn store_path_sizes() {
+        let lines = vec![String::from("1024"), String::from("2048"), String::from("")];
+        assert_eq!(sum_sizes(&lines), 3072);
+    }
+
     #[test]
     fn cmd_arguments() {
         let mut nix = CallOpts::expression("my-cool-expression");
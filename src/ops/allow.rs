@@ -0,0 +1,15 @@
+//! Allow building a project in the background.
+
+use crate::ops::{self, ok_msg, ExitError, OpResult};
+use crate::trust::Trust;
+use crate::NixFile;
+
+/// See the documentation for lorri::cli::Command::Allow for more
+/// details.
+pub fn main(nix_file: &NixFile) -> OpResult {
+    let paths = ops::get_paths()?;
+    Trust::new(paths.allow_dir())
+        .allow(nix_file)
+        .map_err(|e| ExitError::errmsg(format!("Cannot allow {}: {}", nix_file, e)))?;
+    ok_msg(format!("Allowed {}", nix_file))
+}
@@ -0,0 +1,159 @@
+//! List the attributes of a project’s expression, the values `--attr`
+//! (and the `attr` setting) can select.
+
+use crate::cli::AttrsOptions;
+use crate::nix::{self, EvaluationError};
+use crate::ops::{self, ok_msg, ErrorCode, ExitError, OpResult};
+use crate::project::Project;
+use crate::trust::Trust;
+use crate::NixFile;
+
+/// A top-level attribute of a nix file.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Attribute {
+    /// Its name.
+    pub name: String,
+    /// Whether it evaluates to a derivation, which lorri can build.
+    /// `false` if evaluating it fails.
+    pub derivation: bool,
+}
+
+/// See the documentation for lorri::cli::Command::Attrs for more
+/// details.
+pub fn main(project: &Project, opts: AttrsOptions) -> OpResult {
+    let paths = ops::get_paths()?;
+    Trust::new(paths.allow_dir())
+        .check(project.expression())
+        .map_err(|e| ExitError::errmsg(e.to_string()).with_code(ErrorCode::NotAllowed))?;
+    let config = ops::get_config(&paths)?.project(project.expression());
+    let attrs = match attributes(project.expression())? {
+        Some(attrs) => attrs,
+        None => {
+            return ok_msg(format!(
+                "{} is a derivation, lorri builds it without --attr.",
+                project.expression()
+            ))
+        }
+    };
+    if opts.json {
+        return ok_msg(serde_json::to_string_pretty(&attrs).expect("attributes serialize to JSON"));
+    }
+    ok_msg(format(&attrs, config.attr()))
+}
+
+/// The top-level attributes of `nix_file` (called with no arguments
+/// if it is a function), ordered by name, or `None` if it evaluates
+/// to a derivation. Only evaluates each attribute as far as needed to
+/// tell whether it is a derivation.
+pub fn attributes(nix_file: &NixFile) -> Result<Option<Vec<Attribute>>, ExitError> {
+    nix::CallOpts::expression(
+        r#"{ src }:
+        let
+          raw = import (/. + src);
+          value = if builtins.isFunction raw then raw {} else raw;
+          isDerivation = v: (v.type or null) == "derivation";
+          tryDerivation = v:
+            let result = builtins.tryEval (builtins.isAttrs v && isDerivation v);
+            in result.success && result.value;
+        in if builtins.isAttrs value && !(isDerivation value)
+          then map
+            (name: { inherit name; derivation = tryDerivation value.${name}; })
+            (builtins.attrNames value)
+          else null"#,
+    )
+    .argstr("src", &nix_file.to_string())
+    .value()
+    .map_err(|e| {
+        let code = match e {
+            EvaluationError::Io(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                ErrorCode::NixMissing
+            }
+            _ => ErrorCode::EvaluationFailed,
+        };
+        ExitError::errmsg(format!("Cannot evaluate {}: {:?}", nix_file, e)).with_code(code)
+    })
+}
+
+/// The attribute lorri builds when `configured` is the `attr`
+/// setting: the configured one, else `shell` if it is a derivation,
+/// else the only derivation (see `logged-evaluation.nix`).
+fn used<'a>(attrs: &'a [Attribute], configured: Option<&'a str>) -> Option<&'a str> {
+    if configured.is_some() {
+        return configured;
+    }
+    if attrs
+        .iter()
+        .any(|attr| attr.name == "shell" && attr.derivation)
+    {
+        return Some("shell");
+    }
+    let mut derivations = attrs.iter().filter(|attr| attr.derivation);
+    match (derivations.next(), derivations.next()) {
+        (Some(only), None) => Some(&only.name),
+        _ => None,
+    }
+}
+
+/// List `attrs` for humans, marking the one lorri builds.
+fn format(attrs: &[Attribute], configured: Option<&str>) -> String {
+    if attrs.is_empty() {
+        return String::from("The expression has no attributes.");
+    }
+    let used = used(attrs, configured);
+    let width = attrs.iter().map(|attr| attr.name.len()).max().unwrap_or(0);
+    attrs
+        .iter()
+        .map(|attr| {
+            let mut line = format!(
+                "{:width$}  {}",
+                attr.name,
+                if attr.derivation {
+                    "derivation"
+                } else {
+                    "not a derivation"
+                },
+                width = width
+            );
+            if used == Some(attr.name.as_str()) {
+                line.push_str("  (built by lorri)");
+            }
+            line
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format, Attribute};
+
+    fn attr(name: &str, derivation: bool) -> Attribute {
+        Attribute {
+            name: String::from(name),
+            derivation,
+        }
+    }
+
+    #[test]
+    fn the_built_attribute_is_marked() {
+        let attrs = vec![attr("ci", true), attr("lib", false), attr("shell", true)];
+        assert_eq!(
+            format(&attrs, None),
+            "ci     derivation\n\
+             lib    not a derivation\n\
+             shell  derivation  (built by lorri)"
+        );
+        assert_eq!(
+            format(&attrs, Some("ci")),
+            "ci     derivation  (built by lorri)\n\
+             lib    not a derivation\n\
+             shell  derivation"
+        );
+        assert_eq!(
+            format(&[attr("dev", true), attr("lib", false)], None),
+            "dev  derivation  (built by lorri)\n\
+             lib  not a derivation"
+        );
+        assert_eq!(format(&[], None), "The expression has no attributes.");
+    }
+}
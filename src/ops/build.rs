@@ -1,14 +1,260 @@
-//! Run nix-build on the project, doing as much caching and adding
-//! as much user-friendly sauce as possible.
+//! Build the environment of a project once, like the build loop does,
+//! e.g. in CI.
 
-use crate::ops::{ExitError, OpResult};
+use crate::build_loop::{BuildError, BuildId, BuildLoop, UnrecoverableErrors};
+use crate::builder::{self, Phase};
+use crate::cli::BuildOptions;
+use crate::duration;
+use crate::ops::{self, ok, ok_msg, ErrorCode, ExitError, OpResult};
 use crate::project::Project;
+use crate::roots::{self, Roots};
+use crate::NixFile;
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many of the last log lines of a failed build are shown.
+const LOG_EXCERPT_LINES: usize = 20;
+
+/// The exit code if the build timed out, the one of `timeout(1)`.
+const TIMEOUT_EXIT_CODE: i32 = 124;
 
 /// See the documentation for lorri::cli::Command::Build for more
 /// details.
-pub fn main(project: &Project) -> OpResult {
-    Err(ExitError::errmsg(format!(
-        "run `nix-build {}` yourself! :)",
-        project.nix_file
-    )))
+pub fn main(project: &Project, opts: BuildOptions) -> OpResult {
+    let paths = ops::get_paths()?;
+    let config = ops::get_config(&paths)?.project(project.expression());
+    let roots = Roots::from_project(project)
+        .map_err(|e| ExitError::errmsg(format!("Cannot open the GC roots: {}", e)))?;
+
+    let started = Instant::now();
+    let (tx, rx) = channel();
+    let (progress, printer) = if opts.ci {
+        (
+            builder::Progress::with_log(tx),
+            Some(thread::spawn(move || print_groups(rx))),
+        )
+    } else {
+        (builder::Progress::new(), None)
+    };
+    let (done, watched) = channel();
+    let watchdog = opts.timeout.map(|secs| {
+        let nix_file = project.expression().clone();
+        thread::spawn(move || watchdog(&nix_file, Duration::from_secs(secs), &watched))
+    });
+    let mut build_loop = BuildLoop::new(project.expression().clone(), roots.clone(), config);
+    let result = build_loop.once_with(&progress);
+    drop(done);
+    let timed_out = watchdog.map_or(false, |watchdog| watchdog.join().unwrap_or(false));
+    // closes the log, so the printer finishes
+    drop(progress);
+    if let Some(printer) = printer {
+        let _ = printer.join();
+    }
+
+    let summary = |status: &str, build_id: Option<BuildId>, out_path: Option<&PathBuf>| {
+        if opts.ci {
+            println!(
+                "lorri-summary: {}",
+                serde_json::json!({
+                    "status": status,
+                    "nix_file": project.expression().to_string(),
+                    "build_id": build_id,
+                    "duration": duration::as_secs_f64(started.elapsed()),
+                    "out_path": out_path,
+                    "out_link": opts.out_link,
+                })
+            );
+        }
+    };
+
+    if timed_out {
+        let build_id = match result {
+            Ok(ref results) => Some(results.build_id),
+            Err(BuildError::Recoverable(ref failure)) => Some(failure.build_id),
+            Err(BuildError::Unrecoverable(_)) => None,
+        };
+        summary("timeout", build_id, None);
+        return Err(ExitError::exit(
+            TIMEOUT_EXIT_CODE,
+            format!(
+                "Building {} took longer than {} seconds, aborted.",
+                project.expression(),
+                opts.timeout.unwrap_or_default()
+            ),
+        )
+        .with_code(ErrorCode::TimedOut));
+    }
+
+    let build_id = match result {
+        Ok(results) => results.build_id,
+        Err(BuildError::Recoverable(failure)) => {
+            let message = format!("Building {} failed", project.expression());
+            if opts.ci {
+                // CI logs are read after the fact, so all of the log
+                // matters, outside of the folded groups
+                eprintln!("::error::{}\n{}", message, failure.log_lines.join("\n"));
+                summary("failure", Some(failure.build_id), None);
+                return Err(ExitError::errmsg(format!("{}, see above.", message))
+                    .with_code(ErrorCode::EvaluationFailed));
+            }
+            let skip = failure.log_lines.len().saturating_sub(LOG_EXCERPT_LINES);
+            return Err(ExitError::errmsg(format!(
+                "{}:\n{}",
+                message,
+                failure.log_lines[skip..].join("\n")
+            ))
+            .with_code(ErrorCode::EvaluationFailed));
+        }
+        Err(BuildError::Unrecoverable(e)) => {
+            summary("failure", None, None);
+            let code = match e {
+                UnrecoverableErrors::Build(ref e) if e.nix_missing() => ErrorCode::NixMissing,
+                _ => ErrorCode::Error,
+            };
+            return Err(ExitError::errmsg(format!(
+                "Cannot build {}: {:?}",
+                project.expression(),
+                e
+            ))
+            .with_code(code));
+        }
+    };
+
+    let shell = roots.shell_gc_root().store_path().ok_or_else(|| {
+        ExitError::errmsg(format!(
+            "Building {} produced no environment.",
+            project.expression()
+        ))
+    })?;
+    if let Some(ref link) = opts.out_link {
+        roots::add_out_link(&shell, link)
+            .map_err(|e| ExitError::errmsg(format!("Cannot create {}: {:?}", link.display(), e)))?;
+    }
+    if opts.ci {
+        summary("success", Some(build_id), Some(&shell));
+        return ok();
+    }
+    ok_msg(shell.display().to_string())
+}
+
+/// Wait until `done` is closed, or cancel the builds of `nix_file`
+/// once `timeout` passed. Returns whether it cancelled them.
+fn watchdog(nix_file: &NixFile, timeout: Duration, done: &Receiver<()>) -> bool {
+    match done.recv_timeout(timeout) {
+        Err(RecvTimeoutError::Timeout) => {}
+        _ => return false,
+    }
+    // a build which was just starting is not registered yet, so keep
+    // cancelling until it is gone
+    loop {
+        builder::cancel(nix_file);
+        if let Err(RecvTimeoutError::Disconnected) = done.recv_timeout(Duration::from_millis(100)) {
+            return true;
+        }
+    }
+}
+
+/// Print the log lines from `log`, in a foldable group per phase of
+/// the build (see `Groups`).
+fn print_groups(log: Receiver<String>) {
+    let mut groups = Groups::default();
+    for line in log {
+        print!("{}", groups.line(&line));
+    }
+    print!("{}", groups.finish());
+}
+
+/// Splits a build log into groups per `Phase`, marked the way GitHub
+/// Actions folds them (`::group::` and `::endgroup::`). Other CI
+/// systems show the markers as plain lines.
+#[derive(Default)]
+struct Groups {
+    /// The phase of the open group, if any.
+    open: Option<Phase>,
+}
+
+impl Groups {
+    /// The output for the log line `line`, opening a new group first
+    /// if the build entered a new phase.
+    fn line(&mut self, line: &str) -> String {
+        let phase = builder::parse_phase_line(line)
+            .or(self.open)
+            .unwrap_or(Phase::Evaluating);
+        let mut out = String::new();
+        if self.open != Some(phase) {
+            out.push_str(&self.finish());
+            out.push_str(&format!("::group::lorri: {}\n", phase));
+            self.open = Some(phase);
+        }
+        out.push_str(line);
+        out.push('\n');
+        out
+    }
+
+    /// The output closing the open group, if any.
+    fn finish(&mut self) -> String {
+        match self.open.take() {
+            Some(_) => String::from("::endgroup::\n"),
+            None => String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{watchdog, Groups};
+    use crate::NixFile;
+    use std::path::PathBuf;
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn watchdog_fires_after_the_timeout() {
+        let nix_file = NixFile::from(PathBuf::from("/nonexistent/shell.nix"));
+        let (done, watched) = channel::<()>();
+        drop(done);
+        assert!(!watchdog(&nix_file, Duration::from_secs(60), &watched));
+
+        let (done, watched) = channel::<()>();
+        let build = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(done);
+        });
+        assert!(watchdog(&nix_file, Duration::from_millis(10), &watched));
+        build.join().unwrap();
+    }
+
+    #[test]
+    fn a_group_per_phase() {
+        let mut groups = Groups::default();
+        let out = [
+            "trace: evaluating",
+            "copying path '/nix/store/abc-hello' from 'https://cache.nixos.org'...",
+            "downloading 'https://cache.nixos.org/nar/abc.nar.xz'...",
+            "building '/nix/store/def-shell.drv'...",
+            "hello",
+        ]
+        .iter()
+        .map(|line| groups.line(line))
+        .collect::<String>()
+            + &groups.finish();
+        assert_eq!(
+            out,
+            "::group::lorri: evaluating\n\
+             trace: evaluating\n\
+             ::endgroup::\n\
+             ::group::lorri: fetching\n\
+             copying path '/nix/store/abc-hello' from 'https://cache.nixos.org'...\n\
+             downloading 'https://cache.nixos.org/nar/abc.nar.xz'...\n\
+             ::endgroup::\n\
+             ::group::lorri: building\n\
+             building '/nix/store/def-shell.drv'...\n\
+             hello\n\
+             ::endgroup::\n"
+        );
+        assert_eq!(groups.finish(), "");
+    }
 }
@@ -0,0 +1,106 @@
+//! List the recent builds of a project, see `::history`.
+
+use crate::cli::BuildsOptions;
+use crate::duration;
+use crate::history::{self, BuildRecord, Outcome};
+use crate::ops::status::format_duration;
+use crate::ops::{ok, ok_msg, ExitError, OpResult};
+use crate::project::Project;
+use crate::roots::Roots;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// See the documentation for lorri::cli::Command::Builds for more
+/// details.
+pub fn main(project: &Project, opts: BuildsOptions) -> OpResult {
+    let roots = Roots::from_project(project)
+        .map_err(|e| ExitError::errmsg(format!("Cannot open the GC roots: {}", e)))?;
+    let records = history::load(&roots).map_err(|e| {
+        ExitError::errmsg(format!(
+            "Cannot read the builds of {}: {}",
+            project.expression(),
+            e
+        ))
+    })?;
+    let latest = records.iter().rev().take(opts.count).collect::<Vec<_>>();
+
+    if opts.json {
+        return ok_msg(serde_json::to_string_pretty(&latest).expect("builds serialize to JSON"));
+    }
+    if latest.is_empty() {
+        return ok_msg(format!(
+            "{} was not built yet, or only by an older lorri.",
+            project.expression()
+        ));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    for record in latest {
+        print!("{}", format(record, now));
+    }
+    ok()
+}
+
+/// Describe `record` for humans; `now` is in seconds since the epoch.
+fn format(record: &BuildRecord, now: u64) -> String {
+    let outcome = match record.outcome {
+        Outcome::Success => "success",
+        Outcome::Failure => "failure",
+    };
+    let mut out = format!(
+        "{}: {}, {} ago, took {} ({})\n",
+        record.build_id,
+        outcome,
+        format_duration(Duration::from_secs(now.saturating_sub(record.started))),
+        format_duration(duration::from_secs_f64(record.duration)),
+        record.trigger
+    );
+    if let Some(ref changes) = record.env_changes {
+        out.push_str(&format!("  environment: {}\n", changes));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+    use crate::build_loop::{BuildId, Reason};
+    use crate::env::EnvDiff;
+    use crate::history::{BuildRecord, Outcome};
+    use crate::watch::{Change, ChangeKind};
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn human_builds() {
+        let build_id = BuildId::next();
+        let reason = Reason::FilesChanged(vec![Change {
+            path: PathBuf::from("/home/alice/foo/shell.nix"),
+            kind: ChangeKind::Modified,
+        }]);
+        let diff = EnvDiff {
+            added: vec![String::from("FOO")],
+            removed: vec![],
+            changed: vec![],
+            path_added: vec![],
+            path_removed: vec![],
+        };
+        let record = BuildRecord::new(
+            build_id,
+            &reason,
+            UNIX_EPOCH + Duration::from_secs(1000),
+            Duration::from_secs(12),
+            Outcome::Success,
+            Some(&diff),
+        );
+        assert_eq!(
+            format(&record, 1090),
+            format!(
+                "{}: success, 1m 30s ago, took 12s (/home/alice/foo/shell.nix modified)\n  \
+                 environment: added FOO\n",
+                build_id
+            )
+        );
+    }
+}
@@ -0,0 +1,165 @@
+//! Remove what lorri keeps for a project, or for all projects.
+
+use crate::constants::Paths;
+use crate::ops::{forget, ok_msg, ExitError, OpResult};
+use crate::project::Project;
+use crate::roots::ProjectRoots;
+use crate::socket::communicate::{client, ForgetRequest, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+use crate::NixFile;
+use std::path::Path;
+
+/// See the documentation for lorri::cli::Command::Clean for more
+/// details. Cleans up after `nix_file`, or after every project if
+/// `None`.
+pub fn main(paths: &Paths, nix_file: Option<NixFile>) -> OpResult {
+    let socket_path = SocketPath::from(paths.daemon_socket_file());
+    // the daemon has to stop building a project before its state is
+    // removed, or the next build creates it anew
+    let built = daemon_projects(&socket_path).unwrap_or_default();
+    let suffix = "Run `nix-collect-garbage` to free the space.";
+
+    let nix_file = match nix_file {
+        Some(nix_file) => nix_file,
+        None => {
+            for nix_file in built {
+                stop(&socket_path, nix_file)?;
+            }
+            let removed = remove_state(paths.gc_root_dir(), |_| true)?;
+            remove(paths.daemon_projects_file())?;
+            remove(paths.allow_dir())?;
+            return ok_msg(format!(
+                "Removed the state of {} project(s), the list of projects the lorri daemon \
+                 resumes and all `lorri allow` decisions. {}",
+                removed, suffix
+            ));
+        }
+    };
+
+    // the daemon knows projects by their canonical path, lorri
+    // commands run in the project by the path they are given
+    let canonical = std::fs::canonicalize(nix_file.as_os_str())
+        .map(NixFile::from)
+        .unwrap_or_else(|_| nix_file.clone());
+    if built.contains(&canonical) {
+        stop(&socket_path, canonical.clone())?;
+    }
+    let base = paths.gc_root_dir();
+    let state_dirs = [
+        base.join(Project::new(&nix_file, base).hash()),
+        base.join(Project::new(&canonical, base).hash()),
+    ];
+    let removed = remove_state(base, |project| state_dirs.contains(&project.state_dir))?;
+    if removed == 0 {
+        return ok_msg(format!("lorri keeps nothing for {}.", nix_file));
+    }
+    ok_msg(format!(
+        "Removed the GC roots and cached environment of {}. {}",
+        nix_file, suffix
+    ))
+}
+
+/// The projects the lorri daemon listening on `socket_path` builds,
+/// `None` if it isn’t running.
+fn daemon_projects(socket_path: &SocketPath) -> Option<Vec<NixFile>> {
+    let statuses = client::status(DEFAULT_READ_TIMEOUT)
+        .connect(socket_path)
+        .map_err(|e| debug!("Cannot ask the lorri daemon: {}", e))
+        .ok()?
+        .read()
+        .map_err(|e| debug!("Cannot read the status from the lorri daemon: {:?}", e))
+        .ok()?;
+    Some(statuses.into_iter().map(|status| status.nix_file).collect())
+}
+
+/// Make the lorri daemon stop building `nix_file` and remove its state.
+fn stop(socket_path: &SocketPath, nix_file: NixFile) -> Result<(), ExitError> {
+    let response = forget::request(
+        socket_path,
+        ForgetRequest {
+            nix_file: nix_file.clone(),
+            delete_roots: true,
+        },
+    )?;
+    match response.error {
+        Some(error) => Err(ExitError::errmsg(format!(
+            "The lorri daemon could not remove the state of {}: {}",
+            nix_file, error
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Remove the state directories in `base` of the projects `which`
+/// selects, returning how many were removed.
+fn remove_state<F>(base: &Path, which: F) -> Result<usize, ExitError>
+where
+    F: Fn(&ProjectRoots) -> bool,
+{
+    let projects = match ProjectRoots::scan(base) {
+        Ok(projects) => projects,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => {
+            return Err(ExitError::errmsg(format!(
+                "Cannot read {}: {}",
+                base.display(),
+                e
+            )))
+        }
+    };
+    let mut removed = 0;
+    for project in projects.iter().filter(|project| which(project)) {
+        project
+            .remove()
+            .map_err(|e| ExitError::errmsg(format!("Cannot remove {}: {}", project, e)))?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+/// Remove the file or directory `path`, if it exists.
+fn remove(path: &Path) -> Result<(), ExitError> {
+    let removed = if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    };
+    match removed {
+        Err(ref e) if e.kind() != std::io::ErrorKind::NotFound => Err(ExitError::errmsg(format!(
+            "Cannot remove {}: {}",
+            path.display(),
+            e
+        ))),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::remove_state;
+    use crate::project::Project;
+    use crate::NixFile;
+    use std::path::PathBuf;
+
+    #[test]
+    fn only_the_selected_state_is_removed() {
+        let base = tempfile::tempdir().unwrap();
+        let foo = NixFile::from(PathBuf::from("/home/alice/foo/shell.nix"));
+        let bar = NixFile::from(PathBuf::from("/home/alice/bar/shell.nix"));
+        let state_dir = |nix_file| {
+            let project = Project::new(nix_file, base.path());
+            project.gc_root_path().unwrap();
+            base.path().join(project.hash())
+        };
+        let foo_dir = state_dir(&foo);
+        let bar_dir = state_dir(&bar);
+
+        let removed = remove_state(base.path(), |project| project.state_dir == foo_dir);
+        assert_eq!(removed.map_err(|e| e.message().to_string()), Ok(1));
+        assert!(!foo_dir.exists());
+        assert!(bar_dir.exists());
+
+        let missing = base.path().join("missing");
+        assert_eq!(remove_state(&missing, |_| true).ok(), Some(0));
+    }
+}
@@ -0,0 +1,136 @@
+//! Generate shell completion scripts from the CLI definition, and
+//! the candidates they complete dynamically.
+
+use crate::cli::{Arguments, CompleteOptions, CompletionsOptions};
+use crate::constants::Paths;
+use crate::ops::{attrs, ok, ExitError, OpResult};
+use crate::roots::ProjectRoots;
+use crate::trust::Trust;
+use crate::NixFile;
+use std::collections::BTreeSet;
+use std::path::Path;
+use structopt::clap::Shell;
+use structopt::StructOpt;
+
+/// Completes attribute names after `--attr`/`-A`, and project paths
+/// for the commands which take a nix file, by asking `lorri complete`.
+const BASH_DYNAMIC: &str = r#"
+_lorri_dynamic() {
+    local cur="${COMP_WORDS[COMP_CWORD]}"
+    local prev="${COMP_WORDS[COMP_CWORD-1]}"
+    case "$prev" in
+        --attr|-A)
+            COMPREPLY=($(compgen -W "$(lorri complete attrs 2>/dev/null)" -- "$cur"))
+            return 0
+            ;;
+    esac
+    case "${COMP_WORDS[1]}" in
+        ping|forget|stream-events)
+            if [[ $COMP_CWORD -ge 2 && $cur != -* ]]; then
+                COMPREPLY=($(compgen -W "$(lorri complete projects 2>/dev/null)" -- "$cur"))
+                return 0
+            fi
+            ;;
+    esac
+    _lorri "$@"
+}
+complete -F _lorri_dynamic -o bashdefault -o default lorri
+"#;
+
+/// Like `BASH_DYNAMIC`.
+const FISH_DYNAMIC: &str = r#"
+complete -c lorri -n "__fish_seen_subcommand_from shell" -s A -l attr -x -a "(lorri complete attrs 2>/dev/null)"
+complete -c lorri -n "__fish_seen_subcommand_from ping forget stream-events" -x -a "(lorri complete projects 2>/dev/null)"
+"#;
+
+/// See the documentation for lorri::cli::Command::Completions for
+/// more details.
+pub fn main(opts: CompletionsOptions) -> OpResult {
+    let mut out = std::io::stdout();
+    Arguments::clap().gen_completions_to("lorri", opts.shell, &mut out);
+    match opts.shell {
+        Shell::Bash => print!("{}", BASH_DYNAMIC),
+        Shell::Fish => print!("{}", FISH_DYNAMIC),
+        // the generated `_arguments` specs cannot be extended
+        _ => {}
+    }
+    ok()
+}
+
+/// See the documentation for lorri::cli::Command::Complete for more
+/// details. `nix_file` is the current project, if there is one.
+/// Its attributes are only completed if it is allowed, completing
+/// must not evaluate a nix file the user never looked at.
+pub fn candidates(paths: &Paths, nix_file: Option<NixFile>, opts: CompleteOptions) -> OpResult {
+    let candidates = match opts.what.as_str() {
+        "attrs" => match nix_file {
+            Some(ref nix_file) if Trust::new(paths.allow_dir()).check(nix_file).is_ok() => {
+                attributes(nix_file)?
+            }
+            _ => vec![],
+        },
+        _ => projects(paths.gc_root_dir())?,
+    };
+    for candidate in candidates {
+        println!("{}", candidate);
+    }
+    ok()
+}
+
+/// The attributes of `nix_file` which `--attr` can select, empty if
+/// it evaluates to a derivation.
+fn attributes(nix_file: &NixFile) -> Result<Vec<String>, ExitError> {
+    Ok(attrs::attributes(nix_file)?
+        .unwrap_or_default()
+        .into_iter()
+        .map(|attr| attr.name)
+        .collect())
+}
+
+/// The nix files of the projects lorri built, which still exist.
+fn projects(gc_root_dir: &Path) -> Result<Vec<String>, ExitError> {
+    let projects = match ProjectRoots::scan(gc_root_dir) {
+        Ok(projects) => projects,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+        Err(e) => {
+            return Err(ExitError::errmsg(format!(
+                "Cannot read {}: {}",
+                gc_root_dir.display(),
+                e
+            )))
+        }
+    };
+    Ok(projects
+        .into_iter()
+        .filter(|project| !project.is_stale())
+        .filter_map(|project| project.nix_file)
+        .map(|nix_file| nix_file.display().to_string())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::projects;
+    use crate::project::Project;
+    use crate::NixFile;
+
+    #[test]
+    fn existing_projects_are_completed() {
+        let base = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let nix_file = dir.path().join("shell.nix");
+        let gone = dir.path().join("gone.nix");
+        for path in &[&nix_file, &gone] {
+            std::fs::write(path, "{}").unwrap();
+            let project = NixFile::from(path.to_path_buf());
+            Project::new(&project, base.path()).gc_root_path().unwrap();
+        }
+        std::fs::remove_file(&gone).unwrap();
+        assert_eq!(
+            projects(base.path()).map_err(|e| e.message().to_string()),
+            Ok(vec![nix_file.display().to_string()])
+        );
+    }
+}
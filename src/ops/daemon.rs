@@ -1,19 +1,70 @@
-//! Run a BuildLoop for `shell.nix`, watching for input file changes.
-//! Can be used together with `direnv`.
-use crate::daemon::Daemon;
+//! Run the lorri daemon: a BuildLoop for every project pinged by
+//! `lorri direnv` or `lorri ping`, and the socket its clients talk to.
+use crate::build_loop::{Event, Warning};
+use crate::builder;
+use crate::daemon::{Daemon, ForgetProject, IndicateActivity, UpgradeDaemon, PRUNE_INTERVAL};
+use crate::http;
 use crate::ops::{ok, ExitError, OpResult};
+use crate::signal;
 use crate::socket::communicate::listener;
-use crate::socket::communicate::CommunicationType;
+use crate::socket::communicate::{CommunicationType, UpgradeResponse, PROTOCOL_VERSION};
+use crate::socket::systemd;
 use crate::socket::ReadWriter;
+use crate::status_page;
+use crate::trust::Trust;
+use crate::NixFile;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::RawFd;
+use std::path::Path;
+use std::process::Command;
 use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-/// See the documentation for lorri::cli::Command::Shell for more
+/// How long to wait for the build loops to notice that their builds
+/// were cancelled, before exiting anyway.
+const CANCEL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Set by a daemon which replaced itself with an upgraded one (see
+/// `upgrade`), to who owns the socket it passed on: `"systemd"` if it
+/// was activated by systemd, else `"daemon"`.
+const SOCKET_OWNER_ENV: &str = "LORRI_DAEMON_SOCKET_OWNER";
+
+/// What the main loop of the daemon reacts to.
+enum Request {
+    /// A client wants a project to be built.
+    Activity(IndicateActivity),
+    /// A client wants the daemon to stop building a project.
+    Forget(ForgetProject),
+    /// A client wants the daemon to continue as another lorri.
+    Upgrade(UpgradeDaemon),
+    /// The directory of a project was moved, from the first nix file
+    /// to the second.
+    Moved(NixFile, NixFile),
+    /// A signal asked the daemon to stop.
+    Stop(signal::Signal),
+    /// A signal asked the daemon to read its configuration again.
+    Reload,
+}
+
+/// See the documentation for lorri::cli::Command::Daemon for more
 /// details.
 pub fn main() -> OpResult {
     let paths = ::ops::get_paths()?;
     let socket_path = ::socket::path::SocketPath::from(paths.daemon_socket_file());
+    // set if this daemon replaced an older one, see `upgrade`
+    let socket_owner = std::env::var(SOCKET_OWNER_ENV).ok();
+    std::env::remove_var(SOCKET_OWNER_ENV);
     // TODO: move listener into Daemon struct?
-    let listener = listener::Listener::new(&socket_path).map_err(|e| match e {
+    let mut activated = false;
+    let listener = match listener::Listener::activated(&socket_path) {
+        Ok(Some(listener)) => {
+            activated = socket_owner.map_or(true, |owner| owner == "systemd");
+            Ok(listener)
+        }
+        Ok(None) => listener::Listener::new(&socket_path),
+        Err(e) => Err(e),
+    }
+    .map_err(|e| match e {
         ::socket::path::BindError::OtherProcessListening => ExitError::errmsg(format!(
             "Another daemon is already listening on the socket at {}. \
              We are currently only allowing one daemon to be running at the same time.",
@@ -22,44 +73,291 @@ pub fn main() -> OpResult {
         e => panic!("{:?}", e),
     })?;
 
-    let (mut daemon, build_messages_rx) = Daemon::new(&paths);
+    let socket_fd = listener.as_raw_fd();
+
+    let config = ::ops::get_config(&paths)?;
+    let http_listener = match config.http_address().map_err(ExitError::errmsg)? {
+        Some(address) => Some(std::net::TcpListener::bind(address).map_err(|e| {
+            ExitError::errmsg(format!(
+                "Cannot serve the status page at {}: {}",
+                address, e
+            ))
+        })?),
+        None => None,
+    };
+    let (mut daemon, build_messages_rx) = Daemon::new(&paths, config);
+    daemon.save_projects();
 
     // messages sent from accept handlers
     let (accept_messages_tx, accept_messages_rx) = mpsc::channel();
 
+    let (requests_tx, requests_rx) = mpsc::channel();
+    let activities_tx = requests_tx.clone();
+    std::thread::spawn(move || {
+        for activity in accept_messages_rx {
+            if activities_tx.send(Request::Activity(activity)).is_err() {
+                break;
+            }
+        }
+    });
+    let (forget_tx, forget_rx) = mpsc::channel();
+    let forgets_tx = requests_tx.clone();
+    std::thread::spawn(move || {
+        for forget in forget_rx {
+            if forgets_tx.send(Request::Forget(forget)).is_err() {
+                break;
+            }
+        }
+    });
+    let (upgrade_tx, upgrade_rx) = mpsc::channel();
+    let upgrades_tx = requests_tx.clone();
+    std::thread::spawn(move || {
+        for upgrade in upgrade_rx {
+            if upgrades_tx.send(Request::Upgrade(upgrade)).is_err() {
+                break;
+            }
+        }
+    });
+    let moves_tx = requests_tx.clone();
+    let reload_tx = requests_tx.clone();
+    if let Err(e) = signal::on_reload(move |_| {
+        let _ = reload_tx.send(Request::Reload);
+    }) {
+        warn!("Cannot reload the configuration on SIGHUP: {}", e);
+    }
+    signal::on_termination(move |signal| {
+        // the main loop is gone once the daemon exits
+        let _ = requests_tx.send(Request::Stop(signal));
+    });
+
     let handlers = daemon.handlers();
+    let dump_handlers = handlers.clone();
+    // answered here, so it works even if the main loop hangs
+    if let Err(e) = signal::on_dump(move |_| eprintln!("{}", dump_handlers.dump())) {
+        warn!("Cannot dump the state on SIGUSR1: {}", e);
+    }
+
+    if let Some(http_listener) = http_listener {
+        let handlers = handlers.clone();
+        std::thread::spawn(move || {
+            http::serve(http_listener, |path| match path {
+                "/" => http::Response::ok(
+                    "text/html; charset=utf-8",
+                    status_page::html(&handlers.status_page(), now()),
+                ),
+                "/status.json" => http::Response::ok(
+                    "application/json",
+                    status_page::json(&handlers.status_page()),
+                ),
+                "/metrics" => http::Response::ok(
+                    "text/plain; version=0.0.4; charset=utf-8",
+                    handlers.metrics(),
+                ),
+                _ => http::Response::not_found(),
+            })
+        });
+    }
 
     // TODO join handle
     let _accept_loop_handle = std::thread::spawn(move || loop {
         let accept_messages_tx = accept_messages_tx.clone();
+        let forget_tx = forget_tx.clone();
+        let upgrade_tx = upgrade_tx.clone();
         // has to clone handlers once per accept loop,
         // because accept spawns a thread each time.
         let handlers = handlers.clone();
-        let _handle = listener
-            .accept(move |unix_stream, comm_type| match comm_type {
-                CommunicationType::Ping => {
-                    handlers.ping(ReadWriter::new(&unix_stream), accept_messages_tx)
-                }
-            })
-            // TODO
-            .unwrap();
+        let accepted = listener.accept(move |unix_stream, comm_type| match comm_type {
+            CommunicationType::Ping => {
+                handlers.ping(ReadWriter::new(&unix_stream), accept_messages_tx)
+            }
+            CommunicationType::Status => handlers.status(ReadWriter::new(&unix_stream)),
+            CommunicationType::Logs => handlers.logs(ReadWriter::new(&unix_stream)),
+            CommunicationType::Subscribe => handlers.subscribe(ReadWriter::new(&unix_stream)),
+            CommunicationType::Forget => handlers.forget(ReadWriter::new(&unix_stream), forget_tx),
+            CommunicationType::Shell => {
+                handlers.shell(ReadWriter::new(&unix_stream), accept_messages_tx)
+            }
+            CommunicationType::Upgrade => {
+                handlers.upgrade(ReadWriter::new(&unix_stream), upgrade_tx)
+            }
+        });
+        match accepted {
+            Ok(_handle) => {}
+            Err(listener::AcceptError::VersionMismatch { client_version }) => warn!(
+                "Refused a client speaking protocol version {}, this daemon speaks {}. \
+                 Please restart `lorri daemon` and your shells.",
+                client_version, PROTOCOL_VERSION
+            ),
+            Err(e) => warn!("Failed to accept a client: {:?}", e),
+        }
     });
 
+    // connections are accepted from now on
+    if let Err(e) = systemd::notify_ready() {
+        warn!("Failed to notify systemd that the daemon is ready: {}", e);
+    }
+
     // TODO: join handle
-    let _start_build_loop_handle = std::thread::spawn(|| {
+    let _start_build_loop_handle = std::thread::spawn(move || {
         for msg in build_messages_rx {
-            println!("{:#?}", msg);
+            // build output is kept for `lorri logs` instead
+            if let Event::Log(_, _) = msg.event {
+                continue;
+            }
+            if let Event::Warning(Warning::ProjectMoved(ref from, Some(ref to))) = msg.event {
+                let _ = moves_tx.send(Request::Moved(from.clone(), to.clone()));
+            }
+            debug!("{}: {:?}", msg.nix_file, msg.event);
         }
     });
 
-    // For each build instruction, add the corresponding file
-    // to the watch list.
-    for start_build in accept_messages_rx {
-        daemon.add(start_build.nix_file)
+    let trust = Trust::new(paths.allow_dir());
+    daemon.resume(|nix_file| match trust.check(nix_file) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Not resuming: {}", e);
+            false
+        }
+    });
+
+    daemon.prune_roots();
+    let mut last_pruned = Instant::now();
+
+    // For each build instruction of an allowed project, add the
+    // corresponding file to the watch list.
+    loop {
+        match requests_rx.recv_timeout(PRUNE_INTERVAL) {
+            Ok(Request::Activity(start_build)) => match trust.check(&start_build.nix_file) {
+                Ok(()) => daemon.add(start_build.nix_file),
+                Err(e) => warn!("Not building: {}", e),
+            },
+            Ok(Request::Forget(forget)) => {
+                // the client may have given up waiting already
+                let _ = forget.done.send(daemon.forget(&forget.request));
+            }
+            // the build loop waits until the new path is allowed
+            Ok(Request::Moved(from, to)) => daemon.moved(&from, to),
+            Ok(Request::Upgrade(request)) => {
+                upgrade(&daemon, request, socket_fd, activated, &requests_rx)
+            }
+            Ok(Request::Reload) => match ::ops::get_config(&paths) {
+                Ok(config) => daemon.reload(config),
+                Err(e) => warn!("Keeping the current configuration: {}", e.message()),
+            },
+            Ok(Request::Stop(signal)) => {
+                info!("Received {:?}, shutting down", signal);
+                break;
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+        }
+        if last_pruned.elapsed() >= PRUNE_INTERVAL {
+            daemon.prune_roots();
+            last_pruned = Instant::now();
+        }
     }
 
+    // with socket activation, systemd owns the socket and keeps
+    // listening to start the daemon again
+    if !activated {
+        if let Err(e) = std::fs::remove_file(paths.daemon_socket_file()) {
+            warn!("Failed to remove the socket: {}", e);
+        }
+    }
+    shutdown(&daemon, daemon.config().shutdown_wait(), &requests_rx);
     ok()
+}
+
+/// Let running builds finish for up to `wait`, or until another
+/// signal arrives on `requests`, then cancel the remaining ones.
+/// The build loops record their results before returning, so nothing
+/// half-written is left behind.
+fn shutdown(daemon: &Daemon, wait: Duration, requests: &mpsc::Receiver<Request>) {
+    let poll = Duration::from_millis(100);
+    let waiting = Instant::now();
+    if daemon.is_building() && wait > Duration::from_secs(0) {
+        info!("Waiting up to {:?} for running builds to finish", wait);
+    }
+    while daemon.is_building() && waiting.elapsed() < wait {
+        if let Ok(Request::Stop(_)) = requests.recv_timeout(poll) {
+            info!("Received another signal, not waiting any longer");
+            break;
+        }
+    }
+
+    let cancelled = builder::cancel_all();
+    if cancelled > 0 {
+        info!("Cancelled {} running build(s)", cancelled);
+    }
+    let cancelling = Instant::now();
+    while daemon.is_building() && cancelling.elapsed() < CANCEL_TIMEOUT {
+        std::thread::sleep(poll);
+    }
+}
+
+/// Let running builds finish like `shutdown` does, then replace this
+/// process with the lorri the client asked for. It continues on the
+/// same socket and resumes the projects this daemon saved (see
+/// `Daemon::resume`). Only returns if that is not possible.
+fn upgrade(
+    daemon: &Daemon,
+    asked: UpgradeDaemon,
+    socket: RawFd,
+    activated: bool,
+    requests: &mpsc::Receiver<Request>,
+) {
+    let UpgradeDaemon {
+        request,
+        done,
+        answered,
+    } = asked;
+    let executable = request.executable;
+    let answer = |error| {
+        // the client may have given up waiting already
+        let _ = done.send(UpgradeResponse {
+            projects: daemon.project_count(),
+            error,
+        });
+    };
+    if let Err(e) = check_executable(&executable) {
+        answer(Some(format!("Cannot run {}: {}", executable.display(), e)));
+        return;
+    }
+
+    info!("Upgrading to {}", executable.display());
+    shutdown(daemon, daemon.config().shutdown_wait(), requests);
+    answer(None);
+    let _ = answered.recv_timeout(Duration::from_secs(1));
+    let mut command = Command::new(&executable);
+    command.args(std::env::args_os().skip(1)).env(
+        SOCKET_OWNER_ENV,
+        if activated { "systemd" } else { "daemon" },
+    );
+    let e = systemd::exec_with_socket(&mut command, socket);
+    error!(
+        "Failed to execute {}, continuing with this lorri: {}",
+        executable.display(),
+        e
+    );
+}
+
+/// Whether `executable` is a file which can be executed.
+fn check_executable(executable: &Path) -> std::io::Result<()> {
+    let metadata = std::fs::metadata(executable)?;
+    if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "not an executable file",
+        ))
+    }
+}
 
-    // TODO: join all accept handles & accept_loop_handle
-    // handle.join().unwrap();
+/// The current time in seconds since the epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
 }
@@ -0,0 +1,15 @@
+//! Refuse building a project in the background.
+
+use crate::ops::{self, ok_msg, ExitError, OpResult};
+use crate::trust::Trust;
+use crate::NixFile;
+
+/// See the documentation for lorri::cli::Command::Deny for more
+/// details.
+pub fn main(nix_file: &NixFile) -> OpResult {
+    let paths = ops::get_paths()?;
+    Trust::new(paths.allow_dir())
+        .deny(nix_file)
+        .map_err(|e| ExitError::errmsg(format!("Cannot deny {}: {}", nix_file, e)))?;
+    ok_msg(format!("Denied {}", nix_file))
+}
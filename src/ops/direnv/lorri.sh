@@ -0,0 +1,15 @@
+#!/usr/bin/env bash
+# The `use lorri` function for direnv, installed by `lorri direnv-install`
+# into direnv’s library. With it, an `.envrc` only needs to contain:
+#
+#     use lorri
+#
+# Arguments are passed to `lorri direnv`, e.g.
+#
+#     use lorri --shell-file nix/shell.nix
+#
+# `lorri direnv-install` overwrites this file, don’t edit it.
+
+use_lorri() {
+    eval "$(lorri direnv "$@")"
+}
@@ -3,10 +3,16 @@
 mod version;
 
 use self::version::{DirenvVersion, MIN_DIRENV_VERSION};
-use crate::ops::{ok, ok_msg, ExitError, OpResult};
+use crate::build_loop::WatchSet;
+use crate::cli::ExportFormat;
+use crate::env::{self, Passthrough, ShellEnv};
+use crate::logging;
+use crate::ops::{self, export, ok, ok_msg, ping, ErrorCode, ExitError, OpResult};
 use crate::project::Project;
-use crate::socket::communicate::client;
-use crate::socket::communicate::{Ping, DEFAULT_READ_TIMEOUT};
+use crate::roots::Roots;
+use crate::socket::path::SocketPath;
+use crate::trust::Trust;
+use crate::NixFile;
 use std::process::Command;
 
 /// See the documentation for lorri::cli::Command::Direnv for more
@@ -14,49 +20,113 @@ use std::process::Command;
 pub fn main(project: &Project) -> OpResult {
     check_direnv_version()?;
 
-    let mut shell_root = project.gc_root_path().unwrap();
-    shell_root.push("build-0"); // !!!
+    Trust::new(ops::get_paths()?.allow_dir())
+        .check(project.expression())
+        .map_err(|e| ExitError::errmsg(e.to_string()).with_code(ErrorCode::NotAllowed))?;
+
+    let roots = Roots::from_project(project)
+        .map_err(|e| ExitError::errmsg(format!("Cannot open the GC roots: {}", e)))?;
+    let shell_root = roots.current_shell_root();
 
     // TODO: don’t start build/evaluation automatically, let the user decide
-    if let Ok(client) = client::ping(DEFAULT_READ_TIMEOUT).connect(
-        &::socket::path::SocketPath::from(::ops::get_paths()?.daemon_socket_file()),
-    ) {
-        client
-            .write(&Ping {
-                nix_file: project.expression().clone(),
-            })
-            .unwrap();
-    } else {
+    // the daemon knows projects by their canonical path
+    let paths = ops::get_paths()?;
+    let socket_path = SocketPath::from(paths.daemon_socket_file());
+    let daemon_running = std::fs::canonicalize(project.expression().as_os_str())
+        .map(NixFile::from)
+        .map(|nix_file| ping::ping(&socket_path, nix_file).is_ok())
+        .unwrap_or(false);
+    if !daemon_running && logging::show_warnings() {
         eprintln!("Uh oh, your lorri daemon is not running.");
     }
 
+    if std::env::var("DIRENV_IN_ENVRC") != Ok(String::from("1")) && logging::show_warnings() {
+        eprintln!(
+            "Warning: 'lorri direnv' should be executed by direnv from within an `.envrc` file."
+        )
+    }
+
+    // direnv runs us in the environment of the user’s shell
+    let config = ops::get_config(&paths)?.project(project.expression());
+    let unset = blocked_variables(&config.passthrough(), std::env::vars())
+        .iter()
+        .map(|name| format!("unset {}\n", name))
+        .collect::<String>();
+    let watched = WatchSet::load(&roots)
+        .map(|watch_set| watch_inputs(&watch_set))
+        .unwrap_or_default();
+
+    // Nothing will rebuild the project anyway, so use the environment
+    // dumped after its last build; that is instant and needs no nix.
+    if !daemon_running {
+        if let Ok(shell_env) = ShellEnv::load_dump(&roots) {
+            let host = config.passthrough().filter(std::env::vars());
+            let vars = export::changed_vars(&host, shell_env.apply(host.clone()));
+            return ok_msg(format!(
+                "watch_file \"{}\"\n{}{}{}",
+                roots.build_marker_file().display(),
+                watched,
+                unset,
+                export::format(&vars, ExportFormat::Bash)
+            ));
+        }
+    }
+
     if !shell_root.exists() {
         return Err(ExitError::errmsg(
             "Please start `lorri daemon` or run `lorri watch` before using direnv integration.",
         ));
     }
 
-    if std::env::var("DIRENV_IN_ENVRC") != Ok(String::from("1")) {
-        eprintln!(
-            "Warning: 'lorri direnv' should be executed by direnv from within an `.envrc` file."
-        )
-    }
-
     ok_msg(format!(
         r#"
 EVALUATION_ROOT="{}"
 
 watch_file "$EVALUATION_ROOT"
-
+watch_file "{}"
+{}
+{}
 {}
 "#,
-        shell_root.display(),
+        shell_root,
+        roots.build_marker_file().display(),
+        watched,
+        unset,
         include_str!("envrc.bash")
     ))
 }
 
+/// `watch_file` lines for the inputs of the project’s last evaluation,
+/// so direnv marks the environment stale when one of them changes,
+/// even if no daemon rebuilds the project.
+fn watch_inputs(watch_set: &WatchSet) -> String {
+    watch_set
+        .inputs
+        .keys()
+        .map(|path| format!("watch_file {}\n", env::posix_quote(&path.to_string_lossy())))
+        .collect()
+}
+
+/// The variables of `host` which must not pass into the project’s
+/// environment. direnv’s own variables always pass, or it loses track
+/// of the environment.
+fn blocked_variables<I>(passthrough: &Passthrough, host: I) -> Vec<String>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    let mut blocked = host
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|name| !name.starts_with("DIRENV_") && !passthrough.passes(name))
+        // bash can’t unset anything else
+        .filter(|name| name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+        .collect::<Vec<String>>();
+    blocked.sort();
+    blocked
+}
+
 /// Checks `direnv version` against the minimal version lorri requires.
-fn check_direnv_version() -> OpResult {
+pub(crate) fn check_direnv_version() -> OpResult {
     let out = with_command("direnv", |mut cmd| cmd.arg("version").output())?;
     let version = std::str::from_utf8(&out.stdout)
         .map_err(|_| ())
@@ -64,6 +134,7 @@ fn check_direnv_version() -> OpResult {
         .map_err(|()| ExitError {
             exitcode: 1,
             message: "Could not figure out the current `direnv` version (parse error)".to_string(),
+            code: ErrorCode::Error,
         })?;
     if version < MIN_DIRENV_VERSION {
         Err(ExitError::errmsg(format!(
@@ -90,5 +161,51 @@ where
             std::io::ErrorKind::NotFound => format!("`{}`: executable not found", executable),
             _ => format!("Could not start `{}`: {}", executable, a),
         },
+        code: ErrorCode::Error,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{blocked_variables, watch_inputs};
+    use crate::build_loop::WatchSet;
+    use crate::env::Passthrough;
+    use std::path::PathBuf;
+
+    #[test]
+    fn direnv_variables_are_never_blocked() {
+        let host = vec![
+            (String::from("DIRENV_DIR"), String::from("-/home/alice/foo")),
+            (String::from("SSH_AUTH_SOCK"), String::from("/run/ssh")),
+            (String::from("EDITOR"), String::from("vim")),
+        ];
+        let passthrough = Passthrough {
+            allow: Some(vec![String::from("EDITOR")]),
+            deny: vec![],
+        };
+        assert_eq!(
+            blocked_variables(&passthrough, host),
+            vec![String::from("SSH_AUTH_SOCK")]
+        );
+    }
+
+    #[test]
+    fn evaluation_inputs_are_watched() {
+        let mut watch_set = WatchSet::default();
+        watch_set.inputs.insert(
+            PathBuf::from("/home/alice/it's/shell.nix"),
+            vec![PathBuf::from("/home/alice/it's/shell.nix")],
+        );
+        watch_set.inputs.insert(
+            PathBuf::from("/nix/var/nix/profiles/per-user/alice/channels"),
+            vec![PathBuf::from(
+                "/nix/var/nix/profiles/per-user/alice/channels/nixpkgs/default.nix",
+            )],
+        );
+        assert_eq!(
+            watch_inputs(&watch_set),
+            "watch_file '/home/alice/it'\\''s/shell.nix'\n\
+             watch_file '/nix/var/nix/profiles/per-user/alice/channels'\n"
+        );
+    }
+}
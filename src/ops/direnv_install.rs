@@ -0,0 +1,83 @@
+//! Install the `use lorri` function into direnv’s library.
+
+extern crate directories;
+
+use self::directories::BaseDirs;
+use crate::ops::{ok_msg, ExitError, OpResult};
+use std::path::{Path, PathBuf};
+
+/// The library file, which defines `use_lorri`.
+const LIBRARY: &str = include_str!("direnv/lorri.sh");
+
+/// See the documentation for lorri::cli::Command::DirenvInstall for
+/// more details.
+pub fn main() -> OpResult {
+    let file =
+        library_file().ok_or_else(|| ExitError::errmsg("Cannot find your home directory"))?;
+
+    if is_installed(&file) {
+        return ok_msg(format!("{} is up to date.", file.display()));
+    }
+    let write = |file: &Path| -> std::io::Result<()> {
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(file, LIBRARY)
+    };
+    write(&file)
+        .map_err(|e| ExitError::errmsg(format!("Cannot write {}: {}", file.display(), e)))?;
+    ok_msg(format!(
+        "Wrote {}. You can now use `use lorri` in your .envrc files.",
+        file.display()
+    ))
+}
+
+/// Where `lorri direnv-install` writes the library, `None` if the
+/// user’s home directory is unknown.
+pub(crate) fn library_file() -> Option<PathBuf> {
+    let base_dirs = BaseDirs::new()?;
+    Some(
+        lib_dir(
+            std::env::var_os("DIRENV_CONFIG").map(PathBuf::from),
+            base_dirs.config_dir(),
+        )
+        .join("lorri.sh"),
+    )
+}
+
+/// Whether `file` holds the library of this lorri version.
+pub(crate) fn is_installed(file: &Path) -> bool {
+    std::fs::read_to_string(file)
+        .map(|contents| contents == LIBRARY)
+        .unwrap_or(false)
+}
+
+/// direnv’s library directory, whose `*.sh` files it loads before
+/// every `.envrc`: `$DIRENV_CONFIG/lib`, or `direnv/lib` in the
+/// user’s configuration directory.
+fn lib_dir(direnv_config: Option<PathBuf>, config_dir: &Path) -> PathBuf {
+    direnv_config
+        .unwrap_or_else(|| config_dir.join("direnv"))
+        .join("lib")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::lib_dir;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn direnv_lib_dir() {
+        assert_eq!(
+            lib_dir(None, Path::new("/home/alice/.config")),
+            PathBuf::from("/home/alice/.config/direnv/lib")
+        );
+        assert_eq!(
+            lib_dir(
+                Some(PathBuf::from("/etc/direnv")),
+                Path::new("/home/alice/.config")
+            ),
+            PathBuf::from("/etc/direnv/lib")
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! Check the lorri setup of a project for problems.
+
+use crate::build_loop::WatchSet;
+use crate::cli::DoctorOptions;
+use crate::constants::Paths;
+use crate::nix;
+use crate::ops::direnv::check_direnv_version;
+use crate::ops::direnv_install;
+use crate::ops::{ok, ExitError, OpResult};
+use crate::project::Project;
+use crate::roots::Roots;
+use std::ffi::OsString;
+use std::path::Path;
+use std::process::Command;
+
+/// The oldest Nix lorri works with.
+const MIN_NIX_VERSION: NixVersion = NixVersion(2, 0);
+
+/// The result of a single check.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// Everything is fine, with a description of what was checked.
+    Pass(String),
+    /// Something is wrong, and how the user can fix it.
+    Fail { problem: String, hint: String },
+}
+
+impl Outcome {
+    fn fail<P: Into<String>, H: Into<String>>(problem: P, hint: H) -> Outcome {
+        Outcome::Fail {
+            problem: problem.into(),
+            hint: hint.into(),
+        }
+    }
+}
+
+/// See the documentation for lorri::cli::Command::Doctor for more
+/// details.
+pub fn main(paths: &Paths, project: &Project, opts: DoctorOptions) -> OpResult {
+    let roots = Roots::from_project(project)
+        .map_err(|e| ExitError::errmsg(format!("Cannot open the GC roots: {}", e)))?;
+    let nix_file = Path::new(project.expression().as_os_str());
+    let project_dir = nix_file.parent().unwrap_or_else(|| Path::new("/"));
+
+    let mut outcomes = vec![check_nix(), check_evaluation()];
+    outcomes.extend(check_direnv(project_dir));
+    outcomes.push(check_inotify(
+        WatchSet::load(&roots).ok().map(|w| w.watched.len()),
+        std::fs::read_to_string("/proc/sys/fs/inotify/max_user_watches")
+            .ok()
+            .and_then(|limit| limit.trim().parse().ok()),
+    ));
+    outcomes.push(check_writable("GC root directory", paths.gc_root_dir()));
+    if let Some(state_dir) = paths.daemon_projects_file().parent() {
+        outcomes.push(check_writable("state directory", state_dir));
+    }
+    outcomes.extend(check_roots(project, &roots, opts.repair)?);
+
+    let mut failed = 0;
+    for outcome in &outcomes {
+        match outcome {
+            Outcome::Pass(what) => println!("ok: {}", what),
+            Outcome::Fail { problem, hint } => {
+                failed += 1;
+                println!("problem: {}", problem);
+                println!("  hint: {}", hint);
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(ExitError::errmsg(format!(
+            "{} of {} checks found a problem.",
+            failed,
+            outcomes.len()
+        )));
+    }
+    ok()
+}
+
+/// A Nix version, major and minor.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct NixVersion(usize, usize);
+
+impl std::fmt::Display for NixVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}.{}", self.0, self.1)
+    }
+}
+
+/// Parse the output of `nix-instantiate --version`, e.g.
+/// `nix-instantiate (Nix) 2.3.4` or `nix-instantiate (Nix) 2.4pre2021`.
+fn parse_nix_version(output: &str) -> Option<NixVersion> {
+    let version = output.split_whitespace().last()?;
+    let mut parts = version.split('.').map(|part| {
+        part.chars()
+            .take_while(char::is_ascii_digit)
+            .collect::<String>()
+            .parse::<usize>()
+    });
+    let major = parts.next()?.ok()?;
+    let minor = parts.next()?.ok()?;
+    Some(NixVersion(major, minor))
+}
+
+/// Checks that Nix is installed and recent enough.
+fn check_nix() -> Outcome {
+    let output = match Command::new("nix-instantiate").arg("--version").output() {
+        Ok(output) => output,
+        Err(e) => {
+            return Outcome::fail(
+                format!("cannot run `nix-instantiate`: {}", e),
+                "Install Nix (https://nixos.org/nix/) and make sure it is in your PATH.",
+            )
+        }
+    };
+    let output = String::from_utf8_lossy(&output.stdout);
+    match parse_nix_version(&output) {
+        Some(ref version) if *version >= MIN_NIX_VERSION => {
+            Outcome::Pass(format!("Nix {} is installed", version))
+        }
+        Some(version) => Outcome::fail(
+            format!(
+                "Nix is version {}, but lorri needs {} or newer",
+                version, MIN_NIX_VERSION
+            ),
+            "Upgrade Nix.",
+        ),
+        None => Outcome::fail(
+            format!("cannot make sense of the Nix version `{}`", output.trim()),
+            "Check that `nix-instantiate --version` works.",
+        ),
+    }
+}
+
+/// Checks that Nix can evaluate a trivial expression.
+fn check_evaluation() -> Outcome {
+    match nix::CallOpts::expression("1 + 1").value::<u8>() {
+        Ok(2) => Outcome::Pass(String::from("Nix evaluates expressions")),
+        Ok(other) => Outcome::fail(
+            format!("Nix evaluates `1 + 1` to {}", other),
+            "Check your Nix installation.",
+        ),
+        Err(e) => Outcome::fail(
+            format!("Nix cannot evaluate `1 + 1`: {:?}", e),
+            "Check that `nix-instantiate --eval -E '1 + 1'` works, and that \
+             the Nix daemon is running if you use one.",
+        ),
+    }
+}
+
+/// Checks that direnv is installed, hooked into the user’s shell and
+/// set up to use lorri in `project_dir`.
+fn check_direnv(project_dir: &Path) -> Vec<Outcome> {
+    let mut outcomes = vec![match check_direnv_version() {
+        Ok(_) => Outcome::Pass(String::from("direnv is installed")),
+        Err(e) => Outcome::fail(
+            e.message(),
+            "Install direnv (https://direnv.net) to load the environment in your shell.",
+        ),
+    }];
+    let envrc = project_dir.join(".envrc");
+    let contents = match std::fs::read_to_string(&envrc) {
+        Ok(contents) => contents,
+        Err(_) => {
+            outcomes.push(Outcome::fail(
+                format!("{} does not exist", envrc.display()),
+                "Run `lorri init`, or create it with `eval \"$(lorri direnv)\"`.",
+            ));
+            return outcomes;
+        }
+    };
+    if contents.contains("use lorri") {
+        let installed = direnv_install::library_file()
+            .map(|file| direnv_install::is_installed(&file))
+            .unwrap_or(false);
+        outcomes.push(if installed {
+            Outcome::Pass(format!("{} uses lorri", envrc.display()))
+        } else {
+            Outcome::fail(
+                format!(
+                    "{} has `use lorri`, but direnv does not know it",
+                    envrc.display()
+                ),
+                "Run `lorri direnv-install`.",
+            )
+        });
+    } else if contents.contains("lorri direnv") {
+        outcomes.push(Outcome::Pass(format!("{} uses lorri", envrc.display())));
+    } else {
+        outcomes.push(Outcome::fail(
+            format!("{} does not use lorri", envrc.display()),
+            "Add `eval \"$(lorri direnv)\"` to it.",
+        ));
+    }
+    outcomes.push(
+        if direnv_loaded(std::env::var_os("DIRENV_DIR"), project_dir) {
+            Outcome::Pass(String::from("direnv loaded the environment of this shell"))
+        } else {
+            Outcome::fail(
+                format!("direnv did not load {} in this shell", envrc.display()),
+                "Hook direnv into your shell (https://direnv.net/docs/hook.html), \
+                 then run `direnv allow` in the project.",
+            )
+        },
+    );
+    outcomes
+}
+
+/// Whether direnv loaded the `.envrc` of `project_dir`: it sets
+/// `DIRENV_DIR` to `-` followed by the directory.
+fn direnv_loaded(direnv_dir: Option<OsString>, project_dir: &Path) -> bool {
+    let direnv_dir = match direnv_dir {
+        Some(dir) => dir,
+        None => return false,
+    };
+    let direnv_dir = direnv_dir.to_string_lossy();
+    direnv_dir.starts_with('-')
+        && std::fs::canonicalize(&direnv_dir[1..]).ok() == std::fs::canonicalize(project_dir).ok()
+}
+
+/// Checks that the project’s `watched` paths fit into the inotify
+/// `limit` of the user. Other programs (and projects) need watches
+/// too, so a project may use at most half of them.
+fn check_inotify(watched: Option<usize>, limit: Option<usize>) -> Outcome {
+    match (watched, limit) {
+        (Some(watched), Some(limit)) if watched > limit / 2 => Outcome::fail(
+            format!(
+                "the project watches {} paths, but the inotify limit is {}",
+                watched, limit
+            ),
+            format!(
+                "Raise the limit, e.g. with `sysctl fs.inotify.max_user_watches={}`, \
+                 or set `\"watch_backend\": \"poll\"` in the lorri configuration.",
+                (watched * 4).max(limit * 2)
+            ),
+        ),
+        (Some(watched), Some(limit)) => Outcome::Pass(format!(
+            "the project watches {} paths, within the inotify limit of {}",
+            watched, limit
+        )),
+        (None, _) => Outcome::Pass(String::from(
+            "the project was not built yet, cannot check the inotify limit",
+        )),
+        (_, None) => Outcome::Pass(String::from("there is no inotify limit to check")),
+    }
+}
+
+/// Checks that lorri can create files in `dir`, creating it if it
+/// does not exist yet.
+fn check_writable(name: &str, dir: &Path) -> Outcome {
+    let write = std::fs::create_dir_all(dir).and_then(|()| tempfile::tempfile_in(dir));
+    match write {
+        Ok(_) => Outcome::Pass(format!("the {} {} is writable", name, dir.display())),
+        Err(e) => Outcome::fail(
+            format!("cannot write to the {} {}: {}", name, dir.display(), e),
+            format!("Make sure you own {}.", dir.display()),
+        ),
+    }
+}
+
+/// Checks the GC roots of `project`, and removes broken ones if
+/// `repair` is set.
+fn check_roots(project: &Project, roots: &Roots, repair: bool) -> Result<Vec<Outcome>, ExitError> {
+    let problems = roots
+        .verify()
+        .map_err(|e| ExitError::errmsg(format!("Cannot check the GC roots: {}", e)))?;
+
+    if problems.is_empty() {
+        return Ok(vec![Outcome::Pass(format!(
+            "the GC roots of {} are intact",
+            project.expression()
+        ))]);
+    }
+    if !repair {
+        return Ok(problems
+            .iter()
+            .map(|problem| {
+                Outcome::fail(
+                    problem.to_string(),
+                    "Run `lorri doctor --repair` to remove the broken GC roots.",
+                )
+            })
+            .collect());
+    }
+    roots
+        .repair(&problems)
+        .map_err(|e| ExitError::errmsg(format!("Cannot remove the broken GC roots: {}", e)))?;
+    Ok(vec![Outcome::Pass(String::from(
+        "removed the broken GC roots, the next successful build creates them anew",
+    ))])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_inotify, direnv_loaded, parse_nix_version, NixVersion, Outcome};
+    use std::ffi::OsString;
+
+    #[test]
+    fn nix_versions() {
+        assert_eq!(
+            parse_nix_version("nix-instantiate (Nix) 2.3.4\n"),
+            Some(NixVersion(2, 3))
+        );
+        assert_eq!(
+            parse_nix_version("nix-instantiate (Nix) 2.4pre20201102_550e11f"),
+            Some(NixVersion(2, 4))
+        );
+        assert_eq!(parse_nix_version(""), None);
+        assert!(NixVersion(1, 11) < NixVersion(2, 0));
+    }
+
+    #[test]
+    fn inotify_limits() {
+        for watches in &[Some(100), None] {
+            match check_inotify(*watches, Some(8192)) {
+                Outcome::Pass(_) => {}
+                other => panic!("expected a pass, got {:?}", other),
+            }
+        }
+        assert_eq!(
+            check_inotify(Some(5000), Some(8192)),
+            Outcome::fail(
+                "the project watches 5000 paths, but the inotify limit is 8192",
+                "Raise the limit, e.g. with `sysctl fs.inotify.max_user_watches=20000`, \
+                 or set `\"watch_backend\": \"poll\"` in the lorri configuration."
+            )
+        );
+    }
+
+    #[test]
+    fn direnv_dir() {
+        let dir = tempfile::tempdir().expect("tempfile::tempdir() failed us!");
+        let mut loaded = OsString::from("-");
+        loaded.push(dir.path());
+        assert!(direnv_loaded(Some(loaded), dir.path()));
+        assert!(!direnv_loaded(Some(OsString::from("-/")), dir.path()));
+        assert!(!direnv_loaded(None, dir.path()));
+    }
+}
@@ -0,0 +1,108 @@
+//! Print the `nix-build` call lorri makes for a project, to reproduce
+//! and debug its builds without lorri.
+
+use crate::builder::{self, LOGGED_EVALUATION_NIX};
+use crate::cli::DumpNixArgsOptions;
+use crate::env::posix_quote;
+use crate::ops::{self, ok_msg, ExitError, OpResult};
+use crate::project::Project;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+/// The variables of lorri’s environment which change what Nix does.
+/// `nix-build` inherits all of them.
+const NIX_VARIABLES: &[&str] = &["PATH", "HOME", "TMPDIR", "XDG_CACHE_HOME"];
+
+/// See the documentation for lorri::cli::Command::DumpNixArgs for
+/// more details.
+pub fn main(project: &Project, opts: DumpNixArgsOptions) -> OpResult {
+    let paths = ops::get_paths()?;
+    let config = ops::get_config(&paths)?.project(project.expression());
+    let args = builder::nix_build_args(project.expression(), config.attr());
+
+    let instrumentation = match opts.instrumentation {
+        Some(ref path) => {
+            std::fs::write(path, LOGGED_EVALUATION_NIX).map_err(|e| {
+                ExitError::errmsg(format!("Cannot write {}: {}", path.display(), e))
+            })?;
+            path.as_path()
+        }
+        None => Path::new("logged-evaluation.nix"),
+    };
+
+    let mut environment = std::env::vars()
+        .filter(|(name, _)| name.starts_with("NIX_") || NIX_VARIABLES.contains(&name.as_str()))
+        .map(|(name, value)| format!("{}={}", name, word(&value)))
+        .collect::<Vec<_>>();
+    environment.sort();
+
+    let mut out = format!(
+        "# The command lorri runs to build {}:\n{}\n\n\
+         # The environment it runs in (the variables which affect Nix):\n{}\n",
+        project.expression(),
+        command_line(&args, instrumentation),
+        environment.join("\n"),
+    );
+    match opts.instrumentation {
+        Some(ref path) => out.push_str(&format!(
+            "\n# The instrumentation is written to {}.",
+            path.display()
+        )),
+        None => out.push_str(&format!(
+            "\n# The instrumentation, {} (write it with --instrumentation):\n{}",
+            instrumentation.display(),
+            LOGGED_EVALUATION_NIX.trim_end()
+        )),
+    }
+    ok_msg(out)
+}
+
+/// `nix-build` with `args` as a shell command line, which reads the
+/// instrumented expression from the file `instrumentation` instead of
+/// repeating it.
+fn command_line(args: &[OsString], instrumentation: &Path) -> String {
+    std::iter::once(OsStr::new(builder::NIX_BUILD))
+        .chain(args.iter().map(OsString::as_os_str))
+        .map(|arg| {
+            if arg == OsStr::new(LOGGED_EVALUATION_NIX) {
+                format!("\"$(cat {})\"", word(&instrumentation.to_string_lossy()))
+            } else {
+                word(&arg.to_string_lossy())
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `arg` as a single shell word, quoted only if it has to be.
+fn word(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_owned()
+    } else {
+        posix_quote(arg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::command_line;
+    use crate::builder;
+    use crate::NixFile;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn reproducible_command_line() {
+        let nix_file = NixFile::from(PathBuf::from("/home/alice/my project/shell.nix"));
+        let args = builder::nix_build_args(&nix_file, None);
+        assert_eq!(
+            command_line(&args, Path::new("/tmp/logged-evaluation.nix")),
+            format!(
+                "nix-build -vv --expr \"$(cat /tmp/logged-evaluation.nix)\" --no-out-link \
+                 --argstr runTimeClosure {} --argstr attr '' \
+                 --argstr src '/home/alice/my project/shell.nix'",
+                crate::RUN_TIME_CLOSURE
+            )
+        );
+    }
+}
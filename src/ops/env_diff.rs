@@ -0,0 +1,148 @@
+//! Compare the environments two builds of a project produced.
+
+use crate::build_loop::BuildId;
+use crate::cli::EnvDiffOptions;
+use crate::env::EnvDiff;
+use crate::history;
+use crate::ops::{ok_msg, ExitError, OpResult};
+use crate::project::Project;
+use crate::roots::Roots;
+
+/// See the documentation for lorri::cli::Command::EnvDiff for more
+/// details.
+pub fn main(project: &Project, opts: EnvDiffOptions) -> OpResult {
+    let roots = Roots::from_project(project)
+        .map_err(|e| ExitError::errmsg(format!("Cannot open the GC roots: {}", e)))?;
+    let stored = history::env_builds(&roots).map_err(|e| {
+        ExitError::errmsg(format!(
+            "Cannot read the environments of {}: {}",
+            project.expression(),
+            e
+        ))
+    })?;
+    let (old, new) = choose(&stored, opts.old, opts.new).ok_or_else(|| {
+        ExitError::errmsg(format!(
+            "lorri keeps the environments of the last {} successful builds, \
+             which are not enough to compare. Stored: {}",
+            history::MAX_LOGS,
+            if stored.is_empty() {
+                String::from("none")
+            } else {
+                stored
+                    .iter()
+                    .map(BuildId::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            }
+        ))
+    })?;
+    let load = |build_id| {
+        history::load_env(&roots, build_id).map_err(|e| {
+            ExitError::errmsg(format!(
+                "Cannot read the environment of build {}: {}",
+                build_id, e
+            ))
+        })
+    };
+    let diff = load(old)?.diff(&load(new)?);
+    ok_msg(format(old, new, &diff))
+}
+
+/// The builds to compare: `new` (default: the latest) and `old`
+/// (default: the build before `new`), if their environments are
+/// `stored`.
+fn choose(
+    stored: &[BuildId],
+    old: Option<BuildId>,
+    new: Option<BuildId>,
+) -> Option<(BuildId, BuildId)> {
+    let new = match new {
+        Some(new) => new,
+        None => *stored.last()?,
+    };
+    let old = match old {
+        Some(old) => old,
+        None => *stored.iter().rev().find(|build| **build < new)?,
+    };
+    if stored.contains(&old) && stored.contains(&new) {
+        Some((old, new))
+    } else {
+        None
+    }
+}
+
+/// Describe `diff` between the builds `old` and `new` for humans.
+fn format(old: BuildId, new: BuildId, diff: &EnvDiff) -> String {
+    // PATH is among the changed variables if its entries changed
+    if diff.is_empty() {
+        return format!(
+            "The environments of build {} and build {} are the same.",
+            old, new
+        );
+    }
+    let mut out = format!("From build {} to build {}:", old, new);
+    for (what, names) in &[
+        ("added", &diff.added),
+        ("removed", &diff.removed),
+        ("changed", &diff.changed),
+        ("new in PATH", &diff.path_added),
+        ("gone from PATH", &diff.path_removed),
+    ] {
+        if names.is_empty() {
+            continue;
+        }
+        out.push_str(&format!("\n{}:", what));
+        for name in names.iter() {
+            out.push_str(&format!("\n  {}", name));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{choose, format};
+    use crate::build_loop::BuildId;
+    use crate::env::EnvDiff;
+
+    #[test]
+    fn latest_and_previous_by_default() {
+        let (a, b, c) = (BuildId::next(), BuildId::next(), BuildId::next());
+        let stored = [a, b, c];
+        assert_eq!(choose(&stored, None, None), Some((b, c)));
+        assert_eq!(choose(&stored, Some(a), None), Some((a, c)));
+        assert_eq!(choose(&stored, None, Some(b)), Some((a, b)));
+        assert_eq!(choose(&stored, None, Some(a)), None);
+        assert_eq!(choose(&[a], None, None), None);
+        assert_eq!(choose(&stored, Some(BuildId::next()), None), None);
+    }
+
+    #[test]
+    fn human_env_diff() {
+        let (old, new) = (BuildId::next(), BuildId::next());
+        let diff = EnvDiff {
+            added: vec![String::from("FOO")],
+            removed: vec![],
+            changed: vec![String::from("PATH")],
+            path_added: vec![],
+            path_removed: vec![String::from("/nix/store/abc-tool/bin")],
+        };
+        assert_eq!(
+            format(old, new, &diff),
+            format!(
+                "From build {} to build {}:\n\
+                 added:\n  FOO\n\
+                 changed:\n  PATH\n\
+                 gone from PATH:\n  /nix/store/abc-tool/bin",
+                old, new
+            )
+        );
+        assert_eq!(
+            format(old, new, &EnvDiff::default()),
+            format!(
+                "The environments of build {} and build {} are the same.",
+                old, new
+            )
+        );
+    }
+}
@@ -0,0 +1,50 @@
+//! Print the events of the lorri daemon’s projects as they happen,
+//! for editor plugins, status bars and the like.
+
+use crate::daemon::ProjectEvent;
+use crate::ops::{self, ok, ExitError, OpResult};
+use crate::socket::communicate::{client, SubscribeRequest, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+use crate::socket::Timeout;
+use crate::NixFile;
+
+/// See the documentation for lorri::cli::Command::Events for more
+/// details. Prints the events of `nix_file`, or of every project if
+/// `None`, one JSON object per line.
+pub fn main(nix_file: Option<NixFile>) -> OpResult {
+    // the daemon knows projects by their canonical path
+    let nix_file = nix_file.map(|nix_file| {
+        std::fs::canonicalize(nix_file.as_os_str())
+            .map(NixFile::from)
+            .unwrap_or(nix_file)
+    });
+    subscribe(nix_file, |project_event| {
+        println!(
+            "{}",
+            serde_json::to_string(&project_event).expect("events serialize to JSON")
+        )
+    })
+}
+
+/// Pass the events of `nix_file` (or of every project) to `f`, the
+/// recent ones first, until the daemon goes away.
+pub fn subscribe<F>(nix_file: Option<NixFile>, f: F) -> OpResult
+where
+    F: FnMut(ProjectEvent),
+{
+    let paths = ops::get_paths()?;
+    let socket_path = SocketPath::from(paths.daemon_socket_file());
+    let client = client::subscribe(DEFAULT_READ_TIMEOUT)
+        .connect(&socket_path)
+        .map_err(|e| ops::daemon_unreachable(e, &socket_path))?;
+    client
+        .write(&SubscribeRequest { nix_file })
+        .map_err(|e| ExitError::errmsg(format!("Cannot subscribe to the lorri daemon: {:?}", e)))?;
+    // idle projects send no events for a long time
+    client
+        .read_until_closed(&Timeout::Infinite, f)
+        .map_err(|e| {
+            ExitError::errmsg(format!("Cannot read events from the lorri daemon: {:?}", e))
+        })?;
+    ok()
+}
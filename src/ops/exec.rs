@@ -0,0 +1,64 @@
+//! Run a command in the latest environment of a project.
+
+use crate::cli::ExecOptions;
+use crate::env::ShellEnv;
+use crate::ops::{self, ErrorCode, ExitError, OpResult};
+use crate::project::Project;
+use crate::roots::Roots;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// See the documentation for lorri::cli::Command::Exec for more
+/// details.
+pub fn main(project: &Project, opts: ExecOptions) -> OpResult {
+    let roots = Roots::from_project(project)
+        .map_err(|e| ExitError::errmsg(format!("Cannot open the GC roots: {}", e)))?;
+    let shell_root = roots.current_shell_root();
+    if !shell_root.exists() {
+        return Err(ExitError::errmsg(format!(
+            "{} was not built yet, please start `lorri daemon` or run `lorri watch` first.",
+            project.expression()
+        ))
+        .with_code(ErrorCode::NotBuilt));
+    }
+    let (program, args) = opts
+        .command
+        .split_first()
+        .ok_or_else(|| ExitError::errmsg("No command given"))?;
+    let args = args.iter().map(OsString::from).collect::<Vec<_>>();
+    Err(exec_in(project, &roots, OsStr::new(program), &args))
+}
+
+/// Replace lorri with `program`, called with `args`, in the latest
+/// environment of `project`, which has to be built. Only returns if
+/// that fails.
+pub fn exec_in(project: &Project, roots: &Roots, program: &OsStr, args: &[OsString]) -> ExitError {
+    let shell_root = roots.current_shell_root();
+    let shell_env = match ShellEnv::load(&shell_root) {
+        Ok(shell_env) => shell_env,
+        Err(e) => {
+            return ExitError::errmsg(format!(
+                "Cannot read the shell environment {}: {}",
+                shell_root, e
+            ))
+        }
+    };
+
+    let config = match ops::get_paths().and_then(|paths| ops::get_config(&paths)) {
+        Ok(config) => config.project(project.expression()),
+        Err(e) => return e,
+    };
+    let host = config.passthrough().filter(std::env::vars());
+
+    let e = Command::new(program)
+        .args(args)
+        .env_clear()
+        .envs(shell_env.apply(host))
+        .exec();
+    ExitError::errmsg(format!(
+        "Failed to execute {}: {}",
+        program.to_string_lossy(),
+        e
+    ))
+}
@@ -0,0 +1,121 @@
+//! Print the latest environment of a project in various formats.
+
+use crate::cli::{ExportFormat, ExportOptions};
+use crate::env::{self, ShellEnv, Syntax};
+use crate::ops::{self, ok_msg, ErrorCode, ExitError, OpResult};
+use crate::project::Project;
+use crate::roots::Roots;
+use std::collections::{BTreeMap, HashMap};
+
+/// See the documentation for lorri::cli::Command::Export for more
+/// details.
+pub fn main(project: &Project, opts: ExportOptions) -> OpResult {
+    let roots = Roots::from_project(project)
+        .map_err(|e| ExitError::errmsg(format!("Cannot open the GC roots: {}", e)))?;
+    if !roots.env_dump_file().exists() && !roots.current_shell_root().exists() {
+        return Err(ExitError::errmsg(format!(
+            "{} was not built yet, please start `lorri daemon` or run `lorri watch` first.",
+            project.expression()
+        ))
+        .with_code(ErrorCode::NotBuilt));
+    }
+    // the dump is read without nix, so this works offline, too
+    let shell_env = ShellEnv::latest(&roots).map_err(|e| {
+        ExitError::errmsg(format!(
+            "Cannot read the shell environment of {}: {}",
+            project.expression(),
+            e
+        ))
+    })?;
+
+    let config = ops::get_config(&ops::get_paths()?)?.project(project.expression());
+    let host = config.passthrough().filter(std::env::vars());
+    let vars = changed_vars(&host, shell_env.apply(host.clone()));
+    ok_msg(format(&vars, opts.format).trim_end())
+}
+
+/// The variables of `env` which are not set to the same value in `host`.
+pub fn changed_vars(
+    host: &HashMap<String, String>,
+    env: HashMap<String, String>,
+) -> BTreeMap<String, String> {
+    env.into_iter()
+        .filter(|(name, value)| host.get(name) != Some(value))
+        .collect()
+}
+
+/// `vars` in the syntax of `format`.
+pub fn format(vars: &BTreeMap<String, String>, format: ExportFormat) -> String {
+    let changes = || {
+        vars.iter()
+            .map(|(name, value)| (name.clone(), Some(value.clone())))
+            .collect::<BTreeMap<String, Option<String>>>()
+    };
+    match format {
+        ExportFormat::Bash | ExportFormat::Zsh => env::script(&changes(), Syntax::Posix),
+        ExportFormat::Fish => env::script(&changes(), Syntax::Fish),
+        ExportFormat::Json => {
+            serde_json::to_string_pretty(vars).expect("environments serialize to JSON")
+        }
+        ExportFormat::Dotenv => vars
+            .iter()
+            .map(|(name, value)| format!("{}={}\n", name, dotenv_quote(value)))
+            .collect(),
+    }
+}
+
+/// Double-quote `value` for a `.env` file, as read by docker-compose.
+fn dotenv_quote(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changed_vars, format};
+    use crate::cli::ExportFormat;
+    use std::collections::{BTreeMap, HashMap};
+
+    #[test]
+    fn only_changed_variables() {
+        let host = [("PATH", "/usr/bin"), ("HOME", "/home/alice")]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<String, String>>();
+        let mut env = host.clone();
+        env.insert(
+            String::from("PATH"),
+            String::from("/nix/store/a/bin:/usr/bin"),
+        );
+        env.insert(String::from("FOO"), String::from("1"));
+        assert_eq!(
+            changed_vars(&host, env).keys().collect::<Vec<_>>(),
+            vec!["FOO", "PATH"]
+        );
+    }
+
+    #[test]
+    fn formats() {
+        let mut vars = BTreeMap::new();
+        vars.insert(String::from("FOO"), String::from("say \"hi\"\nbye"));
+        assert_eq!(
+            format(&vars, ExportFormat::Bash),
+            "export FOO='say \"hi\"\nbye'\n"
+        );
+        assert_eq!(
+            format(&vars, ExportFormat::Fish),
+            "set -gx FOO 'say \"hi\"\nbye'\n"
+        );
+        assert_eq!(
+            format(&vars, ExportFormat::Dotenv),
+            "FOO=\"say \\\"hi\\\"\\nbye\"\n"
+        );
+        assert_eq!(
+            format(&vars, ExportFormat::Json),
+            "{\n  \"FOO\": \"say \\\"hi\\\"\\nbye\"\n}"
+        );
+    }
+}
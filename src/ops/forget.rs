@@ -0,0 +1,69 @@
+//! Make the lorri daemon stop building a project.
+
+use crate::cli::ForgetOptions;
+use crate::ops::{self, ok_msg, ExitError, OpResult};
+use crate::socket::communicate::{client, ForgetRequest, ForgetResponse};
+use crate::socket::path::SocketPath;
+use crate::socket::Timeout;
+use crate::NixFile;
+
+/// How long to wait for the daemon’s answer. Removing the GC roots
+/// has to wait for the project’s build loop to stop.
+const FORGET_TIMEOUT: Timeout = Timeout::from_millis(15_000);
+
+/// See the documentation for lorri::cli::Command::Forget for more
+/// details.
+pub fn main(nix_file: NixFile, opts: ForgetOptions) -> OpResult {
+    // the daemon knows projects by their canonical path, but a
+    // project whose nix file is gone can be forgotten as well
+    let nix_file = std::fs::canonicalize(nix_file.as_os_str())
+        .or_else(|_| std::env::current_dir().map(|cwd| cwd.join(nix_file.as_os_str())))
+        .map(NixFile::from)
+        .map_err(|e| ExitError::errmsg(format!("Cannot find {}: {}", nix_file, e)))?;
+    let paths = ops::get_paths()?;
+    let response = request(
+        &SocketPath::from(paths.daemon_socket_file()),
+        ForgetRequest {
+            nix_file: nix_file.clone(),
+            delete_roots: opts.delete_roots,
+        },
+    )?;
+
+    if let Some(error) = response.error {
+        return Err(ExitError::errmsg(format!(
+            "Forgot {}, but could not remove its GC roots: {}",
+            nix_file, error
+        )));
+    }
+    let mut message = if response.was_watched {
+        format!("The lorri daemon no longer builds {}.", nix_file)
+    } else {
+        format!("The lorri daemon was not building {}.", nix_file)
+    };
+    if response.roots_deleted {
+        message.push_str(" Removed its GC roots, run `nix-collect-garbage` to free the space.");
+    } else if opts.delete_roots {
+        message.push_str(" It had no GC roots.");
+    }
+    ok_msg(message)
+}
+
+/// Send `request` to the lorri daemon listening on `socket_path`, and
+/// wait for its answer.
+pub fn request(
+    socket_path: &SocketPath,
+    request: ForgetRequest,
+) -> Result<ForgetResponse, ExitError> {
+    let client = client::forget(FORGET_TIMEOUT)
+        .connect(socket_path)
+        .map_err(|e| ops::daemon_unreachable(e, socket_path))?;
+    client
+        .write(&request)
+        .map_err(|e| ExitError::errmsg(format!("Cannot ask the lorri daemon: {:?}", e)))?;
+    client.read().map_err(|e| {
+        ExitError::errmsg(format!(
+            "The lorri daemon did not confirm forgetting {}: {:?}",
+            request.nix_file, e
+        ))
+    })
+}
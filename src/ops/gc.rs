@@ -0,0 +1,70 @@
+//! Remove the GC roots of projects which are gone or abandoned.
+
+use crate::cli::GcOptions;
+use crate::ops::{format_age, ok, ExitError, OpResult};
+use crate::roots::ProjectRoots;
+use std::path::Path;
+use std::time::Duration;
+
+/// See the documentation for lorri::cli::Command::Gc for more
+/// details.
+pub fn main(base_gc_root_path: &Path, opts: GcOptions) -> OpResult {
+    let projects = ProjectRoots::scan(base_gc_root_path).map_err(|e| {
+        ExitError::errmsg(format!(
+            "Cannot read the GC roots in {}: {}",
+            base_gc_root_path.display(),
+            e
+        ))
+    })?;
+    let older_than = opts
+        .older_than
+        .map(|days| Duration::from_secs(days * 24 * 60 * 60));
+
+    let mut removed = 0;
+    for project in projects {
+        let abandoned = older_than.map_or(false, |age| project.not_built_for(age));
+
+        println!(
+            "{} (last built {}{})",
+            project,
+            project
+                .age()
+                .map(format_age)
+                .unwrap_or_else(|| String::from("never")),
+            if project.is_stale() {
+                ", nix file is gone"
+            } else {
+                ""
+            }
+        );
+        for (name, root) in &project.roots {
+            match root.store_path() {
+                Some(store_path) => println!("  {} -> {}", name, store_path.display()),
+                None => println!("  {}", name),
+            }
+        }
+
+        if !(project.is_stale() || abandoned) {
+            continue;
+        }
+        if opts.dry_run {
+            println!("  would be removed");
+            continue;
+        }
+        match project.remove() {
+            Ok(()) => {
+                println!("  removed");
+                removed += 1;
+            }
+            Err(e) => eprintln!("  could not be removed: {}", e),
+        }
+    }
+
+    if !opts.dry_run {
+        println!(
+            "Removed the roots of {} project(s). Run `nix-collect-garbage` to free the space.",
+            removed
+        );
+    }
+    ok()
+}
@@ -1,17 +1,239 @@
 //! The info callable is for printing
 
-use crate::ops::{ok, OpResult};
+use crate::build_loop::WatchSet;
+use crate::cli::InfoOptions;
+use crate::duration;
+use crate::ops::status::format_duration;
+use crate::ops::{self, ok, ok_msg, ExitError, OpResult};
 use crate::project;
+use crate::roots::{RootPath, Roots};
+use crate::socket::communicate::{client, BuildState, ProjectStatus, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
 use crate::VERSION_BUILD_REV;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// What `lorri info` knows about a project, see `--json`.
+#[derive(Serialize, Debug)]
+struct Info {
+    lorri_version: usize,
+    /// The nix file as lorri knows the project by.
+    nix_file: PathBuf,
+    /// `nix_file` with all symlinks resolved, if it exists.
+    resolved_nix_file: Option<PathBuf>,
+    /// See `Project::hash`.
+    project_id: String,
+    gc_roots: Vec<GcRoot>,
+    /// `None` if the project was never built.
+    watched_files: Option<usize>,
+    /// `None` if the lorri daemon doesn’t build the project (or isn’t
+    /// running).
+    last_build: Option<LastBuild>,
+}
+
+/// A GC root of the project.
+#[derive(Serialize, Debug)]
+struct GcRoot {
+    name: String,
+    path: PathBuf,
+    /// The store path the root links to.
+    target: Option<PathBuf>,
+    /// Whether `target` still exists.
+    resolves: bool,
+}
+
+/// What the daemon says about the project’s builds.
+#[derive(Serialize, Debug)]
+struct LastBuild {
+    state: BuildState,
+    /// When the last build finished, in seconds since the epoch.
+    finished: Option<u64>,
+    /// How long the last build took, in seconds.
+    duration: Option<f64>,
+}
 
 /// See the documentation for lorri::cli::Command::Info for more
 /// details.
-pub fn main(project: &project::Project) -> OpResult {
-    println!("lorri version: {}", VERSION_BUILD_REV);
-    println!("Lorri Project Configuration");
-    println!();
-
-    println!("expression: {}", project.expression());
+pub fn main(project: &project::Project, opts: InfoOptions) -> OpResult {
+    let roots = Roots::from_project(project)
+        .map_err(|e| ExitError::errmsg(format!("Cannot open the GC roots: {}", e)))?;
+    let nix_file = project.expression();
+    let root = |name: String, root: RootPath| GcRoot {
+        name,
+        path: root.as_path().to_owned(),
+        target: root.store_path(),
+        resolves: root.exists(),
+    };
+    let mut gc_roots = vec![root(String::from("shell_gc_root"), roots.shell_gc_root())];
+    // the directory only exists after the first build
+    gc_roots.extend(
+        roots
+            .all()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, path)| root(name, path)),
+    );
+    let watch_set = WatchSet::load(&roots).ok();
+    let resolved_nix_file = std::fs::canonicalize(nix_file.as_os_str()).ok();
+    // the daemon knows projects by their canonical path
+    let daemon_nix_file = resolved_nix_file
+        .as_ref()
+        .map_or(nix_file.as_os_str(), |resolved| resolved.as_os_str());
+    let info = Info {
+        lorri_version: VERSION_BUILD_REV,
+        nix_file: PathBuf::from(nix_file.as_os_str()),
+        resolved_nix_file: resolved_nix_file.clone(),
+        project_id: project.hash(),
+        gc_roots,
+        watched_files: watch_set.as_ref().map(|w| w.watched.len()),
+        last_build: daemon_status(ops::get_paths()?.daemon_socket_file(), daemon_nix_file).map(
+            |status| LastBuild {
+                state: status.state,
+                finished: status.last_build,
+                duration: status.last_build_duration,
+            },
+        ),
+    };
 
+    if opts.json {
+        return ok_msg(serde_json::to_string_pretty(&info).expect("info serializes to JSON"));
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    print!("{}", format(&info, now));
+    if let Some(watch_set) = watch_set {
+        println!();
+        println!(
+            "watched inputs ({} paths with a watch):",
+            watch_set.watched.len()
+        );
+        for (reduced, inputs) in watch_set.inputs {
+            println!("  {}", reduced.display());
+            for input in inputs.iter().filter(|input| **input != reduced) {
+                println!("    {}", input.display());
+            }
+        }
+    }
     ok()
 }
+
+/// What the lorri daemon listening on `socket` says about `nix_file`,
+/// if it is running and builds the project.
+fn daemon_status(socket: &Path, nix_file: &OsStr) -> Option<ProjectStatus> {
+    let statuses = client::status(DEFAULT_READ_TIMEOUT)
+        .connect(&SocketPath::from(socket))
+        .map_err(|e| debug!("Cannot ask the lorri daemon: {}", e))
+        .ok()?
+        .read()
+        .map_err(|e| debug!("Cannot read the status from the lorri daemon: {:?}", e))
+        .ok()?;
+    statuses
+        .into_iter()
+        .find(|status| status.nix_file.as_os_str() == nix_file)
+}
+
+/// Describe `info` for humans; `now` is in seconds since the epoch.
+fn format(info: &Info, now: u64) -> String {
+    let mut out = format!("lorri version: {}\n", info.lorri_version);
+    out.push_str(&format!("nix file: {}\n", info.nix_file.display()));
+    match info.resolved_nix_file {
+        Some(ref resolved) if *resolved != info.nix_file => {
+            out.push_str(&format!("  resolves to: {}\n", resolved.display()))
+        }
+        Some(_) => {}
+        None => out.push_str("  (does not exist)\n"),
+    }
+    out.push_str(&format!("project id: {}\n", info.project_id));
+    out.push_str("GC roots:\n");
+    for root in &info.gc_roots {
+        let target = match (&root.target, root.resolves) {
+            (Some(target), true) => format!("-> {}", target.display()),
+            (Some(target), false) => format!("-> {} (garbage collected)", target.display()),
+            (None, _) => String::from("(not built yet)"),
+        };
+        out.push_str(&format!(
+            "  {}: {} {}\n",
+            root.name,
+            root.path.display(),
+            target
+        ));
+    }
+    match info.watched_files {
+        Some(count) => out.push_str(&format!("watched files: {}\n", count)),
+        None => out.push_str("watched files: unknown, the project was not built yet\n"),
+    }
+    let last_build = match info.last_build {
+        Some(ref last_build) => {
+            let state = match last_build.state {
+                BuildState::Pending => "pending",
+                BuildState::Building => "building",
+                BuildState::Success => "success",
+                BuildState::Failure => "failure",
+            };
+            match (last_build.finished, last_build.duration) {
+                (Some(finished), Some(took)) => format!(
+                    "{}, finished {} ago, took {}",
+                    state,
+                    format_duration(Duration::from_secs(now.saturating_sub(finished))),
+                    format_duration(duration::from_secs_f64(took))
+                ),
+                _ => String::from(state),
+            }
+        }
+        None => String::from("unknown, the lorri daemon does not build the project"),
+    };
+    out.push_str(&format!("last build: {}\n", last_build));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format, GcRoot, Info, LastBuild};
+    use crate::socket::communicate::BuildState;
+    use std::path::PathBuf;
+
+    #[test]
+    fn human_info() {
+        let info = Info {
+            lorri_version: 42,
+            nix_file: PathBuf::from("/home/alice/foo/shell.nix"),
+            resolved_nix_file: Some(PathBuf::from("/data/foo/shell.nix")),
+            project_id: String::from("abc"),
+            gc_roots: vec![
+                GcRoot {
+                    name: String::from("shell_gc_root"),
+                    path: PathBuf::from("/gc_roots/abc/shell_gc_root"),
+                    target: Some(PathBuf::from("/nix/store/xyz-env")),
+                    resolves: true,
+                },
+                GcRoot {
+                    name: String::from("build-0"),
+                    path: PathBuf::from("/gc_roots/abc/gc_root/build-0"),
+                    target: Some(PathBuf::from("/nix/store/old-env")),
+                    resolves: false,
+                },
+            ],
+            watched_files: Some(3),
+            last_build: Some(LastBuild {
+                state: BuildState::Failure,
+                finished: Some(1000),
+                duration: Some(12.0),
+            }),
+        };
+        assert_eq!(
+            format(&info, 1090),
+            "lorri version: 42\n\
+             nix file: /home/alice/foo/shell.nix\n  \
+             resolves to: /data/foo/shell.nix\n\
+             project id: abc\n\
+             GC roots:\n  \
+             shell_gc_root: /gc_roots/abc/shell_gc_root -> /nix/store/xyz-env\n  \
+             build-0: /gc_roots/abc/gc_root/build-0 -> /nix/store/old-env (garbage collected)\n\
+             watched files: 3\n\
+             last build: failure, finished 1m 30s ago, took 12s\n"
+        );
+    }
+}
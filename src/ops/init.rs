@@ -1,19 +1,26 @@
 //! Bootstrap a new lorri project
 
+use crate::cli::InitOptions;
+use crate::env::posix_quote;
+use crate::logging;
 use crate::ops::{ok, ok_msg, ExitError, OpResult};
 use std::fs::File;
 use std::io;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 fn create_if_missing(path: &Path, contents: &str, msg: &str) -> Result<(), io::Error> {
     if path.exists() {
-        println!("- {} {}", msg, path.display());
+        if logging::show_warnings() {
+            println!("- {} {}", msg, path.display());
+        }
         Ok(())
     } else {
         let mut f = File::create(path)?;
         f.write_all(contents.as_bytes())?;
-        println!("- Writing {}", path.display());
+        if logging::show_progress() {
+            println!("- Writing {}", path.display());
+        }
         Ok(())
     }
 }
@@ -25,20 +32,107 @@ fn to_op(e: Result<(), io::Error>) -> OpResult {
     }
 }
 
+/// `default_shell` with `<nixpkgs>` replaced by the nixpkgs of
+/// `revision`.
+fn pinned_shell(default_shell: &str, revision: &str) -> Result<String, ExitError> {
+    let valid = !revision.is_empty()
+        && revision
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_');
+    if !valid {
+        return Err(ExitError::errmsg(format!(
+            "{} is not a nixpkgs revision or branch",
+            revision
+        )));
+    }
+    Ok(default_shell.replace(
+        "import <nixpkgs> {}",
+        &format!(
+            "import (builtins.fetchTarball \"https://github.com/NixOS/nixpkgs/archive/{}.tar.gz\") {{}}",
+            revision
+        ),
+    ))
+}
+
+/// `default_envrc`, with `shell_file_arg` (the `--shell-file` option,
+/// if the project’s nix file is not `shell.nix`) passed to lorri.
+fn envrc_for(default_envrc: &str, shell_file_arg: &str) -> String {
+    default_envrc.replace("lorri direnv", &format!("lorri direnv{}", shell_file_arg))
+}
+
 /// See the documentation for lorri::cli::Command::Init for
-/// more details
-pub fn main(default_shell: &str, default_envrc: &str) -> OpResult {
+/// more details. The nix file is written to `shell_file`, if set.
+pub fn main(
+    default_shell: &str,
+    default_envrc: &str,
+    shell_file: Option<PathBuf>,
+    opts: InitOptions,
+) -> OpResult {
+    let shell = match opts.pin {
+        Some(revision) => pinned_shell(default_shell, &revision)?,
+        None => default_shell.to_string(),
+    };
+    let nix_file = shell_file
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("./shell.nix"));
+    if let Some(dir) = nix_file.parent() {
+        to_op(std::fs::create_dir_all(dir))?;
+    }
     to_op(create_if_missing(
-        Path::new("./shell.nix"),
-        default_shell,
-        "shell.nix exists, skipping. Make sure it is of a form that works with nix-shell.",
+        &nix_file,
+        &shell,
+        &format!(
+            "{} exists, skipping. Make sure it is of a form that works with nix-shell.",
+            nix_file.display()
+        ),
     ))?;
 
+    let shell_file_arg = shell_file
+        .as_ref()
+        .map(|file| format!(" --shell-file {}", posix_quote(&file.to_string_lossy())))
+        .unwrap_or_default();
+    let envrc = envrc_for(default_envrc, &shell_file_arg);
     to_op(create_if_missing(
-        Path::new("./.envrc"), 
-        default_envrc,
-        ".envrc exists, skipping. Please add 'eval \"$(lorri direnv)\" to it to set up lorri support.",
+        Path::new("./.envrc"),
+        &envrc,
+        &format!(
+            ".envrc exists, skipping. Please add '{}' to it to set up lorri support.",
+            envrc
+        ),
     ))?;
 
-    ok_msg(String::from("\nSetup done."))
+    ok_msg(format!(
+        "\nSetup done. Next steps:\n\
+         - run `lorri allow{}` to let lorri build {}\n\
+         - run `direnv allow` to let direnv load the environment\n\
+         - start `lorri daemon`, or run `lorri watch{}` in this directory",
+        shell_file_arg,
+        nix_file.display(),
+        shell_file_arg
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{envrc_for, pinned_shell};
+
+    #[test]
+    fn pinning_nixpkgs() {
+        let shell = pinned_shell(include_str!("../trivial-shell.nix"), "nixos-19.09").unwrap();
+        assert!(shell.contains(
+            "import (builtins.fetchTarball \"https://github.com/NixOS/nixpkgs/archive/nixos-19.09.tar.gz\") {}"
+        ));
+        assert!(!shell.contains("<nixpkgs>"));
+        assert!(pinned_shell("", "\"; rm -rf /").is_err());
+    }
+
+    #[test]
+    fn envrc_with_shell_file() {
+        let envrc = "eval \"$(lorri direnv)\"";
+        assert_eq!(envrc_for(envrc, ""), envrc);
+        assert_eq!(
+            envrc_for(envrc, " --shell-file 'nix/shell.nix'"),
+            "eval \"$(lorri direnv --shell-file 'nix/shell.nix')\""
+        );
+    }
 }
@@ -0,0 +1,106 @@
+//! Show the build output the lorri daemon kept for a project, or the
+//! stored output of one of its builds.
+
+use crate::build_loop::BuildId;
+use crate::cli::LogsOptions;
+use crate::history;
+use crate::logging;
+use crate::ops::{self, ok, ExitError, OpResult};
+use crate::project::Project;
+use crate::roots::Roots;
+use crate::socket::communicate::{client, LogLine, LogsRequest, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+use crate::socket::Timeout;
+use crate::NixFile;
+
+/// See the documentation for lorri::cli::Command::Logs for more
+/// details. Shows the logs of `nix_file`, or of every project if `None`.
+pub fn main(nix_file: Option<NixFile>, opts: LogsOptions) -> OpResult {
+    let paths = ops::get_paths()?;
+    let socket_path = SocketPath::from(paths.daemon_socket_file());
+    let prefixed = nix_file.is_none();
+    let print = |lines: Vec<LogLine>| {
+        for line in lines {
+            if prefixed {
+                println!("{}: {}", line.nix_file, line.line);
+            } else {
+                println!("{}", line.line);
+            }
+        }
+    };
+
+    if let (Some(nix_file), Some(build_id)) = (&nix_file, opts.build) {
+        return print_stored(&Project::new(nix_file, paths.gc_root_dir()), Some(build_id));
+    }
+
+    let client = match (
+        client::logs(DEFAULT_READ_TIMEOUT).connect(&socket_path),
+        &nix_file,
+    ) {
+        (Ok(client), _) => client,
+        // without the daemon, the stored output of the last build is
+        // the best we have
+        (Err(_), Some(nix_file)) if !opts.follow => {
+            if logging::show_progress() {
+                eprintln!("The lorri daemon is not running, showing the output of the last build.");
+            }
+            return print_stored(&Project::new(nix_file, paths.gc_root_dir()), None);
+        }
+        (Err(e), _) => return Err(ops::daemon_unreachable(e, &socket_path)),
+    };
+    let read_error = |e| {
+        ExitError::errmsg(format!(
+            "Cannot read the logs from the lorri daemon: {:?}",
+            e
+        ))
+    };
+    client
+        .write(&LogsRequest {
+            // the daemon knows projects by their canonical path
+            nix_file: nix_file.map(|nix_file| {
+                std::fs::canonicalize(nix_file.as_os_str())
+                    .map(NixFile::from)
+                    .unwrap_or(nix_file)
+            }),
+            follow: opts.follow,
+        })
+        .map_err(|e| ExitError::errmsg(format!("Cannot ask the lorri daemon for logs: {:?}", e)))?;
+    print(client.read().map_err(read_error)?);
+    if opts.follow {
+        // new lines arrive whenever a build prints something
+        client
+            .read_until_closed(&Timeout::Infinite, print)
+            .map_err(read_error)?;
+    }
+    ok()
+}
+
+/// Print the stored output of `build_id` of `project`, or of its
+/// latest build if `None`.
+fn print_stored(project: &Project, build_id: Option<BuildId>) -> OpResult {
+    let roots = Roots::from_project(project)
+        .map_err(|e| ExitError::errmsg(format!("Cannot open the GC roots: {}", e)))?;
+    let build_id = match build_id {
+        Some(build_id) => build_id,
+        None => history::logged_builds(&roots)
+            .ok()
+            .and_then(|builds| builds.last().cloned())
+            .ok_or_else(|| {
+                ExitError::errmsg(format!(
+                    "lorri has no output of builds of {}.",
+                    project.expression()
+                ))
+            })?,
+    };
+    let log = history::read_log(&roots, build_id).map_err(|e| {
+        ExitError::errmsg(format!(
+            "Cannot read the output of build {}: {}. lorri keeps the output of the last {} builds, \
+             see `lorri builds`.",
+            build_id,
+            e,
+            history::MAX_LOGS
+        ))
+    })?;
+    print!("{}", log);
+    ok()
+}
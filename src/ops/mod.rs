@@ -1,21 +1,101 @@
 //! Ops are command-line callables.
 
+pub mod allow;
+pub mod attrs;
 pub mod build;
+pub mod builds;
+pub mod clean;
+pub mod completions;
 pub mod daemon;
+pub mod deny;
 pub mod direnv;
+pub mod direnv_install;
+pub mod doctor;
+pub mod dump_nix_args;
+pub mod env_diff;
+pub mod events;
+pub mod exec;
+pub mod export;
+pub mod forget;
+pub mod gc;
 pub mod info;
 pub mod init;
+pub mod logs;
 pub mod ping;
+pub mod roots;
+pub mod run;
+pub mod self_test;
 pub mod shell;
+pub mod status;
 pub mod upgrade;
 pub mod watch;
 
+use crate::socket::communicate::client::InitError;
+use crate::socket::path::SocketPath;
+use std::time::Duration;
+
 /// Set up necessary directories or fail.
 pub fn get_paths() -> Result<::constants::Paths, ExitError> {
     ::constants::Paths::initialize()
         .map_err(|e| ExitError::errmsg(format!("Cannot initialize the lorri paths: {}", e)))
 }
 
+/// Load the user configuration or fail.
+pub fn get_config(paths: &::constants::Paths) -> Result<::config::Config, ExitError> {
+    ::config::Config::load(paths.config_file()).map_err(|e| ExitError::errmsg(e.to_string()))
+}
+
+/// How long ago something happened, in human terms.
+fn format_age(age: Duration) -> String {
+    let days = age.as_secs() / (24 * 60 * 60);
+    match days {
+        0 => String::from("today"),
+        1 => String::from("yesterday"),
+        days => format!("{} days ago", days),
+    }
+}
+
+/// The lorri daemon listening on `socket_path` cannot be asked, see
+/// `InitError`.
+pub fn daemon_unreachable(e: InitError, socket_path: &SocketPath) -> ExitError {
+    let code = match e {
+        InitError::SocketConnect(_) => ErrorCode::DaemonNotRunning,
+        _ => ErrorCode::DaemonError,
+    };
+    ExitError::errmsg(format!("{} (socket: {})", e, socket_path.display())).with_code(code)
+}
+
+/// What went wrong, for tools wrapping lorri (see
+/// `--error-format json`). The names are stable, new ones may be added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    /// Anything without a more specific code.
+    Error,
+    /// There is no nix file for the project.
+    NoProject,
+    /// The user did not `lorri allow` the project (anymore).
+    NotAllowed,
+    /// Nix is not installed, or not in `PATH`.
+    NixMissing,
+    /// Evaluating or building the project’s expression failed.
+    EvaluationFailed,
+    /// The project was never built, so there is no environment.
+    NotBuilt,
+    /// The lorri daemon is not running.
+    DaemonNotRunning,
+    /// The lorri daemon runs, but did not answer as expected.
+    DaemonError,
+    /// A command lorri ran for the user failed, its exit code is
+    /// passed on.
+    CommandFailed,
+    /// The command took longer than the user allowed, e.g. with
+    /// `lorri build --timeout`.
+    TimedOut,
+    /// The command line is invalid, e.g. an unknown option.
+    InvalidArguments,
+}
+
 /// Non-zero exit status from an op
 #[derive(Debug)]
 pub struct ExitError {
@@ -24,6 +104,9 @@ pub struct ExitError {
 
     /// Final dying words
     message: String,
+
+    /// What went wrong, for tools
+    code: ErrorCode,
 }
 
 /// Final result from a CLI operation
@@ -54,6 +137,22 @@ impl ExitError {
         ExitError::err(1, message.into())
     }
 
+    /// Exit with `exitcode`, e.g. to pass on the failure of a command
+    /// the user asked us to run.
+    ///
+    /// Note: panics if exitcode is zero.
+    pub fn exit<T>(exitcode: i32, message: T) -> ExitError
+    where
+        T: Into<String>,
+    {
+        ExitError::err(exitcode, message).with_code(ErrorCode::CommandFailed)
+    }
+
+    /// Tell tools more precisely what went wrong than `ErrorCode::Error`.
+    pub fn with_code(self, code: ErrorCode) -> ExitError {
+        ExitError { code, ..self }
+    }
+
     /// Helpers to create exit results
     ///
     /// Note: err panics if exitcode is zero.
@@ -66,6 +165,7 @@ impl ExitError {
         ExitError {
             exitcode,
             message: message.into(),
+            code: ErrorCode::Error,
         }
     }
 
@@ -78,11 +178,27 @@ impl ExitError {
     pub fn message(&self) -> &str {
         &self.message
     }
+
+    /// What went wrong, see `ErrorCode`
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+
+    /// The error as a JSON object, for `--error-format json`:
+    /// `{"code": "daemon-not-running", "message": "…", "exit_code": 1}`
+    pub fn to_json(&self) -> String {
+        serde_json::json!({
+            "code": self.code,
+            "message": self.message,
+            "exit_code": self.exitcode,
+        })
+        .to_string()
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::ExitError;
+    use super::{ErrorCode, ExitError};
 
     #[test]
     #[should_panic]
@@ -96,7 +212,18 @@ mod tests {
             e => {
                 assert_eq!(e.exitcode(), 1);
                 assert_eq!(e.message(), "bogus");
+                assert_eq!(e.code(), ErrorCode::Error);
             }
         }
     }
+
+    #[test]
+    fn json_errors() {
+        let e = ExitError::errmsg("Cannot connect").with_code(ErrorCode::DaemonNotRunning);
+        assert_eq!(
+            e.to_json(),
+            r#"{"code":"daemon-not-running","exit_code":1,"message":"Cannot connect"}"#
+        );
+        assert_eq!(ExitError::exit(3, "").code(), ErrorCode::CommandFailed);
+    }
 }
@@ -1,22 +1,38 @@
-//! Run a BuildLoop for `shell.nix`, watching for input file changes.
+//! Tell the lorri daemon to build a project.
 //! Can be used together with `direnv`.
-use crate::ops::{ok, OpResult};
+use crate::ops::{self, ok, ExitError, OpResult};
 use crate::NixFile;
 
 use crate::socket::communicate::client;
-use crate::socket::communicate::{Ping, DEFAULT_READ_TIMEOUT};
+use crate::socket::communicate::Ping;
+use crate::socket::path::SocketPath;
+use crate::socket::Timeout;
 
-/// See the documentation for lorri::cli::Command::Shell for more
+/// How long to wait for the daemon. Pings are sent from `.envrc`,
+/// so a hung daemon must not hold up the user’s prompt.
+pub const PING_TIMEOUT: Timeout = Timeout::from_millis(200);
+
+/// See the documentation for lorri::cli::Command::Ping for more
 /// details.
 pub fn main(nix_file: NixFile) -> OpResult {
-    // TODO: set up socket path, make it settable by the user
-    client::ping(DEFAULT_READ_TIMEOUT)
-        // TODO
-        .connect(&::socket::path::SocketPath::from(
-            ::ops::get_paths()?.daemon_socket_file(),
-        ))
-        .unwrap()
+    let nix_file = std::fs::canonicalize(nix_file.as_os_str())
+        .map(NixFile::from)
+        .map_err(|e| ExitError::errmsg(format!("Cannot find {}: {}", nix_file, e)))?;
+    ping(
+        &SocketPath::from(::ops::get_paths()?.daemon_socket_file()),
+        nix_file,
+    )?;
+    ok()
+}
+
+/// Ask the daemon listening on `socket_path` to watch and build
+/// `nix_file`. The daemon does not answer, so this returns as soon
+/// as the message is sent.
+pub fn ping(socket_path: &SocketPath, nix_file: NixFile) -> OpResult {
+    client::ping(PING_TIMEOUT)
+        .connect(socket_path)
+        .map_err(|e| ops::daemon_unreachable(e, socket_path))?
         .write(&Ping { nix_file })
-        .unwrap();
+        .map_err(|e| ExitError::errmsg(format!("Cannot ping the lorri daemon: {:?}", e)))?;
     ok()
 }
@@ -0,0 +1,12 @@
+# passed to fish as --init-command
+
+# lorri shell bumps the generation whenever a build finished
+function __lorri_prompt --on-event fish_prompt
+    test -f "$LORRI_RELOAD_DIR/generation"; or return
+    set -l generation (cat "$LORRI_RELOAD_DIR/generation")
+    if test "$generation" != "$LORRI_GENERATION"
+        set -gx LORRI_GENERATION $generation
+        source "$LORRI_RELOAD_DIR/env"
+        cat "$LORRI_RELOAD_DIR/message"; echo
+    end
+end
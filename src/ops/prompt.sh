@@ -3,11 +3,21 @@
 # loaded by bash, never executed by bash
 if [ "${LORRI_PROMPT_INIT:-0}" -eq 0 ]; then
     LORRI_PROMPT_INIT=1
-    LORRI_PREV_ROOT=$(readlink "$LORRI_SHELL_ROOT")
-    PS1="(lorri) $PS1"
+    # the state of the latest build, see `$LORRI_STATUS_FILE`
+    lorri_status() {
+        case "$(cat "$LORRI_STATUS_FILE" 2>/dev/null)" in
+            building) printf ' …' ;;
+            ok) printf ' ✓' ;;
+            failed) printf ' ✗' ;;
+        esac
+    }
+    PS1="(lorri\$(lorri_status)) $PS1"
 fi
 
-if [ "$(readlink "$LORRI_SHELL_ROOT")" != "$LORRI_PREV_ROOT" ]; then
-    echo "lorri: Reloading"
-    exec nix-shell "$LORRI_SHELL_ROOT"
+# lorri shell bumps the generation whenever a build finished
+if [ -f "$LORRI_RELOAD_DIR/generation" ] \
+    && [ "$(cat "$LORRI_RELOAD_DIR/generation")" != "$LORRI_GENERATION" ]; then
+    LORRI_GENERATION=$(cat "$LORRI_RELOAD_DIR/generation")
+    . "$LORRI_RELOAD_DIR/env"
+    cat "$LORRI_RELOAD_DIR/message"; echo
 fi
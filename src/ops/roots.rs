@@ -0,0 +1,105 @@
+//! List the GC roots of all projects and the disk space they use.
+
+use crate::nix;
+use crate::ops::{format_age, ok, ExitError, OpResult};
+use crate::roots::ProjectRoots;
+use std::path::{Path, PathBuf};
+
+/// See the documentation for lorri::cli::Command::Roots for more
+/// details.
+pub fn main(base_gc_root_path: &Path) -> OpResult {
+    let projects = ProjectRoots::scan(base_gc_root_path).map_err(|e| {
+        ExitError::errmsg(format!(
+            "Cannot read the GC roots in {}: {}",
+            base_gc_root_path.display(),
+            e
+        ))
+    })?;
+
+    let mut all_targets = vec![];
+    for project in projects {
+        println!(
+            "{}{}",
+            project,
+            if project.is_stale() {
+                " (nix file is gone)"
+            } else {
+                ""
+            }
+        );
+        println!(
+            "  last built: {}",
+            project
+                .age()
+                .map(format_age)
+                .unwrap_or_else(|| String::from("never"))
+        );
+
+        let mut targets = vec![];
+        for (name, root) in &project.roots {
+            // roots pointing to garbage collected paths pin nothing
+            match root.store_path().filter(|_| root.exists()) {
+                Some(target) => {
+                    println!(
+                        "  {} -> {} ({})",
+                        name,
+                        target.display(),
+                        size(std::slice::from_ref(&target))
+                    );
+                    targets.push(target);
+                }
+                _ => println!("  {} (dangling)", name),
+            }
+        }
+        println!("  closure size: {}", size(&targets));
+        all_targets.extend(targets);
+    }
+
+    all_targets.sort();
+    all_targets.dedup();
+    println!();
+    println!("total closure size: {}", size(&all_targets));
+    ok()
+}
+
+/// The closure size of `paths`, human-readable.
+fn size(paths: &[PathBuf]) -> String {
+    match nix::closure_size(paths) {
+        Ok(bytes) => format_size(bytes),
+        Err(e) => {
+            debug!(
+                "could not determine the closure size of {:?}: {:?}",
+                paths, e
+            );
+            String::from("unknown size")
+        }
+    }
+}
+
+/// `bytes` in the largest binary unit which keeps the number above 1.
+fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, units[0])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format_size;
+
+    #[test]
+    fn sizes_are_human_readable() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
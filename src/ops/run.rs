@@ -0,0 +1,147 @@
+//! Run a script in the environment of the project it belongs to, as
+//! the interpreter of a shebang: `#!/usr/bin/env lorri-run`.
+
+use crate::build_loop::{BuildError, BuildLoop, UnrecoverableErrors};
+use crate::cli::RunOptions;
+use crate::locate_file::{self, FileLocationError};
+use crate::logging;
+use crate::ops::{self, exec, ErrorCode, ExitError, OpResult};
+use crate::project::Project;
+use crate::roots::Roots;
+use crate::trust::Trust;
+use crate::NixFile;
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// Runs scripts which don't name their interpreter, like `nix-shell`.
+const DEFAULT_INTERPRETER: &str = "bash";
+
+/// How many of the last log lines of a failed build are shown.
+const LOG_EXCERPT_LINES: usize = 20;
+
+/// See the documentation for lorri::cli::Command::Run for more
+/// details. `shell_file` is the name of the nix file to look for.
+pub fn main(gc_root_dir: &Path, shell_file: Option<PathBuf>, opts: RunOptions) -> OpResult {
+    let contents = std::fs::read_to_string(&opts.script)
+        .map_err(|e| ExitError::errmsg(format!("Cannot read {}: {}", opts.script.display(), e)))?;
+    let shell_file = shell_file.unwrap_or_else(|| PathBuf::from("shell.nix"));
+    let script_dir = match opts.script.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let nix_file = locate_file::upwards(script_dir, &shell_file)
+        .map(NixFile::from)
+        .map_err(|e| {
+            let message = match e {
+                FileLocationError::NotFound => format!(
+                    "There is no `{}` in {} or its parent directories.",
+                    shell_file.display(),
+                    script_dir.display()
+                ),
+                FileLocationError::Io(e) => {
+                    format!("Cannot search {}: {}", script_dir.display(), e)
+                }
+            };
+            ExitError::errmsg(message).with_code(ErrorCode::NoProject)
+        })?;
+    let project = Project::new(&nix_file, gc_root_dir);
+    let roots = Roots::from_project(&project)
+        .map_err(|e| ExitError::errmsg(format!("Cannot open the GC roots: {}", e)))?;
+    if !roots.current_shell_root().exists() {
+        build(&project, &roots)?;
+    }
+
+    let interpreter = interpreter(&contents);
+    let args = interpreter[1..]
+        .iter()
+        .map(OsString::from)
+        .chain(std::iter::once(opts.script.clone().into_os_string()))
+        .chain(opts.args.iter().map(OsString::from))
+        .collect::<Vec<_>>();
+    Err(exec::exec_in(
+        &project,
+        &roots,
+        OsStr::new(&interpreter[0]),
+        &args,
+    ))
+}
+
+/// Build `project` once, for a script run before anything built it.
+fn build(project: &Project, roots: &Roots) -> Result<(), ExitError> {
+    let paths = ops::get_paths()?;
+    Trust::new(paths.allow_dir())
+        .check(project.expression())
+        .map_err(|e| ExitError::errmsg(e.to_string()).with_code(ErrorCode::NotAllowed))?;
+    if logging::show_progress() {
+        eprintln!(
+            "lorri: {} was not built yet, building it...",
+            project.expression()
+        );
+    }
+    let config = ops::get_config(&paths)?.project(project.expression());
+    match BuildLoop::new(project.expression().clone(), roots.clone(), config).once() {
+        Ok(_) => Ok(()),
+        Err(BuildError::Recoverable(failure)) => {
+            let skip = failure.log_lines.len().saturating_sub(LOG_EXCERPT_LINES);
+            Err(ExitError::errmsg(format!(
+                "Building {} failed:\n{}",
+                project.expression(),
+                failure.log_lines[skip..].join("\n")
+            ))
+            .with_code(ErrorCode::EvaluationFailed))
+        }
+        Err(BuildError::Unrecoverable(e)) => {
+            let code = match e {
+                UnrecoverableErrors::Build(ref e) if e.nix_missing() => ErrorCode::NixMissing,
+                _ => ErrorCode::Error,
+            };
+            Err(
+                ExitError::errmsg(format!("Cannot build {}: {:?}", project.expression(), e))
+                    .with_code(code),
+            )
+        }
+    }
+}
+
+/// The interpreter (and its arguments) of the script `contents`,
+/// from a `#!lorri-run python3 -u` line among the `#!` lines it
+/// starts with, like `#! nix-shell -i`.
+fn interpreter(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .skip(1)
+        .take_while(|line| line.starts_with("#!"))
+        .map(|line| line[2..].trim_start())
+        .filter(|line| line.starts_with("lorri-run"))
+        .map(|line| {
+            line["lorri-run".len()..]
+                .split_whitespace()
+                .map(String::from)
+                .collect::<Vec<_>>()
+        })
+        .filter(|words| !words.is_empty())
+        .last()
+        .unwrap_or_else(|| vec![String::from(DEFAULT_INTERPRETER)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::interpreter;
+
+    #[test]
+    fn interpreter_lines() {
+        assert_eq!(
+            interpreter("#!/usr/bin/env lorri-run\n#!lorri-run python3 -u\nprint(1)\n"),
+            vec!["python3", "-u"]
+        );
+        assert_eq!(
+            interpreter("#!/usr/bin/env lorri-run\n#! lorri-run  node\n"),
+            vec!["node"]
+        );
+        assert_eq!(
+            interpreter("#!/usr/bin/env lorri-run\necho hi\n#!lorri-run python3\n"),
+            vec!["bash"]
+        );
+        assert_eq!(interpreter(""), vec!["bash"]);
+    }
+}
@@ -0,0 +1,215 @@
+//! Build a bundled shell end-to-end, to tell problems of lorri and Nix
+//! from problems of a project’s expression.
+
+use crate::builder::{self, Info};
+use crate::env::ShellEnv;
+use crate::logging;
+use crate::ops::{ok_msg, ErrorCode, ExitError, OpResult};
+use crate::project::Project;
+use crate::roots::{ProjectRoots, Roots};
+use crate::NixFile;
+use std::path::PathBuf;
+
+/// The expression built, see `self-test-shell.nix`.
+const SELF_TEST_SHELL_SRC: &str = include_str!("../self-test-shell.nix");
+
+/// The variable the expression sets, and its value.
+const MARKER: (&str, &str) = ("LORRI_SELF_TEST", "works");
+
+/// The steps of a build, in order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    /// Nix evaluates the expression.
+    Evaluation,
+    /// Nix builds the environment of the shell.
+    Build,
+    /// lorri protects the environment with GC roots.
+    RootCreation,
+    /// lorri reads the variables of the environment.
+    EnvCapture,
+}
+
+impl Phase {
+    /// How the user can find out more if this phase fails.
+    fn hint(self) -> &'static str {
+        match self {
+            Phase::Evaluation => {
+                "Check that `nix-instantiate --eval -E '1 + 1'` works, \
+                 and run `lorri doctor`."
+            }
+            Phase::Build => {
+                "Check that `nix-build` can build derivations, and that the \
+                 Nix daemon is running if you use one."
+            }
+            Phase::RootCreation => {
+                "Check that you can write to your per-user GC roots in \
+                 /nix/var/nix/gcroots/per-user."
+            }
+            Phase::EnvCapture => "This is a bug in lorri, please report it.",
+        }
+    }
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Phase::Evaluation => "evaluation",
+            Phase::Build => "build",
+            Phase::RootCreation => "root creation",
+            Phase::EnvCapture => "environment capture",
+        })
+    }
+}
+
+/// See the documentation for lorri::cli::Command::SelfTest for more
+/// details.
+pub fn main() -> OpResult {
+    let dir = tempfile::tempdir()
+        .map_err(|e| ExitError::errmsg(format!("Cannot create a directory: {}", e)))?;
+    let nix_file = NixFile::from(dir.path().join("shell.nix"));
+    std::fs::write(nix_file.as_os_str(), SELF_TEST_SHELL_SRC)
+        .map_err(|e| ExitError::errmsg(format!("Cannot write {}: {}", nix_file, e)))?;
+    let state = dir.path().join("state");
+
+    let result = run(&nix_file, &Project::new(&nix_file, &state));
+    // drop the registrations of the roots with Nix along with them
+    if let Ok(projects) = ProjectRoots::scan(&state) {
+        for project in projects {
+            if let Err(e) = project.remove() {
+                warn!("Cannot remove the roots of the self-test: {}", e);
+            }
+        }
+    }
+
+    match result {
+        Ok(()) => ok_msg(
+            "lorri works: it evaluated and built a shell, created its GC roots and \
+             captured its environment. If your project fails, the problem is in its \
+             expression, see `lorri logs` and `lorri build`.",
+        ),
+        Err((phase, e)) => Err(ExitError::errmsg(format!(
+            "lorri self-test failed in the {} phase: {}\n  hint: {}",
+            phase,
+            e.message(),
+            phase.hint()
+        ))
+        .with_code(e.code())),
+    }
+}
+
+/// Build `nix_file` as lorri builds a project, reporting each phase.
+fn run(nix_file: &NixFile, project: &Project) -> Result<(), (Phase, ExitError)> {
+    let info = builder::run(nix_file, None, &builder::Progress::new()).map_err(|e| {
+        let code = if e.nix_missing() {
+            ErrorCode::NixMissing
+        } else {
+            ErrorCode::EvaluationFailed
+        };
+        let message = format!("cannot run nix-build: {:?}", e);
+        (
+            Phase::Evaluation,
+            ExitError::errmsg(message).with_code(code),
+        )
+    })?;
+    if evaluated(&info) {
+        passed(Phase::Evaluation);
+    }
+    let shell = check_build(&info)?;
+    passed(Phase::Build);
+
+    let roots = Roots::from_project(project)
+        .map_err(|e| (Phase::RootCreation, ExitError::errmsg(e.to_string())))?;
+    roots
+        .add("build-0", &shell)
+        .and_then(|_| roots.update_shell_gc_root(&shell))
+        .map_err(|e| (Phase::RootCreation, ExitError::errmsg(format!("{:?}", e))))?;
+    passed(Phase::RootCreation);
+
+    let env = ShellEnv::load(&roots.shell_gc_root())
+        .map_err(|e| (Phase::EnvCapture, ExitError::errmsg(e.to_string())))?
+        .apply(vec![]);
+    match env.get(MARKER.0) {
+        Some(value) if value == MARKER.1 => {
+            passed(Phase::EnvCapture);
+            Ok(())
+        }
+        other => Err((
+            Phase::EnvCapture,
+            ExitError::errmsg(format!(
+                "{} is {:?} instead of {:?}",
+                MARKER.0, other, MARKER.1
+            )),
+        )),
+    }
+}
+
+/// Report that `phase` worked.
+fn passed(phase: Phase) {
+    if logging::show_progress() {
+        println!("{}: ok", phase);
+    }
+}
+
+/// Whether Nix evaluated the expression: the evaluation traces the
+/// shell derivation once it is known.
+fn evaluated(info: &Info) -> bool {
+    info.named_drvs.contains_key("shell")
+}
+
+/// The environment `info` built, or the phase which failed and the
+/// end of its log.
+fn check_build(info: &Info) -> Result<PathBuf, (Phase, ExitError)> {
+    let phase = if evaluated(info) {
+        Phase::Build
+    } else {
+        Phase::Evaluation
+    };
+    match info.drvs.first() {
+        Some(shell) if info.exec_result.success() => Ok(shell.clone()),
+        _ => {
+            let skip = info.log_lines.len().saturating_sub(10);
+            let message = format!(
+                "nix-build exited with {}, its last output:\n{}",
+                info.exec_result,
+                info.log_lines[skip..].join("\n")
+            );
+            Err((
+                phase,
+                ExitError::errmsg(message).with_code(ErrorCode::EvaluationFailed),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_build, Phase};
+    use crate::builder::Info;
+    use std::collections::HashMap;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::PathBuf;
+    use std::process::ExitStatus;
+
+    #[test]
+    fn failed_phase() {
+        let shell = PathBuf::from("/nix/store/abc-lorri-keep-env-hack-lorri-self-test");
+        let mut info = Info {
+            exec_result: ExitStatus::from_raw(1 << 8),
+            named_drvs: HashMap::new(),
+            drvs: vec![],
+            paths: vec![],
+            log_lines: vec![String::from("error: syntax error")],
+        };
+        assert_eq!(check_build(&info).map_err(|e| e.0), Err(Phase::Evaluation));
+
+        info.named_drvs.insert(
+            String::from("shell"),
+            PathBuf::from("/nix/store/abc-lorri-self-test.drv"),
+        );
+        assert_eq!(check_build(&info).map_err(|e| e.0), Err(Phase::Build));
+
+        info.exec_result = ExitStatus::from_raw(0);
+        info.drvs.push(shell.clone());
+        assert_eq!(check_build(&info).map_err(|e| e.0), Ok(shell));
+    }
+}
@@ -2,106 +2,742 @@
 
 use crate::build::{BuildInstruction, NixBuild};
 use crate::build_loop::{BuildLoop, Event};
-use crate::ops::{ok, ExitError, OpResult};
+use crate::cli::ShellOptions;
+use crate::constants::Paths;
+use crate::env::{self, ShellEnv, Syntax};
+use crate::logging;
+use crate::ops::{self, ok, ErrorCode, ExitError, OpResult};
 use crate::project::Project;
 use crate::roots::Roots;
+use crate::socket::communicate::{
+    client, BuildState, ShellMessage, ShellRequest, DEFAULT_READ_TIMEOUT,
+};
+use crate::socket::path::SocketPath;
+use crate::socket::Timeout;
+use crate::trust::Trust;
+use crate::NixFile;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::sync::mpsc::channel;
+use std::sync::mpsc::{channel, Receiver};
 use std::thread;
 
+/// Set in a lorri shell, to the nix file of its project.
+const IN_LORRI_SHELL: &str = "IN_LORRI_SHELL";
+
 /// See the documentation for lorri::cli::Command::Shell for more
 /// details.
-pub fn main(project: Project) -> OpResult {
-    let (tx, rx) = channel();
+pub fn main(project: Project, opts: ShellOptions) -> OpResult {
     let root_nix_file = project.expression();
-    // TODO: handle unwrap
-    let roots = Roots::from_project(&project).unwrap();
-    let mut build_loop = BuildLoop::new(root_nix_file.to_owned(), roots.clone());
-
-    println!(
-        "WARNING: lorri shell is very simplistic and not suppported at the moment. \
-         Please use the other commands."
-    );
+    let original_host = host_environment(root_nix_file, opts.command.is_some())?;
+    let roots = Roots::from_project(&project)
+        .map_err(|e| ExitError::errmsg(format!("Cannot open the GC roots: {}", e)))?;
+    let paths = ops::get_paths()?;
+    let mut config = ops::get_config(&paths)?.project(root_nix_file);
+    let host = config.passthrough().filter(original_host.clone());
+    let host = if opts.pure {
+        env::pure(host, config.pure_keep())
+    } else {
+        host
+    };
 
-    let initial_build_thread = thread::spawn(move || {
-        let result = build_loop.once();
+    // the daemon builds the project with its configured attribute
+    let daemon = match opts.attr {
+        Some(_) => None,
+        None => daemon_builds(&paths, root_nix_file),
+    };
+    let (rx, state) = match daemon {
+        Some(builds) => builds,
+        None => {
+            if opts.attr.is_some() {
+                config.attr = opts.attr;
+            }
+            let (tx, rx) = channel();
+            let mut build_loop = BuildLoop::new(root_nix_file.to_owned(), roots.clone(), config);
+            thread::spawn(move || {
+                build_loop.forever(tx);
+            });
+            (rx, BuildState::Pending)
+        }
+    };
 
-        (result, build_loop)
-    });
+    let user_shell = UserShell::detect(std::env::var_os("SHELL"));
+    // a command is run with `sh`, we don’t need the user’s shell
+    let shell_path = match opts.command {
+        Some(_) => None,
+        None => Some(user_shell.path(&roots)?),
+    };
+    debug!("running with shell: {:?}", shell_path);
 
-    debug!("Building bash...");
-    let bash = NixBuild::build(&BuildInstruction::Expression(
-        "(import <nixpkgs> {}).bashInteractive.out",
-    ))
-    .expect("Failed to get a bashInteractive")
-    .pop()
-    .expect("Failed to receive a bash path");
+    if state != BuildState::Success && logging::show_progress() {
+        eprintln!("Waiting for the first build of {}.", root_nix_file);
+    }
+    wait_for_environment(&rx, state, opts.command.is_some())?;
 
-    debug!("running with bash: {:?}", bash);
-    roots.add("bash", &bash).unwrap();
+    // the build loop keeps it pointing to the latest environment
+    let shell_root = roots.shell_gc_root();
+    let shell_env = ShellEnv::load(&shell_root).map_err(|e| {
+        ExitError::errmsg(format!(
+            "Cannot read the shell environment {}: {}",
+            shell_root, e
+        ))
+    })?;
 
-    println!("Waiting for the builder to produce a drv for the 'shell' attribute.");
+    let shell_path = match (opts.command, shell_path) {
+        (Some(command), _) => return run(&command, shell_env.apply(host)),
+        (None, Some(shell_path)) => shell_path,
+        (None, None) => unreachable!("the shell is only skipped for a command"),
+    };
 
-    let (initial_result, mut build_loop) = initial_build_thread
-        .join()
-        .expect("Failed to join the initial evaluation thread");
+    // holds the reload scripts, and zsh reads its startup files
+    // from here, see `UserShell::hook`
+    let rc_dir = tempfile::tempdir()
+        .map_err(|e| ExitError::errmsg(format!("Cannot create a temporary directory: {}", e)))?;
+    let initial_env = shell_env.apply(host.clone());
+    // for a lorri shell started inside this one, see `host_environment`
+    std::fs::write(
+        rc_dir.path().join("host.json"),
+        serde_json::to_vec(&original_host).expect("environments serialize to JSON"),
+    )
+    .map_err(|e| ExitError::errmsg(format!("Cannot save the environment: {}", e)))?;
+    let mut reloader = Reloader::new(
+        rc_dir.path().to_owned(),
+        user_shell.syntax(),
+        host,
+        initial_env.clone(),
+    );
+    reloader
+        .set_status("ok")
+        .map_err(|e| ExitError::errmsg(format!("Cannot write the build status: {}", e)))?;
 
-    let first_build = match initial_result {
-        Ok(e) => e,
-        Err(e) => {
-            return Err(ExitError::errmsg(format!(
-                "Build for {} never produced a successful result: {:#?}",
-                root_nix_file, e
-            )));
+    // Move the channel to a new thread to log all remaining builds,
+    // and hand every new environment to the running shell.
+    let msg_handler_thread = thread::spawn(move || {
+        for mes in rx {
+            print_build_event(&mes);
+            let status = match mes {
+                Event::Started(..) => Some("building"),
+                Event::Completed(_) => Some("ok"),
+                Event::Failure(_) => Some("failed"),
+                _ => None,
+            };
+            let written = status.map_or(Ok(()), |status| reloader.set_status(status));
+            if let (Err(e), true) = (written, logging::show_warnings()) {
+                eprintln!("Warning: cannot write the build status: {}", e);
+            }
+            if let Event::EnvDiff(diff) = mes {
+                let message = format!("lorri: Reloaded the environment, {}.", diff);
+                let reloaded =
+                    ShellEnv::load(&shell_root).and_then(|env| reloader.reload(&env, &message));
+                if let (Err(e), true) = (reloaded, logging::show_warnings()) {
+                    eprintln!("Warning: cannot reload the shell environment: {}", e);
+                }
+            }
         }
-    };
+    });
 
-    // the `shell` derivation is required in oder to start a shell
-    // TODO: is this actually a derivation? Or an attribute?
-    let shell_drv = first_build
-        .named_drvs
-        .get("shell")
-        .expect("Failed to start the shell: no \"shell\" derivation found");
+    let mut shell = Command::new(&shell_path);
+    shell
+        .env_clear()
+        .envs(initial_env)
+        .env(IN_LORRI_SHELL, root_nix_file.as_os_str())
+        .env("LORRI_SHELL_ROOT", roots.shell_gc_root())
+        .env("LORRI_RELOAD_DIR", rc_dir.path())
+        .env("LORRI_STATUS_FILE", rc_dir.path().join("status"))
+        .env("LORRI_GENERATION", "0");
+    user_shell
+        .hook(&mut shell, rc_dir.path())
+        .map_err(|e| ExitError::errmsg(format!("Cannot set up {}: {}", shell_path.display(), e)))?;
+    shell.status().map_err(|e| {
+        ExitError::errmsg(format!("Failed to execute {}: {}", shell_path.display(), e))
+    })?;
 
-    let build_thread = {
-        thread::spawn(move || {
-            build_loop.forever(tx);
+    drop(msg_handler_thread);
+
+    ok()
+}
+
+/// The builds of `nix_file` by the lorri daemon, and the state of the
+/// project when asking for them, if the daemon is running and may
+/// build the project. The daemon keeps the project’s roots up to date,
+/// so the shell doesn’t have to build anything itself.
+fn daemon_builds(paths: &Paths, nix_file: &NixFile) -> Option<(Receiver<Event>, BuildState)> {
+    // the daemon ignores projects which are not allowed
+    if let Err(e) = Trust::new(paths.allow_dir()).check(nix_file) {
+        debug!("Not using the lorri daemon: {}", e);
+        return None;
+    }
+    let client = client::shell(DEFAULT_READ_TIMEOUT)
+        .connect(&SocketPath::from(paths.daemon_socket_file()))
+        .map_err(|e| debug!("Not using the lorri daemon: {}", e))
+        .ok()?;
+    client
+        .write(&ShellRequest {
+            nix_file: nix_file.clone(),
         })
+        .map_err(|e| debug!("Cannot send the request to the lorri daemon: {:?}", e))
+        .ok()?;
+    let state = match client.read() {
+        Ok(ShellMessage::State(state)) => state,
+        other => {
+            debug!("The lorri daemon did not answer with a state: {:?}", other);
+            return None;
+        }
     };
+    if logging::show_progress() {
+        eprintln!("Using the environment the lorri daemon builds.");
+    }
 
-    // Move the channel to a new thread to log all remaining builds.
-    let msg_handler_thread = thread::spawn(move || {
-        for mes in rx {
-            print_build_event(&mes)
+    let (tx, rx) = channel();
+    thread::spawn(move || {
+        // idle projects send no events for a long time
+        let read = client.read_until_closed(&Timeout::Infinite, |message| {
+            if let ShellMessage::Event(event) = message {
+                let _ = tx.send(event);
+            }
+        });
+        if let Err(e) = read {
+            debug!("Cannot read events from the lorri daemon: {:?}", e);
+        }
+        if logging::show_warnings() {
+            eprintln!("Warning: lost the connection to the lorri daemon, the environment is no longer reloaded.");
         }
     });
+    Some((rx, state))
+}
 
-    Command::new("nix-shell")
-        .arg(shell_drv)
-        .env("NIX_BUILD_SHELL", format!("{}/bin/bash", bash.display()))
-        .env("LORRI_SHELL_ROOT", shell_drv)
-        .env("PROMPT_COMMAND", include_str!("./prompt.sh"))
+/// Wait until the environment can be used, printing the build events.
+/// `state` is the state of the project when the events started.
+///
+/// A shell can start with the result of a previous run, and keeps
+/// waiting while builds fail. A command (`fresh`) needs the result of
+/// a new build, and gives up if it fails. A successful build by the
+/// daemon counts as new: the daemon rebuilds whenever the project
+/// changes.
+fn wait_for_environment(
+    rx: &Receiver<Event>,
+    state: BuildState,
+    fresh: bool,
+) -> Result<(), ExitError> {
+    let mut started = match state {
+        BuildState::Success => return Ok(()),
+        BuildState::Failure if fresh => {
+            return Err(
+                ExitError::errmsg("The last build failed, see `lorri logs`.")
+                    .with_code(ErrorCode::EvaluationFailed),
+            )
+        }
+        BuildState::Failure => {
+            if logging::show_progress() {
+                eprintln!("Waiting for a successful build, fix the error and save the file.");
+            }
+            false
+        }
+        BuildState::Building => true,
+        BuildState::Pending => false,
+    };
+    for event in rx.iter() {
+        print_build_event(&event);
+        match event {
+            Event::Started(..) => started = true,
+            Event::Completed(_) if started || !fresh => return Ok(()),
+            Event::Failure(_) if fresh => {
+                return Err(ExitError::errmsg("The build failed, see above.")
+                    .with_code(ErrorCode::EvaluationFailed))
+            }
+            Event::Failure(_) if logging::show_progress() => {
+                eprintln!("Waiting for a successful build, fix the error and save the file.")
+            }
+            _ => {}
+        }
+    }
+    Err(ExitError::errmsg(
+        "The build loop stopped before producing an environment.",
+    ))
+}
+
+/// The environment to apply the project’s environment to.
+///
+/// Inside of a lorri shell, that is the environment the outer
+/// `lorri shell` was started in, so the environments of the two
+/// projects don’t pile up. An interactive shell of the same project
+/// is refused.
+fn host_environment(
+    nix_file: &NixFile,
+    is_command: bool,
+) -> Result<HashMap<String, String>, ExitError> {
+    let outer = match std::env::var_os(IN_LORRI_SHELL) {
+        Some(outer) => PathBuf::from(outer),
+        None => return Ok(std::env::vars().collect()),
+    };
+    if outer.as_os_str() == nix_file.as_os_str() && !is_command {
+        return Err(ExitError::errmsg(format!(
+            "You are already in a lorri shell for {}.",
+            nix_file
+        )));
+    }
+    let saved = std::env::var_os("LORRI_RELOAD_DIR")
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))
+        .and_then(|dir| read_host(Path::new(&dir)));
+    match saved {
+        Ok(host) => {
+            if !is_command && logging::show_warnings() {
+                eprintln!(
+                    "Warning: Already in a lorri shell for {}, its environment is replaced.",
+                    outer.display()
+                );
+            }
+            Ok(host)
+        }
+        Err(e) => {
+            if logging::show_warnings() {
+                eprintln!(
+                "Warning: Already in a lorri shell for {}, but cannot read its original environment ({}), the environments are combined.",
+                outer.display(),
+                e
+            );
+            }
+            Ok(std::env::vars().collect())
+        }
+    }
+}
+
+/// Read the environment a lorri shell saved in its `reload_dir`.
+fn read_host(reload_dir: &Path) -> Result<HashMap<String, String>, std::io::Error> {
+    let contents = std::fs::read(reload_dir.join("host.json"))?;
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+/// Run `command` with `/bin/sh` in the environment `env`, like
+/// `nix-shell --run`, and exit with its status.
+fn run(command: &str, env: HashMap<String, String>) -> OpResult {
+    // a pure environment might not have `sh` in its `PATH`
+    let status = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .env_clear()
+        .envs(env)
         .status()
-        .expect("Failed to execute bash");
+        .map_err(|e| ExitError::errmsg(format!("Failed to execute sh: {}", e)))?;
+    match status.code() {
+        Some(0) => ok(),
+        Some(code) => Err(ExitError::exit(
+            code,
+            format!("`{}` exited with status {}", command, code),
+        )),
+        None => Err(ExitError::errmsg(format!(
+            "`{}` was killed by a signal",
+            command
+        ))),
+    }
+}
 
-    drop(build_thread);
-    drop(msg_handler_thread);
+/// The shell the user works in, as far as `lorri shell` knows how to
+/// hook into its prompt.
+#[derive(Debug, PartialEq)]
+enum UserShell {
+    /// bash, or any shell we don’t know; we start bashInteractive.
+    Bash,
+    /// zsh, at the given path.
+    Zsh(PathBuf),
+    /// fish, at the given path.
+    Fish(PathBuf),
+}
 
-    ok()
+impl UserShell {
+    /// Choose the shell by the name of the user’s `$SHELL`.
+    fn detect(shell: Option<OsString>) -> UserShell {
+        let path = match shell {
+            Some(shell) => PathBuf::from(shell),
+            None => return UserShell::Bash,
+        };
+        match path.file_name().and_then(|name| name.to_str()) {
+            Some("zsh") => UserShell::Zsh(path),
+            Some("fish") => UserShell::Fish(path),
+            _ => UserShell::Bash,
+        }
+    }
+
+    /// The executable of the shell. bashInteractive is built (and
+    /// kept alive by a GC root) first, unless it was built for the
+    /// same `<nixpkgs>` before.
+    fn path(&self, roots: &Roots) -> Result<PathBuf, ExitError> {
+        match self {
+            UserShell::Bash => {
+                let nixpkgs = nixpkgs_path();
+                let cache_file = roots.bash_cache_file();
+                if let Some(bash) = nixpkgs
+                    .as_ref()
+                    .and_then(|nixpkgs| BashCache::load(&cache_file, nixpkgs))
+                {
+                    debug!("using the cached bash: {:?}", bash);
+                    return Ok(bash.join("bin/bash"));
+                }
+
+                debug!("Building bash...");
+                let bash = NixBuild::build(&BuildInstruction::Expression(
+                    "(import <nixpkgs> {}).bashInteractive.out",
+                ))
+                .map_err(|e| ExitError::errmsg(format!("Failed to build bash: {:?}", e)))?
+                .pop()
+                .ok_or_else(|| ExitError::errmsg("Building bash produced no output"))?;
+                roots.add("bash", &bash).map_err(|e| {
+                    ExitError::errmsg(format!("Cannot add a GC root for bash: {:?}", e))
+                })?;
+                if let Some(nixpkgs) = nixpkgs {
+                    let cache = BashCache {
+                        nixpkgs,
+                        bash: bash.clone(),
+                    };
+                    if let Err(e) = cache.save(&cache_file) {
+                        debug!("could not cache the bash path: {}", e);
+                    }
+                }
+                Ok(bash.join("bin/bash"))
+            }
+            UserShell::Zsh(path) | UserShell::Fish(path) => Ok(path.clone()),
+        }
+    }
+
+    /// How the shell sets variables.
+    fn syntax(&self) -> Syntax {
+        match self {
+            UserShell::Bash | UserShell::Zsh(_) => Syntax::Posix,
+            UserShell::Fish(_) => Syntax::Fish,
+        }
+    }
+
+    /// Install the hook which applies a new environment (see
+    /// `Reloader`) before the next prompt: `PROMPT_COMMAND` for bash,
+    /// a `precmd` hook for zsh and a `fish_prompt` event handler for fish.
+    ///
+    /// zsh has no way to run extra commands after its startup files,
+    /// so we point `ZDOTDIR` to `rc_dir`, whose `.zshrc` sources the
+    /// user’s before installing the hook.
+    fn hook(&self, shell: &mut Command, rc_dir: &Path) -> Result<(), std::io::Error> {
+        match self {
+            UserShell::Bash => {
+                shell.env("PROMPT_COMMAND", include_str!("./prompt.sh"));
+            }
+            UserShell::Zsh(_) => {
+                let user_zdotdir = std::env::var_os("ZDOTDIR")
+                    .or_else(|| std::env::var_os("HOME"))
+                    .unwrap_or_default();
+                std::fs::write(
+                    rc_dir.join(".zshenv"),
+                    "if [ -f \"$LORRI_USER_ZDOTDIR/.zshenv\" ]; then\n    source \"$LORRI_USER_ZDOTDIR/.zshenv\"\nfi\n",
+                )?;
+                std::fs::write(rc_dir.join(".zshrc"), include_str!("./prompt.zsh"))?;
+                shell
+                    .env("LORRI_USER_ZDOTDIR", user_zdotdir)
+                    .env("ZDOTDIR", rc_dir);
+            }
+            UserShell::Fish(_) => {
+                shell
+                    .arg("--init-command")
+                    .arg(include_str!("./prompt.fish"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The bashInteractive built for a `<nixpkgs>`, kept alive by the
+/// project’s `bash` GC root.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct BashCache {
+    /// The store path `<nixpkgs>` resolved to.
+    nixpkgs: PathBuf,
+    /// The output of bashInteractive.
+    bash: PathBuf,
+}
+
+impl BashCache {
+    /// The cached bash in `file`, if it was built for `nixpkgs` and
+    /// still exists.
+    fn load(file: &Path, nixpkgs: &Path) -> Option<PathBuf> {
+        let contents = std::fs::read(file).ok()?;
+        let cache: BashCache = serde_json::from_slice(&contents).ok()?;
+        if cache.nixpkgs == nixpkgs && cache.bash.join("bin/bash").exists() {
+            Some(cache.bash)
+        } else {
+            None
+        }
+    }
+
+    fn save(&self, file: &Path) -> Result<(), std::io::Error> {
+        std::fs::write(file, serde_json::to_vec(self)?)
+    }
+}
+
+/// Where `<nixpkgs>` currently points to, with all symlinks (like
+/// those of channels) resolved.
+fn nixpkgs_path() -> Option<PathBuf> {
+    let output = Command::new("nix-instantiate")
+        .args(&["--find-file", "nixpkgs"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let path = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    path.canonicalize().ok()
+}
+
+/// Keeps a running shell up to date with the latest build.
+///
+/// After each build which changed the environment, a script which
+/// turns the shell’s initial environment into the new one is written
+/// to `env` in `dir`, and what changed to `message`. Then the number
+/// in `generation` is increased. The prompt hooks source the script
+/// and show the message whenever the generation changed.
+///
+/// `status` holds the state of the latest build, `building`, `ok` or
+/// `failed`, for the prompt.
+struct Reloader {
+    dir: PathBuf,
+    syntax: Syntax,
+    /// The environment `lorri shell` was started in.
+    host: HashMap<String, String>,
+    /// The environment the shell was started with.
+    initial: HashMap<String, String>,
+    /// Every variable any build changed from its initial value,
+    /// which a later build might have to change back.
+    touched: BTreeSet<String>,
+    generation: u64,
+}
+
+impl Reloader {
+    fn new(
+        dir: PathBuf,
+        syntax: Syntax,
+        host: HashMap<String, String>,
+        initial: HashMap<String, String>,
+    ) -> Reloader {
+        Reloader {
+            dir,
+            syntax,
+            host,
+            initial,
+            touched: BTreeSet::new(),
+            generation: 0,
+        }
+    }
+
+    /// Make `shell_env` the environment of the shell at its next
+    /// prompt, and tell the user `message` there.
+    fn reload(&mut self, shell_env: &ShellEnv, message: &str) -> Result<(), std::io::Error> {
+        let new = shell_env.apply(self.host.clone());
+        for (name, value) in &new {
+            if self.initial.get(name) != Some(value) {
+                self.touched.insert(name.clone());
+            }
+        }
+        for name in self.initial.keys() {
+            if !new.contains_key(name) {
+                self.touched.insert(name.clone());
+            }
+        }
+        let changes = self
+            .touched
+            .iter()
+            .map(|name| (name.clone(), new.get(name).cloned()))
+            .collect::<BTreeMap<String, Option<String>>>();
+
+        self.generation += 1;
+        self.write("env", &env::script(&changes, self.syntax))?;
+        self.write("message", message)?;
+        self.write("generation", &self.generation.to_string())
+    }
+
+    /// Record the state of the latest build.
+    fn set_status(&self, status: &str) -> Result<(), std::io::Error> {
+        self.write("status", status)
+    }
+
+    /// Replace `name` in `dir` atomically, so the shell never reads
+    /// a partial file.
+    fn write(&self, name: &str, contents: &str) -> Result<(), std::io::Error> {
+        let tmp = self.dir.join(format!(".{}.tmp", name));
+        std::fs::write(&tmp, contents)?;
+        std::fs::rename(&tmp, self.dir.join(name))
+    }
 }
 
 // Log all failing builds, return an iterator of the first
 // build that succeeds.
 fn print_build_event(ev: &Event) {
+    let shown = match ev {
+        Event::Failure(_) => true,
+        Event::Warning(_) => logging::show_warnings(),
+        _ => logging::show_progress(),
+    };
+    if !shown {
+        return;
+    }
     match ev {
-        Event::Completed(_) => {
-            eprintln!("Expressions re-evaluated. Press enter to reload the environment.")
-        }
-        Event::Started => eprintln!("Evaluation started"),
+        Event::Completed(_) => eprintln!("Expressions re-evaluated."),
+        Event::Started(_, reason) => eprintln!("Evaluation started: {}", reason),
+        Event::Warning(warning) => eprintln!("Warning: {}", warning),
+        Event::EnvDiff(diff) => eprintln!("Environment changed: {}", diff),
+        Event::Heartbeat(beat) => eprintln!(
+            "Still running ({:?}, {}s elapsed)",
+            beat.phase,
+            beat.elapsed.as_secs()
+        ),
         // show the last 5 lines of error output
         Event::Failure(err) => eprintln!(
             "Evaluation failed: \n{}",
             err.log_lines[err.log_lines.len().saturating_sub(5)..].join("\n")
         ),
+        // the whole log would drown the shell, failures show its end
+        Event::Log(_, _) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_host, wait_for_environment, BashCache, Reloader, UserShell};
+    use crate::build_loop::{BuildExitFailure, BuildId, Event, Reason};
+    use crate::env::{ShellEnv, Syntax};
+    use crate::socket::communicate::BuildState;
+    use std::collections::HashMap;
+    use std::ffi::OsString;
+    use std::path::PathBuf;
+    use tempfile::tempdir;
+
+    #[test]
+    fn shell_detection() {
+        assert_eq!(UserShell::detect(None), UserShell::Bash);
+        assert_eq!(
+            UserShell::detect(Some(OsString::from("/bin/bash"))),
+            UserShell::Bash
+        );
+        assert_eq!(
+            UserShell::detect(Some(OsString::from("/run/current-system/sw/bin/zsh"))),
+            UserShell::Zsh(PathBuf::from("/run/current-system/sw/bin/zsh"))
+        );
+        assert_eq!(
+            UserShell::detect(Some(OsString::from("/usr/bin/fish"))),
+            UserShell::Fish(PathBuf::from("/usr/bin/fish"))
+        );
+        // we only know how to hook into these
+        assert_eq!(
+            UserShell::detect(Some(OsString::from("/bin/tcsh"))),
+            UserShell::Bash
+        );
+    }
+
+    #[test]
+    fn reloads_revert_earlier_changes() {
+        let dir = tempdir().unwrap();
+        let vars = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect::<HashMap<String, String>>()
+        };
+        let host = vars(&[("PATH", "/usr/bin")]);
+        let first = ShellEnv::from(vars(&[("PATH", "/nix/store/a/bin")]));
+        let mut reloader = Reloader::new(
+            dir.path().to_owned(),
+            Syntax::Posix,
+            host.clone(),
+            first.apply(host),
+        );
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+
+        let second = ShellEnv::from(vars(&[("PATH", "/nix/store/b/bin"), ("FOO", "1")]));
+        reloader.reload(&second, "added FOO").unwrap();
+        assert_eq!(read("generation"), "1");
+        assert_eq!(read("message"), "added FOO");
+        assert_eq!(
+            read("env"),
+            "export FOO='1'\nexport PATH='/nix/store/b/bin:/usr/bin'\n"
+        );
+
+        reloader.set_status("failed").unwrap();
+        assert_eq!(read("status"), "failed");
+
+        reloader.reload(&first, "removed FOO").unwrap();
+        assert_eq!(read("generation"), "2");
+        assert_eq!(
+            read("env"),
+            "unset FOO\nexport PATH='/nix/store/a/bin:/usr/bin'\n"
+        );
+    }
+
+    #[test]
+    fn saved_host_environment() {
+        let dir = tempdir().unwrap();
+        let mut host = HashMap::new();
+        host.insert(String::from("PATH"), String::from("/usr/bin"));
+        std::fs::write(
+            dir.path().join("host.json"),
+            serde_json::to_vec(&host).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(read_host(dir.path()).unwrap(), host);
+        assert!(read_host(&dir.path().join("gone")).is_err());
+    }
+
+    #[test]
+    fn bash_cache() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("bash.json");
+        let bash = dir.path().join("bash");
+        std::fs::create_dir_all(bash.join("bin")).unwrap();
+        std::fs::write(bash.join("bin/bash"), "").unwrap();
+        let nixpkgs = PathBuf::from("/nix/store/aaa-nixpkgs");
+
+        assert_eq!(BashCache::load(&file, &nixpkgs), None);
+        BashCache {
+            nixpkgs: nixpkgs.clone(),
+            bash: bash.clone(),
+        }
+        .save(&file)
+        .unwrap();
+        assert_eq!(BashCache::load(&file, &nixpkgs), Some(bash.clone()));
+        // a channel update invalidates it
+        assert_eq!(
+            BashCache::load(&file, &PathBuf::from("/nix/store/bbb-nixpkgs")),
+            None
+        );
+        // so does garbage collection
+        std::fs::remove_dir_all(&bash).unwrap();
+        assert_eq!(BashCache::load(&file, &nixpkgs), None);
+    }
+
+    #[test]
+    fn commands_need_a_successful_build() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let build_id = BuildId::next();
+        tx.send(Event::Started(build_id, Reason::Startup)).unwrap();
+        tx.send(Event::Failure(BuildExitFailure {
+            build_id,
+            log_lines: vec![String::from("error: syntax error")],
+        }))
+        .unwrap();
+        assert!(wait_for_environment(&rx, BuildState::Pending, true).is_err());
+
+        // a shell keeps waiting, until the build loop stops
+        tx.send(Event::Failure(BuildExitFailure {
+            build_id,
+            log_lines: vec![],
+        }))
+        .unwrap();
+        drop(tx);
+        assert!(wait_for_environment(&rx, BuildState::Pending, false).is_err());
+    }
+
+    #[test]
+    fn daemon_states() {
+        let (tx, rx) = std::sync::mpsc::channel::<Event>();
+        assert!(wait_for_environment(&rx, BuildState::Success, true).is_ok());
+        assert!(wait_for_environment(&rx, BuildState::Failure, true).is_err());
+        // a shell waits for the failure to be fixed
+        drop(tx);
+        assert!(wait_for_environment(&rx, BuildState::Failure, false).is_err());
     }
 }
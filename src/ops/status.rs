@@ -0,0 +1,109 @@
+//! Ask the lorri daemon what it is doing.
+
+use crate::cli::StatusOptions;
+use crate::duration;
+use crate::ops::{self, ok_msg, ExitError, OpResult};
+use crate::socket::communicate::{client, BuildState, ProjectStatus, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// See the documentation for lorri::cli::Command::Status for more
+/// details.
+pub fn main(opts: StatusOptions) -> OpResult {
+    let paths = ops::get_paths()?;
+    let socket_path = SocketPath::from(paths.daemon_socket_file());
+    let statuses = client::status(DEFAULT_READ_TIMEOUT)
+        .connect(&socket_path)
+        .map_err(|e| ops::daemon_unreachable(e, &socket_path))?
+        .read()
+        .map_err(|e| {
+            ExitError::errmsg(format!(
+                "Cannot read the status from the lorri daemon: {:?}",
+                e
+            ))
+        })?;
+
+    if opts.json {
+        return ok_msg(
+            serde_json::to_string_pretty(&statuses).expect("statuses serialize to JSON"),
+        );
+    }
+    if statuses.is_empty() {
+        return ok_msg("The lorri daemon builds no projects yet.");
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    ok_msg(
+        statuses
+            .iter()
+            .map(|status| format(status, now))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    )
+}
+
+/// Describe `status` for humans; `now` is in seconds since the epoch.
+fn format(status: &ProjectStatus, now: u64) -> String {
+    let state = match status.state {
+        BuildState::Pending => "pending",
+        BuildState::Building => "building",
+        BuildState::Success => "success",
+        BuildState::Failure => "failure",
+    };
+    let last_build = match (status.last_build, status.last_build_duration) {
+        (Some(finished), Some(took)) => format!(
+            "{} ago, took {}",
+            format_duration(Duration::from_secs(now.saturating_sub(finished))),
+            format_duration(duration::from_secs_f64(took))
+        ),
+        _ => String::from("never"),
+    };
+    format!(
+        "{}\n  state: {}\n  last build: {}\n  gc root: {}\n  watched files: {}",
+        status.nix_file,
+        state,
+        last_build,
+        status.gc_root.display(),
+        status.watched_files
+    )
+}
+
+/// A duration with the precision that matters for builds.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {}s", secs / 60, secs % 60),
+        _ => format!("{}h {}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+    use crate::socket::communicate::{BuildState, ProjectStatus};
+    use crate::NixFile;
+    use std::path::PathBuf;
+
+    #[test]
+    fn human_status() {
+        let status = ProjectStatus {
+            nix_file: NixFile::from(PathBuf::from("/home/alice/foo/shell.nix")),
+            state: BuildState::Success,
+            last_build: Some(1000),
+            last_build_duration: Some(75.3),
+            gc_root: PathBuf::from("/gc_roots/abc/shell_gc_root"),
+            watched_files: 3,
+        };
+        assert_eq!(
+            format(&status, 1130),
+            "/home/alice/foo/shell.nix\n  \
+             state: success\n  \
+             last build: 2m 10s ago, took 1m 15s\n  \
+             gc root: /gc_roots/abc/shell_gc_root\n  \
+             watched files: 3"
+        );
+    }
+}
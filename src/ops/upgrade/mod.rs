@@ -6,24 +6,44 @@
 
 use crate::changelog;
 use crate::cli;
+use crate::logging;
 use crate::nix;
-use crate::ops::{ExitError, OpResult};
+use crate::ops::{self, ExitError, OpResult};
+use crate::socket::communicate::{client, UpgradeRequest, DEFAULT_READ_TIMEOUT};
+use crate::socket::path::SocketPath;
+use crate::socket::Timeout;
 use crate::VERSION_BUILD_REV;
+use std::path::Path;
 use std::process::Command;
 use tempfile::tempdir;
 
-impl From<cli::UpgradeTo> for String {
-    fn from(desc: cli::UpgradeTo) -> Self {
-        match desc.source.unwrap_or(cli::UpgradeSource::RollingRelease) {
-            cli::UpgradeSource::RollingRelease => String::from("rolling-release"),
-            cli::UpgradeSource::Master => String::from("master"),
-            cli::UpgradeSource::Local(src) => String::from(
-                src.path
-                    .to_str()
-                    .expect("Requested Lorri source directory not UTF-8 clean"),
-            ),
+/// The upgrade expression, which builds lorri from `source`, and a
+/// description of `source` for the user.
+fn upgrade_expression(source: &cli::UpgradeSource) -> (nix::CallOpts, String) {
+    let mut expr = nix::CallOpts::expression(include_str!("./upgrade.nix"));
+    let description = match source {
+        cli::UpgradeSource::RollingRelease => {
+            expr.argstr("branch", "rolling-release");
+            String::from("branch rolling-release")
         }
-    }
+        cli::UpgradeSource::Master => {
+            expr.argstr("branch", "master");
+            String::from("branch master")
+        }
+        cli::UpgradeSource::Branch(dest) => {
+            expr.argstr("branch", &dest.name);
+            format!("branch {}", dest.name)
+        }
+        cli::UpgradeSource::Local(dest) => {
+            let path = dest
+                .path
+                .to_str()
+                .expect("Requested Lorri source directory not UTF-8 clean");
+            expr.argstr("src", path);
+            String::from(path)
+        }
+    };
+    (expr, description)
 }
 
 /// nix-env upgrade Lorri in the default profile.
@@ -32,26 +52,28 @@ pub fn main(upgrade_target: cli::UpgradeTo) -> OpResult {
     1. nix-instantiate the expression
     2. get all the changelog entries from <currentnumber> to <maxnumber>
     3. nix-build the expression's package attribute
-    4. nix-env -i the package
+    4. check that the new lorri runs
+    5. nix-env -i the package
      */
-    let upgrade_expr = include_str!("./upgrade.nix");
-
-    let expr = {
-        let src = String::from(upgrade_target);
-        println!("Upgrading from source: {}", src);
-        let mut expr = nix::CallOpts::expression(upgrade_expr);
-        expr.argstr("src", &src);
-        // ugly hack to prevent expr from being mutable outside,
-        // since I can't sort out how to chain argstr and still
-        // keep a reference
-        expr
-    };
+    let source = upgrade_target
+        .source
+        .unwrap_or(cli::UpgradeSource::RollingRelease);
+    let (expr, description) = upgrade_expression(&source);
+    if logging::show_progress() {
+        println!("Upgrading from source: {}", description);
+    }
 
-    let changelog: changelog::Log = expr.clone().attribute("changelog").value().unwrap();
+    let changelog: changelog::Log = expr
+        .clone()
+        .attribute("changelog")
+        .value()
+        .map_err(|e| ExitError::errmsg(format!("Cannot fetch the new lorri: {:?}", e)))?;
 
-    println!("Changelog when upgrading from {}:", VERSION_BUILD_REV);
+    if logging::show_progress() {
+        println!("Changelog when upgrading from {}:", VERSION_BUILD_REV);
+    }
     for entry in changelog.entries {
-        if VERSION_BUILD_REV < entry.version {
+        if VERSION_BUILD_REV < entry.version && logging::show_progress() {
             println!("{}:", entry.version);
             println!();
             for line in entry.changes.lines() {
@@ -60,28 +82,135 @@ pub fn main(upgrade_target: cli::UpgradeTo) -> OpResult {
         }
     }
 
-    println!("Building ...");
+    if logging::show_progress() {
+        println!("Building ...");
+    }
     let tmpdir = tempdir().unwrap();
-    match expr.clone().attribute("package").path(&tmpdir.path()) {
-        Ok(build_result) => {
-            let status = Command::new("nix-env")
-                .arg("--install")
-                .arg(build_result)
-                .status()
-                .expect("Error: failed to execute nix-env --install");
-
-            if status.success() {
-                Ok(Some(String::from("\nUpgrade successful.")))
-            } else {
-                Err(ExitError::errmsg(String::from(
-                    "\nError: nix-env command was not successful!",
-                )))
-            }
+    let build_result = match expr.clone().attribute("package").path(tmpdir.path()) {
+        Ok(build_result) => build_result,
+        e => {
+            return Err(ExitError::errmsg(format!(
+                "Failed to build the update! Please report a bug!\n\
+                 {:?}",
+                e
+            )))
         }
-        e => Err(ExitError::errmsg(format!(
-            "Failed to build the update! Please report a bug!\n\
-             {:?}",
+    };
+    // the temporary directory goes away, the store path stays
+    let executable = build_result.join("bin").join("lorri");
+    let executable = std::fs::canonicalize(&executable).unwrap_or(executable);
+    verify(&executable).map_err(|e| {
+        ExitError::errmsg(format!(
+            "The new lorri does not run, keeping the installed one: {}",
             e
-        ))),
+        ))
+    })?;
+
+    let status = Command::new("nix-env")
+        .arg("--install")
+        .arg(&build_result)
+        .status()
+        .expect("Error: failed to execute nix-env --install");
+
+    if status.success() {
+        Ok(Some(format!(
+            "\nUpgrade successful.{}",
+            upgrade_daemon(&executable)
+        )))
+    } else {
+        Err(ExitError::errmsg(String::from(
+            "\nError: nix-env command was not successful!",
+        )))
+    }
+}
+
+/// Check that `executable` is a lorri which runs, by asking it for
+/// its version.
+fn verify(executable: &Path) -> Result<(), String> {
+    let output = Command::new(executable)
+        .arg("--version")
+        .output()
+        .map_err(|e| format!("cannot run {}: {}", executable.display(), e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "`{} --version` failed with {}",
+            executable.display(),
+            output.status
+        ));
+    }
+    if !String::from_utf8_lossy(&output.stdout).starts_with("lorri ") {
+        return Err(format!("{} is not lorri", executable.display()));
+    }
+    Ok(())
+}
+
+/// Ask a running lorri daemon to continue as `executable`, keeping its
+/// projects (see `UpgradeRequest`). Returns what happened, for the
+/// user; nothing if no daemon is running.
+fn upgrade_daemon(executable: &Path) -> String {
+    let paths = match ops::get_paths() {
+        Ok(paths) => paths,
+        Err(e) => return format!("\nCannot find the lorri daemon: {}", e.message()),
+    };
+    let socket_path = SocketPath::from(paths.daemon_socket_file());
+    let client = match client::upgrade(DEFAULT_READ_TIMEOUT).connect(&socket_path) {
+        Ok(client) => client,
+        Err(e) => {
+            debug!("Not upgrading the lorri daemon: {}", e);
+            return String::new();
+        }
+    };
+    if logging::show_progress() {
+        println!("Upgrading the lorri daemon ...");
+    }
+    let request = UpgradeRequest {
+        executable: executable.to_owned(),
+    };
+    let mut response = None;
+    // the daemon waits for running builds for up to its `shutdown_wait`
+    let answered = client
+        .write(&request)
+        .and_then(|()| client.read_until_closed(&Timeout::Infinite, |r| response = Some(r)));
+    match (answered, response) {
+        (Ok(()), Some(response)) => match response.error {
+            None => format!(
+                "\nThe lorri daemon continues as the new lorri, with its {} project(s).",
+                response.projects
+            ),
+            Some(error) => format!(
+                "\nThe lorri daemon could not upgrade, please restart it: {}",
+                error
+            ),
+        },
+        (answered, _) => format!(
+            "\nThe lorri daemon did not answer ({:?}), please restart it.",
+            answered.err()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verify;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::Path;
+
+    fn script(dir: &Path, name: &str, body: &str) -> std::path::PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn only_running_lorris_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            verify(&script(dir.path(), "good", "echo lorri 0.1.0")),
+            Ok(())
+        );
+        assert!(verify(&script(dir.path(), "broken", "exit 1")).is_err());
+        assert!(verify(&script(dir.path(), "other", "echo bash 5.0")).is_err());
+        assert!(verify(&dir.path().join("missing")).is_err());
     }
 }
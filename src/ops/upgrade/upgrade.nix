@@ -2,27 +2,16 @@
   # support selecting either a given branch, or a local checkout.
   #
   # Calling this with `--argstr src $(pwd)` will install the version
-  # of lorri present in $(pwd).
-  src ? "rolling-release",
+  # of lorri present in $(pwd), `--argstr branch master` the version
+  # on the master branch.
+  src ? null,
+  branch ? "rolling-release",
 }:
 let
-  inherit (builtins) fetchGit hasAttr getAttr trace;
-
-  sources = {
-    master = fetchGit {
+  path = if src == null
+    then builtins.fetchGit {
       url = "https://github.com/target/lorri.git";
-      ref = "master";
-    };
-
-    rolling-release = fetchGit {
-      url = "https://github.com/target/lorri.git";
-      ref = "rolling-release";
-    };
-
-    local = fetchGit src;
-  };
-
-  path = if hasAttr src sources
-    then getAttr src sources
-    else sources.local;
+      ref = branch;
+    }
+    else builtins.fetchGit src;
 in (import "${path}/release.nix" { src = path; } )
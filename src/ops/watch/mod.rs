@@ -0,0 +1,344 @@
+//! Run a BuildLoop for `shell.nix`, watching for input file changes.
+//! Can be used together with `direnv`.
+use crate::build_loop::{BuildId, BuildLoop, Event, Reason, Warning, WatchSet};
+use crate::builder;
+use crate::cli::WatchOptions;
+use crate::duration;
+use crate::ops::{self, events, ok, ok_msg, ping, ErrorCode, ExitError, OpResult};
+use crate::project::Project;
+use crate::roots::Roots;
+use crate::signal;
+use crate::socket::path::SocketPath;
+use crate::state_dump;
+use crate::status_page;
+use crate::trust::Trust;
+use crate::NixFile;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod tui;
+
+/// How many of the last log lines of a failed build `--json` prints.
+const LOG_EXCERPT_LINES: usize = 20;
+
+/// How many recent events are kept for the dump on `SIGUSR1`.
+const RECENT_EVENTS: usize = 20;
+
+/// See the documentation for lorri::cli::Command::Shell for more
+/// details.
+pub fn main(project: &Project, opts: WatchOptions) -> OpResult {
+    let paths = ops::get_paths()?;
+    Trust::new(paths.allow_dir())
+        .check(project.expression())
+        .map_err(|e| ExitError::errmsg(e.to_string()).with_code(ErrorCode::NotAllowed))?;
+    let config = ops::get_config(&paths)?.project(project.expression());
+    if opts.dry_run {
+        return dry_run(project, config.attr());
+    }
+    if opts.attach {
+        return attach(project, opts.json);
+    }
+    if opts.tui {
+        return tui::main(project, config, Trust::new(paths.allow_dir()));
+    }
+
+    let (tx, rx) = channel();
+    // TODO: handle unwrap
+    let roots = Roots::from_project(project).unwrap();
+
+    let mut build_loop = BuildLoop::new(project.expression().to_owned(), roots.clone(), config);
+    build_loop.check_trust(Trust::new(paths.allow_dir()));
+
+    let gc_root_dir = project.base_gc_root_path.to_owned();
+    let build_thread = {
+        thread::spawn(move || loop {
+            build_loop.forever(tx.clone());
+            match build_loop.follow_move(&gc_root_dir) {
+                Ok(true) => {}
+                Ok(false) => break,
+                Err(e) => {
+                    warn!("Cannot continue with the moved project: {}", e);
+                    break;
+                }
+            }
+        })
+    };
+
+    let recent = Arc::new(Mutex::new(VecDeque::new()));
+    {
+        let recent = recent.clone();
+        let nix_file = project.expression().clone();
+        let dumped = signal::on_dump(move |_| {
+            let recent_events = recent
+                .lock()
+                .expect("recent events mutex poisoned")
+                .iter()
+                .cloned()
+                .collect::<Vec<Event>>();
+            let state = recent_events
+                .iter()
+                .rev()
+                .find(|event| match event {
+                    Event::Started(_, _) | Event::Completed(_) | Event::Failure(_) => true,
+                    _ => false,
+                })
+                .map_or_else(|| String::from("no build yet"), status_page::summary);
+            let dump = state_dump::Project {
+                nix_file: &nix_file,
+                state,
+                watch_set: WatchSet::load(&roots).ok(),
+                recent_events: &recent_events,
+            };
+            eprintln!("{}", state_dump::dump("lorri watch", None, &[dump]));
+        });
+        if let Err(e) = dumped {
+            warn!("Cannot dump the state on SIGUSR1: {}", e);
+        }
+    }
+
+    let mut nix_file = project.expression().clone();
+    for msg in rx {
+        if let Event::Warning(Warning::ProjectMoved(_, Some(ref moved))) = msg {
+            nix_file = moved.clone();
+        }
+        // log lines are kept in the build logs instead
+        match msg {
+            Event::Log(_, _) => {}
+            _ => {
+                let mut recent = recent.lock().expect("recent events mutex poisoned");
+                if recent.len() == RECENT_EVENTS {
+                    recent.pop_front();
+                }
+                recent.push_back(msg.clone());
+            }
+        }
+        print_event(&nix_file, &msg, opts.json);
+    }
+
+    build_thread.join().unwrap();
+
+    ok()
+}
+
+/// Print what the daemon does for the project, like a build loop of
+/// our own would.
+fn attach(project: &Project, json: bool) -> OpResult {
+    let nix_file = std::fs::canonicalize(project.expression().as_os_str())
+        .map(NixFile::from)
+        .map_err(|e| ExitError::errmsg(format!("Cannot find {}: {}", project.expression(), e)))?;
+    let paths = ops::get_paths()?;
+    ping::ping(
+        &SocketPath::from(paths.daemon_socket_file()),
+        nix_file.clone(),
+    )?;
+    events::subscribe(Some(nix_file), |project_event| {
+        print_event(&project_event.nix_file, &project_event.event, json)
+    })
+}
+
+/// Print `event` for humans, or as a line of JSON (see `WatchEvent`).
+/// Log lines are left out, failures carry the ones which matter.
+fn print_event(nix_file: &NixFile, event: &Event, json: bool) {
+    if let Event::Log(_, _) = event {
+        return;
+    }
+    if !json {
+        println!("{:#?}", event);
+        return;
+    }
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if let Some(event) = WatchEvent::new(nix_file, event, time) {
+        println!(
+            "{}",
+            serde_json::to_string(&event).expect("events serialize to JSON")
+        );
+    }
+}
+
+/// An event as `lorri watch --json` prints it. Unlike `Event`, its
+/// schema is stable: every field is always present, `null` or empty
+/// if it does not apply to the event’s `type`.
+#[derive(Serialize, Debug, PartialEq)]
+struct WatchEvent {
+    /// `started`, `completed`, `failure`, `heartbeat`, `warning` or
+    /// `env_diff`.
+    #[serde(rename = "type")]
+    kind: &'static str,
+    /// The build the event belongs to.
+    build_id: Option<BuildId>,
+    /// When the event was received, in seconds since the epoch.
+    time: u64,
+    /// The nix file of the project.
+    nix_file: PathBuf,
+    /// `started`: the changed files, `completed`: the GC roots of the
+    /// environment, `warning`: the paths which are no longer watched,
+    /// `env_diff`: the new `PATH` entries.
+    paths: Vec<PathBuf>,
+    /// `failure`: the last lines of the build log.
+    log: Vec<String>,
+    /// A description for humans: why the build started, what a
+    /// running build does, the warning or the changed variables.
+    message: Option<String>,
+    /// `heartbeat`: seconds since the build started.
+    elapsed: Option<f64>,
+    /// `completed`: the result of a build before lorri started, no
+    /// `started` event precedes it.
+    cached: bool,
+}
+
+impl WatchEvent {
+    /// `None` for events which are not printed (log lines).
+    fn new(nix_file: &NixFile, event: &Event, time: u64) -> Option<WatchEvent> {
+        let mut out = WatchEvent {
+            kind: "",
+            build_id: None,
+            time,
+            nix_file: PathBuf::from(nix_file.as_os_str()),
+            paths: vec![],
+            log: vec![],
+            message: None,
+            elapsed: None,
+            cached: false,
+        };
+        match event {
+            Event::Started(build_id, reason) => {
+                out.kind = "started";
+                out.build_id = Some(*build_id);
+                if let Reason::FilesChanged(changes) = reason {
+                    out.paths = changes.iter().map(|c| c.path.clone()).collect();
+                }
+                out.message = Some(reason.to_string());
+            }
+            Event::Completed(results) => {
+                out.kind = "completed";
+                out.build_id = Some(results.build_id);
+                out.cached = results.cached;
+                let mut roots = results.named_drvs.iter().collect::<Vec<_>>();
+                roots.sort_by_key(|&(name, _)| name);
+                out.paths = roots
+                    .into_iter()
+                    .map(|(_, root)| root.as_path().to_owned())
+                    .collect();
+            }
+            Event::Failure(failure) => {
+                out.kind = "failure";
+                out.build_id = Some(failure.build_id);
+                let skip = failure.log_lines.len().saturating_sub(LOG_EXCERPT_LINES);
+                out.log = failure.log_lines[skip..].to_vec();
+            }
+            Event::Heartbeat(heartbeat) => {
+                out.kind = "heartbeat";
+                out.build_id = Some(heartbeat.build_id);
+                out.message = Some(heartbeat.phase.to_string());
+                out.elapsed = Some(duration::as_secs_f64(heartbeat.elapsed));
+            }
+            Event::Warning(warning) => {
+                out.kind = "warning";
+                if let Warning::ExcludedBuildOutputs(paths) = warning {
+                    out.paths = paths.clone();
+                }
+                out.message = Some(warning.to_string().trim_end().to_owned());
+            }
+            Event::EnvDiff(diff) => {
+                out.kind = "env_diff";
+                out.paths = diff.path_added.iter().map(PathBuf::from).collect();
+                out.message = Some(diff.to_string().trim_end().to_owned());
+            }
+            Event::Log(_, _) => return None,
+        }
+        Some(out)
+    }
+}
+
+/// Report what building the project would do, without building it.
+fn dry_run(project: &Project, attr: Option<&str>) -> OpResult {
+    let result = builder::dry_run(project.expression(), attr).map_err(|e| {
+        let code = if e.nix_missing() {
+            ErrorCode::NixMissing
+        } else {
+            ErrorCode::EvaluationFailed
+        };
+        ExitError::errmsg(format!(
+            "Evaluation of {} failed: {:?}",
+            project.expression(),
+            e
+        ))
+        .with_code(code)
+    })?;
+
+    if result.to_build.is_empty() && result.to_fetch.is_empty() {
+        return ok_msg("Nothing to build or fetch, everything is up to date.");
+    }
+
+    let list = |paths: &[PathBuf]| {
+        paths
+            .iter()
+            .map(|p| format!("  {}\n", p.display()))
+            .collect::<String>()
+    };
+    ok_msg(format!(
+        "{} derivations would be built:\n{}{} paths would be fetched:\n{}",
+        result.to_build.len(),
+        list(&result.to_build),
+        result.to_fetch.len(),
+        list(&result.to_fetch)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WatchEvent;
+    use crate::build_loop::{BuildExitFailure, BuildId, Event, Reason};
+    use crate::watch::{Change, ChangeKind};
+    use crate::NixFile;
+    use std::path::PathBuf;
+
+    #[test]
+    fn json_events_have_a_stable_schema() {
+        let nix_file = NixFile::from(PathBuf::from("/home/alice/foo/shell.nix"));
+        let build_id = BuildId::next();
+        let started = Event::Started(
+            build_id,
+            Reason::FilesChanged(vec![Change {
+                path: PathBuf::from("/home/alice/foo/default.nix"),
+                kind: ChangeKind::Modified,
+            }]),
+        );
+        let json = serde_json::to_value(WatchEvent::new(&nix_file, &started, 1000)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "started",
+                "build_id": serde_json::to_value(build_id).unwrap(),
+                "time": 1000,
+                "nix_file": "/home/alice/foo/shell.nix",
+                "paths": ["/home/alice/foo/default.nix"],
+                "log": [],
+                "message": "/home/alice/foo/default.nix modified",
+                "elapsed": null,
+                "cached": false,
+            })
+        );
+
+        let failure = Event::Failure(BuildExitFailure {
+            build_id,
+            log_lines: (0..30).map(|i| format!("line {}", i)).collect(),
+        });
+        let event = WatchEvent::new(&nix_file, &failure, 1000).unwrap();
+        assert_eq!(event.kind, "failure");
+        assert_eq!(event.log.len(), super::LOG_EXCERPT_LINES);
+        assert_eq!(event.log.last().map(String::as_str), Some("line 29"));
+
+        assert_eq!(
+            WatchEvent::new(&nix_file, &Event::Log(build_id, String::from("hi")), 1000),
+            None
+        );
+    }
+}
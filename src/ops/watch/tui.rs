@@ -0,0 +1,585 @@
+//! `lorri watch --tui`: the status of the build loop, its log and its
+//! inputs on one screen, with keys to rebuild, pause and quit.
+
+extern crate nix;
+
+use self::nix::sys::termios::{self, SetArg, Termios};
+use crate::build_loop::{BuildId, BuildLoop, Event, WatchSet};
+use crate::builder::{self, Phase};
+use crate::config::ProjectConfig;
+use crate::ops::{ok, ExitError, OpResult};
+use crate::project::Project;
+use crate::roots::Roots;
+use crate::trust::Trust;
+use crate::NixFile;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many log lines are kept to scroll back to.
+const MAX_LOG_LINES: usize = 5000;
+
+/// How often the screen is redrawn without events, so the time a
+/// build takes keeps counting.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The key bindings, as shown in the last line.
+const KEYS_HELP: &str = "r rebuild  p pause  ↑/↓ PgUp/PgDn scroll  q quit";
+
+/// What the screen is updated for.
+enum Input {
+    /// The build loop did something.
+    Event(Event),
+    /// The user pressed a key.
+    Key(Key),
+    /// The build loop continues with the project at its new location,
+    /// see `BuildLoop::follow_move`.
+    Moved(NixFile, Roots),
+}
+
+/// The keys `lorri watch --tui` reacts to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Key {
+    /// `r`: build now, even without changes.
+    Rebuild,
+    /// `p` or space: stop building changes, or resume.
+    Pause,
+    /// `q` or Ctrl-C.
+    Quit,
+    /// Arrow up or `k`: scroll the log back a line.
+    Up,
+    /// Arrow down or `j`: scroll the log forward a line.
+    Down,
+    /// Page up: scroll the log back a page.
+    PageUp,
+    /// Page down: scroll the log forward a page.
+    PageDown,
+}
+
+/// The keys which send more than one byte.
+const ESCAPE_SEQUENCES: [(&[u8], Key); 4] = [
+    (b"\x1b[A", Key::Up),
+    (b"\x1b[B", Key::Down),
+    (b"\x1b[5~", Key::PageUp),
+    (b"\x1b[6~", Key::PageDown),
+];
+
+/// The keys in `bytes`, read from a terminal in raw mode. Unknown
+/// keys are ignored.
+fn parse_keys(bytes: &[u8]) -> Vec<Key> {
+    let mut keys = vec![];
+    let mut rest = bytes;
+    while let Some((&first, tail)) = rest.split_first() {
+        let escape = ESCAPE_SEQUENCES
+            .iter()
+            .find(|(sequence, _)| rest.starts_with(sequence));
+        if let Some((sequence, key)) = escape {
+            keys.push(*key);
+            rest = &rest[sequence.len()..];
+            continue;
+        }
+        match first {
+            b'r' => keys.push(Key::Rebuild),
+            b'p' | b' ' => keys.push(Key::Pause),
+            // Ctrl-C does not send SIGINT in raw mode
+            b'q' | 3 => keys.push(Key::Quit),
+            b'k' => keys.push(Key::Up),
+            b'j' => keys.push(Key::Down),
+            _ => {}
+        }
+        rest = tail;
+    }
+    keys
+}
+
+/// What the build loop is doing, as far as the screen is concerned.
+#[derive(Debug, PartialEq)]
+enum Status {
+    /// No build started yet.
+    Waiting,
+    /// A build is running.
+    Building {
+        /// The running build.
+        build_id: BuildId,
+        /// Why it started, see `Reason`.
+        reason: String,
+        /// What it is doing, updated by heartbeats.
+        phase: Phase,
+        /// When it started.
+        started: Instant,
+    },
+    /// The last build succeeded.
+    Succeeded(BuildId),
+    /// The last build failed, its log tells why.
+    Failed(BuildId),
+}
+
+/// Everything the screen shows.
+struct State {
+    /// The project being watched.
+    nix_file: NixFile,
+    /// See `Status`.
+    status: Status,
+    /// Whether the user paused the build loop.
+    paused: bool,
+    /// The last `MAX_LOG_LINES` log lines of all builds, with a line
+    /// marking the start of each build.
+    log: VecDeque<String>,
+    /// How many lines the log is scrolled back from its end.
+    scroll: usize,
+    /// The files and directories watched, see `WatchSet::inputs`.
+    inputs: Vec<PathBuf>,
+    /// The last warning, until the next build starts.
+    warning: Option<String>,
+}
+
+impl State {
+    fn new(nix_file: NixFile) -> State {
+        State {
+            nix_file,
+            status: Status::Waiting,
+            paused: false,
+            log: VecDeque::new(),
+            scroll: 0,
+            inputs: vec![],
+            warning: None,
+        }
+    }
+
+    /// Show what `event` changed.
+    fn event(&mut self, event: Event) {
+        match event {
+            Event::Started(build_id, reason) => {
+                self.push_log(format!("lorri: build {} started: {}", build_id, reason));
+                self.status = Status::Building {
+                    build_id,
+                    reason: reason.to_string(),
+                    phase: Phase::Evaluating,
+                    started: Instant::now(),
+                };
+                self.warning = None;
+            }
+            Event::Completed(results) => {
+                if results.cached {
+                    self.push_log(format!(
+                        "lorri: reusing the result of build {}",
+                        results.build_id
+                    ));
+                }
+                self.status = Status::Succeeded(results.build_id)
+            }
+            Event::Failure(failure) => self.status = Status::Failed(failure.build_id),
+            Event::Heartbeat(heartbeat) => {
+                if let Status::Building {
+                    build_id,
+                    ref mut phase,
+                    ..
+                } = self.status
+                {
+                    if build_id == heartbeat.build_id {
+                        *phase = heartbeat.phase;
+                    }
+                }
+            }
+            Event::Warning(warning) => {
+                let warning = warning.to_string().trim_end().to_owned();
+                self.push_log(format!("lorri: {}", warning));
+                self.warning = Some(warning);
+            }
+            Event::EnvDiff(diff) => self.push_log(format!("lorri: environment: {}", diff)),
+            Event::Log(_, line) => self.push_log(line),
+        }
+    }
+
+    /// Scroll the log for `key`, for a log pane of `log_height` lines.
+    fn scroll(&mut self, key: Key, log_height: usize) {
+        let max = self.log.len().saturating_sub(log_height);
+        self.scroll = match key {
+            Key::Up => self.scroll + 1,
+            Key::Down => self.scroll.saturating_sub(1),
+            Key::PageUp => self.scroll + log_height,
+            Key::PageDown => self.scroll.saturating_sub(log_height),
+            _ => self.scroll,
+        }
+        .min(max);
+    }
+
+    fn push_log(&mut self, line: String) {
+        if self.log.len() == MAX_LOG_LINES {
+            self.log.pop_front();
+        }
+        self.log.push_back(line);
+        // keep showing the same lines while scrolled back
+        if self.scroll > 0 {
+            self.scroll = (self.scroll + 1).min(self.log.len().saturating_sub(1));
+        }
+    }
+
+    /// How many lines the log pane and the inputs pane get on a
+    /// screen `height` lines high.
+    fn layout(&self, height: usize) -> (usize, usize) {
+        // title, status, warning, the two pane headers, keys
+        let available = height.saturating_sub(6);
+        let inputs = self.inputs.len().max(1).min((available / 4).max(2));
+        (available.saturating_sub(inputs), inputs)
+    }
+
+    /// The lines of a screen `width` columns wide and `height` lines
+    /// high, at time `now`.
+    fn render(&self, width: usize, height: usize, now: Instant) -> Vec<String> {
+        let (log_height, inputs_height) = self.layout(height);
+        let mut lines = vec![
+            format!(
+                "lorri watch {}{}",
+                self.nix_file,
+                if self.paused { "  [paused]" } else { "" }
+            ),
+            self.status_line(now),
+            self.warning
+                .as_ref()
+                .map(|warning| format!("warning: {}", warning))
+                .unwrap_or_default(),
+            pane_header(
+                "log",
+                if self.scroll > 0 {
+                    format!("scrolled back {} lines", self.scroll)
+                } else {
+                    String::new()
+                },
+                width,
+            ),
+        ];
+
+        let end = self.log.len() - self.scroll;
+        let start = end.saturating_sub(log_height);
+        lines.extend(self.log.iter().skip(start).take(end - start).cloned());
+        lines.resize(4 + log_height, String::new());
+
+        lines.push(pane_header(
+            "watched inputs",
+            self.inputs.len().to_string(),
+            width,
+        ));
+        if self.inputs.len() > inputs_height {
+            let shown = inputs_height.saturating_sub(1);
+            lines.extend(self.inputs[..shown].iter().map(|p| p.display().to_string()));
+            lines.push(format!("... and {} more", self.inputs.len() - shown));
+        } else {
+            lines.extend(self.inputs.iter().map(|p| p.display().to_string()));
+        }
+        lines.resize(5 + log_height + inputs_height, String::new());
+
+        lines.push(String::from(if self.paused {
+            "r rebuild  p resume  ↑/↓ PgUp/PgDn scroll  q quit"
+        } else {
+            KEYS_HELP
+        }));
+        lines.truncate(height);
+        lines.iter().map(|line| fit(line, width)).collect()
+    }
+
+    fn status_line(&self, now: Instant) -> String {
+        match self.status {
+            Status::Waiting => String::from("waiting for the first build"),
+            Status::Building {
+                build_id,
+                ref reason,
+                phase,
+                started,
+            } => format!(
+                "build {}: {} for {}s ({})",
+                build_id,
+                phase,
+                if now > started {
+                    (now - started).as_secs()
+                } else {
+                    0
+                },
+                reason
+            ),
+            Status::Succeeded(build_id) => format!("build {} succeeded", build_id),
+            Status::Failed(build_id) => format!("build {} failed, see the log", build_id),
+        }
+    }
+}
+
+/// A line separating panes: `── title (detail) ──...`.
+fn pane_header(title: &str, detail: String, width: usize) -> String {
+    let mut header = format!("── {} ", title);
+    if !detail.is_empty() {
+        header.push_str(&format!("({}) ", detail));
+    }
+    let used = header.chars().count();
+    header.push_str(&"─".repeat(width.saturating_sub(used)));
+    header
+}
+
+/// `line` cut to `width` characters, without control characters
+/// (e.g. colors), which would mess up the screen.
+fn fit(line: &str, width: usize) -> String {
+    line.chars()
+        .map(|c| if c == '\t' { ' ' } else { c })
+        .filter(|c| !c.is_control())
+        .take(width)
+        .collect()
+}
+
+/// The inputs of the last build, empty if there was none yet.
+fn watched_inputs(roots: &Roots) -> Vec<PathBuf> {
+    WatchSet::load(roots)
+        .map(|watch_set| watch_set.inputs.keys().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// The terminal in raw mode on the alternate screen, restored when
+/// dropped.
+struct Terminal {
+    /// The settings to restore.
+    original: Termios,
+}
+
+impl Terminal {
+    fn enter() -> Result<Terminal, ExitError> {
+        let is_tty = |fd| self::nix::unistd::isatty(fd).unwrap_or(false);
+        if !is_tty(0) || !is_tty(1) {
+            return Err(ExitError::errmsg(
+                "lorri watch --tui needs a terminal, use --json for scripts.",
+            ));
+        }
+        let original = termios::tcgetattr(0)
+            .map_err(|e| ExitError::errmsg(format!("Cannot read the terminal settings: {}", e)))?;
+        let mut raw = original.clone();
+        termios::cfmakeraw(&mut raw);
+        termios::tcsetattr(0, SetArg::TCSANOW, &raw)
+            .map_err(|e| ExitError::errmsg(format!("Cannot set up the terminal: {}", e)))?;
+        let terminal = Terminal { original };
+        // alternate screen, hidden cursor
+        terminal.write("\x1b[?1049h\x1b[?25l");
+        Ok(terminal)
+    }
+
+    /// The width and height of the terminal.
+    fn size(&self) -> (usize, usize) {
+        let mut size: self::nix::libc::winsize = unsafe { std::mem::zeroed() };
+        match unsafe { self::nix::libc::ioctl(1, self::nix::libc::TIOCGWINSZ, &mut size) } {
+            0 if size.ws_col > 0 && size.ws_row > 0 => {
+                (usize::from(size.ws_col), usize::from(size.ws_row))
+            }
+            _ => (80, 24),
+        }
+    }
+
+    /// Replace the screen with `lines`.
+    fn draw(&self, lines: &[String]) {
+        let mut screen = String::from("\x1b[H");
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 {
+                screen.push_str("\r\n");
+            }
+            screen.push_str(line);
+            screen.push_str("\x1b[K");
+        }
+        screen.push_str("\x1b[J");
+        self.write(&screen);
+    }
+
+    fn write(&self, out: &str) {
+        let mut stdout = std::io::stdout();
+        if let Err(e) = stdout
+            .write_all(out.as_bytes())
+            .and_then(|_| stdout.flush())
+        {
+            debug!("could not write to the terminal: {}", e);
+        }
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.write("\x1b[?25h\x1b[?1049l");
+        if let Err(e) = termios::tcsetattr(0, SetArg::TCSANOW, &self.original) {
+            warn!("Cannot restore the terminal settings: {}", e);
+        }
+    }
+}
+
+/// See the documentation for lorri::cli::Command::Watch for more
+/// details. `trust` has to allow every build.
+pub fn main(project: &Project, config: ProjectConfig, trust: Trust) -> OpResult {
+    let nix_file = project.expression().clone();
+    let mut roots = Roots::from_project(project)
+        .map_err(|e| ExitError::errmsg(format!("Cannot open the GC roots: {}", e)))?;
+    let terminal = Terminal::enter()?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let rebuild = Arc::new(AtomicBool::new(false));
+    let paused = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = channel();
+    let mut build_loop = BuildLoop::new(nix_file.clone(), roots.clone(), config);
+    build_loop.stop_on(stop.clone());
+    build_loop.rebuild_on(rebuild.clone());
+    build_loop.pause_on(paused.clone());
+    build_loop.check_trust(trust);
+    let events = tx.clone();
+    let gc_root_dir = project.base_gc_root_path.to_owned();
+    thread::spawn(move || loop {
+        let loop_events = events.clone();
+        build_loop.forever(move |event| {
+            let _ = loop_events.send(Input::Event(event));
+        });
+        match build_loop.follow_move(&gc_root_dir) {
+            Ok(true) => {
+                let moved = build_loop.nix_file().clone();
+                let _ = events.send(Input::Moved(moved, build_loop.roots().clone()));
+            }
+            Ok(false) => break,
+            Err(e) => {
+                warn!("Cannot continue with the moved project: {}", e);
+                break;
+            }
+        }
+    });
+    thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut buf = [0; 64];
+        while let Ok(n) = stdin.read(&mut buf) {
+            if n == 0 {
+                break;
+            }
+            for key in parse_keys(&buf[..n]) {
+                if tx.send(Input::Key(key)).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+
+    let mut state = State::new(nix_file.clone());
+    state.inputs = watched_inputs(&roots);
+    'screen: loop {
+        let (width, height) = terminal.size();
+        terminal.draw(&state.render(width, height, Instant::now()));
+        let first = match rx.recv_timeout(REDRAW_INTERVAL) {
+            Ok(input) => input,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        // draw once for a burst of log lines
+        for input in std::iter::once(first).chain(rx.try_iter()) {
+            match input {
+                Input::Event(event) => {
+                    let finished = match event {
+                        Event::Completed(_) | Event::Failure(_) => true,
+                        _ => false,
+                    };
+                    state.event(event);
+                    if finished {
+                        state.inputs = watched_inputs(&roots);
+                    }
+                }
+                Input::Moved(nix_file, moved_roots) => {
+                    state.nix_file = nix_file;
+                    state.inputs = watched_inputs(&moved_roots);
+                    roots = moved_roots;
+                }
+                Input::Key(Key::Quit) => break 'screen,
+                Input::Key(Key::Rebuild) => rebuild.store(true, Ordering::SeqCst),
+                Input::Key(Key::Pause) => {
+                    state.paused = !state.paused;
+                    paused.store(state.paused, Ordering::SeqCst);
+                }
+                Input::Key(key) => {
+                    let log_height = state.layout(height).0;
+                    state.scroll(key, log_height)
+                }
+            }
+        }
+    }
+
+    stop.store(true, Ordering::SeqCst);
+    builder::cancel(&state.nix_file);
+    drop(terminal);
+    ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_keys, Key, State, KEYS_HELP};
+    use crate::build_loop::{BuildExitFailure, BuildId, Event, Reason};
+    use crate::NixFile;
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn keys() {
+        assert_eq!(
+            parse_keys(b"rp q\x1b[A\x1b[Bx\x1b[5~\x1b[6~jk\x03"),
+            vec![
+                Key::Rebuild,
+                Key::Pause,
+                Key::Pause,
+                Key::Quit,
+                Key::Up,
+                Key::Down,
+                Key::PageUp,
+                Key::PageDown,
+                Key::Down,
+                Key::Up,
+                Key::Quit,
+            ]
+        );
+    }
+
+    #[test]
+    fn screen() {
+        let mut state = State::new(NixFile::from(PathBuf::from("/src/shell.nix")));
+        state.inputs = (0..5)
+            .map(|i| PathBuf::from(format!("/src/{}.nix", i)))
+            .collect();
+        let build_id = BuildId::next();
+        state.event(Event::Started(build_id, Reason::Startup));
+        for i in 0..10 {
+            state.event(Event::Log(build_id, format!("line {}", i)));
+        }
+        let now = Instant::now() + Duration::from_secs(3);
+        let screen = state.render(30, 12, now);
+        assert_eq!(
+            screen,
+            vec![
+                String::from("lorri watch /src/shell.nix"),
+                format!("build {}: evaluating for 3s (first build)", build_id)
+                    .chars()
+                    .take(30)
+                    .collect(),
+                String::new(),
+                String::from("── log ───────────────────────"),
+                String::from("line 6"),
+                String::from("line 7"),
+                String::from("line 8"),
+                String::from("line 9"),
+                String::from("── watched inputs (5) ────────"),
+                String::from("/src/0.nix"),
+                String::from("... and 4 more"),
+                KEYS_HELP.chars().take(30).collect(),
+            ]
+        );
+
+        let log_height = state.layout(12).0;
+        state.scroll(Key::PageUp, log_height);
+        state.event(Event::Failure(BuildExitFailure {
+            build_id,
+            log_lines: vec![],
+        }));
+        state.paused = true;
+        let screen = state.render(80, 12, now);
+        assert_eq!(screen[0], "lorri watch /src/shell.nix  [paused]");
+        assert_eq!(screen[1], format!("build {} failed, see the log", build_id));
+        assert!(screen[3].starts_with("── log (scrolled back 4 lines) ──"));
+        assert_eq!(&screen[4..8], &["line 2", "line 3", "line 4", "line 5"]);
+    }
+}
@@ -1,7 +1,7 @@
 //! Given a list of paths, reduce them to a minimum set of paths
 //! which should be watched for changes.
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::path::{Path, PathBuf};
 
 #[derive(PartialEq, Debug)]
@@ -67,6 +67,32 @@ pub fn reduce_paths(paths: &[PathBuf]) -> HashSet<PathBuf> {
         })
 }
 
+/// Group `paths` by the path of `reduced` (see `reduce_paths`) which
+/// covers them. Paths which were removed by the reduction are left out.
+pub fn group_paths(
+    paths: &[PathBuf],
+    reduced: &HashSet<PathBuf>,
+) -> BTreeMap<PathBuf, Vec<PathBuf>> {
+    let mut groups: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    for path in paths {
+        let covering = reduced
+            .iter()
+            .filter(|reduced| path.starts_with(reduced))
+            .max_by_key(|reduced| reduced.components().count());
+        if let Some(covering) = covering {
+            groups
+                .entry(covering.clone())
+                .or_default()
+                .push(path.clone());
+        }
+    }
+    for group in groups.values_mut() {
+        group.sort();
+        group.dedup();
+    }
+    groups
+}
+
 /// Reduce a path coming from a user's channel to the location where
 /// the channel becomes switchable.
 ///
@@ -149,25 +175,71 @@ fn reduce_channel_path(path: &PathBuf) -> ReductionOp {
 /// symlinks inside of it.
 ///
 /// Note that because store paths are immutable, these paths can
-/// be discarded.
+/// be discarded. Evaluation reports the out-of-store originals of
+/// copied sources separately, those are kept.
 fn reduce_nix_store_path(path: &PathBuf) -> ReductionOp {
     let nix_store = Path::new("/nix/store");
 
-    // This is only a valid reduction if the Nix store path
-    // does not contain a symlink to a location out of the Nix store.
-    // Because of that, we check that it starts with /nix/store before
-    // and after making it canonical.
+    // A Nix store path can contain a symlink to a location out of the
+    // Nix store, which can change. Because of that, we check whether
+    // it still starts with /nix/store after making it canonical.
     if !path.starts_with(nix_store) {
         return ReductionOp::NoOpinion;
     }
 
-    if let Ok(path) = path.canonicalize() {
-        // Verify the path still starts with /nix/store
-        // (see the prior comment block)
-        if path.starts_with(nix_store) {
-            return ReductionOp::Reduction(PathReduction::Remove);
+    match path.canonicalize() {
+        Ok(ref canonical) if !canonical.starts_with(nix_store) => {
+            ReductionOp::Reduction(PathReduction::Reduced(canonical.clone()))
         }
+        // in the store, or gone (e.g. garbage collected)
+        _ => ReductionOp::Reduction(PathReduction::Remove),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{group_paths, reduce_paths};
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+
+    #[test]
+    fn store_paths_are_removed() {
+        let temp = tempfile::tempdir().unwrap();
+        let paths = vec![
+            PathBuf::from("/nix/store/00000000000000000000000000000000-gone/default.nix"),
+            temp.path().join("shell.nix"),
+        ];
+        assert_eq!(
+            reduce_paths(&paths),
+            vec![temp.path().join("shell.nix")].into_iter().collect()
+        );
     }
 
-    ReductionOp::NoOpinion
+    #[test]
+    fn groups_by_covering_path() {
+        let p = PathBuf::from;
+        let paths = vec![
+            p("/home/alice/foo/shell.nix"),
+            p("/home/alice/foo/nix/sources.nix"),
+            p("/home/alice/bar.nix"),
+            p("/nix/store/aaaa-source/default.nix"),
+        ];
+        let reduced = vec![p("/home/alice/foo"), p("/home/alice/bar.nix")]
+            .into_iter()
+            .collect::<HashSet<_>>();
+
+        let groups = group_paths(&paths, &reduced);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(
+            groups[&p("/home/alice/foo")],
+            vec![
+                p("/home/alice/foo/nix/sources.nix"),
+                p("/home/alice/foo/shell.nix")
+            ]
+        );
+        assert_eq!(
+            groups[&p("/home/alice/bar.nix")],
+            vec![p("/home/alice/bar.nix")]
+        );
+    }
 }
@@ -3,6 +3,7 @@
 
 use std::io;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 use NixFile;
 
@@ -27,6 +28,101 @@ pub enum ProjectLoadError {
     Io(io::Error),
 }
 
+/// Identifies the nix file a project state directory belongs to,
+/// stored in `project.json` inside of it. The file’s device and
+/// inode stay the same when the project directory is moved, so its
+/// state can be found again under the new path.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct ProjectFile {
+    nix_file: PathBuf,
+    device: u64,
+    inode: u64,
+}
+
+impl ProjectFile {
+    fn read(state_dir: &Path) -> Option<ProjectFile> {
+        let contents = std::fs::read(state_dir.join("project.json")).ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+}
+
+/// The nix file of the project `state_dir` (a directory in the base
+/// GC root directory) belongs to, if it was recorded.
+pub fn recorded_nix_file(state_dir: &Path) -> Option<PathBuf> {
+    ProjectFile::read(state_dir).map(|project_file| project_file.nix_file)
+}
+
+/// Write the `ProjectFile` of `nix_file` to `state_dir`, unless it is
+/// up to date. Saving the file atomically gives it a new inode, so the
+/// `BuildLoop` does this before every build.
+pub fn record(state_dir: &Path, nix_file: &NixFile) -> Result<(), io::Error> {
+    let metadata = std::fs::metadata(nix_file.as_os_str())?;
+    let project_file = ProjectFile {
+        nix_file: PathBuf::from(nix_file.as_os_str()),
+        device: metadata.dev(),
+        inode: metadata.ino(),
+    };
+    if ProjectFile::read(state_dir).as_ref() == Some(&project_file) {
+        return Ok(());
+    }
+    std::fs::write(
+        state_dir.join("project.json"),
+        serde_json::to_vec(&project_file)?,
+    )
+}
+
+/// How many directories `moved_nix_file` looks through at most.
+const MAX_MOVE_SEARCH: usize = 10_000;
+
+/// Where the nix file recorded in `state_dir` is now, if its project
+/// directory (or a parent) was moved: the same file, with the same
+/// name and as deep below the nearest directory which still exists.
+pub fn moved_nix_file(state_dir: &Path) -> Option<PathBuf> {
+    let recorded = ProjectFile::read(state_dir)?;
+    if recorded.nix_file.exists() {
+        return None;
+    }
+    let file_name = recorded.nix_file.file_name()?;
+    let base = recorded
+        .nix_file
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.is_dir())?;
+    let depth = recorded
+        .nix_file
+        .strip_prefix(base)
+        .ok()?
+        .components()
+        .count()
+        - 1;
+
+    let mut dirs = vec![base.to_path_buf()];
+    let mut searched = 0;
+    for _ in 0..depth {
+        let mut below = vec![];
+        for dir in dirs {
+            for entry in std::fs::read_dir(&dir).into_iter().flatten().flatten() {
+                // `file_type` doesn’t follow symlinks, which could lead in circles
+                if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                    searched += 1;
+                    if searched > MAX_MOVE_SEARCH {
+                        debug!("Not searching more than {} directories", MAX_MOVE_SEARCH);
+                        return None;
+                    }
+                    below.push(entry.path());
+                }
+            }
+        }
+        dirs = below;
+    }
+    dirs.into_iter()
+        .map(|dir| dir.join(file_name))
+        .find(|candidate| match std::fs::metadata(candidate) {
+            Ok(metadata) => metadata.dev() == recorded.device && metadata.ino() == recorded.inode,
+            Err(_) => false,
+        })
+}
+
 impl<'a, 'b> Project<'a, 'b> {
     /// Given an absolute path to a shell.nix,
     /// construct a Project and a ProjectConfig.
@@ -46,16 +142,69 @@ impl<'a, 'b> Project<'a, 'b> {
     /// Absolute path to the projects' gc root directory, for pinning
     /// build and evaluation products
     pub fn gc_root_path(&self) -> Result<PathBuf, std::io::Error> {
+        self.gc_root_path_and_previous_id().map(|(path, _)| path)
+    }
+
+    /// `gc_root_path`, and the id (see `hash`) the project had before
+    /// it was moved, if its state directory was taken over just now.
+    /// The roots in it have to be registered again, see
+    /// `Roots::take_over`.
+    pub fn gc_root_path_and_previous_id(
+        &self,
+    ) -> Result<(PathBuf, Option<String>), std::io::Error> {
         // TODO: use a hash of the project’s abolute path here
         // to avoid collisions
-        let path = self.base_gc_root_path.join(self.hash()).join("gc_root");
+        let state_dir = self.base_gc_root_path.join(self.hash());
+        let mut previous_id = None;
+        if !state_dir.is_dir() {
+            if let Some(moved) = self.moved_state_dir() {
+                info!(
+                    "{} was moved, taking over its state from {}",
+                    self.nix_file,
+                    moved.display()
+                );
+                std::fs::rename(&moved, &state_dir)?;
+                std::fs::remove_file(state_dir.join("project.json"))?;
+                previous_id = moved
+                    .file_name()
+                    .map(|name| name.to_string_lossy().into_owned());
+            }
+        }
 
+        let path = state_dir.join("gc_root");
         if !path.is_dir() {
             debug!("Creating all directories for GC roots in {:?}", path);
             std::fs::create_dir_all(&path)?;
         }
+        if let Err(e) = record(&state_dir, self.nix_file) {
+            debug!("Could not write {:?}/project.json: {}", state_dir, e);
+        }
 
-        Ok(path.to_path_buf())
+        Ok((path.to_path_buf(), previous_id))
+    }
+
+    /// The state directory of this project from before its directory
+    /// (or a parent) was moved: it belongs to the same file, which
+    /// doesn’t exist under its old path anymore.
+    fn moved_state_dir(&self) -> Option<PathBuf> {
+        let nix_file = Path::new(self.nix_file.as_os_str());
+        let metadata = std::fs::metadata(nix_file).ok()?;
+        for entry in std::fs::read_dir(self.base_gc_root_path).ok()? {
+            let dir = match entry {
+                Ok(entry) => entry.path(),
+                Err(_) => continue,
+            };
+            if let Some(recorded) = ProjectFile::read(&dir) {
+                if recorded.device == metadata.dev()
+                    && recorded.inode == metadata.ino()
+                    && recorded.nix_file.file_name() == nix_file.file_name()
+                    && !recorded.nix_file.exists()
+                {
+                    return Some(dir);
+                }
+            }
+        }
+        None
     }
 
     /// Generate a "unique" ID for this project based on its absolute path
@@ -63,3 +212,65 @@ impl<'a, 'b> Project<'a, 'b> {
         format!("{:x}", md5::compute(self.nix_file.as_os_str().as_bytes()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{moved_nix_file, record, Project};
+    use crate::NixFile;
+    use tempfile::tempdir;
+
+    #[test]
+    fn moved_projects_keep_their_state() {
+        let gc_root = tempdir().unwrap();
+        let projects = tempdir().unwrap();
+        let old_dir = projects.path().join("old");
+        std::fs::create_dir(&old_dir).unwrap();
+        std::fs::write(old_dir.join("shell.nix"), "{}").unwrap();
+
+        let old_file = NixFile::from(old_dir.join("shell.nix"));
+        let old = Project::new(&old_file, gc_root.path());
+        let roots = old.gc_root_path().unwrap();
+        std::fs::write(roots.join("build-0"), "").unwrap();
+
+        let new_dir = projects.path().join("new");
+        std::fs::rename(&old_dir, &new_dir).unwrap();
+        let new_file = NixFile::from(new_dir.join("shell.nix"));
+        let new = Project::new(&new_file, gc_root.path());
+        let roots = new.gc_root_path().unwrap();
+        assert!(roots.join("build-0").exists());
+        assert!(!gc_root.path().join(old.hash()).exists());
+
+        // an unrelated project starts from scratch
+        std::fs::create_dir(&old_dir).unwrap();
+        std::fs::write(old_dir.join("shell.nix"), "{}").unwrap();
+        let roots = old.gc_root_path().unwrap();
+        assert!(!roots.join("build-0").exists());
+    }
+
+    #[test]
+    fn moved_nix_files_are_found() {
+        let gc_root = tempdir().unwrap();
+        let projects = tempdir().unwrap();
+        let old_dir = projects.path().join("src").join("old");
+        std::fs::create_dir_all(&old_dir).unwrap();
+        std::fs::write(old_dir.join("shell.nix"), "{}").unwrap();
+        let old_file = NixFile::from(old_dir.join("shell.nix"));
+        let state_dir = gc_root
+            .path()
+            .join(Project::new(&old_file, gc_root.path()).hash());
+        Project::new(&old_file, gc_root.path())
+            .gc_root_path()
+            .unwrap();
+        assert_eq!(moved_nix_file(&state_dir), None);
+
+        // saved atomically, the file is a new one
+        std::fs::write(old_dir.join("shell.nix.tmp"), "{ }").unwrap();
+        std::fs::rename(old_dir.join("shell.nix.tmp"), old_dir.join("shell.nix")).unwrap();
+        record(&state_dir, &old_file).unwrap();
+
+        // a parent of the project directory is renamed
+        std::fs::rename(projects.path().join("src"), projects.path().join("code")).unwrap();
+        let new_dir = projects.path().join("code").join("old");
+        assert_eq!(moved_nix_file(&state_dir), Some(new_dir.join("shell.nix")));
+    }
+}
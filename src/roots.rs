@@ -1,8 +1,116 @@
-//! TODO
-use crate::project::Project;
+//! GC roots, which keep the results of a project’s builds alive.
+use crate::project::{self, Project};
+use crate::NixFile;
+use std::collections::HashMap;
 use std::env;
+use std::ffi::OsStr;
 use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// A GC root: a symlink in a project’s state directory, pointing to
+/// the store path it keeps alive.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct RootPath(PathBuf);
+
+impl RootPath {
+    /// The path of the root symlink itself.
+    pub fn as_path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Whether the root points to a store path which still exists.
+    pub fn exists(&self) -> bool {
+        self.0.exists()
+    }
+
+    /// The store path the root points to, if it is a symlink.
+    pub fn store_path(&self) -> Option<PathBuf> {
+        self.0.read_link().ok()
+    }
+
+    /// The file `name` in the store path, e.g. `bash-export` of
+    /// a shell environment.
+    pub fn output(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+
+    /// The environment variables of the shell environment this root
+    /// points to, as exported by its builder.
+    pub fn read_env(&self) -> Result<HashMap<String, String>, std::io::Error> {
+        let export = std::fs::read_to_string(self.output("bash-export"))?;
+        Ok(parse_bash_export(&export))
+    }
+}
+
+impl From<PathBuf> for RootPath {
+    fn from(path: PathBuf) -> RootPath {
+        RootPath(path)
+    }
+}
+
+impl AsRef<Path> for RootPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<OsStr> for RootPath {
+    fn as_ref(&self) -> &OsStr {
+        self.0.as_os_str()
+    }
+}
+
+impl std::fmt::Display for RootPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0.display())
+    }
+}
+
+/// Parse the output of bash’s `export`, which declares every variable
+/// on its own line like `declare -x NAME="value"`. Inside the quotes,
+/// `"`, `\`, `$` and `` ` `` are escaped with a backslash; newlines
+/// are kept as they are.
+fn parse_bash_export(export: &str) -> HashMap<String, String> {
+    let mut env = HashMap::new();
+    let mut rest = export;
+    while let Some(start) = rest.find("declare -x ") {
+        rest = &rest[start + "declare -x ".len()..];
+        let name_end = rest.find(&['=', '\n'][..]).unwrap_or(rest.len());
+        let name = rest[..name_end].to_string();
+        rest = &rest[name_end..];
+        if !rest.starts_with("=\"") {
+            // declared, but without a value
+            continue;
+        }
+
+        let mut value = String::new();
+        let mut chars = rest[2..].char_indices();
+        let mut end = rest.len() - 2;
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, escaped)) if "\"\\$`".contains(escaped) => value.push(escaped),
+                    Some((_, other)) => {
+                        value.push('\\');
+                        value.push(other);
+                    }
+                    None => value.push('\\'),
+                },
+                '"' => {
+                    end = i + 1;
+                    break;
+                }
+                c => value.push(c),
+            }
+        }
+        rest = &rest[2 + end..];
+        env.insert(name, value);
+    }
+    env
+}
 
 /// Roots manipulation
 #[derive(Clone)]
@@ -16,49 +124,544 @@ impl Roots {
     /// Construct a Roots struct based on a project's GC root directory
     /// and ID.
     pub fn from_project(project: &Project) -> Result<Roots, std::io::Error> {
-        Ok(Roots {
-            root_dir: project.gc_root_path()?,
+        let (root_dir, moved_from) = project.gc_root_path_and_previous_id()?;
+        let roots = Roots {
+            root_dir,
             id: project.hash(),
-        })
+        };
+        if let Some(old_id) = moved_from {
+            if let Err(e) = roots.take_over(&old_id) {
+                warn!(
+                    "Failed to register the GC roots of {} again: {:?}",
+                    project.nix_file, e
+                );
+            }
+        }
+        Ok(roots)
+    }
+
+    /// Record which file `nix_file` is in the project’s state
+    /// directory, see `project::record`.
+    pub fn record_project(&self, nix_file: &NixFile) -> Result<(), std::io::Error> {
+        project::record(self.root_dir.parent().unwrap_or(&self.root_dir), nix_file)
+    }
+
+    /// Where the project’s nix file is now, if its directory was
+    /// moved, see `project::moved_nix_file`.
+    pub fn moved_nix_file(&self) -> Option<NixFile> {
+        project::moved_nix_file(self.root_dir.parent().unwrap_or(&self.root_dir)).map(NixFile::from)
+    }
+
+    /// Where the `BuildLoop` records the project’s current
+    /// `build_loop::WatchSet`, next to the roots directory.
+    pub fn watch_set_file(&self) -> PathBuf {
+        self.root_dir
+            .parent()
+            .unwrap_or(&self.root_dir)
+            .join("watched.json")
+    }
+
+    /// Where the `BuildLoop` records the project’s builds (see
+    /// `::history`), next to the roots directory.
+    pub fn history_file(&self) -> PathBuf {
+        self.root_dir
+            .parent()
+            .unwrap_or(&self.root_dir)
+            .join("history.json")
+    }
+
+    /// Where the `BuildLoop` stores the output of the project’s
+    /// latest builds (see `::history::BuildLog`), next to the roots
+    /// directory.
+    pub fn build_log_dir(&self) -> PathBuf {
+        self.root_dir
+            .parent()
+            .unwrap_or(&self.root_dir)
+            .join("logs")
+    }
+
+    /// Where the `BuildLoop` stores the environments of the project’s
+    /// latest builds (see `::history::save_env`), next to the roots
+    /// directory.
+    pub fn env_history_dir(&self) -> PathBuf {
+        self.root_dir
+            .parent()
+            .unwrap_or(&self.root_dir)
+            .join("envs")
+    }
+
+    /// Where the `BuildLoop` dumps the environment of the project’s
+    /// latest successful build, next to the roots directory.
+    pub fn env_dump_file(&self) -> PathBuf {
+        self.root_dir
+            .parent()
+            .unwrap_or(&self.root_dir)
+            .join("env.json")
+    }
+
+    /// Where `lorri shell` remembers which bashInteractive it built,
+    /// next to the roots directory.
+    pub fn bash_cache_file(&self) -> PathBuf {
+        self.root_dir
+            .parent()
+            .unwrap_or(&self.root_dir)
+            .join("bash.json")
+    }
+
+    /// A file next to the roots directory which is replaced after
+    /// every successful build. `lorri direnv` has direnv watch it, so
+    /// direnv reloads the environment by itself.
+    pub fn build_marker_file(&self) -> PathBuf {
+        self.root_dir
+            .parent()
+            .unwrap_or(&self.root_dir)
+            .join("build_marker")
+    }
+
+    /// Replace `build_marker_file` with one containing `contents`.
+    /// Like `atomic_symlink`, the file is written under a temporary
+    /// name and renamed into place, so direnv never reads half of it.
+    pub fn update_build_marker(&self, contents: &str) -> Result<(), std::io::Error> {
+        let dest = self.build_marker_file();
+        let tmp = dest.with_file_name(format!(".build_marker.{}.tmp", std::process::id()));
+        std::fs::write(&tmp, contents)?;
+        let renamed = std::fs::rename(&tmp, &dest);
+        if renamed.is_err() {
+            if let Err(e) = std::fs::remove_file(&tmp) {
+                debug!("Failed to remove {:?}: {}", tmp, e);
+            }
+        }
+        renamed
+    }
+
+    /// A symlink to the latest successfully built shell environment
+    /// of the project, at a fixed path next to the roots directory:
+    /// `<gc root dir>/<project hash>/shell_gc_root`. Tools can source
+    /// it without talking to a running lorri.
+    pub fn shell_gc_root(&self) -> RootPath {
+        RootPath(
+            self.root_dir
+                .parent()
+                .unwrap_or(&self.root_dir)
+                .join("shell_gc_root"),
+        )
+    }
+
+    /// `shell_gc_root`, or the root of the environment lorri versions
+    /// without it created, if only that one exists.
+    pub fn current_shell_root(&self) -> RootPath {
+        let shell_root = self.shell_gc_root();
+        if shell_root.exists() {
+            shell_root
+        } else {
+            self.root("build-0")
+        }
+    }
+
+    /// Point `shell_gc_root` to the shell environment `store_path`.
+    /// Call `add` for it first, the link is no GC root by itself.
+    pub fn update_shell_gc_root(&self, store_path: &Path) -> Result<(), AddRootError> {
+        let path = self.shell_gc_root();
+        debug!("Pointing {:?} to {:?}", path, store_path);
+        atomic_symlink(store_path, path.as_path())
+    }
+
+    /// Point `<project_dir>/.lorri/gc_root` to the shell environment
+    /// `store_path`, for scripts in the project. `.lorri` is created
+    /// with a `.gitignore`, so it is never committed by accident.
+    pub fn update_project_gc_root(
+        &self,
+        project_dir: &Path,
+        store_path: &Path,
+    ) -> Result<(), AddRootError> {
+        let dir = project_dir.join(".lorri");
+        if !dir.is_dir() {
+            std::fs::create_dir_all(&dir).map_err(|e| AddRootError::create_dir_all(e, &dir))?;
+        }
+        let gitignore = dir.join(".gitignore");
+        if !gitignore.exists() {
+            std::fs::write(&gitignore, "*\n").map_err(|e| {
+                AddRootError::Io(e, format!("Failed to write {}", gitignore.display()))
+            })?;
+        }
+        atomic_symlink(store_path, &dir.join("gc_root"))
+    }
+
+    /// The root `add` creates for `name`, whether it exists or not.
+    pub fn root(&self, name: &str) -> RootPath {
+        RootPath(self.root_dir.join(name))
+    }
+
+    /// All roots created by a previous `add`, also those whose store
+    /// path was garbage collected, as pairs of root name and root
+    /// path, ordered by name.
+    pub fn all(&self) -> Result<Vec<(String, RootPath)>, std::io::Error> {
+        let mut roots = vec![];
+        for entry in std::fs::read_dir(&self.root_dir)? {
+            let path = entry?.path();
+            match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) if !is_temporary(name) => {
+                    roots.push((name.to_string(), RootPath(path.clone())))
+                }
+                _ => {}
+            }
+        }
+        roots.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(roots)
+    }
+
+    /// All roots created by a previous `add` whose store path still
+    /// exists, as pairs of root name and root path.
+    pub fn existing(&self) -> Result<Vec<(String, RootPath)>, std::io::Error> {
+        // `exists()` follows the symlink, so a root pointing to
+        // a garbage-collected store path is skipped.
+        Ok(self
+            .all()?
+            .into_iter()
+            .filter(|(_, root)| root.exists())
+            .collect())
     }
 
     /// Store a new root under name
-    pub fn add(&self, name: &str, store_path: &PathBuf) -> Result<PathBuf, AddRootError> {
-        let mut path = self.root_dir.clone();
-        path.push(name);
+    pub fn add(&self, name: &str, store_path: &PathBuf) -> Result<RootPath, AddRootError> {
+        let path = self.root(name).0;
 
         debug!("Adding root from {:?} to {:?}", store_path, path,);
-        std::fs::remove_file(&path).or_else(|e| AddRootError::remove(e, &path))?;
+        atomic_symlink(store_path, &path)?;
+        self.register(
+            per_user_gc_roots().as_ref().map(PathBuf::as_path),
+            name,
+            store_path,
+            &path,
+        )?;
+        Ok(RootPath(path))
+    }
 
-        std::fs::remove_file(&path).or_else(|e| AddRootError::remove(e, &path))?;
+    /// Register the roots again after the project was moved and took
+    /// over the state directory of the project `old_id` (see
+    /// `Project::gc_root_path`). Nix’ links to them still lead to the
+    /// old state directory, so they protect nothing anymore and are
+    /// removed.
+    pub fn take_over(&self, old_id: &str) -> Result<(), AddRootError> {
+        self.take_over_with(old_id, per_user_gc_roots().as_ref().map(PathBuf::as_path))
+    }
 
-        symlink(&store_path, &path).map_err(|e| AddRootError::symlink(e, &store_path, &path))?;
+    fn take_over_with(&self, old_id: &str, per_user: Option<&Path>) -> Result<(), AddRootError> {
+        let existing = self.existing().map_err(|e| {
+            AddRootError::Io(e, format!("Failed to read {}", self.root_dir.display()))
+        })?;
+        for (name, root) in existing {
+            if let Some(store_path) = root.store_path() {
+                self.register(per_user, &name, &store_path, root.as_path())?;
+            }
+        }
 
-        let mut root = if let Ok(path) = env::var("NIX_STATE_DIR") {
-            PathBuf::from(path)
-        } else {
-            PathBuf::from("/nix/var/nix/")
-        };
-        root.push("gcroots");
-        root.push("per-user");
+        let prefix = format!("{}-", old_id);
+        let registrations = per_user.and_then(|dir| std::fs::read_dir(dir).ok());
+        for entry in registrations.into_iter().flatten() {
+            let registration = match entry {
+                Ok(entry) => entry.path(),
+                Err(_) => continue,
+            };
+            let theirs = match registration.file_name().and_then(|n| n.to_str()) {
+                Some(name) => name.starts_with(&prefix),
+                None => false,
+            };
+            if theirs {
+                debug!("Removing {:?}", registration);
+                std::fs::remove_file(&registration).map_err(|e| {
+                    AddRootError::Io(e, format!("Failed to remove {}", registration.display()))
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Make the root `path` of `name`, which points to `store_path`,
+    /// known to Nix: through the user’s directory `per_user` (see
+    /// `register_per_user`), or else through `nix-store`.
+    fn register(
+        &self,
+        per_user: Option<&Path>,
+        name: &str,
+        store_path: &Path,
+        path: &Path,
+    ) -> Result<(), AddRootError> {
+        // A symlink in our state directory protects nothing by itself,
+        // Nix has to know about it as an indirect root.
+        match per_user {
+            Some(dir) => match self.register_per_user(dir, name, path) {
+                Ok(()) => return Ok(()),
+                Err(e) => debug!("Cannot register {:?} in {:?}: {:?}", path, dir, e),
+            },
+            None => debug!("Neither $USER nor $LOGNAME is set"),
+        }
+        register_with_nix_store(store_path, path)
+    }
+
+    /// Find roots which don’t protect anything anymore: roots whose
+    /// store path was garbage collected (e.g. while the project was not
+    /// registered), and registrations with Nix whose root is gone.
+    pub fn verify(&self) -> Result<Vec<RootProblem>, std::io::Error> {
+        self.verify_with(per_user_gc_roots().as_ref().map(PathBuf::as_path))
+    }
+
+    fn verify_with(&self, per_user: Option<&Path>) -> Result<Vec<RootProblem>, std::io::Error> {
+        let mut problems = vec![];
+        for entry in std::fs::read_dir(&self.root_dir)? {
+            let path = entry?.path();
+            if !path.exists() {
+                problems.push(RootProblem::Dangling(path));
+            }
+        }
 
-        // TODO: check on start
-        root.push(env::var("USER").expect("env var 'USER' must be set"));
+        let prefix = format!("{}-", self.id);
+        let registrations = per_user.and_then(|dir| std::fs::read_dir(dir).ok());
+        for entry in registrations.into_iter().flatten() {
+            let registration = entry?.path();
+            let ours = registration
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map_or(false, |name| name.starts_with(&prefix));
+            let root_gone = registration
+                .read_link()
+                .map(|root| root.symlink_metadata().is_err())
+                .unwrap_or(false);
+            if ours && root_gone {
+                problems.push(RootProblem::Orphaned(registration));
+            }
+        }
+        problems.sort();
+        Ok(problems)
+    }
+
+    /// Remove the broken links found by `verify`. The next successful
+    /// build creates all roots of the project anew.
+    pub fn repair(&self, problems: &[RootProblem]) -> Result<(), std::io::Error> {
+        for problem in problems {
+            let path = match problem {
+                RootProblem::Dangling(path) | RootProblem::Orphaned(path) => path,
+            };
+            debug!("Removing {:?}", path);
+            std::fs::remove_file(path)?;
+        }
+        Ok(())
+    }
 
+    /// Register `path` as an indirect root by linking to it from the
+    /// user’s directory in `/nix/var/nix/gcroots/per-user`.
+    fn register_per_user(&self, dir: &Path, name: &str, path: &Path) -> Result<(), AddRootError> {
         // The user directory sometimes doesn’t exist,
         // but we can create it (it’s root but `rwxrwxrwx`)
-        if !root.is_dir() {
-            std::fs::create_dir_all(&root).map_err(|e| AddRootError::create_dir_all(e, &root))?;
+        if !dir.is_dir() {
+            std::fs::create_dir_all(dir).map_err(|e| AddRootError::create_dir_all(e, dir))?;
         }
 
-        root.push(format!("{}-{}", self.id, name));
-
+        let root = dir.join(format!("{}-{}", self.id, name));
         debug!("Connecting root from {:?} to {:?}", path, root,);
-        std::fs::remove_file(&root).or_else(|e| AddRootError::remove(e, &root))?;
+        atomic_symlink(path, &root)
+    }
+}
+
+/// Register `path` as an indirect root through `nix-store`, which
+/// asks the Nix daemon on multi-user installations. Needed when the
+/// per-user GC root directory is not writable (or `$USER` is unset).
+fn register_with_nix_store(store_path: &Path, path: &Path) -> Result<(), AddRootError> {
+    debug!("Registering {:?} as an indirect root with nix-store", path);
+    let output = Command::new("nix-store")
+        .arg("--realise")
+        .arg(store_path)
+        .arg("--add-root")
+        .arg(path)
+        .arg("--indirect")
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| AddRootError::Io(e, String::from("Failed to execute nix-store")))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(AddRootError::NixStore(output))
+    }
+}
+
+/// Point the symlink `link` (like the `result` of `nix-build`) to the
+/// build result `store_path`, and register it with Nix as an indirect
+/// GC root, so the result lives as long as the link.
+pub fn add_out_link(store_path: &Path, link: &Path) -> Result<(), AddRootError> {
+    // Nix registers the absolute path of the link
+    let link = std::env::current_dir()
+        .map_err(|e| AddRootError::Io(e, String::from("Failed to find the current directory")))?
+        .join(link);
+    atomic_symlink(store_path, &link)?;
+    register_with_nix_store(store_path, &link)
+}
+
+/// Point the symlink `dest` to `src`, replacing whatever `dest` was.
+/// The link is created under a temporary name and renamed into place,
+/// so concurrent writers (e.g. `lorri shell` and the daemon building
+/// the same project) neither fail with `EEXIST` nor leave `dest`
+/// missing in between; the last writer wins.
+fn atomic_symlink(src: &Path, dest: &Path) -> Result<(), AddRootError> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let name = dest
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tmp = dest.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    symlink(src, &tmp).map_err(|e| AddRootError::symlink(e, src, &tmp))?;
+    std::fs::rename(&tmp, dest).map_err(|e| {
+        if let Err(e) = std::fs::remove_file(&tmp) {
+            debug!("Failed to remove {:?}: {}", tmp, e);
+        }
+        AddRootError::rename(e, &tmp, dest)
+    })
+}
+
+/// Temporary links of `atomic_symlink` which were never renamed
+/// into place, e.g. because lorri was killed, are no roots.
+fn is_temporary(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(".tmp")
+}
+
+/// The directory of the user’s indirect GC roots, where `Roots::add`
+/// registers every root. `None` if the user name is unknown.
+fn per_user_gc_roots() -> Option<PathBuf> {
+    let mut root = if let Ok(path) = env::var("NIX_STATE_DIR") {
+        PathBuf::from(path)
+    } else {
+        PathBuf::from("/nix/var/nix/")
+    };
+    root.push("gcroots");
+    root.push("per-user");
+    root.push(env::var_os("USER").or_else(|| env::var_os("LOGNAME"))?);
+    Some(root)
+}
+
+/// The GC roots of one project, as found in the base GC root
+/// directory (see `ProjectRoots::scan`).
+#[derive(Debug)]
+pub struct ProjectRoots {
+    /// The state directory of the project, named after its hash.
+    pub state_dir: PathBuf,
+    /// The project’s nix file, unless the state directory was
+    /// created before lorri recorded it.
+    pub nix_file: Option<PathBuf>,
+    /// The roots, as pairs of root name and root path.
+    pub roots: Vec<(String, RootPath)>,
+    /// When a root was last added, i.e. the last successful build.
+    pub last_built: Option<SystemTime>,
+}
+
+impl ProjectRoots {
+    /// All projects with a state directory in `base_gc_root_path`,
+    /// sorted by their nix file.
+    pub fn scan(base_gc_root_path: &Path) -> Result<Vec<ProjectRoots>, std::io::Error> {
+        let mut projects = vec![];
+        for entry in std::fs::read_dir(base_gc_root_path)? {
+            let state_dir = entry?.path();
+            if !state_dir.is_dir() {
+                continue;
+            }
+            let mut roots = vec![];
+            let mut last_built = None;
+            if let Ok(entries) = std::fs::read_dir(state_dir.join("gc_root")) {
+                for entry in entries {
+                    let path = entry?.path();
+                    let name = match path.file_name().and_then(|n| n.to_str()) {
+                        Some(name) if !is_temporary(name) => name.to_string(),
+                        _ => continue,
+                    };
+                    let modified = path.symlink_metadata().and_then(|m| m.modified()).ok();
+                    last_built = last_built.max(modified);
+                    roots.push((name, RootPath(path)));
+                }
+            }
+            roots.sort();
+            projects.push(ProjectRoots {
+                nix_file: project::recorded_nix_file(&state_dir),
+                state_dir,
+                roots,
+                last_built,
+            });
+        }
+        projects.sort_by(|a, b| (&a.nix_file, &a.state_dir).cmp(&(&b.nix_file, &b.state_dir)));
+        Ok(projects)
+    }
+
+    /// The project’s nix file was recorded, but doesn’t exist anymore.
+    /// Its roots only keep garbage alive.
+    pub fn is_stale(&self) -> bool {
+        self.nix_file.as_ref().map_or(false, |file| !file.exists())
+    }
+
+    /// How long ago the project was last built, if ever.
+    pub fn age(&self) -> Option<Duration> {
+        self.last_built
+            .and_then(|built| SystemTime::now().duration_since(built).ok())
+    }
+
+    /// The project was built, but not within the last `max_age`.
+    pub fn not_built_for(&self, max_age: Duration) -> bool {
+        self.age().map_or(false, |age| age > max_age)
+    }
+
+    /// Remove the project’s state directory, including all roots and
+    /// their registrations with Nix.
+    pub fn remove(&self) -> Result<(), std::io::Error> {
+        let id = self
+            .state_dir
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        if let Some(per_user) = per_user_gc_roots() {
+            for (name, root) in &self.roots {
+                let registration = per_user.join(format!("{}-{}", id, name));
+                // only remove registrations which are actually ours
+                if registration.read_link().ok().as_ref().map(PathBuf::as_path)
+                    == Some(root.as_path())
+                {
+                    std::fs::remove_file(&registration)?;
+                }
+            }
+        }
+        std::fs::remove_dir_all(&self.state_dir)
+    }
+}
 
-        symlink(&path, &root).map_err(|e| AddRootError::symlink(e, &path, &root))?;
+impl std::fmt::Display for ProjectRoots {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.nix_file {
+            Some(nix_file) => write!(f, "{}", nix_file.display()),
+            None => write!(f, "unknown project in {}", self.state_dir.display()),
+        }
+    }
+}
 
-        Ok(path)
+/// A broken GC root of a project, found by `Roots::verify`.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RootProblem {
+    /// This root points to a store path which doesn’t exist anymore.
+    Dangling(PathBuf),
+    /// This registration with Nix points to a root which doesn’t
+    /// exist anymore.
+    Orphaned(PathBuf),
+}
+
+impl std::fmt::Display for RootProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RootProblem::Dangling(path) => {
+                write!(f, "{} points to a deleted store path", path.display())
+            }
+            RootProblem::Orphaned(path) => {
+                write!(f, "{} points to a deleted root", path.display())
+            }
+        }
     }
 }
 
@@ -67,6 +670,9 @@ impl Roots {
 pub enum AddRootError {
     /// IO-related errors
     Io(std::io::Error, String),
+
+    /// `nix-store --add-root` failed to register the root
+    NixStore(std::process::Output),
 }
 
 impl AddRootError {
@@ -78,17 +684,12 @@ impl AddRootError {
         )
     }
 
-    /// Ignore NotFound errors (it is after all a remove), and otherwise
-    /// return an error explaining a delete on path failed.
-    fn remove(err: std::io::Error, path: &Path) -> Result<(), AddRootError> {
-        if err.kind() == std::io::ErrorKind::NotFound {
-            Ok(())
-        } else {
-            Err(AddRootError::Io(
-                err,
-                format!("Failed to delete {}", path.display()),
-            ))
-        }
+    /// Return an error explaining what rename failed
+    fn rename(err: std::io::Error, src: &Path, dest: &Path) -> AddRootError {
+        AddRootError::Io(
+            err,
+            format!("Failed to rename {} to {}", src.display(), dest.display()),
+        )
     }
 
     /// Return an error explaining what symlink failed
@@ -99,3 +700,245 @@ impl AddRootError {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{atomic_symlink, parse_bash_export, ProjectRoots, RootPath, RootProblem, Roots};
+    use crate::project::Project;
+    use crate::NixFile;
+    use std::os::unix::fs::symlink;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[test]
+    fn environment_of_a_root() {
+        let temp = tempdir().unwrap();
+        std::fs::write(
+            temp.path().join("bash-export"),
+            "declare -x HOME=\"/home/alice\"\n\
+             declare -x OLDPWD\n\
+             declare -x PS1=\"\\\\\\$ \\\"quoted\\\" \\`cmd\\`\"\n\
+             declare -x shellHook=\"echo one\n\
+             echo two\"\n",
+        )
+        .unwrap();
+        let root = RootPath::from(temp.path().to_path_buf());
+        let env = root.read_env().unwrap();
+        assert_eq!(env["HOME"], "/home/alice");
+        assert_eq!(env["PS1"], "\\$ \"quoted\" `cmd`");
+        assert_eq!(env["shellHook"], "echo one\necho two");
+        assert!(!env.contains_key("OLDPWD"));
+        assert_eq!(parse_bash_export("").len(), 0);
+    }
+
+    #[test]
+    fn concurrent_roots_do_not_clash() {
+        let temp = tempdir().unwrap();
+        let dest = temp.path().join("attr-shell");
+        let threads = (0..8)
+            .map(|i| {
+                let target = temp.path().join(format!("target-{}", i));
+                let dest = dest.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        atomic_symlink(&target, &dest).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        assert!(dest.read_link().unwrap().starts_with(temp.path()));
+        // no temporary links are left behind
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn shell_gc_root_is_next_to_the_roots() {
+        let temp = tempdir().unwrap();
+        let roots = Roots {
+            root_dir: temp.path().join("gc_root"),
+            id: String::from("project"),
+        };
+        std::fs::write(temp.path().join("env"), "").unwrap();
+        roots
+            .update_shell_gc_root(&temp.path().join("env"))
+            .unwrap();
+        assert_eq!(
+            roots.shell_gc_root().as_path(),
+            temp.path().join("shell_gc_root")
+        );
+        assert_eq!(
+            roots.shell_gc_root().store_path(),
+            Some(temp.path().join("env"))
+        );
+    }
+
+    #[test]
+    fn build_marker_is_replaced() {
+        let temp = tempdir().unwrap();
+        let roots = Roots {
+            root_dir: temp.path().join("gc_root"),
+            id: String::from("project"),
+        };
+        roots.update_build_marker("1").unwrap();
+        roots.update_build_marker("2").unwrap();
+        assert_eq!(
+            std::fs::read_to_string(roots.build_marker_file()).unwrap(),
+            "2"
+        );
+        // no temporary files are left behind
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn project_gc_root_is_gitignored() {
+        let temp = tempdir().unwrap();
+        let roots = Roots {
+            root_dir: temp.path().join("gc_root"),
+            id: String::from("project"),
+        };
+        let project = temp.path().join("project");
+        roots
+            .update_project_gc_root(&project, &temp.path().join("env"))
+            .unwrap();
+        assert_eq!(
+            project.join(".lorri/gc_root").read_link().unwrap(),
+            temp.path().join("env")
+        );
+        assert_eq!(
+            std::fs::read_to_string(project.join(".lorri/.gitignore")).unwrap(),
+            "*\n"
+        );
+    }
+
+    #[test]
+    fn per_user_registration() {
+        let temp = tempdir().unwrap();
+        let roots = Roots {
+            root_dir: temp.path().join("gc_root"),
+            id: String::from("project"),
+        };
+        let per_user = temp.path().join("per-user").join("alice");
+        let root = temp.path().join("gc_root").join("build-0");
+        roots
+            .register_per_user(&per_user, "build-0", &root)
+            .unwrap();
+        assert_eq!(per_user.join("project-build-0").read_link().unwrap(), root);
+    }
+
+    #[test]
+    fn moved_roots_are_registered_again() {
+        let temp = tempdir().unwrap();
+        let root_dir = temp.path().join("gc_root");
+        let per_user = temp.path().join("per-user");
+        std::fs::create_dir(&root_dir).unwrap();
+        std::fs::create_dir(&per_user).unwrap();
+        let roots = Roots {
+            root_dir: root_dir.clone(),
+            id: String::from("new"),
+        };
+        std::fs::write(temp.path().join("alive"), "").unwrap();
+        symlink(temp.path().join("alive"), root_dir.join("build-0")).unwrap();
+        let old_root = temp.path().join("old").join("gc_root").join("build-0");
+        symlink(&old_root, per_user.join("old-build-0")).unwrap();
+        symlink(temp.path().join("other"), per_user.join("other-build-0")).unwrap();
+
+        roots.take_over_with("old", Some(&per_user)).unwrap();
+        assert_eq!(
+            per_user.join("new-build-0").read_link().unwrap(),
+            root_dir.join("build-0")
+        );
+        assert!(per_user.join("old-build-0").symlink_metadata().is_err());
+        assert!(per_user.join("other-build-0").symlink_metadata().is_ok());
+        assert!(roots.verify_with(Some(&per_user)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn broken_roots_are_found_and_removed() {
+        let temp = tempdir().unwrap();
+        let root_dir = temp.path().join("gc_root");
+        let per_user = temp.path().join("per-user");
+        std::fs::create_dir(&root_dir).unwrap();
+        std::fs::create_dir(&per_user).unwrap();
+        let roots = Roots {
+            root_dir: root_dir.clone(),
+            id: String::from("project"),
+        };
+        std::fs::write(temp.path().join("alive"), "").unwrap();
+        symlink(temp.path().join("alive"), root_dir.join("build-0")).unwrap();
+        symlink(temp.path().join("collected"), root_dir.join("build-1")).unwrap();
+        symlink(root_dir.join("build-0"), per_user.join("project-build-0")).unwrap();
+        symlink(
+            root_dir.join("attr-gone"),
+            per_user.join("project-attr-gone"),
+        )
+        .unwrap();
+        symlink(temp.path().join("other"), per_user.join("other-build-0")).unwrap();
+
+        let problems = roots.verify_with(Some(&per_user)).unwrap();
+        assert_eq!(
+            problems,
+            vec![
+                RootProblem::Dangling(root_dir.join("build-1")),
+                RootProblem::Orphaned(per_user.join("project-attr-gone")),
+            ]
+        );
+        roots.repair(&problems).unwrap();
+        assert!(roots.verify_with(Some(&per_user)).unwrap().is_empty());
+        assert!(root_dir.join("build-0").exists());
+    }
+
+    #[test]
+    fn scan_finds_stale_projects() {
+        let gc_root = tempdir().unwrap();
+        let projects = tempdir().unwrap();
+        for name in &["alive", "gone"] {
+            let dir = projects.path().join(name);
+            std::fs::create_dir(&dir).unwrap();
+            std::fs::write(dir.join("shell.nix"), "{}").unwrap();
+            let nix_file = NixFile::from(dir.join("shell.nix"));
+            let roots = Project::new(&nix_file, gc_root.path())
+                .gc_root_path()
+                .unwrap();
+            symlink(dir.join("shell.nix"), roots.join("build-0")).unwrap();
+        }
+        std::fs::remove_dir_all(projects.path().join("gone")).unwrap();
+
+        let found = ProjectRoots::scan(gc_root.path()).unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(
+            found.iter().map(ProjectRoots::is_stale).collect::<Vec<_>>(),
+            vec![false, true]
+        );
+        assert_eq!(found[1].roots.len(), 1);
+        assert!(found[1].last_built.is_some());
+        assert!(!found[1].not_built_for(Duration::from_secs(60)));
+
+        found[1].remove().unwrap();
+        assert_eq!(ProjectRoots::scan(gc_root.path()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn existing_skips_dangling_roots() {
+        let temp = tempdir().unwrap();
+        let roots = Roots {
+            root_dir: temp.path().to_owned(),
+            id: String::from("project"),
+        };
+        std::fs::write(temp.path().join("target"), "").unwrap();
+        symlink(temp.path().join("target"), temp.path().join("build-0")).unwrap();
+        symlink(temp.path().join("gone"), temp.path().join("attr-shell")).unwrap();
+
+        let mut names = roots
+            .existing()
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["build-0", "target"]);
+    }
+}
@@ -0,0 +1,8 @@
+# The shell `lorri self-test` builds. It depends on nothing, not even
+# nixpkgs, so only Nix and lorri itself can make it fail.
+derivation {
+  name = "lorri-self-test";
+  system = builtins.currentSystem;
+  builder = "/bin/sh";
+  LORRI_SELF_TEST = "works";
+}
@@ -0,0 +1,154 @@
+//! Reacting to the signals which ask lorri to stop (`SIGTERM` and
+//! `SIGINT`), the daemon to reload its configuration (`SIGHUP`), or
+//! to dump its state for debugging (`SIGUSR1`).
+//!
+//! The signal handler only writes the signal number to a pipe; a
+//! thread reads it from there and does the actual work, so it is not
+//! restricted to async-signal-safe functions. By default lorri
+//! cancels its running builds (see `builder::cancel_all`) and exits,
+//! commands which need to clean up more can replace that with
+//! `on_termination`.
+
+extern crate nix;
+
+use self::nix::fcntl::OFlag;
+use self::nix::libc::c_int;
+use self::nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet};
+use self::nix::unistd::{pipe2, read, write};
+use crate::builder;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Mutex;
+
+pub use self::nix::sys::signal::Signal;
+
+/// The signals which ask lorri to stop.
+const TERMINATION: [Signal; 2] = [Signal::SIGTERM, Signal::SIGINT];
+
+/// The writing end of the pipe the signal handler writes to.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+type Callback = Box<dyn Fn(Signal) + Send>;
+
+lazy_static! {
+    /// Called for every termination signal, instead of `terminate`.
+    static ref ON_TERMINATION: Mutex<Option<Callback>> = Mutex::new(None);
+    /// Called for every `SIGHUP`, see `on_reload`.
+    static ref ON_RELOAD: Mutex<Option<Callback>> = Mutex::new(None);
+    /// Called for every `SIGUSR1`, see `on_dump`.
+    static ref ON_DUMP: Mutex<Option<Callback>> = Mutex::new(None);
+}
+
+extern "C" fn handle(signal: c_int) {
+    let pipe = PIPE.load(Ordering::SeqCst);
+    if pipe >= 0 {
+        // nothing sensible to do if the pipe is full
+        let _ = write(pipe, &[signal as u8]);
+    }
+}
+
+/// Handle termination signals from now on. Call it once, before
+/// starting any builds.
+pub fn install() -> nix::Result<()> {
+    // not inherited by the programs lorri executes
+    let (reader, writer) = pipe2(OFlag::O_CLOEXEC)?;
+    PIPE.store(writer, Ordering::SeqCst);
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 1];
+        while let Ok(1) = read(reader, &mut buf) {
+            if let Ok(signal) = Signal::from_c_int(c_int::from(buf[0])) {
+                dispatch(signal);
+            }
+        }
+    });
+    for signal in &TERMINATION {
+        handle_signal(*signal)?;
+    }
+    Ok(())
+}
+
+fn handle_signal(signal: Signal) -> nix::Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(handle),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // the handler only touches the pipe, which is set up by `install`
+    unsafe { sigaction(signal, &action) }.map(|_| ())
+}
+
+/// Call `callback` for every termination signal, instead of exiting.
+/// Signals keep arriving while the callback cleans up, e.g. a second
+/// `^C` from an impatient user.
+pub fn on_termination<F>(callback: F)
+where
+    F: Fn(Signal) + Send + 'static,
+{
+    *ON_TERMINATION
+        .lock()
+        .expect("termination callback mutex poisoned") = Some(Box::new(callback));
+}
+
+/// Call `callback` for every `SIGHUP`, instead of exiting.
+/// Only works after `install`.
+pub fn on_reload<F>(callback: F) -> nix::Result<()>
+where
+    F: Fn(Signal) + Send + 'static,
+{
+    *ON_RELOAD.lock().expect("reload callback mutex poisoned") = Some(Box::new(callback));
+    handle_signal(Signal::SIGHUP)
+}
+
+/// Call `callback` for every `SIGUSR1`, instead of exiting, e.g. to
+/// write what the process is doing to its log.
+/// Only works after `install`.
+pub fn on_dump<F>(callback: F) -> nix::Result<()>
+where
+    F: Fn(Signal) + Send + 'static,
+{
+    *ON_DUMP.lock().expect("dump callback mutex poisoned") = Some(Box::new(callback));
+    handle_signal(Signal::SIGUSR1)
+}
+
+fn dispatch(signal: Signal) {
+    if signal == Signal::SIGHUP {
+        if let Some(ref callback) = *ON_RELOAD.lock().expect("reload callback mutex poisoned") {
+            return callback(signal);
+        }
+    }
+    if signal == Signal::SIGUSR1 {
+        if let Some(ref callback) = *ON_DUMP.lock().expect("dump callback mutex poisoned") {
+            return callback(signal);
+        }
+    }
+    match *ON_TERMINATION
+        .lock()
+        .expect("termination callback mutex poisoned")
+    {
+        Some(ref callback) => callback(signal),
+        None => terminate(signal),
+    }
+}
+
+/// Cancel the running builds and exit like the signal would have.
+fn terminate(signal: Signal) -> ! {
+    builder::cancel_all();
+    std::process::exit(128 + signal as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dispatch, on_termination, Signal};
+    use std::sync::mpsc;
+
+    #[test]
+    fn callback_replaces_exiting() {
+        let (tx, rx) = mpsc::channel();
+        on_termination(move |signal| tx.send(signal).unwrap());
+        dispatch(Signal::SIGTERM);
+        dispatch(Signal::SIGINT);
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![Signal::SIGTERM, Signal::SIGINT]
+        );
+    }
+}
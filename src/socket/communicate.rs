@@ -10,15 +10,28 @@
 //! we support.
 
 use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 
+use crate::build_loop::Event;
+use crate::daemon::ProjectEvent;
 use crate::socket::path::{BindError, BindLock, SocketPath};
-use crate::socket::{ReadWriteError, ReadWriter, Timeout};
+use crate::socket::systemd;
+use crate::socket::{ReadError, ReadWriteError, ReadWriter, Timeout};
 use crate::NixFile;
 
 /// We declare 1s as the time readers should wait
 /// for the other side to send something.
 pub const DEFAULT_READ_TIMEOUT: Timeout = Timeout::from_millis(1000);
 
+/// Version of the protocol between lorri clients and the daemon.
+/// Increase it whenever a message changes incompatibly.
+///
+/// Every connection starts with the client sending this version,
+/// followed by its `CommunicationType`. Clients from before the
+/// protocol was versioned sent `CommunicationType::Ping` right away,
+/// which the daemon reads as version 0.
+pub const PROTOCOL_VERSION: u32 = 1;
+
 /// Enum of all communication modes the lorri daemon supports.
 #[derive(Serialize, Deserialize)]
 pub enum CommunicationType {
@@ -26,6 +39,21 @@ pub enum CommunicationType {
     // TODO: rename to IndicateActivity (along with all other `ping` things)
     // issue: https://github.com/target/lorri/issues/101
     Ping,
+    /// Ask the daemon for the `ProjectStatus` of every project.
+    Status,
+    /// Ask the daemon for recent build log lines, see `LogsRequest`.
+    Logs,
+    /// Receive the events of the daemon’s projects as they happen,
+    /// see `SubscribeRequest`.
+    Subscribe,
+    /// Ask the daemon to stop building a project, see `ForgetRequest`.
+    Forget,
+    /// Use the environment the daemon builds for a project in
+    /// `lorri shell`, see `ShellRequest`.
+    Shell,
+    /// Ask the daemon to replace itself with another lorri, see
+    /// `UpgradeRequest`.
+    Upgrade,
 }
 
 /// Message sent by the client to ask the server to start
@@ -36,21 +64,163 @@ pub struct Ping {
     pub nix_file: NixFile,
 }
 
+/// Message sent by the client to ask for the daemon’s recently
+/// buffered build log lines. See `CommunicationType::Logs`.
+/// The daemon answers with batches of `LogLine`s.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LogsRequest {
+    /// Only the lines of this project, or of every project if `None`.
+    pub nix_file: Option<NixFile>,
+    /// Keep the connection open and send new lines as they arrive.
+    pub follow: bool,
+}
+
+/// Message sent by the client to receive the events of the daemon’s
+/// projects. See `CommunicationType::Subscribe`.
+///
+/// The daemon first replays the recent events of each project (see
+/// `daemon::EVENT_BUFFER_SIZE`), which tell its current state, then
+/// sends every new `ProjectEvent` until the client hangs up.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SubscribeRequest {
+    /// Only the events of this project, or of every project if `None`.
+    pub nix_file: Option<NixFile>,
+}
+
+/// Message sent by the client to make the daemon stop building a
+/// project and forget about it. See `CommunicationType::Forget`.
+/// The daemon answers with a `ForgetResponse` once it is done.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ForgetRequest {
+    /// The nix file of the project.
+    pub nix_file: NixFile,
+    /// Also remove the project’s GC roots and cached state.
+    pub delete_roots: bool,
+}
+
+/// The daemon’s answer to a `ForgetRequest`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ForgetResponse {
+    /// Whether the daemon was building the project.
+    pub was_watched: bool,
+    /// Whether GC roots were removed.
+    pub roots_deleted: bool,
+    /// Why the GC roots could not be removed, if they couldn’t.
+    pub error: Option<String>,
+}
+
+/// Message sent by `lorri shell` to use the environment the daemon
+/// builds for a project, instead of building it itself.
+/// See `CommunicationType::Shell`.
+///
+/// The daemon starts building the project if it didn’t yet, answers
+/// with a `ShellMessage::State`, then sends every new event of the
+/// project but its log lines as a `ShellMessage::Event`, until the
+/// client hangs up.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShellRequest {
+    /// The nix file of the project.
+    pub nix_file: NixFile,
+}
+
+/// What the daemon sends to a `lorri shell`, see `ShellRequest`.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum ShellMessage {
+    /// The state of the project when the shell connected. After a
+    /// `BuildState::Success`, the project’s `shell_gc_root` points to
+    /// its latest environment.
+    State(BuildState),
+    /// A new event of the project’s `BuildLoop`.
+    Event(Event),
+}
+
+/// Message sent by `lorri self-upgrade` to make the daemon continue
+/// as the lorri in `executable`. See `CommunicationType::Upgrade`.
+///
+/// The daemon lets its running builds finish (for up to its
+/// `shutdown_wait`), answers with an `UpgradeResponse` and then
+/// executes the new lorri in its place. That one keeps listening on
+/// the same socket and resumes all projects.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpgradeRequest {
+    /// The `lorri` binary to continue with.
+    pub executable: PathBuf,
+}
+
+/// The daemon’s answer to an `UpgradeRequest`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct UpgradeResponse {
+    /// How many projects the new daemon resumes.
+    pub projects: usize,
+    /// Why the daemon does not upgrade, if it doesn’t.
+    pub error: Option<String>,
+}
+
+/// A line of a project’s build log.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogLine {
+    /// The nix file of the project.
+    pub nix_file: NixFile,
+    /// The line, without a trailing newline.
+    pub line: String,
+}
+
+/// What the project’s `BuildLoop` in the daemon is doing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildState {
+    /// No build started yet.
+    Pending,
+    /// A build is running.
+    Building,
+    /// The last build succeeded.
+    Success,
+    /// The last build failed.
+    Failure,
+}
+
+/// The daemon’s answer to `CommunicationType::Status`, one for each
+/// project it builds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProjectStatus {
+    /// The nix file of the project.
+    pub nix_file: NixFile,
+    /// What the project’s `BuildLoop` is doing.
+    pub state: BuildState,
+    /// When the last build finished, in seconds since the Unix epoch.
+    pub last_build: Option<u64>,
+    /// How long the last build took, in seconds.
+    pub last_build_duration: Option<f64>,
+    /// The GC root of the project’s shell environment.
+    pub gc_root: PathBuf,
+    /// How many paths the `BuildLoop` watches.
+    pub watched_files: usize,
+}
+
 /// No message can be sent through this socket end (empty type).
 pub enum NoMessage {}
 
 /// `Listener` and possible errors.
 pub mod listener {
     use super::*;
+    use std::os::unix::io::{AsRawFd, RawFd};
     use std::os::unix::net::{UnixListener, UnixStream};
 
-    /// If a connection on the socket is attempted and the first
-    /// message is of a `ConnectionType`, the `Listener` returns
-    /// this message as an ack.
-    /// In all other cases the `Listener` returns no answer (the
-    /// bad client should time out after some time).
+    /// The `Listener`’s answer to the protocol version and
+    /// `CommunicationType` a client opens the connection with.
+    /// If the client’s message cannot be decoded, the `Listener`
+    /// returns no answer (the bad client should time out after
+    /// some time).
     #[derive(Debug, Serialize, Deserialize)]
-    pub struct ConnectionAccepted();
+    pub enum Handshake {
+        /// The daemon speaks the client’s protocol version.
+        Accepted,
+        /// The daemon speaks another version of the protocol.
+        VersionMismatch {
+            /// The `PROTOCOL_VERSION` of the daemon.
+            daemon_version: u32,
+        },
+    }
 
     /// Server-side part of a socket transmission,
     /// listening for incoming messages.
@@ -75,6 +245,11 @@ pub mod listener {
         Accept(std::io::Error),
         /// The client’s message could not be decoded.
         Message(ReadWriteError),
+        /// The client speaks another version of the protocol.
+        VersionMismatch {
+            /// The protocol version of the client.
+            client_version: u32,
+        },
     }
 
     impl Listener {
@@ -88,6 +263,26 @@ pub mod listener {
             })
         }
 
+        /// The file descriptor of the listening socket, e.g. to pass
+        /// it on with `systemd::exec_with_socket`.
+        pub fn as_raw_fd(&self) -> RawFd {
+            self.listener.as_raw_fd()
+        }
+
+        /// Use the socket systemd passed, if the daemon was started
+        /// by socket activation (see `socket::systemd`). `socket_path`
+        /// should be the path it is bound to, it is locked like by `new`.
+        pub fn activated(socket_path: &SocketPath) -> Result<Option<Listener>, BindError> {
+            match systemd::listen_socket() {
+                Some(l) => Ok(Some(Listener {
+                    listener: l,
+                    bind_lock: socket_path.lock()?,
+                    accept_timeout: DEFAULT_READ_TIMEOUT,
+                })),
+                None => Ok(None),
+            }
+        }
+
         /// Accept a new connection on the socket,
         /// read the communication type and then delegate to the
         /// corresponding handling subroutine.
@@ -105,16 +300,42 @@ pub mod listener {
         {
             // - socket accept
             let (unix_stream, _) = self.listener.accept().map_err(AcceptError::Accept)?;
-            // - read first message as a `CommunicationType`
-            let comm_type: CommunicationType =
-                ReadWriter::<CommunicationType, ConnectionAccepted>::new(&unix_stream)
-                    .react(self.accept_timeout.clone(), |_| ConnectionAccepted())
-                    .map_err(AcceptError::Message)?;
+            // - read the protocol version
+            let client_version: u32 = ReadWriter::<u32, Handshake>::new(&unix_stream)
+                .read(&self.accept_timeout)
+                .map_err(|e| AcceptError::Message(ReadWriteError::R(e)))?;
+            let comm_type = match client_version {
+                // the `CommunicationType::Ping` of an unversioned client,
+                // which expects no answer
+                0 => {
+                    warn!(
+                        "An outdated lorri client connected, \
+                         please restart your shells to use the current lorri"
+                    );
+                    CommunicationType::Ping
+                }
+                PROTOCOL_VERSION => {
+                    // - read the `CommunicationType` and accept it
+                    ReadWriter::<CommunicationType, Handshake>::new(&unix_stream)
+                        .react(self.accept_timeout.clone(), |_| Handshake::Accepted)
+                        .map_err(AcceptError::Message)?
+                }
+                client_version => {
+                    ReadWriter::<u32, Handshake>::new(&unix_stream)
+                        .write(
+                            &self.accept_timeout,
+                            &Handshake::VersionMismatch {
+                                daemon_version: PROTOCOL_VERSION,
+                            },
+                        )
+                        .map_err(|e| AcceptError::Message(ReadWriteError::W(e)))?;
+                    return Err(AcceptError::VersionMismatch { client_version });
+                }
+            };
             // spawn a thread with the accept handler
             Ok(std::thread::spawn(move || handler(unix_stream, comm_type)))
         }
     }
-
 }
 
 /// Clients that can talk to a `Listener`.
@@ -154,8 +375,39 @@ pub mod client {
     pub enum InitError {
         /// `connect()` syscall failed.
         SocketConnect(std::io::Error),
-        /// Handshake failed (write `ConnectionType`, read `ConnectionAccepted`).
+        /// Handshake failed (write `ConnectionType`, read `Handshake`).
+        /// Daemons from before the protocol was versioned never answer.
         ServerHandshake(ReadWriteError),
+        /// The daemon speaks another version of the protocol.
+        VersionMismatch {
+            /// The protocol version of the daemon.
+            daemon_version: u32,
+        },
+    }
+
+    impl std::fmt::Display for InitError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            match self {
+                InitError::SocketConnect(e) => write!(
+                    f,
+                    "Cannot connect to the lorri daemon ({}), is `lorri daemon` running?",
+                    e
+                ),
+                InitError::ServerHandshake(e) => write!(
+                    f,
+                    "The lorri daemon did not answer ({:?}). It is probably an older \
+                     version of lorri, please restart `lorri daemon`.",
+                    e
+                ),
+                InitError::VersionMismatch { daemon_version } => write!(
+                    f,
+                    "The lorri daemon speaks protocol version {}, but this lorri speaks \
+                     version {}. Please restart `lorri daemon` and your shells, so they \
+                     run the same version of lorri.",
+                    daemon_version, PROTOCOL_VERSION
+                ),
+            }
+        }
     }
 
     // builder pattern for timeouts?
@@ -180,11 +432,17 @@ pub mod client {
             // - connect to `socket_path`
             let socket = socket_path.connect().map_err(InitError::SocketConnect)?;
 
-            // - send initial message with the CommunicationType
+            // - send the protocol version and the CommunicationType
             // - wait for server to acknowledge connect
-            let _: listener::ConnectionAccepted = ReadWriter::new(&socket)
+            ReadWriter::<listener::Handshake, u32>::new(&socket)
+                .write(&self.timeout, &PROTOCOL_VERSION)
+                .map_err(|e| InitError::ServerHandshake(ReadWriteError::W(e)))?;
+            let handshake: listener::Handshake = ReadWriter::new(&socket)
                 .communicate(self.timeout.clone(), &self.comm_type)
                 .map_err(InitError::ServerHandshake)?;
+            if let listener::Handshake::VersionMismatch { daemon_version } = handshake {
+                return Err(InitError::VersionMismatch { daemon_version });
+            }
 
             Ok(Client {
                 comm_type: self.comm_type,
@@ -196,32 +454,95 @@ pub mod client {
         }
 
         /// Read a message returned by the connected `Listener`.
-        pub fn read(self) -> Result<R, Error>
+        pub fn read(&self) -> Result<R, Error>
         where
             R: serde::de::DeserializeOwned,
         {
-            let sock = &self.socket.ok_or(Error::NotConnected)?;
+            let sock = self.socket.as_ref().ok_or(Error::NotConnected)?;
             let rw: ReadWriter<R, W> = ReadWriter::new(sock);
             rw.read(&self.timeout)
                 .map_err(|e| Error::Message(ReadWriteError::R(e)))
         }
 
+        /// Read messages returned by the connected `Listener` and pass
+        /// each of them to `f`, until the `Listener` closes the
+        /// connection. Each message is waited for up to `timeout`.
+        pub fn read_until_closed<F>(&self, timeout: &Timeout, mut f: F) -> Result<(), Error>
+        where
+            R: serde::de::DeserializeOwned,
+            F: FnMut(R),
+        {
+            let sock = self.socket.as_ref().ok_or(Error::NotConnected)?;
+            let rw: ReadWriter<R, W> = ReadWriter::new(sock);
+            loop {
+                match rw.read(timeout) {
+                    Ok(mes) => f(mes),
+                    Err(ReadError::Deserialize(ref e)) if is_closed(e) => return Ok(()),
+                    Err(e) => return Err(Error::Message(ReadWriteError::R(e))),
+                }
+            }
+        }
+
         /// Write a message to the connected `Listener`.
-        pub fn write(self, mes: &W) -> Result<(), Error>
+        pub fn write(&self, mes: &W) -> Result<(), Error>
         where
             W: serde::Serialize,
         {
-            let sock = &self.socket.ok_or(Error::NotConnected)?;
+            let sock = self.socket.as_ref().ok_or(Error::NotConnected)?;
             let mut rw: ReadWriter<R, W> = ReadWriter::new(sock);
             rw.write(&self.timeout, mes)
                 .map_err(|e| Error::Message(ReadWriteError::W(e)))
         }
     }
 
+    /// Whether reading failed because the other side closed the
+    /// connection before sending anything.
+    fn is_closed(e: &bincode::Error) -> bool {
+        match **e {
+            bincode::ErrorKind::Io(ref io) => io.kind() == std::io::ErrorKind::UnexpectedEof,
+            _ => false,
+        }
+    }
+
     /// Client for the `Ping` communication type.
     /// Reading and writing messages is bounded by `timeout`.
     pub fn ping(timeout: Timeout) -> Client<NoMessage, Ping> {
         Client::bake(timeout, CommunicationType::Ping)
     }
 
+    /// Client for the `Status` communication type.
+    /// Reading and writing messages is bounded by `timeout`.
+    pub fn status(timeout: Timeout) -> Client<Vec<ProjectStatus>, NoMessage> {
+        Client::bake(timeout, CommunicationType::Status)
+    }
+
+    /// Client for the `Subscribe` communication type.
+    /// Reading and writing messages is bounded by `timeout`.
+    pub fn subscribe(timeout: Timeout) -> Client<ProjectEvent, SubscribeRequest> {
+        Client::bake(timeout, CommunicationType::Subscribe)
+    }
+
+    /// Client for the `Logs` communication type.
+    /// Reading and writing messages is bounded by `timeout`.
+    pub fn logs(timeout: Timeout) -> Client<Vec<LogLine>, LogsRequest> {
+        Client::bake(timeout, CommunicationType::Logs)
+    }
+
+    /// Client for the `Forget` communication type.
+    /// Reading and writing messages is bounded by `timeout`.
+    pub fn forget(timeout: Timeout) -> Client<ForgetResponse, ForgetRequest> {
+        Client::bake(timeout, CommunicationType::Forget)
+    }
+
+    /// Client for the `Shell` communication type.
+    /// Reading and writing messages is bounded by `timeout`.
+    pub fn shell(timeout: Timeout) -> Client<ShellMessage, ShellRequest> {
+        Client::bake(timeout, CommunicationType::Shell)
+    }
+
+    /// Client for the `Upgrade` communication type.
+    /// Reading and writing messages is bounded by `timeout`.
+    pub fn upgrade(timeout: Timeout) -> Client<UpgradeResponse, UpgradeRequest> {
+        Client::bake(timeout, CommunicationType::Upgrade)
+    }
 }
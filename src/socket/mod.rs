@@ -2,6 +2,7 @@
 
 pub mod communicate;
 pub mod path;
+pub mod systemd;
 
 use std::convert::TryFrom;
 use std::io::Write;
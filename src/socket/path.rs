@@ -95,6 +95,13 @@ impl<'a> SocketPath<'a> {
         Ok((l, lock))
     }
 
+    /// Lock this socket path for a listener which is already bound
+    /// to it, like the socket systemd passes on socket activation.
+    /// Other processes then can’t `bind` to the path.
+    pub fn lock(&self) -> Result<BindLock, BindError> {
+        self.try_locking()
+    }
+
     /// `connect(2)` to this socket path.
     pub fn connect(&self) -> std::io::Result<UnixStream> {
         UnixStream::connect(self.0)
@@ -0,0 +1,173 @@
+//! Running the daemon as a systemd user service: socket activation
+//! (see `sd_listen_fds(3)`) and readiness notification (see
+//! `sd_notify(3)`).
+//!
+//! The daemon also passes its socket on the same way when it replaces
+//! itself with an upgraded lorri, see `exec_with_socket`.
+
+extern crate nix;
+
+use self::nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use self::nix::unistd::{close, dup2};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+/// The first file descriptor systemd passes, the following ones
+/// are numbered consecutively.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// The listening socket systemd passed to this process, if it was
+/// started by a `.socket` unit. Like `sd_listen_fds(3)`, the
+/// variables describing it are removed from the environment, so
+/// they are not passed on to nix.
+pub fn listen_socket() -> Option<UnixListener> {
+    let passed = passed_fds(
+        std::env::var("LISTEN_PID").ok(),
+        std::env::var("LISTEN_FDS").ok(),
+        std::process::id(),
+    );
+    for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    if passed == 0 {
+        return None;
+    }
+    if passed > 1 {
+        warn!(
+            "systemd passed {} sockets, only using the first one",
+            passed
+        );
+    }
+    if let Err(e) = fcntl(SD_LISTEN_FDS_START, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+        warn!(
+            "Failed to set FD_CLOEXEC on the socket systemd passed: {}",
+            e
+        );
+    }
+    // systemd guarantees the descriptor is open and ours alone
+    Some(unsafe { UnixListener::from_raw_fd(SD_LISTEN_FDS_START) })
+}
+
+/// How many file descriptors systemd passed to the process `pid`,
+/// according to `$LISTEN_PID` and `$LISTEN_FDS`.
+fn passed_fds(listen_pid: Option<String>, listen_fds: Option<String>, pid: u32) -> u32 {
+    // the variables may have been meant for our parent
+    if listen_pid.and_then(|p| p.parse::<u32>().ok()) != Some(pid) {
+        return 0;
+    }
+    listen_fds.and_then(|n| n.parse().ok()).unwrap_or(0)
+}
+
+/// Replace this process with `command`, passing the listening socket
+/// `socket` on like systemd does, so `listen_socket` finds it there.
+/// The process ID stays the same, which `$LISTEN_PID` relies on.
+/// Only returns if that fails, leaving the file descriptors as they were.
+pub fn exec_with_socket(command: &mut Command, socket: RawFd) -> std::io::Error {
+    let to_io = |e: nix::Error| std::io::Error::new(std::io::ErrorKind::Other, e);
+    // whatever uses the descriptor now is needed again if `exec` fails
+    let saved = if socket == SD_LISTEN_FDS_START {
+        None
+    } else {
+        match fcntl(
+            SD_LISTEN_FDS_START,
+            FcntlArg::F_DUPFD_CLOEXEC(SD_LISTEN_FDS_START),
+        ) {
+            Ok(saved) => Some(saved),
+            Err(nix::Error::Sys(nix::errno::Errno::EBADF)) => None,
+            Err(e) => return to_io(e),
+        }
+    };
+    let passed = if socket == SD_LISTEN_FDS_START {
+        Ok(SD_LISTEN_FDS_START)
+    } else {
+        dup2(socket, SD_LISTEN_FDS_START)
+    }
+    .and_then(|fd| fcntl(fd, FcntlArg::F_SETFD(FdFlag::empty())));
+    let error = match passed {
+        Ok(_) => command
+            .env("LISTEN_PID", std::process::id().to_string())
+            .env("LISTEN_FDS", "1")
+            .exec(),
+        Err(e) => to_io(e),
+    };
+
+    let restored = match saved {
+        Some(saved) => dup2(saved, SD_LISTEN_FDS_START).and_then(|_| close(saved)),
+        None if socket == SD_LISTEN_FDS_START => {
+            fcntl(SD_LISTEN_FDS_START, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)).map(|_| ())
+        }
+        None => close(SD_LISTEN_FDS_START),
+    };
+    if let Err(e) = restored {
+        warn!(
+            "Failed to restore file descriptor {}: {}",
+            SD_LISTEN_FDS_START, e
+        );
+    }
+    error
+}
+
+/// Tell systemd that the daemon accepts connections, if it runs as a
+/// `Type=notify` service (i.e. `$NOTIFY_SOCKET` is set).
+pub fn notify_ready() -> std::io::Result<()> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(socket) => notify(&socket, "READY=1"),
+        None => Ok(()),
+    }
+}
+
+/// Send `state` to the notification socket `socket`, which is a path
+/// or, if it starts with `@`, the name of an abstract socket.
+fn notify(socket: &OsStr, state: &str) -> std::io::Result<()> {
+    let sender = UnixDatagram::unbound()?;
+    match socket.as_bytes().split_first() {
+        #[cfg(target_os = "linux")]
+        Some((b'@', name)) => {
+            use self::nix::sys::socket::{sendto, MsgFlags, SockAddr, UnixAddr};
+            use std::os::unix::io::AsRawFd;
+            let to_io = |e: nix::Error| std::io::Error::new(std::io::ErrorKind::Other, e);
+            let addr = UnixAddr::new_abstract(name).map_err(to_io)?;
+            sendto(
+                sender.as_raw_fd(),
+                state.as_bytes(),
+                &SockAddr::Unix(addr),
+                MsgFlags::empty(),
+            )
+            .map_err(to_io)?;
+        }
+        _ => {
+            sender.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{notify, passed_fds};
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn only_fds_for_this_process_count() {
+        let var = |s: &str| Some(String::from(s));
+        assert_eq!(passed_fds(var("42"), var("1"), 42), 1);
+        assert_eq!(passed_fds(var("41"), var("1"), 42), 0);
+        assert_eq!(passed_fds(None, var("1"), 42), 0);
+        assert_eq!(passed_fds(var("42"), var("nope"), 42), 0);
+    }
+
+    #[test]
+    fn readiness_is_sent() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("notify");
+        let systemd = UnixDatagram::bind(&path).unwrap();
+        notify(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = systemd.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+}
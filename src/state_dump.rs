@@ -0,0 +1,124 @@
+//! A description of what a lorri process is doing, for humans: its
+//! projects, what they watch, the queue and running builds, and the
+//! recent events. `lorri daemon` and `lorri watch` write it to their
+//! log on `SIGUSR1` (see `signal::on_dump`), to debug an instance
+//! which seems stuck without restarting it.
+
+use crate::build_loop::{BuildSlots, Event, WatchSet};
+use crate::builder;
+use crate::status_page;
+use crate::NixFile;
+
+/// How many watched paths are listed per project, the rest are
+/// only counted.
+const MAX_WATCHED_PATHS: usize = 50;
+
+/// What the dump shows about a project.
+pub struct Project<'a> {
+    /// The project’s nix file.
+    pub nix_file: &'a NixFile,
+    /// What its build loop is doing, e.g. `building for 12s`.
+    pub state: String,
+    /// What it watched after the last build, if known.
+    pub watch_set: Option<WatchSet>,
+    /// Its recent events, oldest first.
+    pub recent_events: &'a [Event],
+}
+
+/// The dump of the process called `process` (e.g. `lorri daemon`),
+/// building `projects`, sharing `slots` if it limits its builds.
+pub fn dump(process: &str, slots: Option<&BuildSlots>, projects: &[Project]) -> String {
+    let mut out = format!(
+        "=== state of {} (pid {}), dumped on SIGUSR1 ===\n",
+        process,
+        std::process::id()
+    );
+    if let Some(slots) = slots {
+        out.push_str(&format!(
+            "build slots: {} of {} taken, {} builds queued\n",
+            slots.taken(),
+            slots.size(),
+            slots.waiting()
+        ));
+    }
+    let running = builder::running();
+    if running.is_empty() {
+        out.push_str("running nix-build processes: none\n");
+    } else {
+        out.push_str("running nix-build processes:\n");
+        for (group, nix_file) in running {
+            out.push_str(&format!("  process group {}: {}\n", group, nix_file));
+        }
+    }
+    out.push_str(&format!("projects: {}\n", projects.len()));
+    for project in projects {
+        out.push_str(&format!(
+            "\n{}\n  state: {}\n",
+            project.nix_file, project.state
+        ));
+        match project.watch_set {
+            None => out.push_str("  watched paths: unknown, no build finished yet\n"),
+            Some(ref watch_set) => {
+                out.push_str(&format!("  watched paths: {}\n", watch_set.watched.len()));
+                for path in watch_set.watched.iter().take(MAX_WATCHED_PATHS) {
+                    out.push_str(&format!("    {}\n", path.display()));
+                }
+                if watch_set.watched.len() > MAX_WATCHED_PATHS {
+                    out.push_str(&format!(
+                        "    ... and {} more\n",
+                        watch_set.watched.len() - MAX_WATCHED_PATHS
+                    ));
+                }
+            }
+        }
+        out.push_str(&format!(
+            "  recent events: {}\n",
+            project.recent_events.len()
+        ));
+        for event in project.recent_events {
+            out.push_str(&format!("    {}\n", status_page::summary(event)));
+        }
+    }
+    out.push_str("=== end of state ===");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{dump, Project};
+    use crate::build_loop::{BuildId, BuildSlots, Event, Reason, WatchSet};
+    use crate::NixFile;
+    use std::path::PathBuf;
+
+    #[test]
+    fn projects_and_their_events() {
+        let nix_file = NixFile::from(PathBuf::from("/src/shell.nix"));
+        let build_id = BuildId::next();
+        let events = [Event::Started(build_id, Reason::Startup)];
+        let watch_set = WatchSet {
+            watched: (0..60)
+                .map(|i| PathBuf::from(format!("/src/{}.nix", i)))
+                .collect(),
+            ..WatchSet::default()
+        };
+        let out = dump(
+            "lorri daemon",
+            Some(&BuildSlots::new(2)),
+            &[Project {
+                nix_file: &nix_file,
+                state: String::from("building for 3s"),
+                watch_set: Some(watch_set),
+                recent_events: &events,
+            }],
+        );
+        assert!(out.contains("build slots: 0 of 2 taken, 0 builds queued\n"));
+        assert!(out.contains("\n/src/shell.nix\n  state: building for 3s\n"));
+        assert!(out.contains("  watched paths: 60\n    /src/0.nix\n"));
+        assert!(out.contains("    ... and 10 more\n"));
+        assert!(out.contains(&format!(
+            "  recent events: 1\n    build {} started: first build\n",
+            build_id
+        )));
+        assert!(out.ends_with("=== end of state ==="));
+    }
+}
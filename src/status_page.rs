@@ -0,0 +1,142 @@
+//! A read-only status page of the daemon, as HTML for browsers and as
+//! JSON for scripts (see `Config::http_address`).
+
+use crate::build_loop::Event;
+use crate::socket::communicate::{BuildState, ProjectStatus};
+use serde_json::json;
+
+/// How often browsers reload the HTML page, in seconds.
+const REFRESH_SECS: u64 = 5;
+
+/// A project as shown on the status page.
+#[derive(Serialize, Debug)]
+pub struct ProjectPage {
+    /// What the daemon is doing for the project.
+    #[serde(flatten)]
+    pub status: ProjectStatus,
+    /// The events the daemon keeps to replay to subscribers, oldest
+    /// first (see `daemon::EVENT_BUFFER_SIZE`).
+    pub recent_events: Vec<Event>,
+}
+
+/// The page as JSON: `{"projects": [...]}`.
+pub fn json(projects: &[ProjectPage]) -> String {
+    serde_json::to_string_pretty(&json!({ "projects": projects }))
+        .expect("the status page serializes to JSON")
+}
+
+/// The page as HTML; `now` is in seconds since the epoch.
+pub fn html(projects: &[ProjectPage], now: u64) -> String {
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta http-equiv=\"refresh\" content=\"{}\">\n\
+         <title>lorri daemon</title>\n</head>\n<body>\n<h1>lorri daemon</h1>\n",
+        REFRESH_SECS
+    );
+    if projects.is_empty() {
+        page.push_str("<p>The lorri daemon builds no projects yet.</p>\n");
+    }
+    for project in projects {
+        let status = &project.status;
+        let last_build = match (status.last_build, status.last_build_duration) {
+            (Some(finished), Some(took)) => {
+                format!("{}s ago, took {:.0}s", now.saturating_sub(finished), took)
+            }
+            _ => String::from("never"),
+        };
+        page.push_str(&format!(
+            "<h2>{}</h2>\n<p>state: <b>{}</b><br>\nlast build: {}<br>\n\
+             watched files: {}</p>\n<ol>\n",
+            escape(&status.nix_file.to_string()),
+            state(status.state),
+            last_build,
+            status.watched_files
+        ));
+        for event in &project.recent_events {
+            page.push_str(&format!("<li>{}</li>\n", escape(&summary(event))));
+        }
+        page.push_str("</ol>\n");
+    }
+    page.push_str("<p><a href=\"/status.json\">JSON</a></p>\n</body>\n</html>\n");
+    page
+}
+
+/// The name of `state`, as in `lorri status`.
+pub fn state(state: BuildState) -> &'static str {
+    match state {
+        BuildState::Pending => "pending",
+        BuildState::Building => "building",
+        BuildState::Success => "success",
+        BuildState::Failure => "failure",
+    }
+}
+
+/// One line about `event`.
+pub fn summary(event: &Event) -> String {
+    match event {
+        Event::Started(build_id, reason) => format!("build {} started: {}", build_id, reason),
+        Event::Completed(results) if results.cached => {
+            format!("reusing the result of build {}", results.build_id)
+        }
+        Event::Completed(results) => format!("build {} succeeded", results.build_id),
+        Event::Failure(failure) => format!("build {} failed", failure.build_id),
+        Event::Heartbeat(heartbeat) => format!(
+            "build {} running for {}s ({:?})",
+            heartbeat.build_id,
+            heartbeat.elapsed.as_secs(),
+            heartbeat.phase
+        ),
+        Event::Warning(warning) => format!("warning: {}", warning),
+        Event::EnvDiff(diff) => format!("environment changed: {}", diff),
+        Event::Log(build_id, line) => format!("build {}: {}", build_id, line),
+    }
+}
+
+/// Escape `text` for HTML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{html, json, ProjectPage};
+    use crate::build_loop::{BuildId, Event, Reason};
+    use crate::socket::communicate::{BuildState, ProjectStatus};
+    use crate::NixFile;
+    use std::path::PathBuf;
+
+    fn page() -> Vec<ProjectPage> {
+        vec![ProjectPage {
+            status: ProjectStatus {
+                nix_file: NixFile::from(PathBuf::from("/home/alice/<foo>/shell.nix")),
+                state: BuildState::Building,
+                last_build: Some(1000),
+                last_build_duration: Some(12.0),
+                gc_root: PathBuf::from("/gc_roots/abc/shell_gc_root"),
+                watched_files: 3,
+            },
+            recent_events: vec![Event::Started(BuildId::next(), Reason::Scheduled)],
+        }]
+    }
+
+    #[test]
+    fn html_is_escaped() {
+        let html = html(&page(), 1030);
+        assert!(html.contains("<h2>/home/alice/&lt;foo&gt;/shell.nix</h2>"));
+        assert!(html.contains("state: <b>building</b>"));
+        assert!(html.contains("last build: 30s ago, took 12s"));
+        assert!(html.contains("started: scheduled rebuild</li>"));
+    }
+
+    #[test]
+    fn json_has_status_and_events() {
+        let value: serde_json::Value = serde_json::from_str(&json(&page())).unwrap();
+        let project = &value["projects"][0];
+        assert_eq!(project["state"], "building");
+        assert_eq!(project["watched_files"], 3);
+        assert!(project["recent_events"][0]["Started"].is_array());
+    }
+}
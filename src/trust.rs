@@ -0,0 +1,161 @@
+//! Which nix files the user allowed lorri to evaluate.
+//!
+//! Evaluating a nix file runs arbitrary code, so like direnv with
+//! `.envrc` files, lorri only builds a project in the background
+//! (`lorri watch`, `lorri daemon`, `lorri direnv`) after the user
+//! approved its nix file with `lorri allow`. Approval is bound to the
+//! file’s contents: once the file changes, it has to be allowed again.
+//! The contents are compared by their SHA-256 hash, so a file which
+//! collides with an allowed one cannot be crafted.
+
+use crate::sha256;
+use crate::NixFile;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// The decisions of the user, one file per nix file in `dir`.
+pub struct Trust {
+    dir: PathBuf,
+}
+
+/// What the user decided about a nix file, stored as JSON.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Decision {
+    nix_file: PathBuf,
+    /// SHA-256 of the allowed contents; `None` if the file was denied.
+    allowed: Option<String>,
+}
+
+/// Why a project may not be built.
+#[derive(Debug)]
+pub enum NotAllowed {
+    /// The user never decided about the nix file.
+    Unknown(NixFile),
+    /// The nix file changed since the user allowed it.
+    Changed(NixFile),
+    /// The user denied the nix file.
+    Denied(NixFile),
+    /// The nix file or the decision about it cannot be read.
+    Io(NixFile, std::io::Error),
+}
+
+impl std::fmt::Display for NotAllowed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            NotAllowed::Unknown(nix_file) => write!(
+                f,
+                "{} is not allowed yet. Evaluating it can run arbitrary code, \
+                 check its contents and run `lorri allow` to build it.",
+                nix_file
+            ),
+            NotAllowed::Changed(nix_file) => write!(
+                f,
+                "{} changed since it was allowed. Check its contents and run \
+                 `lorri allow` to build it again.",
+                nix_file
+            ),
+            NotAllowed::Denied(nix_file) => {
+                write!(f, "{} is denied, run `lorri allow` to build it.", nix_file)
+            }
+            NotAllowed::Io(nix_file, e) => {
+                write!(f, "Cannot check whether {} is allowed: {}", nix_file, e)
+            }
+        }
+    }
+}
+
+impl Trust {
+    /// The decisions recorded in `dir` (see `Paths::allow_dir`).
+    pub fn new(dir: &Path) -> Trust {
+        Trust {
+            dir: dir.to_owned(),
+        }
+    }
+
+    /// Whether `nix_file`, as it is now, may be built.
+    pub fn check(&self, nix_file: &NixFile) -> Result<(), NotAllowed> {
+        let io_error = |e| NotAllowed::Io(nix_file.clone(), e);
+        let decision = match std::fs::read(self.decision_file(nix_file)) {
+            Ok(contents) => serde_json::from_slice::<Decision>(&contents)
+                .map_err(|e| io_error(std::io::Error::new(std::io::ErrorKind::InvalidData, e)))?,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(NotAllowed::Unknown(nix_file.clone()))
+            }
+            Err(e) => return Err(io_error(e)),
+        };
+        match decision.allowed {
+            None => Err(NotAllowed::Denied(nix_file.clone())),
+            Some(ref hash) if *hash == contents_hash(nix_file).map_err(io_error)? => Ok(()),
+            Some(_) => Err(NotAllowed::Changed(nix_file.clone())),
+        }
+    }
+
+    /// Allow building `nix_file` with its current contents.
+    pub fn allow(&self, nix_file: &NixFile) -> Result<(), std::io::Error> {
+        let allowed = Some(contents_hash(nix_file)?);
+        self.record(nix_file, allowed)
+    }
+
+    /// Refuse building `nix_file` until it is allowed again.
+    pub fn deny(&self, nix_file: &NixFile) -> Result<(), std::io::Error> {
+        self.record(nix_file, None)
+    }
+
+    fn record(&self, nix_file: &NixFile, allowed: Option<String>) -> Result<(), std::io::Error> {
+        let decision = Decision {
+            nix_file: PathBuf::from(nix_file.as_os_str()),
+            allowed,
+        };
+        std::fs::create_dir_all(&self.dir)?;
+        let path = self.decision_file(nix_file);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&decision)?)?;
+        std::fs::rename(&tmp, &path)
+    }
+
+    fn decision_file(&self, nix_file: &NixFile) -> PathBuf {
+        self.dir.join(format!(
+            "{:x}.json",
+            md5::compute(nix_file.as_os_str().as_bytes())
+        ))
+    }
+}
+
+fn contents_hash(nix_file: &NixFile) -> Result<String, std::io::Error> {
+    let contents = std::fs::read(nix_file.as_os_str())?;
+    Ok(sha256::hex_digest(&contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NotAllowed, Trust};
+    use crate::NixFile;
+    use tempfile::tempdir;
+
+    #[test]
+    fn allowing_and_denying() {
+        let temp = tempdir().unwrap();
+        let trust = Trust::new(&temp.path().join("allow"));
+        let nix_file = NixFile::from(temp.path().join("shell.nix"));
+        std::fs::write(temp.path().join("shell.nix"), "{}").unwrap();
+
+        match trust.check(&nix_file) {
+            Err(NotAllowed::Unknown(_)) => {}
+            other => panic!("{:?}", other),
+        }
+        trust.allow(&nix_file).unwrap();
+        trust.check(&nix_file).unwrap();
+
+        std::fs::write(temp.path().join("shell.nix"), "builtins.exec []").unwrap();
+        match trust.check(&nix_file) {
+            Err(NotAllowed::Changed(_)) => {}
+            other => panic!("{:?}", other),
+        }
+
+        trust.deny(&nix_file).unwrap();
+        match trust.check(&nix_file) {
+            Err(NotAllowed::Denied(_)) => {}
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Find out which kind of filesystem a path lives on.
+
+extern crate nix;
+
+use std::path::Path;
+
+/// Filesystems which don’t (reliably) report changes via inotify,
+/// because the files can change on another machine or on the host
+/// of a virtual machine or container.
+#[cfg(target_os = "linux")]
+const REMOTE_FILESYSTEMS: &[u32] = &[
+    0x6969,      // NFS
+    0x517B,      // SMB
+    0xFF53_4D42, // CIFS
+    0xFE53_4D42, // SMB2
+    0x0102_1997, // 9p
+    0x6573_5546, // FUSE, includes virtiofs and most Docker for Mac mounts
+    0x7375_7245, // Coda
+    0x5346_414F, // AFS
+    0x00C3_6400, // Ceph
+];
+
+/// Is `path` on a network or virtual machine filesystem, where
+/// changes have to be found by polling? `false` if unsure.
+#[cfg(target_os = "linux")]
+pub fn is_remote(path: &Path) -> bool {
+    let mut stat: nix::libc::statfs = unsafe { std::mem::zeroed() };
+    match nix::sys::statfs::statfs(path, &mut stat) {
+        Ok(()) => REMOTE_FILESYSTEMS.contains(&(stat.f_type as u32)),
+        Err(e) => {
+            debug!("Cannot determine the filesystem of {:?}: {}", path, e);
+            false
+        }
+    }
+}
+
+/// Is `path` on a network or virtual machine filesystem, where
+/// changes have to be found by polling? `false` if unsure.
+#[cfg(not(target_os = "linux"))]
+pub fn is_remote(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_remote;
+    use tempfile::tempdir;
+
+    #[test]
+    fn nonexistent_path_is_not_remote() {
+        let temp = tempdir().unwrap();
+        assert!(!is_remote(&temp.path().join("does/not/exist")));
+    }
+}
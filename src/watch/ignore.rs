@@ -0,0 +1,400 @@
+//! Decide which paths should never be watched, using the
+//! pattern syntax of `.gitignore` files.
+//!
+//! See `gitignore(5)` for the syntax. In short:
+//!
+//! - `*` matches anything but `/`, `?` a single character but `/`,
+//!   `[a-z]` a character class and `**` any number of directories.
+//! - A pattern without a `/` matches the file name at any depth,
+//!   a pattern containing a `/` is relative to its base directory.
+//! - A trailing `/` only matches directories.
+//! - A leading `!` re-includes a path a previous pattern excluded.
+
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// A single gitignore-style pattern.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    regex: Regex,
+    /// `!pattern`, re-includes matching paths
+    negated: bool,
+    /// `pattern/`, only matches directories
+    dir_only: bool,
+}
+
+impl Pattern {
+    /// Parse one line of a `.gitignore` file.
+    /// Returns `None` for blank lines and comments.
+    pub fn new(line: &str) -> Option<Pattern> {
+        let mut pattern = line.trim_end();
+        if pattern.is_empty() || pattern.starts_with('#') {
+            return None;
+        }
+
+        let negated = pattern.starts_with('!');
+        if negated {
+            pattern = &pattern[1..];
+        }
+        let dir_only = pattern.ends_with('/');
+        if dir_only {
+            pattern = pattern.trim_end_matches('/');
+        }
+        if pattern.is_empty() {
+            return None;
+        }
+
+        // a pattern containing a slash is relative to the base
+        // directory, otherwise it matches a file name at any depth
+        let regex = if pattern.contains('/') {
+            format!("^{}$", glob_to_regex(pattern.trim_start_matches('/')))
+        } else {
+            format!("^(?:.*/)?{}$", glob_to_regex(pattern))
+        };
+
+        Some(Pattern {
+            regex: Regex::new(&regex).ok()?,
+            negated,
+            dir_only,
+        })
+    }
+
+    /// Does the pattern match `path`, which is relative to the
+    /// pattern’s base directory?
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        self.regex.is_match(&path.to_string_lossy())
+    }
+}
+
+/// Translate the glob syntax of a pattern to a regular expression.
+fn glob_to_regex(glob: &str) -> String {
+    let chars = glob.chars().collect::<Vec<char>>();
+    let mut regex = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i + 1) == Some(&'*') => {
+                let starts_component = i == 0 || chars[i - 1] == '/';
+                if starts_component && chars.get(i + 2) == Some(&'/') {
+                    // `**/` matches zero or more directories
+                    regex.push_str("(?:.*/)?");
+                    i += 3;
+                } else {
+                    regex.push_str(".*");
+                    i += 2;
+                }
+            }
+            '*' => {
+                regex.push_str("[^/]*");
+                i += 1;
+            }
+            '?' => {
+                regex.push_str("[^/]");
+                i += 1;
+            }
+            '[' => match character_class(&chars[i..]) {
+                Some((class, len)) => {
+                    regex.push_str(&class);
+                    i += len;
+                }
+                None => {
+                    regex.push_str(r"\[");
+                    i += 1;
+                }
+            },
+            '\\' if i + 1 < chars.len() => {
+                regex.push_str(&regex::escape(&chars[i + 1].to_string()));
+                i += 2;
+            }
+            c => {
+                regex.push_str(&regex::escape(&c.to_string()));
+                i += 1;
+            }
+        }
+    }
+    regex
+}
+
+/// Translate a glob character class starting at `chars[0] == '['`.
+/// Returns the regex class and the number of characters consumed,
+/// or `None` if the class is never closed.
+fn character_class(chars: &[char]) -> Option<(String, usize)> {
+    let mut class = String::from("[");
+    let mut i = 1;
+    if chars.get(i) == Some(&'!') || chars.get(i) == Some(&'^') {
+        class.push('^');
+        i += 1;
+    }
+    // a `]` right at the start is part of the class
+    if chars.get(i) == Some(&']') {
+        class.push_str(r"\]");
+        i += 1;
+    }
+    while let Some(&c) = chars.get(i) {
+        match c {
+            ']' => {
+                class.push(']');
+                return Some((class, i + 1));
+            }
+            '\\' | '[' | '&' | '~' => {
+                class.push('\\');
+                class.push(c);
+            }
+            c => class.push(c),
+        }
+        i += 1;
+    }
+    None
+}
+
+/// Temporary and backup files of well-known editors, which are
+/// written while editing and never influence the build.
+const EDITOR_TEMP_FILES: &[&str] = &[
+    // vim
+    "*.swp",
+    "*.swo",
+    "*.swx",
+    "4913",
+    "*~",
+    // emacs
+    r"\#*#",
+    ".#*",
+    // JetBrains IDEs
+    "*___jb_tmp___",
+    "*___jb_old___",
+    // kate
+    ".*.kate-swp",
+];
+
+/// An ordered list of patterns; later patterns take precedence.
+#[derive(Debug, Clone, Default)]
+pub struct Patterns(Vec<Pattern>);
+
+impl Patterns {
+    /// Parse the contents of a `.gitignore` file.
+    pub fn parse(contents: &str) -> Patterns {
+        Patterns::from_lines(contents.lines())
+    }
+
+    /// Patterns matching the temporary files of well-known editors.
+    pub fn editor_temp_files() -> Patterns {
+        Patterns::from_lines(EDITOR_TEMP_FILES)
+    }
+
+    /// Parse a list of patterns, one per item.
+    pub fn from_lines<I, S>(lines: I) -> Patterns
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Patterns(
+            lines
+                .into_iter()
+                .filter_map(|line| Pattern::new(line.as_ref()))
+                .collect(),
+        )
+    }
+
+    /// Append the patterns of `other`, which take precedence.
+    pub fn extend(&mut self, other: Patterns) {
+        self.0.extend(other.0)
+    }
+
+    /// Whether the last pattern matching `path` (relative to the
+    /// patterns’ base directory) ignores it (`Some(true)`) or
+    /// re-includes it (`Some(false)`). `None` if nothing matches.
+    pub fn ignores(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        self.0
+            .iter()
+            .rev()
+            .find(|pattern| pattern.matches(path, is_dir))
+            .map(|pattern| !pattern.negated)
+    }
+
+    /// Whether `path` (relative to the patterns’ base directory) or
+    /// any of its parent directories is ignored.
+    pub fn ignores_path(&self, path: &Path, is_dir: bool) -> bool {
+        let mut prefix = PathBuf::new();
+        let mut components = path.components().peekable();
+        while let Some(component) = components.next() {
+            prefix.push(component);
+            let prefix_is_dir = components.peek().is_some() || is_dir;
+            if self.ignores(&prefix, prefix_is_dir) == Some(true) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Answers whether paths are ignored by git, reading the
+/// `.gitignore` files of the repository they are in.
+#[derive(Debug, Default)]
+pub struct GitIgnore {
+    /// Patterns from the `.gitignore` in a directory, by directory.
+    cache: HashMap<PathBuf, Patterns>,
+}
+
+impl GitIgnore {
+    /// Create an empty `GitIgnore`; files are read lazily.
+    pub fn new() -> GitIgnore {
+        GitIgnore::default()
+    }
+
+    /// Is `path` ignored by a `.gitignore` file of the git repository
+    /// it belongs to? Paths outside of git repositories are never ignored.
+    ///
+    /// Like git, a path is also ignored if any of its parent
+    /// directories is ignored.
+    pub fn is_ignored(&mut self, path: &Path) -> bool {
+        let root = match path.ancestors().find(|dir| dir.join(".git").exists()) {
+            Some(root) => root.to_path_buf(),
+            None => return false,
+        };
+        let relative = match path.strip_prefix(&root) {
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => return false,
+        };
+
+        let mut parent = root.clone();
+        for component in relative.components() {
+            let current = parent.join(component);
+            if component.as_os_str() == ".git" {
+                return true;
+            }
+            let is_dir = current.is_dir();
+
+            // every .gitignore from the root down to the parent
+            // directory applies, deeper files take precedence
+            let mut ignored = false;
+            for dir in parent.ancestors().take_while(|dir| dir.starts_with(&root)) {
+                let patterns = self.patterns(dir, &root);
+                let relative = current.strip_prefix(dir).expect("dir is an ancestor");
+                if let Some(result) = patterns.ignores(relative, is_dir) {
+                    ignored = result;
+                    break;
+                }
+            }
+            if ignored {
+                return true;
+            }
+            parent = current;
+        }
+        false
+    }
+
+    /// The patterns which apply to paths below `dir`.
+    fn patterns(&mut self, dir: &Path, root: &Path) -> &Patterns {
+        self.cache.entry(dir.to_path_buf()).or_insert_with(|| {
+            let read =
+                |path: PathBuf| Patterns::parse(&std::fs::read_to_string(path).unwrap_or_default());
+            let mut patterns = Patterns::default();
+            if dir == root {
+                patterns.extend(read(root.join(".git/info/exclude")));
+            }
+            patterns.extend(read(dir.join(".gitignore")));
+            patterns
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GitIgnore, Pattern, Patterns};
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        Pattern::new(pattern)
+            .unwrap()
+            .matches(Path::new(path), false)
+    }
+
+    #[test]
+    fn glob_syntax() {
+        assert!(matches("*.swp", "foo.swp"));
+        assert!(matches("*.swp", "src/.foo.swp"));
+        assert!(!matches("*.swp", "foo.swp.bak"));
+        assert!(matches("/target", "target"));
+        assert!(!matches("/target", "src/target"));
+        assert!(matches("src/*.rs", "src/main.rs"));
+        assert!(!matches("src/*.rs", "src/bin/main.rs"));
+        assert!(matches("src/**/*.rs", "src/main.rs"));
+        assert!(matches("src/**/*.rs", "src/bin/main.rs"));
+        assert!(matches("**/result", "a/b/result"));
+        assert!(matches("build/**", "build/a/b"));
+        assert!(matches("foo?", "foo1"));
+        assert!(matches("[#]*#", "#foo#"));
+        assert!(matches("file[!0-9]", "filea"));
+        assert!(!matches("file[!0-9]", "file1"));
+        assert!(matches(r"\#notacomment", "#notacomment"));
+        assert!(Pattern::new("# comment").is_none());
+        assert!(Pattern::new("   ").is_none());
+    }
+
+    #[test]
+    fn dir_only_and_negation() {
+        let patterns = Patterns::parse("node_modules/\n*.log\n!keep.log\n");
+        let p = Path::new;
+        assert_eq!(patterns.ignores(p("node_modules"), true), Some(true));
+        assert_eq!(patterns.ignores(p("node_modules"), false), None);
+        assert_eq!(patterns.ignores(p("a.log"), false), Some(true));
+        assert_eq!(patterns.ignores(p("keep.log"), false), Some(false));
+        assert_eq!(patterns.ignores(p("shell.nix"), false), None);
+    }
+
+    #[test]
+    fn editor_temp_files() {
+        let patterns = Patterns::editor_temp_files();
+        let ignored = |path| patterns.ignores_path(Path::new(path), false);
+        assert!(ignored(".shell.nix.swp"));
+        assert!(ignored("nix/4913"));
+        assert!(ignored("shell.nix~"));
+        assert!(ignored("#shell.nix#"));
+        assert!(ignored(".#shell.nix"));
+        assert!(ignored("default.nix___jb_tmp___"));
+        assert!(!ignored("shell.nix"));
+        assert!(!ignored("nix/sources.json"));
+    }
+
+    #[test]
+    fn ignored_parent_directories() {
+        let patterns = Patterns::from_lines(&["target/", "/build"]);
+        let p = Path::new;
+        assert!(patterns.ignores_path(p("target/debug/foo"), false));
+        assert!(patterns.ignores_path(p("sub/target"), true));
+        assert!(!patterns.ignores_path(p("sub/target"), false));
+        assert!(patterns.ignores_path(p("build/out"), false));
+        assert!(!patterns.ignores_path(p("sub/build/out"), false));
+        assert!(!patterns.ignores_path(p("src/main.rs"), false));
+    }
+
+    #[test]
+    fn gitignore_files_in_repository() {
+        let temp = tempdir().unwrap();
+        let root = temp.path();
+        std::fs::create_dir_all(root.join(".git")).unwrap();
+        std::fs::create_dir_all(root.join("target/debug")).unwrap();
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join(".gitignore"), "/target\n*.tmp\n").unwrap();
+        std::fs::write(root.join("sub/.gitignore"), "!important.tmp\n").unwrap();
+
+        let mut gitignore = GitIgnore::new();
+        assert!(gitignore.is_ignored(&root.join("target")));
+        assert!(gitignore.is_ignored(&root.join("target/debug")));
+        assert!(gitignore.is_ignored(&root.join("foo.tmp")));
+        assert!(gitignore.is_ignored(&root.join("sub/foo.tmp")));
+        assert!(gitignore.is_ignored(&root.join(".git")));
+        assert!(!gitignore.is_ignored(&root.join("sub/important.tmp")));
+        assert!(!gitignore.is_ignored(&root.join("shell.nix")));
+        assert!(!gitignore.is_ignored(&root.join("sub")));
+
+        // outside of a repository nothing is ignored
+        let outside = tempdir().unwrap();
+        assert!(!gitignore.is_ignored(&outside.path().join("foo.tmp")));
+    }
+}
@@ -0,0 +1,1255 @@
+//! Recursively watch paths for changes, in an extensible and
+//! cross-platform way.
+
+extern crate nix;
+
+pub mod filesystem;
+pub mod ignore;
+pub mod scripted;
+pub mod watchman;
+
+use self::ignore::{GitIgnore, Patterns};
+use self::watchman::Watchman;
+use crate::mpsc::FilterTimeoutIterator;
+use notify::{PollWatcher, RecommendedWatcher, RecursiveMode, Watcher};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvError, Sender};
+use std::time::Duration;
+
+/// What happened to a changed path.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChangeKind {
+    /// The path was created
+    Created,
+    /// The contents or metadata of the path changed
+    Modified,
+    /// The path was removed
+    Removed,
+    /// The path was renamed, to or from another name
+    Renamed,
+}
+
+/// A change to a watched path.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// The changed path
+    pub path: PathBuf,
+    /// What happened to it
+    pub kind: ChangeKind,
+}
+
+impl std::fmt::Display for Change {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = match self.kind {
+            ChangeKind::Created => "created",
+            ChangeKind::Modified => "modified",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Renamed => "renamed",
+        };
+        write!(f, "{} {}", self.path.display(), kind)
+    }
+}
+
+/// What a `BuildLoop` needs from a filesystem watcher. Implemented
+/// by `Watch`, and by `scripted::ScriptedWatch` for tests.
+pub trait FileWatcher: Send {
+    /// Watch exactly `paths` (and their children).
+    fn update(&mut self, paths: &[PathBuf]) -> Result<(), notify::Error>;
+    /// Never report changes below `paths` again.
+    fn exclude(&mut self, paths: &[PathBuf]);
+    /// See `Watch::respect_gitignore`.
+    fn respect_gitignore(&mut self, enabled: bool);
+    /// See `Watch::ignore`.
+    fn ignore(&mut self, base: PathBuf, patterns: Patterns);
+    /// Non-blocking, the changes which happened so far.
+    fn drain_changes(&mut self) -> Vec<Change>;
+    /// Block until something changes. `None` if the watcher stopped.
+    fn wait_for_change(&mut self) -> Option<Vec<Change>>;
+    /// Block until something changes. `None` if `timeout` passed first.
+    fn block_timeout(&mut self, timeout: Duration) -> Option<Vec<Change>>;
+    /// Whether the watch limit was reached since the last call.
+    fn take_watch_limit_reached(&mut self) -> bool;
+    /// Every watched path, sorted.
+    fn watched_paths(&self) -> Vec<PathBuf>;
+    /// Why the watcher was restarted since the last call, if it was.
+    fn take_restart(&mut self) -> Option<String>;
+}
+
+impl FileWatcher for Watch {
+    fn update(&mut self, paths: &[PathBuf]) -> Result<(), notify::Error> {
+        Watch::update(self, paths)
+    }
+    fn exclude(&mut self, paths: &[PathBuf]) {
+        Watch::exclude(self, paths)
+    }
+    fn respect_gitignore(&mut self, enabled: bool) {
+        Watch::respect_gitignore(self, enabled)
+    }
+    fn ignore(&mut self, base: PathBuf, patterns: Patterns) {
+        Watch::ignore(self, base, patterns)
+    }
+    fn drain_changes(&mut self) -> Vec<Change> {
+        Watch::drain_changes(self)
+    }
+    fn wait_for_change(&mut self) -> Option<Vec<Change>> {
+        Watch::wait_for_change(self).ok()
+    }
+    fn block_timeout(&mut self, timeout: Duration) -> Option<Vec<Change>> {
+        Watch::block_timeout(self, timeout).ok()
+    }
+    fn take_watch_limit_reached(&mut self) -> bool {
+        Watch::take_watch_limit_reached(self)
+    }
+    fn watched_paths(&self) -> Vec<PathBuf> {
+        Watch::watched_paths(self)
+    }
+    fn take_restart(&mut self) -> Option<String> {
+        Watch::take_restart(self)
+    }
+}
+
+/// How changes to the filesystem are detected.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    /// Poll if the project is on a network or virtual machine
+    /// filesystem, use the native backend otherwise.
+    Auto,
+    /// The operating system’s notifications (e.g. inotify).
+    Native,
+    /// Regularly check all watched paths for changes.
+    Poll,
+    /// Subscribe to a running Watchman daemon, for very large projects.
+    Watchman,
+}
+
+enum Notifier {
+    Native(RecommendedWatcher),
+    Poll(PollWatcher, Duration),
+    Watchman(Watchman),
+}
+
+impl Notifier {
+    fn watch(&mut self, path: &Path, mode: RecursiveMode) -> Result<(), notify::Error> {
+        match self {
+            Notifier::Native(watcher) => watcher.watch(path, mode),
+            Notifier::Poll(watcher, _) => watcher.watch(path, mode),
+            Notifier::Watchman(watchman) => watchman.watch(path),
+        }
+    }
+
+    fn unwatch(&mut self, path: &Path) -> Result<(), notify::Error> {
+        match self {
+            Notifier::Native(watcher) => watcher.unwatch(path),
+            Notifier::Poll(watcher, _) => watcher.unwatch(path),
+            Notifier::Watchman(watchman) => watchman.unwatch(path),
+        }
+    }
+
+    /// A fresh notifier of the same kind, without any watches,
+    /// sending its events to `tx`.
+    fn restart(&self, tx: Sender<notify::RawEvent>) -> Result<Notifier, notify::Error> {
+        Ok(match self {
+            Notifier::Native(_) => Notifier::Native(Watcher::new_raw(tx)?),
+            Notifier::Poll(_, interval) => Notifier::Poll(poll_watcher(tx, *interval)?, *interval),
+            Notifier::Watchman(_) => Notifier::Watchman(Watchman::new(tx)?),
+        })
+    }
+}
+
+/// A dynamic list of paths to watch for changes, and
+/// react to changes when they occur.
+pub struct Watch {
+    notify: Notifier,
+    rx: std::sync::mpsc::Receiver<notify::RawEvent>,
+    watches: HashSet<PathBuf>,
+    /// Paths registered with `notify`: the watches, and the parent
+    /// directories of watched files.
+    registered: HashSet<PathBuf>,
+    /// Registrations from before the running `update`, which are
+    /// removed unless they are still needed.
+    previously_registered: HashSet<PathBuf>,
+    /// Watched paths by their canonical path, if that is different.
+    /// FSEvents on macOS reports canonical paths, e.g. under
+    /// `/private/var` for a watch on `/var`.
+    canonical: HashMap<PathBuf, PathBuf>,
+    /// Paths (and everything below them) whose changes are ignored.
+    excluded: HashSet<PathBuf>,
+    /// Skip paths which are ignored by git.
+    respect_gitignore: bool,
+    /// User-configured ignore patterns, relative to the base directory.
+    ignore: (PathBuf, Patterns),
+    /// Watched paths which don’t exist: either they were removed or
+    /// renamed over, e.g. by an editor saving atomically, and their
+    /// watch is gone with the old file; or they never existed. They
+    /// are watched (again) once the file is created.
+    missing: RefCell<HashSet<PathBuf>>,
+    /// The operating system refused to add more watches since
+    /// `take_watch_limit_reached` was last called.
+    watch_limit_reached: bool,
+    /// Directories which are not (completely) watched because the
+    /// limit of watches was reached. Changes anywhere below them are
+    /// reported, see `reach_watch_limit`.
+    over_limit: HashSet<PathBuf>,
+    /// The backend reported an error (or lost events), and has to
+    /// be restarted before it can be trusted again.
+    failure: RefCell<Option<String>>,
+    /// Why the backend was restarted since `take_restart` was last called.
+    restarted: Option<String>,
+}
+
+impl Watch {
+    /// Instantiate a new Watch.
+    pub fn init() -> Result<Watch, notify::Error> {
+        let (tx, rx) = channel();
+        Ok(Watch::with_notifier(
+            Notifier::Native(Watcher::new_raw(tx)?),
+            rx,
+        ))
+    }
+
+    /// Instantiate a new Watch which checks the watched paths for
+    /// changes every `interval`, for filesystems without working
+    /// change notifications.
+    pub fn init_polling(interval: Duration) -> Result<Watch, notify::Error> {
+        let (tx, rx) = channel();
+        Ok(Watch::with_notifier(
+            Notifier::Poll(poll_watcher(tx, interval)?, interval),
+            rx,
+        ))
+    }
+
+    /// Instantiate a new Watch which receives changes from the
+    /// Watchman daemon.
+    pub fn init_watchman() -> Result<Watch, notify::Error> {
+        let (tx, rx) = channel();
+        Ok(Watch::with_notifier(
+            Notifier::Watchman(Watchman::new(tx)?),
+            rx,
+        ))
+    }
+
+    /// Instantiate a new Watch for the project in `project_dir`,
+    /// resolving `Backend::Auto` by looking at its filesystem.
+    pub fn init_for(
+        backend: Backend,
+        poll_interval: Duration,
+        project_dir: &Path,
+    ) -> Result<Watch, notify::Error> {
+        match backend {
+            Backend::Native => Watch::init(),
+            Backend::Poll => Watch::init_polling(poll_interval),
+            Backend::Watchman => Watch::init_watchman(),
+            Backend::Auto if filesystem::is_remote(project_dir) => {
+                info!(
+                    "{} is on a network filesystem, polling for changes",
+                    project_dir.display()
+                );
+                Watch::init_polling(poll_interval)
+            }
+            Backend::Auto => Watch::init(),
+        }
+    }
+
+    fn with_notifier(notify: Notifier, rx: std::sync::mpsc::Receiver<notify::RawEvent>) -> Watch {
+        Watch {
+            notify,
+            watches: HashSet::new(),
+            registered: HashSet::new(),
+            previously_registered: HashSet::new(),
+            canonical: HashMap::new(),
+            excluded: HashSet::new(),
+            respect_gitignore: true,
+            ignore: (PathBuf::from("/"), Patterns::editor_temp_files()),
+            missing: RefCell::new(HashSet::new()),
+            watch_limit_reached: false,
+            over_limit: HashSet::new(),
+            failure: RefCell::new(None),
+            restarted: None,
+            rx,
+        }
+    }
+
+    /// Whether paths ignored by git (via `.gitignore` files of the
+    /// repository they are in) should be skipped. Enabled by default.
+    pub fn respect_gitignore(&mut self, enabled: bool) {
+        self.respect_gitignore = enabled;
+    }
+
+    /// Never watch, or react to changes of, paths matching `patterns`
+    /// (in `.gitignore` syntax). Patterns containing a `/` are
+    /// relative to `base`.
+    ///
+    /// Temporary files of well-known editors are always ignored, unless
+    /// `patterns` re-include them (e.g. `!*~`).
+    pub fn ignore(&mut self, base: PathBuf, patterns: Patterns) {
+        let mut all = Patterns::editor_temp_files();
+        all.extend(patterns);
+        self.ignore = (base, all);
+    }
+
+    /// Extend the watch list with an additional list of paths.
+    /// Note: Watch maintains a list of already watched paths, and
+    /// will not add duplicates.
+    ///
+    /// The targets of symlinks are watched as well. Since the links
+    /// themselves are watched, retargeting one triggers a rebuild, which
+    /// extends the watch list again and picks up the new target.
+    pub fn extend(&mut self, paths: &[PathBuf]) -> Result<(), notify::Error> {
+        // read the .gitignore files anew, they might have changed
+        let mut gitignore = if self.respect_gitignore {
+            Some(GitIgnore::new())
+        } else {
+            None
+        };
+
+        // Shallow paths first, so a file is usually already covered
+        // by the watch on its directory when we get to it.
+        let mut paths = paths.iter().collect::<Vec<&PathBuf>>();
+        paths.sort_by_key(|path| (path.components().count(), *path));
+        paths.dedup();
+
+        let mut targets = vec![];
+        for path in paths {
+            // store paths are immutable, watching them is a waste
+            if path.starts_with("/nix/store") {
+                debug!("Not watching {:?}, it is in the Nix store", path);
+                continue;
+            }
+            if let Some(target) = symlink_target(path) {
+                if !self.watches.contains(&target) {
+                    debug!("Watching {:?}, the target of {:?}", target, path);
+                    targets.push(target);
+                }
+            }
+            if self.watches.contains(path) {
+                continue;
+            }
+            if self.is_ignored(path, path.is_dir()) {
+                debug!("Not watching {:?}, it matches an ignore pattern", path);
+                continue;
+            }
+            if is_ignored(&mut gitignore, path) {
+                debug!("Not watching {:?}, it is ignored by git", path);
+                continue;
+            }
+            self.add_path(&path)?;
+            if path.is_dir() {
+                self.add_path_recursively(&path, &mut gitignore)?;
+            }
+        }
+
+        if targets.is_empty() {
+            Ok(())
+        } else {
+            self.extend(&targets)
+        }
+    }
+
+    /// Whether the operating system’s limit of watches (e.g.
+    /// `fs.inotify.max_user_watches`) was reached since the last call.
+    /// Once that happens, subdirectories are no longer watched and
+    /// files are only watched through their parent directory.
+    pub fn take_watch_limit_reached(&mut self) -> bool {
+        std::mem::replace(&mut self.watch_limit_reached, false)
+    }
+
+    /// Replace the watch list with `paths`, like `extend` does for an
+    /// empty watch list. Paths which are no longer in the list (e.g.
+    /// files which are no longer imported) are not watched anymore.
+    pub fn update(&mut self, paths: &[PathBuf]) -> Result<(), notify::Error> {
+        let old_watches = std::mem::replace(&mut self.watches, HashSet::new());
+        let old_over_limit = std::mem::replace(&mut self.over_limit, HashSet::new());
+        self.previously_registered = std::mem::replace(&mut self.registered, HashSet::new());
+
+        if let Err(e) = self.extend(paths) {
+            // keep everything, nothing was unwatched yet
+            self.watches.extend(old_watches);
+            self.over_limit.extend(old_over_limit);
+            let previously_registered =
+                std::mem::replace(&mut self.previously_registered, HashSet::new());
+            self.registered.extend(previously_registered);
+            return Err(e);
+        }
+
+        for stale in std::mem::replace(&mut self.previously_registered, HashSet::new()) {
+            debug!("No longer watching {:?}", stale);
+            if let Err(e) = self.notify.unwatch(&stale) {
+                debug!("Failed to unwatch {:?}: {:?}", stale, e);
+            }
+        }
+        let watches = &self.watches;
+        self.canonical
+            .retain(|_, watched| watches.contains(watched));
+        self.missing.get_mut().retain(|path| watches.contains(path));
+        Ok(())
+    }
+
+    /// Why the backend was restarted since the last call, if it was.
+    ///
+    /// When the backend fails (e.g. its event queue overflowed, or it
+    /// lost the connection to Watchman), it is replaced by a new one
+    /// watching the same paths. Changes in between may have been lost.
+    pub fn take_restart(&mut self) -> Option<String> {
+        self.restarted.take()
+    }
+
+    /// Every path with a watch, sorted.
+    pub fn watched_paths(&self) -> Vec<PathBuf> {
+        let mut paths = self.watches.iter().cloned().collect::<Vec<_>>();
+        paths.sort();
+        paths
+    }
+
+    /// Never react to changes of `paths` (or anything below them) again.
+    pub fn exclude(&mut self, paths: &[PathBuf]) {
+        for path in paths {
+            debug!("Excluding path {:?}", path);
+            self.excluded.insert(path.clone());
+        }
+    }
+
+    /// Non-blocking, drain all events received so far and return
+    /// the paths which changed.
+    pub fn drain_changes(&mut self) -> Vec<Change> {
+        let mut changes: Vec<Change> = vec![];
+        for change in self.try_iter().filter_map(|event| self.change(&event)) {
+            if !changes.contains(&change) {
+                changes.push(change);
+            }
+        }
+        self.recover();
+        self.watch_created();
+        changes
+    }
+
+    /// Wait for a batch of changes to arrive, returning when they do.
+    pub fn wait_for_change(&mut self) -> Result<Vec<Change>, ()> {
+        self.block()
+    }
+
+    /// Block until we have at least one event.
+    /// `Err` if the backend stopped and could not be restarted.
+    pub fn block(&mut self) -> Result<Vec<Change>, ()> {
+        let received = self.blocking_iter().next();
+        let first = match received {
+            Some(event) => event,
+            None => {
+                debug!("No event received!");
+                return self.restart("the backend stopped".to_string());
+            }
+        };
+
+        let changes = self.process_ready(first);
+        if !self.recover() {
+            return Err(());
+        }
+        self.watch_created();
+        Ok(changes)
+    }
+
+    /// Block until we have at least one event
+    pub fn block_timeout(&mut self, timeout: Duration) -> Result<Vec<Change>, ()> {
+        let received = self.timeout_iter(timeout).next();
+        match received {
+            Some(Ok(first)) => {
+                let changes = self.process_ready(first);
+                if !self.recover() {
+                    return Err(());
+                }
+                self.watch_created();
+                Ok(changes)
+            }
+            Some(Err(RecvError)) => self.restart("the backend stopped".to_string()),
+            None => Err(()),
+        }
+    }
+
+    /// Restart the backend if it reported a failure.
+    /// `false` if that was necessary, but did not work.
+    fn recover(&mut self) -> bool {
+        match self.failure.get_mut().take() {
+            Some(reason) => self.restart(reason).is_ok(),
+            None => true,
+        }
+    }
+
+    /// Replace the backend by a new one and register all paths with
+    /// it again. Events may have been lost in between, so the result
+    /// is an (empty) change, which should be treated like any other.
+    fn restart(&mut self, reason: String) -> Result<Vec<Change>, ()> {
+        warn!("Restarting the file watcher: {}", reason);
+        let (tx, rx) = channel();
+        self.notify = match self.notify.restart(tx) {
+            Ok(notify) => notify,
+            Err(e) => {
+                warn!("Failed to restart the file watcher: {:?}", e);
+                return Err(());
+            }
+        };
+        self.rx = rx;
+        for path in self.registered.clone() {
+            if let Err(e) = self.notify.watch(&path, RecursiveMode::NonRecursive) {
+                warn!("Failed to watch {:?} again: {:?}", path, e);
+                self.registered.remove(&path);
+            }
+        }
+        self.restarted = Some(reason);
+        self.watch_created();
+        Ok(vec![])
+    }
+
+    /// Watch missing directories once they are created. Until then,
+    /// the watch on the parent directory notices when they are.
+    /// Files are watched through their directory anyway.
+    fn watch_created(&mut self) {
+        let missing = std::mem::replace(self.missing.get_mut(), HashSet::new());
+        for path in missing {
+            if !path.exists() {
+                self.missing.get_mut().insert(path);
+                continue;
+            }
+            if !path.is_dir() {
+                continue;
+            }
+            debug!("Watching created path {:?}", path);
+            let watched = self.notify.watch(&path, RecursiveMode::NonRecursive);
+            if let Err(e) = self.record_registration(&path, watched) {
+                warn!("Failed to watch {:?}: {:?}", path, e);
+            }
+        }
+    }
+
+    fn blocking_iter<'a>(&'a self) -> impl 'a + Iterator<Item = notify::RawEvent> {
+        self.rx
+            .iter()
+            .filter(move |event| self.event_is_interesting(event))
+            .inspect(move |event| self.handle_event(event))
+    }
+
+    fn timeout_iter<'a>(
+        &'a self,
+        timeout: Duration,
+    ) -> impl 'a + Iterator<Item = Result<notify::RawEvent, RecvError>> {
+        FilterTimeoutIterator::new(&self.rx, timeout, move |event| {
+            self.event_is_interesting(event)
+        })
+        .inspect(move |event| {
+            if let Ok(event) = event {
+                self.handle_event(event)
+            }
+        })
+    }
+
+    fn try_iter<'a>(&'a self) -> impl 'a + Iterator<Item = notify::RawEvent> {
+        self.rx
+            .try_iter()
+            .filter(move |event| self.event_is_interesting(event))
+            .inspect(move |event| self.handle_event(event))
+    }
+
+    /// Non-blocking, read all the events already received -- draining
+    /// the event queue. Returns the changes, starting with `first`.
+    fn process_ready(&self, first: notify::RawEvent) -> Vec<Change> {
+        let mut changes: Vec<Change> = vec![];
+        for event in Some(first).into_iter().chain(self.try_iter()) {
+            debug!("Received event: {:#?}", event);
+            if let Some(change) = self.change(&event) {
+                if !changes.contains(&change) {
+                    changes.push(change);
+                }
+            }
+        }
+        info!("Found {} changes", changes.len());
+        changes
+    }
+
+    fn change(&self, event: &notify::RawEvent) -> Option<Change> {
+        let path = self.normalize(event.path.as_ref()?);
+        let kind = match event.op {
+            // failures of the backend are not changes of their path
+            Err(_) => return None,
+            Ok(op) if op.contains(notify::op::REMOVE) => ChangeKind::Removed,
+            Ok(op) if op.contains(notify::op::RENAME) => ChangeKind::Renamed,
+            Ok(op) if op.contains(notify::op::CREATE) => ChangeKind::Created,
+            _ => ChangeKind::Modified,
+        };
+        Some(Change { path, kind })
+    }
+
+    fn handle_event(&self, event: &notify::RawEvent) {
+        debug!("Watch Event: {:#?}", event);
+        match (&event.op, &event.path) {
+            (Ok(op), Some(path))
+                if op.intersects(notify::op::REMOVE | notify::op::RENAME)
+                    && self.watches.contains(&self.normalize(path)) =>
+            {
+                info!("identified file removal: {:?}", path);
+                self.missing.borrow_mut().insert(self.normalize(path));
+            }
+            otherwise => {
+                debug!("watch event: {:#?}", otherwise);
+            }
+        }
+    }
+
+    fn add_path_recursively(
+        &mut self,
+        path: &PathBuf,
+        gitignore: &mut Option<GitIgnore>,
+    ) -> Result<(), notify::Error> {
+        if path.canonicalize()?.starts_with(Path::new("/nix/store")) {
+            return Ok(());
+        }
+
+        for entry in path.read_dir()? {
+            if self.watch_limit_reached {
+                debug!(
+                    "Watch limit reached, watching {:?} through an ancestor",
+                    path
+                );
+                self.over_limit.insert(path.clone());
+                break;
+            }
+            let subpath = entry?.path();
+
+            if subpath.is_dir()
+                && !self.is_ignored(&subpath, true)
+                && !is_ignored(gitignore, &subpath)
+            {
+                self.add_path(&subpath)?;
+                self.add_path_recursively(&subpath, gitignore)?;
+            }
+
+            // Skip adding files, watching in the dir will handle it.
+        }
+        Ok(())
+    }
+
+    fn add_path(&mut self, path: &PathBuf) -> Result<(), notify::Error> {
+        if path.symlink_metadata().is_err() {
+            return self.add_missing_path(path);
+        }
+
+        if !self.watches.contains(path) {
+            debug!("Watching path {:?}", path);
+
+            // Changes to files are reported by the watch on their
+            // directory, which is added below. Registering only the
+            // directories saves a lot of watches and syscalls.
+            if path.is_dir() || path.parent().is_none() {
+                self.register(path)?;
+            }
+            self.watches.insert(path.clone());
+            if let Ok(canonical) = path.canonicalize() {
+                if &canonical != path {
+                    self.canonical.insert(canonical, path.clone());
+                }
+            }
+        }
+
+        if let Some(parent) = path.parent() {
+            if !self.watches.contains(parent) {
+                debug!("Watching parent path {:?}", parent);
+                self.register(parent)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Watch the closest existing parent directory of `path`, which
+    /// doesn’t exist (yet), to notice when it is created. Missing
+    /// directories in between are watched once they are created.
+    fn add_missing_path(&mut self, path: &Path) -> Result<(), notify::Error> {
+        debug!("{:?} does not exist, watching for its creation", path);
+        let mut missing = path;
+        while let Some(parent) = missing.parent() {
+            self.watches.insert(missing.to_path_buf());
+            self.missing.get_mut().insert(missing.to_path_buf());
+            if parent.exists() {
+                return self.register(parent);
+            }
+            missing = parent;
+        }
+        Ok(())
+    }
+
+    /// Register `path` with `notify`, unless it already is.
+    fn register(&mut self, path: &Path) -> Result<(), notify::Error> {
+        if self.registered.contains(path) {
+            return Ok(());
+        }
+        if !self.previously_registered.remove(path) {
+            let watched = self.notify.watch(path, RecursiveMode::NonRecursive);
+            return self.record_registration(path, watched);
+        }
+        self.registered.insert(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Keep track of `path`, which `notify` was asked to watch with
+    /// the result `watched`.
+    fn record_registration(
+        &mut self,
+        path: &Path,
+        watched: Result<(), notify::Error>,
+    ) -> Result<(), notify::Error> {
+        match watched {
+            Ok(()) => {
+                self.registered.insert(path.to_path_buf());
+                Ok(())
+            }
+            Err(ref e) if is_watch_limit(e) => {
+                self.reach_watch_limit(path);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The operating system refused to watch `path`, so watch its
+    /// nearest ancestor instead, reusing its registration if it has
+    /// one. That reports the changes of `path` itself, e.g. when it is
+    /// removed; changes below it are reported as well, should the
+    /// backend deliver them.
+    fn reach_watch_limit(&mut self, path: &Path) {
+        warn!("Reached the limit of watches, cannot watch {:?}", path);
+        self.watch_limit_reached = true;
+        self.over_limit.insert(path.to_path_buf());
+        for ancestor in path.ancestors().skip(1) {
+            let registered = self.registered.contains(ancestor)
+                || self.previously_registered.remove(ancestor)
+                || self
+                    .notify
+                    .watch(ancestor, RecursiveMode::NonRecursive)
+                    .is_ok();
+            if registered {
+                debug!("Watching {:?} for the changes of {:?}", ancestor, path);
+                self.registered.insert(ancestor.to_path_buf());
+                return;
+            }
+        }
+    }
+
+    fn event_is_interesting(&self, event: &notify::RawEvent) -> bool {
+        // Failures of the backend are always interesting, they have
+        // to be handled before trusting it again.
+        match event.op {
+            Err(ref e) => {
+                *self.failure.borrow_mut() = Some(e.to_string());
+                return true;
+            }
+            Ok(op) if op.contains(notify::op::RESCAN) => {
+                *self.failure.borrow_mut() = Some("events were lost".to_string());
+                return true;
+            }
+            Ok(_) => {}
+        }
+        match event.path {
+            Some(ref path) => {
+                let path = &self.normalize(path);
+                (path_match(&self.watches, path)
+                    || self.over_limit.iter().any(|dir| path.starts_with(dir)))
+                    && !self.excluded.iter().any(|ex| path.starts_with(ex))
+                    && !self.is_ignored(path, path.is_dir())
+            }
+            None => false,
+        }
+    }
+
+    /// Translate a canonical path reported by the operating system
+    /// back to the (non-canonical) path we watch it by.
+    fn normalize(&self, path: &Path) -> PathBuf {
+        for (canonical, watched) in &self.canonical {
+            if let Ok(rest) = path.strip_prefix(canonical) {
+                return if rest.as_os_str().is_empty() {
+                    watched.clone()
+                } else {
+                    watched.join(rest)
+                };
+            }
+        }
+        path.to_path_buf()
+    }
+
+    /// Does `path` match one of the configured ignore patterns?
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let (base, patterns) = &self.ignore;
+        let relative = path
+            .strip_prefix(base)
+            .unwrap_or_else(|_| path.strip_prefix("/").unwrap_or(path));
+        patterns.ignores_path(relative, is_dir)
+    }
+}
+
+/// A `PollWatcher` checking for changes every `interval`.
+fn poll_watcher(
+    tx: Sender<notify::RawEvent>,
+    interval: Duration,
+) -> Result<PollWatcher, notify::Error> {
+    let millis = interval.as_secs() * 1000 + u64::from(interval.subsec_millis());
+    PollWatcher::with_delay_ms(tx, millis.min(u64::from(std::u32::MAX)) as u32)
+}
+
+/// If `path` is a symlink, the path it points to.
+fn symlink_target(path: &Path) -> Option<PathBuf> {
+    if !path.symlink_metadata().ok()?.file_type().is_symlink() {
+        return None;
+    }
+    let target = path.read_link().ok()?;
+    // relative targets are relative to the directory of the link
+    Some(match path.parent() {
+        Some(parent) => parent.join(target),
+        None => target,
+    })
+}
+
+/// Did adding a watch fail because the user’s limit of watches
+/// is exhausted? inotify reports that as `ENOSPC`.
+fn is_watch_limit(error: &notify::Error) -> bool {
+    match error {
+        notify::Error::Io(e) => e.raw_os_error() == Some(nix::libc::ENOSPC),
+        _ => false,
+    }
+}
+
+fn is_ignored(gitignore: &mut Option<GitIgnore>, path: &Path) -> bool {
+    match gitignore {
+        Some(gitignore) => gitignore.is_ignored(path),
+        None => false,
+    }
+}
+
+/// Determine if the event path is covered by our list of watched
+/// paths.
+///
+/// Returns true if:
+///   - the event's path directly names a path in our
+///     watch list
+///   - the event's path names a canonicalized path in our watch list
+///   - the event's path's parent directly names a path in our watch
+///     list
+///   - the event's path's parent names a canonicalized path in our
+///     watch list
+fn path_match(watched_paths: &HashSet<PathBuf>, event_path: &Path) -> bool {
+    let event_parent = event_path.parent();
+
+    let matches = |watched: &Path| {
+        if event_path == watched {
+            debug!(
+                "Event path ({:?}) directly matches watched path",
+                event_path
+            );
+
+            return true;
+        }
+
+        if let Some(parent) = event_parent {
+            if parent == watched {
+                debug!(
+                    "Event path ({:?}) parent ({:?}) matches watched path",
+                    event_path, parent
+                );
+                return true;
+            }
+        }
+
+        false
+    };
+
+    watched_paths.iter().any(|watched| {
+        if matches(watched) {
+            return true;
+        }
+
+        if let Ok(canonicalized_watch) = watched.canonicalize() {
+            if matches(&canonicalized_watch) {
+                return true;
+            }
+        }
+
+        false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ignore::Patterns;
+    use super::{is_watch_limit, nix, Change, ChangeKind, Notifier, Watch};
+    use crate::bash::expect_bash;
+    use notify::Watcher;
+    use std::path::PathBuf;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use tempfile::tempdir;
+
+    #[cfg(target_os = "macos")]
+    fn macos_eat_late_notifications(watcher: &mut Watch) {
+        // Sometimes a brand new watch will send a CREATE notification
+        // for a file which was just created, even if the watch was
+        // created after the file was made.
+        //
+        // Our tests want to be very precise about which events are
+        // received when, so expect these initial events and swallow
+        // them.
+        //
+        // Note, this is racey in the kernel. Otherwise I'd assert
+        // this is_ok().
+        watcher.block_timeout(Duration::from_millis(250)).is_ok();
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    fn macos_eat_late_notifications(watcher: &mut Watch) {
+        // If we're supposedly dealing with a late notification on
+        // macOS, we'd better not receive any messages on other
+        // platforms.
+        //
+        // If we do receive any notifications, our test is broken.
+        assert!(watcher.block_timeout(Duration::from_millis(250)).is_err());
+    }
+
+    #[test]
+    fn trivial_watch_whole_directory() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"mkdir -p "$1""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[temp.path().to_path_buf()]).unwrap();
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+    }
+
+    #[test]
+    fn trivial_watch_specific_file() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"mkdir -p "$1""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[temp.path().join("foo")]).unwrap();
+        macos_eat_late_notifications(&mut watcher);
+
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+    }
+
+    #[test]
+    fn excluded_paths_are_ignored() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"mkdir -p "$1""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[temp.path().to_path_buf()]).unwrap();
+        macos_eat_late_notifications(&mut watcher);
+
+        expect_bash(r#"touch "$1/result""#, &[temp.path().as_os_str()]);
+        std::thread::sleep(Duration::from_millis(50));
+        let changes = watcher.drain_changes();
+        assert!(changes
+            .iter()
+            .any(|change| change.path == temp.path().join("result")));
+
+        watcher.exclude(&[temp.path().join("result")]);
+        expect_bash(r#"echo 1 > "$1/result""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_err());
+
+        expect_bash(r#"touch "$1/bar""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+    }
+
+    #[test]
+    fn ignore_patterns() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"mkdir -p "$1/target/debug""#, &[temp.path().as_os_str()]);
+        watcher.ignore(
+            temp.path().to_path_buf(),
+            Patterns::from_lines(&["target/", "*.log"]),
+        );
+        watcher.extend(&[temp.path().to_path_buf()]).unwrap();
+        macos_eat_late_notifications(&mut watcher);
+        assert!(!watcher.watches.contains(&temp.path().join("target/debug")));
+
+        expect_bash(r#"touch "$1/target/foo""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"touch "$1/build.log""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_err());
+
+        expect_bash(r#"touch "$1/shell.nix""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+    }
+
+    #[test]
+    fn canonical_event_paths_are_normalized() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+        let real = temp.path().canonicalize().unwrap().join("real");
+        let link = temp.path().join("link");
+
+        expect_bash(r#"mkdir -p "$1""#, &[real.as_os_str()]);
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+        watcher.extend(&[link.clone()]).unwrap();
+
+        assert_eq!(watcher.normalize(&real.join("foo")), link.join("foo"));
+        assert_eq!(watcher.normalize(&real), link);
+        assert_eq!(
+            watcher.normalize(&temp.path().join("other")),
+            temp.path().join("other")
+        );
+    }
+
+    #[test]
+    fn symlink_targets_are_watched() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+        let link = temp.path().join("shell.nix");
+
+        expect_bash(
+            r#"mkdir -p "$1/nix" && touch "$1/nix/a.nix" "$1/nix/b.nix""#,
+            &[temp.path().as_os_str()],
+        );
+        std::os::unix::fs::symlink("nix/a.nix", &link).unwrap();
+        watcher.extend(&[link.clone()]).unwrap();
+        assert!(watcher.watches.contains(&temp.path().join("nix/a.nix")));
+        macos_eat_late_notifications(&mut watcher);
+
+        expect_bash(r#"echo 1 > "$1/nix/a.nix""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+
+        // retargeting the link is a change, the new target is
+        // picked up by the next extend
+        expect_bash(
+            r#"ln -sfn nix/b.nix "$1/shell.nix""#,
+            &[temp.path().as_os_str()],
+        );
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+        watcher.extend(&[link.clone()]).unwrap();
+        assert!(watcher.watches.contains(&temp.path().join("nix/b.nix")));
+    }
+
+    #[test]
+    fn update_prunes_stale_watches() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+        let old = temp.path().join("old");
+        let new = temp.path().join("new");
+
+        expect_bash(
+            r#"mkdir -p "$1/old/sub" "$1/new""#,
+            &[temp.path().as_os_str()],
+        );
+        watcher.update(&[old.clone(), new.clone()]).unwrap();
+        assert!(watcher.watched_paths().contains(&old.join("sub")));
+
+        watcher.update(&[new.clone()]).unwrap();
+        assert_eq!(watcher.watched_paths(), vec![new.clone()]);
+        macos_eat_late_notifications(&mut watcher);
+
+        expect_bash(r#"touch "$1/old/sub/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_err());
+
+        expect_bash(r#"touch "$1/new/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+    }
+
+    #[test]
+    fn missing_files_are_watched() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        watcher
+            .extend(&[
+                temp.path().join("local.nix"),
+                temp.path().join("nix/sources.nix"),
+            ])
+            .unwrap();
+        macos_eat_late_notifications(&mut watcher);
+
+        expect_bash(r#"touch "$1/unrelated""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_err());
+
+        expect_bash(r#"touch "$1/local.nix""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+        expect_bash(r#"echo 1 > "$1/local.nix""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+
+        expect_bash(r#"mkdir "$1/nix""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+        expect_bash(r#"touch "$1/nix/sources.nix""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+    }
+
+    #[test]
+    fn files_are_watched_through_their_directory() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"mkdir -p "$1/nix""#, &[temp.path().as_os_str()]);
+        let mut files = vec![];
+        for i in 0..100 {
+            let file = temp.path().join(format!("nix/{}.nix", i));
+            std::fs::write(&file, "{}").unwrap();
+            files.push(file);
+        }
+        let first = files[0].clone();
+        files.push(first);
+        watcher.extend(&files).unwrap();
+        assert_eq!(watcher.watched_paths().len(), 100);
+        assert_eq!(
+            watcher.registered.iter().collect::<Vec<_>>(),
+            vec![&temp.path().join("nix")]
+        );
+        macos_eat_late_notifications(&mut watcher);
+
+        expect_bash(r#"echo 1 > "$1/nix/42.nix""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+    }
+
+    #[test]
+    fn store_paths_are_not_watched() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let store_path = PathBuf::from("/nix/store/00000000000000000000000000000000-foo");
+        watcher.extend(&[store_path]).unwrap();
+        assert!(watcher.watched_paths().is_empty());
+    }
+
+    #[test]
+    fn watch_limit_errors() {
+        let enospc = std::io::Error::from_raw_os_error(nix::libc::ENOSPC);
+        assert!(is_watch_limit(&notify::Error::Io(enospc)));
+        assert!(!is_watch_limit(&notify::Error::PathNotFound));
+    }
+
+    #[test]
+    fn watch_limit_falls_back_to_an_ancestor() {
+        let (tx, rx) = channel();
+        let notifier = Notifier::Native(Watcher::new_raw(tx.clone()).unwrap());
+        let mut watcher = Watch::with_notifier(notifier, rx);
+        let temp = tempdir().unwrap();
+        let unregistered = temp.path().join("deep");
+        std::fs::create_dir(&unregistered).unwrap();
+
+        let enospc = std::io::Error::from_raw_os_error(nix::libc::ENOSPC);
+        watcher
+            .record_registration(&unregistered, Err(notify::Error::Io(enospc)))
+            .unwrap();
+        assert!(watcher.take_watch_limit_reached());
+        assert!(watcher.registered.contains(temp.path()));
+        assert!(!watcher.registered.contains(&unregistered));
+
+        let below = unregistered.join("nix").join("sources.nix");
+        for path in &[temp.path().join("unrelated"), below.clone()] {
+            tx.send(notify::RawEvent {
+                path: Some(path.clone()),
+                op: Ok(notify::op::WRITE),
+                cookie: None,
+            })
+            .unwrap();
+        }
+        assert_eq!(
+            watcher.block_timeout(Duration::from_millis(50)),
+            Ok(vec![Change {
+                path: below,
+                kind: ChangeKind::Modified
+            }])
+        );
+    }
+
+    #[test]
+    fn lost_events_restart_the_backend() {
+        let (tx, rx) = channel();
+        let notifier = Notifier::Native(Watcher::new_raw(tx.clone()).unwrap());
+        let mut watcher = Watch::with_notifier(notifier, rx);
+        let temp = tempdir().unwrap();
+        watcher.extend(&[temp.path().to_path_buf()]).unwrap();
+
+        tx.send(notify::RawEvent {
+            path: None,
+            op: Ok(notify::op::RESCAN),
+            cookie: None,
+        })
+        .unwrap();
+        assert_eq!(watcher.block_timeout(Duration::from_millis(50)), Ok(vec![]));
+        assert_eq!(watcher.take_restart(), Some("events were lost".to_string()));
+        assert_eq!(watcher.take_restart(), None);
+
+        // the new backend watches the same paths
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+    }
+
+    #[test]
+    fn polling_watch() {
+        let mut watcher = Watch::init_polling(Duration::from_millis(20)).unwrap();
+        let temp = tempdir().unwrap();
+
+        watcher.extend(&[temp.path().to_path_buf()]).unwrap();
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(500)).is_ok());
+    }
+
+    #[test]
+    fn atomic_save_keeps_watching() {
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+        let foo = temp.path().join("foo");
+
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[foo.clone()]).unwrap();
+        macos_eat_late_notifications(&mut watcher);
+
+        // delete and recreate, like an editor saving atomically
+        expect_bash(r#"rm "$1/foo""#, &[temp.path().as_os_str()]);
+        let changes = watcher.block_timeout(Duration::from_millis(50)).unwrap();
+        assert!(changes.contains(&Change {
+            path: foo.clone(),
+            kind: ChangeKind::Removed
+        }));
+        assert!(watcher.missing.borrow().contains(&foo));
+
+        expect_bash(r#"echo 1 > "$1/foo""#, &[temp.path().as_os_str()]);
+        let changes = watcher.block_timeout(Duration::from_millis(50)).unwrap();
+        assert!(changes.contains(&Change {
+            path: foo.clone(),
+            kind: ChangeKind::Created
+        }));
+        assert!(watcher.missing.borrow().is_empty());
+
+        expect_bash(r#"echo 2 > "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+    }
+
+    #[test]
+    fn rename_over_vim() {
+        // Vim renames files in to place for atomic writes
+        let mut watcher = Watch::init().expect("failed creating Watch");
+        let temp = tempdir().unwrap();
+
+        expect_bash(r#"mkdir -p "$1""#, &[temp.path().as_os_str()]);
+        expect_bash(r#"touch "$1/foo""#, &[temp.path().as_os_str()]);
+        watcher.extend(&[temp.path().join("foo")]).unwrap();
+        macos_eat_late_notifications(&mut watcher);
+
+        // bar is not watched, expect error
+        expect_bash(r#"echo 1 > "$1/bar""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_err());
+
+        // Rename bar to foo, expect a notification
+        expect_bash(r#"mv "$1/bar" "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+
+        // Do it a second time
+        expect_bash(r#"echo 1 > "$1/bar""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_err());
+
+        // Rename bar to foo, expect a notification
+        expect_bash(r#"mv "$1/bar" "$1/foo""#, &[temp.path().as_os_str()]);
+        assert!(watcher.block_timeout(Duration::from_millis(50)).is_ok());
+    }
+}
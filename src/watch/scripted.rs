@@ -0,0 +1,114 @@
+//! An in-memory `FileWatcher`, which replays scripted changes
+//! instead of watching the filesystem. Makes the behaviour of a
+//! `BuildLoop` testable without waiting for real filesystem events.
+
+use super::ignore::Patterns;
+use super::{Change, FileWatcher};
+use std::collections::{HashSet, VecDeque};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// What the next wait for changes results in.
+#[derive(Clone, Debug)]
+pub enum Step {
+    /// These changes happen
+    Changes(Vec<Change>),
+    /// Nothing happens until the timeout
+    Timeout,
+    /// The backend fails for this reason and is restarted
+    Restart(String),
+}
+
+/// A `FileWatcher` replaying a script.
+#[derive(Debug, Default)]
+pub struct ScriptedWatch {
+    /// Results of waiting for changes, in order
+    steps: VecDeque<Step>,
+    /// Results of `drain_changes`, in order
+    drains: VecDeque<Vec<Change>>,
+    watched: Vec<PathBuf>,
+    excluded: HashSet<PathBuf>,
+    restarted: Option<String>,
+}
+
+impl ScriptedWatch {
+    /// An empty script: there are never any changes.
+    pub fn new() -> ScriptedWatch {
+        ScriptedWatch::default()
+    }
+
+    /// Script the result of the next wait for changes
+    /// after the ones already scripted.
+    pub fn then(mut self, step: Step) -> ScriptedWatch {
+        self.steps.push_back(step);
+        self
+    }
+
+    /// Script the changes happening during the next build
+    /// (returned by `drain_changes`) after the ones already scripted.
+    pub fn during_build(mut self, changes: Vec<Change>) -> ScriptedWatch {
+        self.drains.push_back(changes);
+        self
+    }
+
+    fn next_step(&mut self) -> Option<Vec<Change>> {
+        match self.steps.pop_front() {
+            Some(Step::Changes(changes)) => Some(self.filter(changes)),
+            Some(Step::Restart(reason)) => {
+                self.restarted = Some(reason);
+                Some(vec![])
+            }
+            Some(Step::Timeout) | None => None,
+        }
+    }
+
+    fn filter(&self, changes: Vec<Change>) -> Vec<Change> {
+        changes
+            .into_iter()
+            .filter(|change| !self.excluded.iter().any(|ex| change.path.starts_with(ex)))
+            .collect()
+    }
+}
+
+impl FileWatcher for ScriptedWatch {
+    fn update(&mut self, paths: &[PathBuf]) -> Result<(), notify::Error> {
+        self.watched = paths.to_vec();
+        self.watched.sort();
+        Ok(())
+    }
+
+    fn exclude(&mut self, paths: &[PathBuf]) {
+        self.excluded.extend(paths.iter().cloned());
+    }
+
+    // the script decides which changes are reported
+    fn respect_gitignore(&mut self, _enabled: bool) {}
+
+    fn ignore(&mut self, _base: PathBuf, _patterns: Patterns) {}
+
+    fn drain_changes(&mut self) -> Vec<Change> {
+        let changes = self.drains.pop_front().unwrap_or_default();
+        self.filter(changes)
+    }
+
+    /// Returns `None` once the script is exhausted.
+    fn wait_for_change(&mut self) -> Option<Vec<Change>> {
+        self.next_step()
+    }
+
+    fn block_timeout(&mut self, _timeout: Duration) -> Option<Vec<Change>> {
+        self.next_step()
+    }
+
+    fn take_watch_limit_reached(&mut self) -> bool {
+        false
+    }
+
+    fn watched_paths(&self) -> Vec<PathBuf> {
+        self.watched.clone()
+    }
+
+    fn take_restart(&mut self) -> Option<String> {
+        self.restarted.take()
+    }
+}
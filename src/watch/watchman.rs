@@ -0,0 +1,272 @@
+//! A `Watch` backend which subscribes to a running Watchman daemon
+//! (https://facebook.github.io/watchman/).
+//!
+//! Watchman crawls and watches whole projects by itself, so
+//! registering thousands of paths is cheap and doesn’t run into
+//! the inotify watch limit. We subscribe to the changes of exactly
+//! the watched paths and forward them as `notify::RawEvent`s.
+
+use serde_json::{json, Value};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::mpsc::Sender;
+use std::thread;
+
+/// Name of our subscriptions. Subscribing again with the same name
+/// replaces the previous subscription.
+const SUBSCRIPTION: &str = "lorri";
+
+/// A connection to the Watchman daemon.
+pub struct Watchman {
+    /// Commands are written here; responses and subscription
+    /// updates are read by a separate thread.
+    stream: UnixStream,
+    /// The watched paths by the Watchman root they are in, relative
+    /// to that root. `true` marks directories, whose direct children
+    /// are watched as well.
+    roots: HashMap<PathBuf, BTreeSet<(PathBuf, bool)>>,
+}
+
+impl Watchman {
+    /// Connect to the Watchman daemon (starting it if necessary) and
+    /// forward the changes to watched paths to `tx`.
+    pub fn new(tx: Sender<notify::RawEvent>) -> Result<Watchman, notify::Error> {
+        let sockname = watchman(&["get-sockname"])?;
+        let sockname = sockname["unix_domain"]
+            .as_str()
+            .or_else(|| sockname["sockname"].as_str())
+            .ok_or_else(|| error("watchman get-sockname returned no socket"))?;
+
+        let stream = UnixStream::connect(sockname)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        thread::spawn(move || {
+            for line in reader.lines() {
+                let pdu = match line.map(|line| serde_json::from_str::<Value>(&line)) {
+                    Ok(Ok(pdu)) => pdu,
+                    Ok(Err(e)) => {
+                        warn!("Invalid response from watchman: {}", e);
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!("Lost the connection to watchman: {}", e);
+                        return;
+                    }
+                };
+                if let Some(e) = pdu["error"].as_str() {
+                    warn!("watchman: {}", e);
+                }
+                for path in changed_paths(&pdu) {
+                    let event = notify::RawEvent {
+                        path: Some(path),
+                        op: Ok(notify::op::WRITE),
+                        cookie: None,
+                    };
+                    if tx.send(event).is_err() {
+                        // the Watch was dropped
+                        return;
+                    }
+                }
+            }
+        });
+
+        Ok(Watchman {
+            stream,
+            roots: HashMap::new(),
+        })
+    }
+
+    /// Watch `path`, and its direct children if it is a directory.
+    pub fn watch(&mut self, path: &Path) -> Result<(), notify::Error> {
+        let is_dir = path.is_dir();
+        let (root, relative) = match self.root_of(path) {
+            Some(found) => found,
+            None => {
+                let dir = if is_dir {
+                    path
+                } else {
+                    path.parent().unwrap_or(path)
+                };
+                let response = watchman(&["watch-project", &dir.to_string_lossy()])?;
+                let root = response["watch"]
+                    .as_str()
+                    .map(PathBuf::from)
+                    .ok_or_else(|| error("watchman watch-project returned no root"))?;
+                self.roots.entry(root).or_default();
+                self.root_of(path)
+                    .ok_or_else(|| error("watchman watched an unrelated root"))?
+            }
+        };
+
+        let inserted = self
+            .roots
+            .get_mut(&root)
+            .expect("root was just found")
+            .insert((relative, is_dir));
+        if inserted {
+            self.subscribe(&root)?;
+        }
+        Ok(())
+    }
+
+    /// Stop watching `path`.
+    pub fn unwatch(&mut self, path: &Path) -> Result<(), notify::Error> {
+        let (root, relative) = match self.root_of(path) {
+            Some(found) => found,
+            None => return Err(notify::Error::WatchNotFound),
+        };
+        let found = {
+            let watched = self.roots.get_mut(&root).expect("root was just found");
+            let before = watched.len();
+            *watched = watched
+                .iter()
+                .filter(|(path, _)| path != &relative)
+                .cloned()
+                .collect();
+            watched.len() != before
+        };
+        if !found {
+            return Err(notify::Error::WatchNotFound);
+        }
+        self.subscribe(&root)
+    }
+
+    /// (Re-)subscribe to the changes of the watched paths in `root`.
+    fn subscribe(&mut self, root: &Path) -> Result<(), notify::Error> {
+        let command = json!([
+            "subscribe",
+            root,
+            SUBSCRIPTION,
+            {
+                "expression": expression(&self.roots[root]),
+                "fields": ["name"],
+                "empty_on_fresh_instance": true
+            }
+        ]);
+        writeln!(self.stream, "{}", command)?;
+        Ok(())
+    }
+
+    /// The Watchman root `path` is in, and `path` relative to it.
+    /// Paths are also tried in their canonical form, which is how
+    /// Watchman reports roots.
+    fn root_of(&self, path: &Path) -> Option<(PathBuf, PathBuf)> {
+        let canonical = path.canonicalize().ok();
+        let candidates = Some(path)
+            .into_iter()
+            .chain(canonical.as_ref().map(PathBuf::as_path));
+        for candidate in candidates {
+            for root in self.roots.keys() {
+                if let Ok(relative) = candidate.strip_prefix(root) {
+                    return Some((root.clone(), relative.to_path_buf()));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Run a watchman command and parse its JSON output.
+fn watchman(args: &[&str]) -> Result<Value, notify::Error> {
+    let output = Command::new("watchman").args(args).output()?;
+    if !output.status.success() {
+        return Err(error(&format!(
+            "watchman {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        )));
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|e| error(&format!("watchman returned invalid JSON: {}", e)))
+}
+
+fn error(message: &str) -> notify::Error {
+    notify::Error::Generic(message.to_string())
+}
+
+/// The query expression matching the watched paths of a root.
+fn expression(watched: &BTreeSet<(PathBuf, bool)>) -> Value {
+    let names = watched
+        .iter()
+        .map(|(path, _)| path.to_string_lossy().into_owned())
+        .collect::<Vec<String>>();
+    let mut expression = vec![json!("anyof"), json!(["name", names, "wholename"])];
+    for (dir, _) in watched.iter().filter(|(_, is_dir)| *is_dir) {
+        expression.push(json!(["dirname", dir, ["depth", "eq", 0]]));
+    }
+    Value::Array(expression)
+}
+
+/// The changed paths a subscription update reports, if it is one.
+fn changed_paths(pdu: &Value) -> Vec<PathBuf> {
+    if pdu["subscription"].as_str() != Some(SUBSCRIPTION)
+        || pdu["is_fresh_instance"].as_bool() == Some(true)
+    {
+        return vec![];
+    }
+    let root = match pdu["root"].as_str() {
+        Some(root) => Path::new(root),
+        None => return vec![],
+    };
+    pdu["files"]
+        .as_array()
+        .map(|files| {
+            files
+                .iter()
+                .filter_map(|name| name.as_str())
+                .map(|name| root.join(name))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changed_paths, expression};
+    use serde_json::json;
+    use std::collections::BTreeSet;
+    use std::path::PathBuf;
+
+    #[test]
+    fn subscription_updates() {
+        let update = json!({
+            "subscription": "lorri",
+            "root": "/home/alice/foo",
+            "files": ["shell.nix", "nix/sources.json"],
+            "is_fresh_instance": false
+        });
+        assert_eq!(
+            changed_paths(&update),
+            vec![
+                PathBuf::from("/home/alice/foo/shell.nix"),
+                PathBuf::from("/home/alice/foo/nix/sources.json")
+            ]
+        );
+
+        let fresh = json!({
+            "subscription": "lorri",
+            "root": "/home/alice/foo",
+            "files": ["shell.nix"],
+            "is_fresh_instance": true
+        });
+        assert!(changed_paths(&fresh).is_empty());
+        assert!(changed_paths(&json!({ "version": "4.9.0" })).is_empty());
+    }
+
+    #[test]
+    fn subscription_expression() {
+        let mut watched = BTreeSet::new();
+        watched.insert((PathBuf::from("shell.nix"), false));
+        watched.insert((PathBuf::from("nix"), true));
+        assert_eq!(
+            expression(&watched),
+            json!([
+                "anyof",
+                ["name", ["nix", "shell.nix"], "wholename"],
+                ["dirname", "nix", ["depth", "eq", 0]]
+            ])
+        );
+    }
+}
@@ -2,17 +2,31 @@ extern crate lorri;
 extern crate tempfile;
 
 use lorri::build_loop;
+use lorri::constants::Paths;
+use lorri::daemon::{ForgetProject, UpgradeDaemon};
 use lorri::socket::communicate::{client, listener};
-use lorri::socket::communicate::{CommunicationType, Ping};
+use lorri::socket::communicate::{
+    BuildState, CommunicationType, ForgetRequest, Ping, ShellMessage, ShellRequest,
+    SubscribeRequest, UpgradeRequest, UpgradeResponse, PROTOCOL_VERSION,
+};
 use lorri::socket::path::SocketPath;
 use lorri::socket::{ReadWriter, Timeout};
+use lorri::trust::Trust;
 use lorri::NixFile;
 use std::io::{Error, ErrorKind};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 
+/// A `shell.nix` in `dir` which the daemon may build (see `Trust`).
+fn allowed_nix_file(paths: &Paths, dir: &Path) -> NixFile {
+    let nix_file = NixFile::from(dir.join("shell.nix"));
+    std::fs::write(dir.join("shell.nix"), "{}").unwrap();
+    Trust::new(paths.allow_dir()).allow(&nix_file).unwrap();
+    nix_file
+}
+
 /// This tests the basic working of the client/daemon setup.
 ///
 /// The daemon starts listening, the client sends a message
@@ -34,10 +48,11 @@ pub fn start_job_with_ping() -> std::io::Result<()> {
     let socket_path = SocketPath::from(p);
     let listener = listener::Listener::new(&socket_path).unwrap();
 
-    let paths = lorri::constants::Paths::initialize()?;
+    let paths = Paths::below(tempdir.path());
+    let nix_file = allowed_nix_file(&paths, tempdir.path());
 
     // The daemon knows how to build stuff
-    let (mut daemon, build_events_rx) = ::lorri::daemon::Daemon::new(&paths);
+    let (mut daemon, build_events_rx) = ::lorri::daemon::Daemon::new(&paths, Default::default());
 
     let handlers = daemon.handlers();
     // listen for incoming messages
@@ -48,6 +63,14 @@ pub fn start_job_with_ping() -> std::io::Result<()> {
                 CommunicationType::Ping => {
                     handlers.ping(ReadWriter::new(&unix_stream), accept_messages_tx)
                 }
+                CommunicationType::Status
+                | CommunicationType::Logs
+                | CommunicationType::Subscribe
+                | CommunicationType::Forget
+                | CommunicationType::Shell
+                | CommunicationType::Upgrade => {
+                    panic!("only expected a ping")
+                }
             })
             .unwrap()
     });
@@ -55,9 +78,7 @@ pub fn start_job_with_ping() -> std::io::Result<()> {
     client::ping(Timeout::from_millis(100))
         .connect(&socket_path)
         .unwrap()
-        .write(&Ping {
-            nix_file: NixFile::from(PathBuf::from("/who/cares")),
-        })
+        .write(&Ping { nix_file })
         .unwrap();
 
     // The client pinged, so now a message should have arrived
@@ -71,8 +92,9 @@ pub fn start_job_with_ping() -> std::io::Result<()> {
     match build_events_rx
         .recv_timeout(Duration::from_millis(100))
         .unwrap()
+        .event
     {
-        build_loop::Event::Started => Ok(()),
+        build_loop::Event::Started(_, _) => Ok(()),
         ev => Err(Error::new(
             ErrorKind::Other,
             format!("didn’t expect event {:?}", ev),
@@ -84,6 +106,301 @@ pub fn start_job_with_ping() -> std::io::Result<()> {
     Ok(())
 }
 
+/// Clients speaking another protocol version are told so
+/// instead of being sent garbled messages.
+#[test]
+pub fn other_protocol_versions_are_refused() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let socket_path = SocketPath::from(p);
+    let listener = listener::Listener::new(&socket_path).unwrap();
+
+    let accept_handle = thread::spawn(move || listener.accept(|_, _| ()).map(|_| ()));
+
+    let socket = socket_path.connect()?;
+    ReadWriter::<listener::Handshake, u32>::new(&socket)
+        .write(&Timeout::from_millis(100), &(PROTOCOL_VERSION + 1))
+        .unwrap();
+    match ReadWriter::<listener::Handshake, u32>::new(&socket)
+        .read(&Timeout::from_millis(100))
+        .unwrap()
+    {
+        listener::Handshake::VersionMismatch { daemon_version } => {
+            assert_eq!(daemon_version, PROTOCOL_VERSION)
+        }
+        other => panic!("didn’t expect handshake {:?}", other),
+    }
+
+    match accept_handle.join().unwrap() {
+        Err(listener::AcceptError::VersionMismatch { client_version }) => {
+            assert_eq!(client_version, PROTOCOL_VERSION + 1)
+        }
+        other => panic!("didn’t expect {:?}", other),
+    }
+    Ok(())
+}
+
+/// Clients from before the protocol was versioned can still ping.
+#[test]
+pub fn unversioned_clients_can_ping() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let socket_path = SocketPath::from(p);
+    let listener = listener::Listener::new(&socket_path).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let accept_handle = thread::spawn(move || {
+        listener
+            .accept(move |unix_stream, comm_type| match comm_type {
+                CommunicationType::Ping => {
+                    let ping: Ping = ReadWriter::<Ping, ()>::new(&unix_stream)
+                        .read(&Timeout::from_millis(100))
+                        .unwrap();
+                    tx.send(ping.nix_file).unwrap();
+                }
+                CommunicationType::Status
+                | CommunicationType::Logs
+                | CommunicationType::Subscribe
+                | CommunicationType::Forget
+                | CommunicationType::Shell
+                | CommunicationType::Upgrade => {
+                    panic!("only expected a ping")
+                }
+            })
+            .unwrap()
+    });
+
+    let socket = socket_path.connect()?;
+    let mut rw = ReadWriter::<(), CommunicationType>::new(&socket);
+    rw.write(&Timeout::from_millis(100), &CommunicationType::Ping)
+        .unwrap();
+    ReadWriter::<(), Ping>::new(&socket)
+        .write(
+            &Timeout::from_millis(100),
+            &Ping {
+                nix_file: NixFile::from(PathBuf::from("/who/cares")),
+            },
+        )
+        .unwrap();
+
+    accept_handle.join().unwrap().join().unwrap();
+    assert_eq!(
+        rx.recv_timeout(Duration::from_millis(100)).unwrap(),
+        NixFile::from(PathBuf::from("/who/cares"))
+    );
+    Ok(())
+}
+
+/// Subscribers first get the recent events of the project they
+/// are interested in, then the new ones.
+#[test]
+pub fn subscribers_get_replayed_events() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let socket_path = SocketPath::from(p);
+    let listener = listener::Listener::new(&socket_path).unwrap();
+
+    let paths = Paths::below(tempdir.path());
+    let (mut daemon, build_events_rx) = ::lorri::daemon::Daemon::new(&paths, Default::default());
+    let nix_file = allowed_nix_file(&paths, tempdir.path());
+    daemon.add(nix_file.clone());
+    // the build started before anyone subscribed
+    build_events_rx
+        .recv_timeout(Duration::from_millis(100))
+        .unwrap();
+
+    let handlers = daemon.handlers();
+    let accept_handle = thread::spawn(move || {
+        listener
+            .accept(move |unix_stream, comm_type| match comm_type {
+                CommunicationType::Subscribe => handlers.subscribe(ReadWriter::new(&unix_stream)),
+                _ => panic!("only expected a subscription"),
+            })
+            .unwrap()
+    });
+
+    let subscription = client::subscribe(Timeout::from_millis(1000))
+        .connect(&socket_path)
+        .unwrap();
+    subscription
+        .write(&SubscribeRequest {
+            nix_file: Some(nix_file.clone()),
+        })
+        .unwrap();
+    let project_event = subscription.read().unwrap();
+    assert_eq!(project_event.nix_file, nix_file);
+    match project_event.event {
+        build_loop::Event::Started(_, _) => {}
+        ev => panic!("didn’t expect event {:?}", ev),
+    }
+
+    drop(subscription);
+    drop(accept_handle);
+    Ok(())
+}
+
+/// Forgetting a project stops its `BuildLoop` and answers the client
+/// once the daemon is done.
+#[test]
+pub fn forgotten_projects_stop_building() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let socket_path = SocketPath::from(p);
+    let listener = listener::Listener::new(&socket_path).unwrap();
+
+    let paths = Paths::below(tempdir.path());
+    let (mut daemon, build_events_rx) = ::lorri::daemon::Daemon::new(&paths, Default::default());
+    let nix_file = allowed_nix_file(&paths, tempdir.path());
+    daemon.add(nix_file.clone());
+    build_events_rx
+        .recv_timeout(Duration::from_millis(100))
+        .unwrap();
+
+    let (forget_tx, forget_rx) = mpsc::channel();
+    let handlers = daemon.handlers();
+    let accept_handle = thread::spawn(move || {
+        listener
+            .accept(move |unix_stream, comm_type| match comm_type {
+                CommunicationType::Forget => {
+                    handlers.forget(ReadWriter::new(&unix_stream), forget_tx)
+                }
+                _ => panic!("only expected a forget request"),
+            })
+            .unwrap()
+    });
+
+    let client = client::forget(Timeout::from_millis(1000))
+        .connect(&socket_path)
+        .unwrap();
+    client
+        .write(&ForgetRequest {
+            nix_file: nix_file.clone(),
+            delete_roots: false,
+        })
+        .unwrap();
+    let forget: ForgetProject = forget_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    forget.done.send(daemon.forget(&forget.request)).unwrap();
+    let response = client.read().unwrap();
+    assert!(response.was_watched);
+    assert!(!response.roots_deleted);
+
+    // forgetting twice is harmless
+    assert!(
+        !daemon
+            .forget(&ForgetRequest {
+                nix_file,
+                delete_roots: false,
+            })
+            .was_watched
+    );
+
+    accept_handle.join().unwrap().join().unwrap();
+    Ok(())
+}
+
+/// A `lorri shell` learns the state of its project, and the daemon
+/// is told to build the project in case it doesn’t yet.
+#[test]
+pub fn shells_get_the_state_of_their_project() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let socket_path = SocketPath::from(p);
+    let listener = listener::Listener::new(&socket_path).unwrap();
+
+    let paths = Paths::below(tempdir.path());
+    let (mut daemon, build_events_rx) = ::lorri::daemon::Daemon::new(&paths, Default::default());
+    let nix_file = allowed_nix_file(&paths, tempdir.path());
+    daemon.add(nix_file.clone());
+    build_events_rx
+        .recv_timeout(Duration::from_millis(100))
+        .unwrap();
+
+    let (accept_messages_tx, accept_messages_rx) = mpsc::channel();
+    let handlers = daemon.handlers();
+    let accept_handle = thread::spawn(move || {
+        listener
+            .accept(move |unix_stream, comm_type| match comm_type {
+                CommunicationType::Shell => {
+                    handlers.shell(ReadWriter::new(&unix_stream), accept_messages_tx)
+                }
+                _ => panic!("only expected a shell"),
+            })
+            .unwrap()
+    });
+
+    let client = client::shell(Timeout::from_millis(1000))
+        .connect(&socket_path)
+        .unwrap();
+    client
+        .write(&ShellRequest {
+            nix_file: nix_file.clone(),
+        })
+        .unwrap();
+    match client.read().unwrap() {
+        // the build of `{}` fails soon
+        ShellMessage::State(BuildState::Building) | ShellMessage::State(BuildState::Failure) => {}
+        other => panic!("didn’t expect {:?}", other),
+    }
+    let activity = accept_messages_rx
+        .recv_timeout(Duration::from_millis(100))
+        .unwrap();
+    assert_eq!(activity.nix_file, nix_file);
+
+    drop(client);
+    drop(accept_handle);
+    Ok(())
+}
+
+/// Upgrade requests are passed to the daemon, and its answer is sent
+/// back before the daemon replaces itself.
+#[test]
+pub fn upgrade_requests_are_answered() -> std::io::Result<()> {
+    let tempdir = tempfile::tempdir()?;
+    let p = &tempdir.path().join("socket");
+    let socket_path = SocketPath::from(p);
+    let listener = listener::Listener::new(&socket_path).unwrap();
+
+    let paths = Paths::below(tempdir.path());
+    let (daemon, _build_events_rx) = ::lorri::daemon::Daemon::new(&paths, Default::default());
+    let (upgrade_tx, upgrade_rx) = mpsc::channel();
+    let handlers = daemon.handlers();
+    let accept_handle = thread::spawn(move || {
+        listener
+            .accept(move |unix_stream, comm_type| match comm_type {
+                CommunicationType::Upgrade => {
+                    handlers.upgrade(ReadWriter::new(&unix_stream), upgrade_tx)
+                }
+                _ => panic!("only expected an upgrade request"),
+            })
+            .unwrap()
+    });
+
+    let client = client::upgrade(Timeout::from_millis(1000))
+        .connect(&socket_path)
+        .unwrap();
+    client
+        .write(&UpgradeRequest {
+            executable: PathBuf::from("/new/lorri"),
+        })
+        .unwrap();
+    let upgrade: UpgradeDaemon = upgrade_rx.recv_timeout(Duration::from_secs(1)).unwrap();
+    assert_eq!(upgrade.request.executable, PathBuf::from("/new/lorri"));
+    let response = UpgradeResponse {
+        projects: 0,
+        error: None,
+    };
+    upgrade.done.send(response.clone()).unwrap();
+    assert_eq!(client.read().unwrap(), response);
+    // the daemon may go ahead once the client has its answer
+    assert_eq!(
+        upgrade.answered.recv_timeout(Duration::from_secs(1)),
+        Err(mpsc::RecvTimeoutError::Disconnected)
+    );
+
+    accept_handle.join().unwrap().join().unwrap();
+    Ok(())
+}
+
 #[test]
 pub fn start_two_listeners_on_same_socket() -> std::io::Result<()> {
     let tempdir = tempfile::tempdir()?;
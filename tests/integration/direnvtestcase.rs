@@ -4,6 +4,7 @@
 use direnv::DirenvEnv;
 use lorri::{
     build_loop::{BuildError, BuildLoop, BuildResults},
+    config::ProjectConfig,
     ops::direnv,
     project::Project,
     roots::Roots,
@@ -34,7 +35,11 @@ impl DirenvTestCase {
         let pdpath = projectdir.path().to_owned();
         let project = Project::new(&shell_file, &pdpath);
 
-        let build_loop = BuildLoop::new(shell_file.clone(), Roots::from_project(&project).unwrap());
+        let build_loop = BuildLoop::new(
+            shell_file.clone(),
+            Roots::from_project(&project).unwrap(),
+            ProjectConfig::default(),
+        );
 
         DirenvTestCase {
             shell_file: shell_file.clone(),